@@ -39,8 +39,9 @@ end
 
 a = print(nil, false)
 print(a)
--- c = coroutine.create(function (...)
---     print("from coroutine")
--- end)
+c = coroutine.create(function (...)
+    print("from coroutine", ...)
+end)
+coroutine.resume(c, 1, 2)
 
 
@@ -0,0 +1,1671 @@
+use std::{
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+use crate::{
+    interpreter::{
+        value::{LuaString, Value},
+        LuaError,
+    },
+    parser::{
+        expression::{Expression, Field},
+        prefix_expression::{
+            Argument, CallSuffix, PExprAction, PrefixExpression, Primary, Selector,
+        },
+        statement::{
+            Block, FunctionName, If, LocalVariable, Parameters, Return, Statement, Variable,
+        },
+    },
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOperator {
+    Addition,
+    Subtraction,
+    Multiplication,
+    Division,
+    IntegerDivision,
+    Modulo,
+    Exponentiation,
+    Concatenation,
+    BitwiseAnd,
+    BitwiseOr,
+    BitwiseXor,
+    BitwiseLeftShift,
+    BitwiseRightShift,
+    Equals,
+    Different,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOperator {
+    Negation,
+    BooleanNegation,
+    BitwiseNegation,
+    Length,
+}
+
+/// Instructions of a compiled function.
+///
+/// Every function works on its own window of the value stack, in which locals
+/// occupy the first slots in declaration order and temporaries are pushed above
+/// them. Slots are relative to the start of that window. Locals captured by
+/// inner functions live in cells instead, so closures can share them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instruction {
+    Nil,
+    True,
+    False,
+    Constant(usize),
+    /// Drops every value above the given slot.
+    Truncate(usize),
+    Local(usize),
+    SetLocal(usize),
+    Cell(usize),
+    SetCell(usize),
+    /// Moves the value of a local into a fresh cell.
+    NewCell {
+        cell: usize,
+        slot: usize,
+    },
+    Upvalue(usize),
+    SetUpvalue(usize),
+    /// Indexes an upvalue with a constant key, like the global variables in
+    /// `_ENV`.
+    UpvalueField {
+        upvalue: usize,
+        key: usize,
+    },
+    SetUpvalueField {
+        upvalue: usize,
+        key: usize,
+    },
+    Index,
+    Field(usize),
+    /// Replaces an object by its method and the object itself.
+    Method(usize),
+    SetIndex {
+        table: usize,
+        key: usize,
+    },
+    SetField {
+        table: usize,
+        key: usize,
+    },
+    NewTable,
+    /// Stores every value above the table slot in its array part.
+    SetList {
+        table: usize,
+    },
+    Closure(usize),
+    /// Pushes the given number of variable arguments, or all of them.
+    VarArg(Option<usize>),
+    /// Calls the function at the slot with every value above it, keeping the
+    /// given number of results, or all of them.
+    Call {
+        func: usize,
+        results: Option<usize>,
+    },
+    /// Calls the function at the slot in place of the current function, which
+    /// returns whatever the callee returns.
+    TailCall {
+        func: usize,
+    },
+    /// Returns every value from the slot up.
+    Return {
+        first: usize,
+    },
+    Jump(usize),
+    JumpIfFalse(usize),
+    /// Jumps keeping the value if it is falsy, otherwise drops it.
+    And(usize),
+    /// Jumps keeping the value if it is truthy, otherwise drops it.
+    Or(usize),
+    Binary(BinaryOperator),
+    Unary(UnaryOperator),
+    /// Marks a local as to-be-closed.
+    MarkClose(usize),
+    /// Closes every to-be-closed local from the slot up.
+    Close(usize),
+    NumericForPrepare {
+        base: usize,
+        exit: usize,
+    },
+    NumericForLoop {
+        base: usize,
+        body: usize,
+    },
+    GenericForLoop {
+        base: usize,
+        body: usize,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capture {
+    Cell(usize),
+    Upvalue(usize),
+}
+
+#[derive(Debug, Default)]
+pub struct Proto {
+    pub name: String,
+    pub code: Vec<Instruction>,
+    pub constants: Vec<Value>,
+    pub protos: Vec<Rc<Proto>>,
+    pub captures: Vec<Capture>,
+    pub parameters: usize,
+    pub is_vararg: bool,
+    pub cells: usize,
+    /// What the values used by some instructions are, for error messages.
+    pub descriptions: HashMap<usize, String>,
+}
+
+/// Compiles a chunk into the prototype of a vararg function.
+pub fn compile(block: &Block, name: &str) -> Result<Rc<Proto>, LuaError> {
+    let mut analysis = CaptureAnalysis::default();
+    analysis.block(block);
+    let mut compiler = Compiler {
+        functions: vec![],
+        captured: analysis.captured,
+    };
+    let parameters = Parameters {
+        name_list: vec![],
+        var_arg: true,
+    };
+    let proto = compiler.function(name, &parameters, None, block)?;
+    Ok(Rc::new(proto))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Attribute {
+    None,
+    Const,
+    Close,
+}
+
+#[derive(Debug)]
+struct Local {
+    name: String,
+    cell: Option<usize>,
+    attribute: Attribute,
+}
+
+#[derive(Debug)]
+struct Upvalue {
+    name: String,
+    attribute: Attribute,
+}
+
+#[derive(Debug)]
+struct Label {
+    name: String,
+    pc: usize,
+    locals: usize,
+}
+
+#[derive(Debug)]
+struct Goto {
+    name: String,
+    pc: usize,
+    locals: usize,
+}
+
+#[derive(Debug)]
+struct Scope {
+    locals: usize,
+    is_loop: bool,
+    breaks: Vec<usize>,
+    labels: Vec<Label>,
+    gotos: Vec<Goto>,
+}
+
+#[derive(Debug, Default)]
+struct Function {
+    proto: Proto,
+    locals: Vec<Local>,
+    upvalues: Vec<Upvalue>,
+    scopes: Vec<Scope>,
+    depth: usize,
+    strings: HashMap<LuaString, usize>,
+}
+
+enum Resolved {
+    Local(usize),
+    Cell(usize),
+    Upvalue(usize),
+    Global,
+}
+
+enum Target {
+    Local(usize),
+    Cell(usize),
+    Upvalue(usize),
+    UpvalueField { upvalue: usize, key: usize },
+    Index { table: usize, key: usize },
+    Field { table: usize, key: usize },
+}
+
+struct Compiler {
+    functions: Vec<Function>,
+    captured: HashSet<*const String>,
+}
+
+impl Compiler {
+    fn current(&mut self) -> &mut Function {
+        self.functions.last_mut().unwrap()
+    }
+
+    fn pc(&self) -> usize {
+        self.functions.last().unwrap().proto.code.len()
+    }
+
+    fn depth(&self) -> usize {
+        self.functions.last().unwrap().depth
+    }
+
+    fn set_depth(&mut self, depth: usize) {
+        self.current().depth = depth;
+    }
+
+    fn emit(&mut self, instruction: Instruction) -> usize {
+        let function = self.current();
+        function.depth = match instruction {
+            Instruction::Nil
+            | Instruction::True
+            | Instruction::False
+            | Instruction::Constant(_)
+            | Instruction::Local(_)
+            | Instruction::Cell(_)
+            | Instruction::Upvalue(_)
+            | Instruction::UpvalueField { .. }
+            | Instruction::Method(_)
+            | Instruction::NewTable
+            | Instruction::Closure(_) => function.depth + 1,
+            Instruction::SetLocal(_)
+            | Instruction::SetCell(_)
+            | Instruction::SetUpvalue(_)
+            | Instruction::SetUpvalueField { .. }
+            | Instruction::Index
+            | Instruction::SetIndex { .. }
+            | Instruction::SetField { .. }
+            | Instruction::JumpIfFalse(_)
+            | Instruction::And(_)
+            | Instruction::Or(_)
+            | Instruction::Binary(_) => function.depth - 1,
+            Instruction::Truncate(depth) => depth,
+            Instruction::SetList { table } => table + 1,
+            Instruction::VarArg(Some(n)) => function.depth + n,
+            Instruction::Call { func, results } => func + results.unwrap_or(0),
+            _ => function.depth,
+        };
+        function.proto.code.push(instruction);
+        function.proto.code.len() - 1
+    }
+
+    fn patch(&mut self, pc: usize, target: usize) {
+        let instruction = &mut self.current().proto.code[pc];
+        *instruction = match *instruction {
+            Instruction::Jump(_) => Instruction::Jump(target),
+            Instruction::JumpIfFalse(_) => Instruction::JumpIfFalse(target),
+            Instruction::And(_) => Instruction::And(target),
+            Instruction::Or(_) => Instruction::Or(target),
+            Instruction::NumericForPrepare { base, .. } => {
+                Instruction::NumericForPrepare { base, exit: target }
+            }
+            i => unreachable!("Expected jump, found {:?}", i),
+        };
+    }
+
+    fn describe(&mut self, pc: usize, description: Option<String>) {
+        if let Some(description) = description {
+            self.current().proto.descriptions.insert(pc, description);
+        }
+    }
+
+    fn constant(&mut self, value: Value) -> usize {
+        let function = self.current();
+        if let Value::String(s) = &value {
+            if let Some(&index) = function.strings.get(s) {
+                return index;
+            }
+            function
+                .strings
+                .insert(s.clone(), function.proto.constants.len());
+        }
+        function.proto.constants.push(value);
+        function.proto.constants.len() - 1
+    }
+
+    fn string_constant(&mut self, s: impl AsRef<[u8]>) -> usize {
+        self.constant(Value::String(s.as_ref().into()))
+    }
+
+    fn function(
+        &mut self,
+        name: &str,
+        parameters: &Parameters,
+        method: Option<&String>,
+        body: &Block,
+    ) -> Result<Proto, LuaError> {
+        let mut function = Function::default();
+        function.proto.name = name.to_string();
+        function.proto.is_vararg = parameters.var_arg;
+        self.functions.push(function);
+        self.open_scope(false);
+        let mut names: Vec<_> = method.into_iter().map(|m| ("self", m)).collect();
+        names.extend(parameters.name_list.iter().map(|n| (n.as_str(), n)));
+        self.current().proto.parameters = names.len();
+        self.set_depth(names.len());
+        for (name, declaration) in names {
+            self.declare_local(name, declaration, Attribute::None);
+        }
+        self.block_contents(body, false)?;
+        let depth = self.depth();
+        self.emit(Instruction::Return { first: depth });
+        self.close_scope()?;
+        let mut function = self.functions.pop().unwrap();
+        if let Some(goto) = function
+            .scopes
+            .pop()
+            .and_then(|s| s.gotos.into_iter().next())
+        {
+            return Err(LuaError::new(format!(
+                "no visible label '{}' for goto",
+                goto.name
+            )));
+        }
+        Ok(function.proto)
+    }
+
+    /// Declares a local whose value was just pushed on top of the stack.
+    fn declare_local(&mut self, name: &str, declaration: *const String, attribute: Attribute) {
+        let slot = self.current().locals.len();
+        let cell = if self.captured.contains(&declaration) {
+            let function = self.current();
+            function.proto.cells += 1;
+            Some(function.proto.cells - 1)
+        } else {
+            None
+        };
+        self.current().locals.push(Local {
+            name: name.to_string(),
+            cell,
+            attribute,
+        });
+        if let Some(cell) = cell {
+            self.emit(Instruction::NewCell { cell, slot });
+        }
+    }
+
+    /// Declares a local that is never visible to Lua code.
+    fn declare_hidden(&mut self, name: &str) {
+        self.current().locals.push(Local {
+            name: name.to_string(),
+            cell: None,
+            attribute: Attribute::None,
+        });
+    }
+
+    fn resolve(&mut self, name: &str) -> (Resolved, Attribute) {
+        let level = self.functions.len() - 1;
+        self.resolve_in(level, name)
+            .unwrap_or((Resolved::Global, Attribute::None))
+    }
+
+    fn resolve_in(&mut self, level: usize, name: &str) -> Option<(Resolved, Attribute)> {
+        let function = &self.functions[level];
+        if let Some((slot, local)) = function
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, l)| l.name == name)
+        {
+            let resolved = match local.cell {
+                Some(cell) => Resolved::Cell(cell),
+                None => Resolved::Local(slot),
+            };
+            return Some((resolved, local.attribute));
+        }
+        if let Some(index) = function.upvalues.iter().position(|u| u.name == name) {
+            return Some((Resolved::Upvalue(index), function.upvalues[index].attribute));
+        }
+        if level == 0 {
+            // Free names are fields of `_ENV`, the only upvalue of a chunk
+            if name != "_ENV" {
+                return None;
+            }
+            self.functions[0].upvalues.push(Upvalue {
+                name: name.to_string(),
+                attribute: Attribute::None,
+            });
+            return Some((Resolved::Upvalue(0), Attribute::None));
+        }
+        let (resolved, attribute) = self.resolve_in(level - 1, name)?;
+        let capture = match resolved {
+            Resolved::Cell(cell) => Capture::Cell(cell),
+            Resolved::Upvalue(index) => Capture::Upvalue(index),
+            Resolved::Local(_) => unreachable!("Captured local '{name}' is not in a cell"),
+            Resolved::Global => unreachable!("Expected variable, found global"),
+        };
+        let function = &mut self.functions[level];
+        function.proto.captures.push(capture);
+        function.upvalues.push(Upvalue {
+            name: name.to_string(),
+            attribute,
+        });
+        Some((Resolved::Upvalue(function.upvalues.len() - 1), attribute))
+    }
+
+    fn open_scope(&mut self, is_loop: bool) {
+        let locals = self.current().locals.len();
+        self.current().scopes.push(Scope {
+            locals,
+            is_loop,
+            breaks: vec![],
+            labels: vec![],
+            gotos: vec![],
+        });
+    }
+
+    /// Leaves the innermost scope, returning the jumps of its `break` statements.
+    fn close_scope(&mut self) -> Result<Vec<usize>, LuaError> {
+        let scope = self.current().scopes.pop().unwrap();
+        if self.has_to_be_closed(scope.locals) {
+            self.emit(Instruction::Close(scope.locals));
+        }
+        if self.depth() > scope.locals {
+            self.emit(Instruction::Truncate(scope.locals));
+        }
+        let function = self.current();
+        function.locals.truncate(scope.locals);
+        match function.scopes.last_mut() {
+            Some(outer) => {
+                for mut goto in scope.gotos {
+                    goto.locals = goto.locals.min(scope.locals);
+                    outer.gotos.push(goto);
+                }
+            }
+            // Gotos that are still pending are reported by the function
+            None => function.scopes.push(scope_with_gotos(scope.gotos)),
+        }
+        Ok(scope.breaks)
+    }
+
+    fn has_to_be_closed(&self, from: usize) -> bool {
+        self.functions.last().unwrap().locals[from..]
+            .iter()
+            .any(|l| l.attribute == Attribute::Close)
+    }
+
+    fn block(&mut self, block: &Block, is_loop: bool) -> Result<Vec<usize>, LuaError> {
+        self.open_scope(is_loop);
+        self.block_contents(block, false)?;
+        self.close_scope()
+    }
+
+    fn block_contents(&mut self, block: &Block, is_repeat: bool) -> Result<(), LuaError> {
+        for (i, statement) in block.statements.iter().enumerate() {
+            if let Statement::Label(name) = statement {
+                let is_last = !is_repeat
+                    && block.return_statement.is_none()
+                    && block.statements[(i + 1)..]
+                        .iter()
+                        .all(|s| matches!(s, Statement::Label(_) | Statement::Empty));
+                self.label(name, is_last)?;
+                continue;
+            }
+            self.statement(statement)?;
+        }
+        if let Some(Return(expr_list)) = &block.return_statement {
+            self.return_statement(expr_list.as_deref().unwrap_or(&[]))?;
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, statement: &Statement) -> Result<(), LuaError> {
+        match statement {
+            Statement::Empty | Statement::Label(_) => Ok(()),
+            Statement::LocalVariables {
+                variables,
+                expr_list,
+            } => self.local_variables(variables, expr_list.as_deref().unwrap_or(&[])),
+            Statement::Assignment {
+                variable_list,
+                expr_list,
+            } => self.assignment(variable_list, expr_list),
+            Statement::FunctionCall { prefix_exp, call } => {
+                let depth = self.depth();
+                self.prefix_expression(prefix_exp, Some(call), Some(0))?;
+                self.set_depth(depth);
+                Ok(())
+            }
+            Statement::Do(block) => self.block(block, false).map(|_| ()),
+            Statement::While { condition, block } => self.while_statement(condition, block),
+            Statement::Repeat { block, condition } => self.repeat(block, condition),
+            Statement::If { ifs, r#else } => self.if_statement(ifs, r#else.as_ref()),
+            Statement::NumericalFor {
+                control,
+                initial,
+                limit,
+                step,
+                block,
+            } => self.numerical_for(control, initial, limit, step.as_ref(), block),
+            Statement::GenericFor {
+                variables,
+                expr_list,
+                block,
+            } => self.generic_for(variables, expr_list, block),
+            Statement::FunctionDefinition {
+                function_name,
+                parameters,
+                body,
+            } => self.function_definition(function_name, parameters.as_ref(), body),
+            Statement::LocalFunctionDefinition {
+                name,
+                parameters,
+                body,
+            } => self.local_function_definition(name, parameters.as_ref(), body),
+            Statement::Break => self.break_statement(),
+            Statement::Goto(name) => self.goto(name),
+        }
+    }
+
+    fn local_variables(
+        &mut self,
+        variables: &[LocalVariable],
+        expr_list: &[Expression],
+    ) -> Result<(), LuaError> {
+        let mut attributes = vec![];
+        for variable in variables {
+            let attribute = match variable.attribute.as_deref() {
+                None => Attribute::None,
+                Some("const") => Attribute::Const,
+                Some("close") => Attribute::Close,
+                Some(other) => {
+                    return Err(LuaError::new(format!("unknown attribute '{other}'")));
+                }
+            };
+            attributes.push(attribute);
+        }
+        if attributes
+            .iter()
+            .filter(|&&a| a == Attribute::Close)
+            .count()
+            > 1
+        {
+            return Err(LuaError::new(
+                "multiple to-be-closed variables in local list",
+            ));
+        }
+        self.expression_list(expr_list, Some(variables.len()))?;
+        for (variable, attribute) in variables.iter().zip(attributes) {
+            let slot = self.current().locals.len();
+            self.declare_local(&variable.name, &variable.name, attribute);
+            if attribute == Attribute::Close {
+                let pc = self.emit(Instruction::MarkClose(slot));
+                self.describe(pc, Some(variable.name.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    fn assignment(
+        &mut self,
+        variable_list: &[Variable],
+        expr_list: &[Expression],
+    ) -> Result<(), LuaError> {
+        let depth = self.depth();
+        let mut targets = vec![];
+        for variable in variable_list {
+            let target = match variable {
+                Variable::Name(name) => self.name_target(name)?,
+                Variable::Selector {
+                    prefix_expr,
+                    selector,
+                } => {
+                    self.prefix_expression(prefix_expr, None, Some(1))?;
+                    let table = self.depth() - 1;
+                    match selector {
+                        Selector::Dot(name) => Target::Field {
+                            table,
+                            key: self.string_constant(name),
+                        },
+                        Selector::Key(key) => {
+                            self.expression(key)?;
+                            Target::Index {
+                                table,
+                                key: table + 1,
+                            }
+                        }
+                    }
+                }
+            };
+            targets.push(target);
+        }
+        self.expression_list(expr_list, Some(targets.len()))?;
+        for target in targets.into_iter().rev() {
+            self.assign(target);
+        }
+        if self.depth() > depth {
+            self.emit(Instruction::Truncate(depth));
+        }
+        Ok(())
+    }
+
+    fn name_target(&mut self, name: &str) -> Result<Target, LuaError> {
+        let (resolved, attribute) = self.resolve(name);
+        if attribute != Attribute::None {
+            return Err(LuaError::new(format!(
+                "attempt to assign to const variable '{name}'"
+            )));
+        }
+        Ok(match resolved {
+            Resolved::Local(slot) => Target::Local(slot),
+            Resolved::Cell(cell) => Target::Cell(cell),
+            Resolved::Upvalue(index) => Target::Upvalue(index),
+            Resolved::Global => {
+                let key = self.string_constant(name);
+                match self.resolve("_ENV").0 {
+                    Resolved::Upvalue(upvalue) => Target::UpvalueField { upvalue, key },
+                    environment => {
+                        self.push_variable(environment);
+                        Target::Field {
+                            table: self.depth() - 1,
+                            key,
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    /// Assigns the value on top of the stack to the target.
+    fn assign(&mut self, target: Target) {
+        let instruction = match target {
+            Target::Local(slot) => Instruction::SetLocal(slot),
+            Target::Cell(cell) => Instruction::SetCell(cell),
+            Target::Upvalue(index) => Instruction::SetUpvalue(index),
+            Target::UpvalueField { upvalue, key } => Instruction::SetUpvalueField { upvalue, key },
+            Target::Index { table, key } => Instruction::SetIndex { table, key },
+            Target::Field { table, key } => Instruction::SetField { table, key },
+        };
+        self.emit(instruction);
+    }
+
+    fn while_statement(&mut self, condition: &Expression, block: &Block) -> Result<(), LuaError> {
+        let start = self.pc();
+        self.expression(condition)?;
+        let exit = self.emit(Instruction::JumpIfFalse(0));
+        let breaks = self.block(block, true)?;
+        self.emit(Instruction::Jump(start));
+        let end = self.pc();
+        self.patch(exit, end);
+        for pc in breaks {
+            self.patch(pc, end);
+        }
+        Ok(())
+    }
+
+    fn repeat(&mut self, block: &Block, condition: &Expression) -> Result<(), LuaError> {
+        let start = self.pc();
+        self.open_scope(true);
+        self.block_contents(block, true)?;
+        let locals = self.current().scopes.last().unwrap().locals;
+        let has_to_be_closed = self.has_to_be_closed(locals);
+        self.expression(condition)?;
+        if has_to_be_closed {
+            let again = self.emit(Instruction::JumpIfFalse(0));
+            let breaks = self.close_scope()?;
+            let exit = self.emit(Instruction::Jump(0));
+            self.patch(again, self.pc());
+            self.emit(Instruction::Close(locals));
+            self.emit(Instruction::Truncate(locals));
+            self.emit(Instruction::Jump(start));
+            let end = self.pc();
+            for pc in breaks.into_iter().chain([exit]) {
+                self.patch(pc, end);
+            }
+        } else {
+            // The locals of the body are dropped before checking the result
+            let again = self.emit(Instruction::JumpIfFalse(0));
+            let depth = self.depth();
+            let breaks = self.close_scope()?;
+            let exit = self.emit(Instruction::Jump(0));
+            self.patch(again, self.pc());
+            if depth > locals {
+                self.emit(Instruction::Truncate(locals));
+            }
+            self.emit(Instruction::Jump(start));
+            let end = self.pc();
+            for pc in breaks.into_iter().chain([exit]) {
+                self.patch(pc, end);
+            }
+        }
+        Ok(())
+    }
+
+    fn if_statement(&mut self, ifs: &[If], r#else: Option<&Block>) -> Result<(), LuaError> {
+        let mut ends = vec![];
+        for (i, r#if) in ifs.iter().enumerate() {
+            self.expression(&r#if.condition)?;
+            let next = self.emit(Instruction::JumpIfFalse(0));
+            self.block(&r#if.block, false)?;
+            if i + 1 < ifs.len() || r#else.is_some() {
+                ends.push(self.emit(Instruction::Jump(0)));
+            }
+            self.patch(next, self.pc());
+        }
+        if let Some(block) = r#else {
+            self.block(block, false)?;
+        }
+        let end = self.pc();
+        for pc in ends {
+            self.patch(pc, end);
+        }
+        Ok(())
+    }
+
+    fn numerical_for(
+        &mut self,
+        control: &String,
+        initial: &Expression,
+        limit: &Expression,
+        step: Option<&Expression>,
+        block: &Block,
+    ) -> Result<(), LuaError> {
+        self.open_scope(false);
+        let base = self.depth();
+        self.expression(initial)?;
+        self.expression(limit)?;
+        match step {
+            Some(step) => self.expression(step)?,
+            None => {
+                let one = self.constant(Value::Integer(1));
+                self.emit(Instruction::Constant(one));
+            }
+        }
+        for name in ["(for state)", "(for limit)", "(for step)"] {
+            self.declare_hidden(name);
+        }
+        let prepare = self.emit(Instruction::NumericForPrepare { base, exit: 0 });
+        let body = self.pc();
+        self.set_depth(base + 4);
+        self.open_scope(true);
+        self.declare_local(control, control, Attribute::None);
+        self.block_contents(block, false)?;
+        let breaks = self.close_scope()?;
+        self.emit(Instruction::NumericForLoop { base, body });
+        let end = self.pc();
+        self.patch(prepare, end);
+        for pc in breaks {
+            self.patch(pc, end);
+        }
+        self.set_depth(base + 3);
+        self.close_scope()?;
+        Ok(())
+    }
+
+    fn generic_for(
+        &mut self,
+        variables: &[String],
+        expr_list: &[Expression],
+        block: &Block,
+    ) -> Result<(), LuaError> {
+        self.open_scope(false);
+        let base = self.depth();
+        self.expression_list(expr_list, Some(4))?;
+        for name in ["(for state)", "(for state)", "(for state)"] {
+            self.declare_hidden(name);
+        }
+        self.current().locals.push(Local {
+            name: "(for state)".to_string(),
+            cell: None,
+            attribute: Attribute::Close,
+        });
+        let mark = self.emit(Instruction::MarkClose(base + 3));
+        self.describe(mark, Some("(for state)".to_string()));
+        let call = self.emit(Instruction::Jump(0));
+        let body = self.pc();
+        self.set_depth(base + 4 + variables.len());
+        self.open_scope(true);
+        for variable in variables {
+            self.declare_local(variable, variable, Attribute::None);
+        }
+        // The variables were already pushed by the call of the iterator
+        self.set_depth(base + 4 + variables.len());
+        self.block_contents(block, false)?;
+        let breaks = self.close_scope()?;
+        self.patch(call, self.pc());
+        for slot in base..(base + 3) {
+            self.emit(Instruction::Local(slot));
+        }
+        self.emit(Instruction::Call {
+            func: base + 4,
+            results: Some(variables.len()),
+        });
+        self.emit(Instruction::GenericForLoop { base, body });
+        let end = self.pc();
+        for pc in breaks {
+            self.patch(pc, end);
+        }
+        self.set_depth(base + 4);
+        self.close_scope()?;
+        Ok(())
+    }
+
+    fn function_definition(
+        &mut self,
+        function_name: &FunctionName,
+        parameters: Option<&Parameters>,
+        body: &Block,
+    ) -> Result<(), LuaError> {
+        let FunctionName { names, method } = function_name;
+        let mut full_name = names.join(".");
+        if let Some(method) = method {
+            full_name = format!("{full_name}:{method}");
+        }
+        let depth = self.depth();
+        if names.len() == 1 && method.is_none() {
+            let target = self.name_target(&names[0])?;
+            self.closure(&full_name, parameters, None, body)?;
+            self.assign(target);
+            if self.depth() > depth {
+                self.emit(Instruction::Truncate(depth));
+            }
+            return Ok(());
+        }
+        self.variable(&names[0]);
+        let last = if method.is_some() {
+            names.len()
+        } else {
+            names.len() - 1
+        };
+        for name in &names[1..last] {
+            let key = self.string_constant(name);
+            self.emit(Instruction::Field(key));
+        }
+        let key = match method {
+            Some(method) => self.string_constant(method),
+            None => self.string_constant(&names[last]),
+        };
+        self.closure(&full_name, parameters, method.as_ref(), body)?;
+        self.emit(Instruction::SetField { table: depth, key });
+        self.emit(Instruction::Truncate(depth));
+        Ok(())
+    }
+
+    fn local_function_definition(
+        &mut self,
+        name: &String,
+        parameters: Option<&Parameters>,
+        body: &Block,
+    ) -> Result<(), LuaError> {
+        self.emit(Instruction::Nil);
+        self.declare_local(name, name, Attribute::None);
+        let (target, _) = self.resolve(name);
+        self.closure(name, parameters, None, body)?;
+        match target {
+            Resolved::Local(slot) => self.emit(Instruction::SetLocal(slot)),
+            Resolved::Cell(cell) => self.emit(Instruction::SetCell(cell)),
+            _ => unreachable!("Expected local function"),
+        };
+        Ok(())
+    }
+
+    fn closure(
+        &mut self,
+        name: &str,
+        parameters: Option<&Parameters>,
+        method: Option<&String>,
+        body: &Block,
+    ) -> Result<(), LuaError> {
+        let default = Parameters {
+            name_list: vec![],
+            var_arg: false,
+        };
+        let proto = self.function(name, parameters.unwrap_or(&default), method, body)?;
+        let function = self.current();
+        function.proto.protos.push(Rc::new(proto));
+        let index = function.proto.protos.len() - 1;
+        self.emit(Instruction::Closure(index));
+        Ok(())
+    }
+
+    fn break_statement(&mut self) -> Result<(), LuaError> {
+        let Some(scope) = self.current().scopes.iter().rev().find(|s| s.is_loop) else {
+            return Err(LuaError::new("break outside a loop"));
+        };
+        let locals = scope.locals;
+        if self.has_to_be_closed(locals) {
+            self.emit(Instruction::Close(locals));
+        }
+        let depth = self.depth();
+        self.emit(Instruction::Truncate(locals));
+        let pc = self.emit(Instruction::Jump(0));
+        self.set_depth(depth);
+        let scope = self.current().scopes.iter_mut().rev().find(|s| s.is_loop);
+        scope.unwrap().breaks.push(pc);
+        Ok(())
+    }
+
+    fn label(&mut self, name: &str, is_last: bool) -> Result<(), LuaError> {
+        let function = self.current();
+        let is_repeated = function
+            .scopes
+            .iter()
+            .flat_map(|s| s.labels.iter())
+            .any(|l| l.name == name);
+        if is_repeated {
+            return Err(LuaError::new(format!("label '{name}' already defined")));
+        }
+        let scope = function.scopes.last().unwrap();
+        let locals = if is_last {
+            scope.locals
+        } else {
+            function.locals.len()
+        };
+        let pc = function.proto.code.len();
+        let scope = function.scopes.last_mut().unwrap();
+        scope.labels.push(Label {
+            name: name.to_string(),
+            pc,
+            locals,
+        });
+        let (resolved, pending) = std::mem::take(&mut scope.gotos)
+            .into_iter()
+            .partition(|g| g.name == name);
+        scope.gotos = pending;
+        for goto in resolved {
+            if goto.locals < locals {
+                let local = &self.current().locals[goto.locals].name;
+                return Err(LuaError::new(format!(
+                    "<goto {name}> jumps into the scope of local '{local}'"
+                )));
+            }
+            let code = &mut self.current().proto.code;
+            code[goto.pc] = Instruction::Close(locals);
+            code[goto.pc + 1] = Instruction::Truncate(locals);
+            code[goto.pc + 2] = Instruction::Jump(pc);
+        }
+        Ok(())
+    }
+
+    fn goto(&mut self, name: &str) -> Result<(), LuaError> {
+        let function = self.current();
+        let label = function
+            .scopes
+            .iter()
+            .rev()
+            .flat_map(|s| s.labels.iter())
+            .find(|l| l.name == name);
+        let depth = function.depth;
+        if let Some(&Label { pc, locals, .. }) = label {
+            if self.has_to_be_closed(locals) {
+                self.emit(Instruction::Close(locals));
+            }
+            self.emit(Instruction::Truncate(locals));
+            self.emit(Instruction::Jump(pc));
+        } else {
+            let locals = function.locals.len();
+            let pc = self.emit(Instruction::Close(0));
+            self.emit(Instruction::Truncate(0));
+            self.emit(Instruction::Jump(0));
+            let scope = self.current().scopes.last_mut().unwrap();
+            scope.gotos.push(Goto {
+                name: name.to_string(),
+                pc,
+                locals,
+            });
+        }
+        self.set_depth(depth);
+        Ok(())
+    }
+
+    fn return_statement(&mut self, expr_list: &[Expression]) -> Result<(), LuaError> {
+        let first = self.depth();
+        self.expression_list(expr_list, None)?;
+        // Calls in a return are tail calls, unless there are variables to close
+        // once they return
+        let last = self.pc() - 1;
+        if let (
+            [_],
+            Instruction::Call {
+                func,
+                results: None,
+            },
+        ) = (expr_list, self.current().proto.code[last])
+        {
+            if !self.has_to_be_closed(0) {
+                self.current().proto.code[last] = Instruction::TailCall { func };
+            }
+        }
+        self.emit(Instruction::Return { first });
+        self.set_depth(first);
+        Ok(())
+    }
+
+    /// Pushes the values of the expressions adjusted to `wanted`, or all of
+    /// them if `wanted` is `None`.
+    fn expression_list(
+        &mut self,
+        expr_list: &[Expression],
+        wanted: Option<usize>,
+    ) -> Result<(), LuaError> {
+        let depth = self.depth();
+        let Some((last, rest)) = expr_list.split_last() else {
+            for _ in 0..wanted.unwrap_or(0) {
+                self.emit(Instruction::Nil);
+            }
+            return Ok(());
+        };
+        for expression in rest {
+            self.expression(expression)?;
+        }
+        if is_multiple(last) {
+            let results = wanted.map(|n| n.saturating_sub(rest.len()));
+            self.multiple(last, results)?;
+        } else {
+            self.expression(last)?;
+        }
+        if let Some(wanted) = wanted {
+            for _ in expr_list.len()..wanted {
+                self.emit(Instruction::Nil);
+            }
+            if self.depth() > depth + wanted {
+                self.emit(Instruction::Truncate(depth + wanted));
+            }
+        }
+        Ok(())
+    }
+
+    /// Compiles a call or a vararg expression, keeping the given number of
+    /// values, or all of them.
+    fn multiple(
+        &mut self,
+        expression: &Expression,
+        results: Option<usize>,
+    ) -> Result<(), LuaError> {
+        match expression {
+            Expression::VarArg => {
+                self.check_vararg()?;
+                self.emit(Instruction::VarArg(results));
+            }
+            Expression::PrefixExpression(prefix_expr) => {
+                self.prefix_expression(prefix_expr, None, results)?;
+            }
+            _ => unreachable!("Expected multiple results, found {:?}", expression),
+        }
+        Ok(())
+    }
+
+    fn check_vararg(&mut self) -> Result<(), LuaError> {
+        if self.current().proto.is_vararg {
+            Ok(())
+        } else {
+            Err(LuaError::new("cannot use '...' outside a vararg function"))
+        }
+    }
+
+    /// Pushes exactly one value.
+    fn expression(&mut self, expression: &Expression) -> Result<(), LuaError> {
+        match expression {
+            Expression::Nil => {
+                self.emit(Instruction::Nil);
+            }
+            Expression::True => {
+                self.emit(Instruction::True);
+            }
+            Expression::False => {
+                self.emit(Instruction::False);
+            }
+            Expression::Integer(n) => {
+                let k = self.constant(Value::Integer(*n));
+                self.emit(Instruction::Constant(k));
+            }
+            Expression::Float(n) => {
+                let k = self.constant(Value::Float(*n));
+                self.emit(Instruction::Constant(k));
+            }
+            Expression::String(s) => {
+                let k = self.string_constant(s);
+                self.emit(Instruction::Constant(k));
+            }
+            Expression::VarArg => {
+                self.check_vararg()?;
+                self.emit(Instruction::VarArg(Some(1)));
+            }
+            Expression::Table(fields) => self.table(fields)?,
+            Expression::Lambda { parameters, body } => {
+                self.closure("anonymous", parameters.as_ref(), None, body)?
+            }
+            Expression::PrefixExpression(prefix_expr) => {
+                self.prefix_expression(prefix_expr, None, Some(1))?
+            }
+            Expression::Negation(e) => self.unary(UnaryOperator::Negation, e)?,
+            Expression::BooleanNegation(e) => self.unary(UnaryOperator::BooleanNegation, e)?,
+            Expression::BitwiseNegation(e) => self.unary(UnaryOperator::BitwiseNegation, e)?,
+            Expression::Length(e) => self.unary(UnaryOperator::Length, e)?,
+            Expression::BooleanAnd { lhs, rhs } => {
+                self.expression(lhs)?;
+                let jump = self.emit(Instruction::And(0));
+                self.expression(rhs)?;
+                self.patch(jump, self.pc());
+            }
+            Expression::BooleanOr { lhs, rhs } => {
+                self.expression(lhs)?;
+                let jump = self.emit(Instruction::Or(0));
+                self.expression(rhs)?;
+                self.patch(jump, self.pc());
+            }
+            Expression::Addition { lhs, rhs } => self.binary(BinaryOperator::Addition, lhs, rhs)?,
+            Expression::Subtraction { lhs, rhs } => {
+                self.binary(BinaryOperator::Subtraction, lhs, rhs)?
+            }
+            Expression::Multiplication { lhs, rhs } => {
+                self.binary(BinaryOperator::Multiplication, lhs, rhs)?
+            }
+            Expression::Division { lhs, rhs } => self.binary(BinaryOperator::Division, lhs, rhs)?,
+            Expression::IntegerDivision { lhs, rhs } => {
+                self.binary(BinaryOperator::IntegerDivision, lhs, rhs)?
+            }
+            Expression::Modulo { lhs, rhs } => self.binary(BinaryOperator::Modulo, lhs, rhs)?,
+            Expression::Exponentiation { lhs, rhs } => {
+                self.binary(BinaryOperator::Exponentiation, lhs, rhs)?
+            }
+            Expression::Concatenation { lhs, rhs } => {
+                self.binary(BinaryOperator::Concatenation, lhs, rhs)?
+            }
+            Expression::Equals { lhs, rhs } => self.binary(BinaryOperator::Equals, lhs, rhs)?,
+            Expression::Different { lhs, rhs } => {
+                self.binary(BinaryOperator::Different, lhs, rhs)?
+            }
+            Expression::Less { lhs, rhs } => self.binary(BinaryOperator::Less, lhs, rhs)?,
+            Expression::LessOrEqual { lhs, rhs } => {
+                self.binary(BinaryOperator::LessOrEqual, lhs, rhs)?
+            }
+            Expression::Greater { lhs, rhs } => self.binary(BinaryOperator::Greater, lhs, rhs)?,
+            Expression::GreaterOrEqual { lhs, rhs } => {
+                self.binary(BinaryOperator::GreaterOrEqual, lhs, rhs)?
+            }
+            Expression::BitwiseAnd { lhs, rhs } => {
+                self.binary(BinaryOperator::BitwiseAnd, lhs, rhs)?
+            }
+            Expression::BitwiseOr { lhs, rhs } => {
+                self.binary(BinaryOperator::BitwiseOr, lhs, rhs)?
+            }
+            Expression::BitwiseXor { lhs, rhs } => {
+                self.binary(BinaryOperator::BitwiseXor, lhs, rhs)?
+            }
+            Expression::BitwiseLeftShift { lhs, rhs } => {
+                self.binary(BinaryOperator::BitwiseLeftShift, lhs, rhs)?
+            }
+            Expression::BitwiseRightShift { lhs, rhs } => {
+                self.binary(BinaryOperator::BitwiseRightShift, lhs, rhs)?
+            }
+        }
+        Ok(())
+    }
+
+    fn unary(&mut self, operator: UnaryOperator, operand: &Expression) -> Result<(), LuaError> {
+        self.expression(operand)?;
+        self.emit(Instruction::Unary(operator));
+        Ok(())
+    }
+
+    fn binary(
+        &mut self,
+        operator: BinaryOperator,
+        lhs: &Expression,
+        rhs: &Expression,
+    ) -> Result<(), LuaError> {
+        self.expression(lhs)?;
+        self.expression(rhs)?;
+        self.emit(Instruction::Binary(operator));
+        Ok(())
+    }
+
+    fn table(&mut self, fields: &[Field]) -> Result<(), LuaError> {
+        let table = self.depth();
+        self.emit(Instruction::NewTable);
+        let mut has_items = false;
+        for (i, field) in fields.iter().enumerate() {
+            match field {
+                Field::ExprKey { key, value } => {
+                    let depth = self.depth();
+                    self.expression(key)?;
+                    self.expression(value)?;
+                    self.emit(Instruction::SetIndex { table, key: depth });
+                    self.emit(Instruction::Truncate(depth));
+                }
+                Field::NameKey { name, value } => {
+                    self.expression(value)?;
+                    let key = self.string_constant(name);
+                    self.emit(Instruction::SetField { table, key });
+                }
+                Field::Expr(expression) => {
+                    has_items = true;
+                    if i + 1 == fields.len() && is_multiple(expression) {
+                        self.multiple(expression, None)?;
+                    } else {
+                        self.expression(expression)?;
+                    }
+                }
+            }
+        }
+        if has_items {
+            self.emit(Instruction::SetList { table });
+        }
+        Ok(())
+    }
+
+    /// Pushes the value of a variable, returning its description.
+    fn variable(&mut self, name: &str) -> String {
+        let (resolved, _) = self.resolve(name);
+        let description = match resolved {
+            Resolved::Local(_) | Resolved::Cell(_) | Resolved::Upvalue(_) => {
+                self.variable_description(name)
+            }
+            Resolved::Global => {
+                let key = self.string_constant(name);
+                let pc = match self.resolve("_ENV").0 {
+                    Resolved::Upvalue(upvalue) => {
+                        self.emit(Instruction::UpvalueField { upvalue, key })
+                    }
+                    environment => {
+                        self.push_variable(environment);
+                        self.emit(Instruction::Field(key))
+                    }
+                };
+                let environment = self.variable_description("_ENV");
+                self.describe(pc, Some(environment));
+                return format!("global '{name}'");
+            }
+        };
+        self.push_variable(resolved);
+        description
+    }
+
+    fn variable_description(&mut self, name: &str) -> String {
+        match self.resolve(name).0 {
+            Resolved::Local(_) | Resolved::Cell(_) => format!("local '{name}'"),
+            Resolved::Upvalue(_) => format!("upvalue '{name}'"),
+            Resolved::Global => format!("global '{name}'"),
+        }
+    }
+
+    fn push_variable(&mut self, resolved: Resolved) {
+        let instruction = match resolved {
+            Resolved::Local(slot) => Instruction::Local(slot),
+            Resolved::Cell(cell) => Instruction::Cell(cell),
+            Resolved::Upvalue(index) => Instruction::Upvalue(index),
+            Resolved::Global => unreachable!("Expected variable, found global"),
+        };
+        self.emit(instruction);
+    }
+
+    /// Compiles a prefix expression, followed by `call` if given. If the
+    /// expression ends with a call, `results` values are kept, otherwise one.
+    fn prefix_expression(
+        &mut self,
+        prefix_expr: &PrefixExpression,
+        call: Option<&CallSuffix>,
+        results: Option<usize>,
+    ) -> Result<(), LuaError> {
+        let PrefixExpression { primary, actions } = prefix_expr;
+        let mut description = match primary {
+            Primary::Name(name) => Some(self.variable(name)),
+            Primary::Expression(expression) => {
+                self.expression(expression)?;
+                None
+            }
+        };
+        let count = actions.len() + call.iter().len();
+        let actions = actions
+            .iter()
+            .map(|action| match action {
+                PExprAction::Selector(selector) => Err(selector),
+                PExprAction::Call(call) => Ok(call),
+            })
+            .chain(call.map(Ok));
+        for (i, action) in actions.enumerate() {
+            let is_last = i + 1 == count;
+            match action {
+                Err(Selector::Dot(name)) => {
+                    let key = self.string_constant(name);
+                    let pc = self.emit(Instruction::Field(key));
+                    self.describe(pc, description);
+                    description = Some(format!("field '{name}'"));
+                }
+                Err(Selector::Key(key)) => {
+                    self.expression(key)?;
+                    let pc = self.emit(Instruction::Index);
+                    self.describe(pc, description);
+                    description = match key {
+                        Expression::String(s) => {
+                            Some(format!("field '{}'", String::from_utf8_lossy(s)))
+                        }
+                        _ => None,
+                    };
+                }
+                Ok(call) => {
+                    let results = if is_last { results } else { Some(1) };
+                    description = self.call(call, description, results)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn call(
+        &mut self,
+        call: &CallSuffix,
+        description: Option<String>,
+        results: Option<usize>,
+    ) -> Result<Option<String>, LuaError> {
+        let (argument, description) = match call {
+            CallSuffix::Simple(argument) => (argument, description),
+            CallSuffix::Method { name, argument } => {
+                let key = self.string_constant(name);
+                let pc = self.emit(Instruction::Method(key));
+                self.describe(pc, description);
+                (argument, Some(format!("method '{name}'")))
+            }
+        };
+        let func = match call {
+            CallSuffix::Simple(_) => self.depth() - 1,
+            CallSuffix::Method { .. } => self.depth() - 2,
+        };
+        match argument {
+            Argument::List(expr_list) => self.expression_list(expr_list, None)?,
+            Argument::String(s) => {
+                let k = self.string_constant(s);
+                self.emit(Instruction::Constant(k));
+            }
+            Argument::Table(table) => self.expression(table)?,
+        }
+        let pc = self.emit(Instruction::Call { func, results });
+        self.describe(pc, description);
+        Ok(None)
+    }
+}
+
+fn scope_with_gotos(gotos: Vec<Goto>) -> Scope {
+    Scope {
+        locals: 0,
+        is_loop: false,
+        breaks: vec![],
+        labels: vec![],
+        gotos,
+    }
+}
+
+fn is_multiple(expression: &Expression) -> bool {
+    match expression {
+        Expression::VarArg => true,
+        Expression::PrefixExpression(PrefixExpression { actions, .. }) => {
+            matches!(actions.last(), Some(PExprAction::Call(_)))
+        }
+        _ => false,
+    }
+}
+
+/// Finds the locals that are used by inner functions, identified by the
+/// address of their name in the syntax tree.
+#[derive(Default)]
+struct CaptureAnalysis<'a> {
+    variables: Vec<(&'a str, *const String, usize)>,
+    level: usize,
+    captured: HashSet<*const String>,
+}
+
+impl<'a> CaptureAnalysis<'a> {
+    fn declare(&mut self, name: &'a String) {
+        self.variables.push((name, name, self.level));
+    }
+
+    fn reference(&mut self, name: &str) {
+        let variable = self.variables.iter().rev().find(|(n, _, _)| *n == name);
+        match variable {
+            Some(&(_, declaration, level)) if level < self.level => {
+                self.captured.insert(declaration);
+            }
+            Some(_) => {}
+            None if name != "_ENV" => self.reference("_ENV"),
+            None => {}
+        }
+    }
+
+    fn block(&mut self, block: &'a Block) {
+        let mark = self.variables.len();
+        self.statements(block);
+        self.variables.truncate(mark);
+    }
+
+    fn statements(&mut self, block: &'a Block) {
+        for statement in block.statements.iter() {
+            self.statement(statement);
+        }
+        if let Some(Return(Some(expr_list))) = &block.return_statement {
+            self.expressions(expr_list);
+        }
+    }
+
+    fn function(
+        &mut self,
+        parameters: &'a Option<Parameters>,
+        method: Option<&'a String>,
+        body: &'a Block,
+    ) {
+        let mark = self.variables.len();
+        self.level += 1;
+        if let Some(method) = method {
+            self.variables.push(("self", method, self.level));
+        }
+        for name in parameters.iter().flat_map(|p| p.name_list.iter()) {
+            self.declare(name);
+        }
+        self.block(body);
+        self.level -= 1;
+        self.variables.truncate(mark);
+    }
+
+    fn statement(&mut self, statement: &'a Statement) {
+        match statement {
+            Statement::Empty | Statement::Label(_) | Statement::Break | Statement::Goto(_) => {}
+            Statement::Assignment {
+                variable_list,
+                expr_list,
+            } => {
+                for variable in variable_list {
+                    match variable {
+                        Variable::Name(name) => self.reference(name),
+                        Variable::Selector {
+                            prefix_expr,
+                            selector,
+                        } => {
+                            self.prefix_expression(prefix_expr);
+                            if let Selector::Key(key) = selector {
+                                self.expression(key);
+                            }
+                        }
+                    }
+                }
+                self.expressions(expr_list);
+            }
+            Statement::FunctionCall { prefix_exp, call } => {
+                self.prefix_expression(prefix_exp);
+                self.call(call);
+            }
+            Statement::Do(block) => self.block(block),
+            Statement::While { condition, block } => {
+                self.expression(condition);
+                self.block(block);
+            }
+            Statement::Repeat { block, condition } => {
+                let mark = self.variables.len();
+                self.statements(block);
+                self.expression(condition);
+                self.variables.truncate(mark);
+            }
+            Statement::If { ifs, r#else } => {
+                for r#if in ifs {
+                    self.expression(&r#if.condition);
+                    self.block(&r#if.block);
+                }
+                if let Some(block) = r#else {
+                    self.block(block);
+                }
+            }
+            Statement::NumericalFor {
+                control,
+                initial,
+                limit,
+                step,
+                block,
+            } => {
+                self.expression(initial);
+                self.expression(limit);
+                if let Some(step) = step {
+                    self.expression(step);
+                }
+                let mark = self.variables.len();
+                self.declare(control);
+                self.block(block);
+                self.variables.truncate(mark);
+            }
+            Statement::GenericFor {
+                variables,
+                expr_list,
+                block,
+            } => {
+                self.expressions(expr_list);
+                let mark = self.variables.len();
+                for variable in variables {
+                    self.declare(variable);
+                }
+                self.block(block);
+                self.variables.truncate(mark);
+            }
+            Statement::FunctionDefinition {
+                function_name,
+                parameters,
+                body,
+            } => {
+                self.reference(&function_name.names[0]);
+                self.function(parameters, function_name.method.as_ref(), body);
+            }
+            Statement::LocalFunctionDefinition {
+                name,
+                parameters,
+                body,
+            } => {
+                self.declare(name);
+                self.function(parameters, None, body);
+            }
+            Statement::LocalVariables {
+                variables,
+                expr_list,
+            } => {
+                if let Some(expr_list) = expr_list {
+                    self.expressions(expr_list);
+                }
+                for variable in variables {
+                    self.declare(&variable.name);
+                }
+            }
+        }
+    }
+
+    fn expressions(&mut self, expr_list: &'a [Expression]) {
+        for expression in expr_list {
+            self.expression(expression);
+        }
+    }
+
+    fn expression(&mut self, expression: &'a Expression) {
+        match expression {
+            Expression::Integer(_)
+            | Expression::Float(_)
+            | Expression::String(_)
+            | Expression::True
+            | Expression::False
+            | Expression::Nil
+            | Expression::VarArg => {}
+            Expression::Table(fields) => {
+                for field in fields {
+                    match field {
+                        Field::ExprKey { key, value } => {
+                            self.expression(key);
+                            self.expression(value);
+                        }
+                        Field::NameKey { value, .. } => self.expression(value),
+                        Field::Expr(value) => self.expression(value),
+                    }
+                }
+            }
+            Expression::Negation(e)
+            | Expression::BooleanNegation(e)
+            | Expression::BitwiseNegation(e)
+            | Expression::Length(e) => self.expression(e),
+            Expression::PrefixExpression(prefix_expr) => self.prefix_expression(prefix_expr),
+            Expression::Lambda { parameters, body } => self.function(parameters, None, body),
+            Expression::Addition { lhs, rhs }
+            | Expression::Subtraction { lhs, rhs }
+            | Expression::Multiplication { lhs, rhs }
+            | Expression::Division { lhs, rhs }
+            | Expression::IntegerDivision { lhs, rhs }
+            | Expression::Modulo { lhs, rhs }
+            | Expression::BooleanOr { lhs, rhs }
+            | Expression::BooleanAnd { lhs, rhs }
+            | Expression::Equals { lhs, rhs }
+            | Expression::Different { lhs, rhs }
+            | Expression::Greater { lhs, rhs }
+            | Expression::Less { lhs, rhs }
+            | Expression::GreaterOrEqual { lhs, rhs }
+            | Expression::LessOrEqual { lhs, rhs }
+            | Expression::BitwiseAnd { lhs, rhs }
+            | Expression::BitwiseOr { lhs, rhs }
+            | Expression::BitwiseXor { lhs, rhs }
+            | Expression::BitwiseLeftShift { lhs, rhs }
+            | Expression::BitwiseRightShift { lhs, rhs }
+            | Expression::Concatenation { lhs, rhs }
+            | Expression::Exponentiation { lhs, rhs } => {
+                self.expression(lhs);
+                self.expression(rhs);
+            }
+        }
+    }
+
+    fn prefix_expression(&mut self, prefix_expr: &'a PrefixExpression) {
+        match &prefix_expr.primary {
+            Primary::Name(name) => self.reference(name),
+            Primary::Expression(expression) => self.expression(expression),
+        }
+        for action in prefix_expr.actions.iter() {
+            match action {
+                PExprAction::Selector(Selector::Key(key)) => self.expression(key),
+                PExprAction::Selector(Selector::Dot(_)) => {}
+                PExprAction::Call(call) => self.call(call),
+            }
+        }
+    }
+
+    fn call(&mut self, call: &'a CallSuffix) {
+        let argument = match call {
+            CallSuffix::Simple(argument) => argument,
+            CallSuffix::Method { argument, .. } => argument,
+        };
+        match argument {
+            Argument::List(expr_list) => self.expressions(expr_list),
+            Argument::String(_) => {}
+            Argument::Table(table) => self.expression(table),
+        }
+    }
+}
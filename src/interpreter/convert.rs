@@ -0,0 +1,436 @@
+//! Conversions between Rust and Lua values, so functions written in Rust can
+//! take and return ordinary Rust types.
+
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    ops::{Deref, DerefMut},
+};
+
+use crate::std::{argument, argument_error, type_name};
+
+use super::{
+    value::{LuaString, Table, Value},
+    Interpreter, LuaError,
+};
+
+/// Why a Lua value could not be converted to a Rust one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConversionError {
+    /// The value is not of the type named, as in "number expected".
+    Expected(&'static str),
+    /// The value has the right type but not a usable value.
+    Invalid(String),
+}
+
+impl ConversionError {
+    /// The message of the error for a value of type `got`.
+    pub fn message(&self, got: &str) -> String {
+        match self {
+            ConversionError::Expected(expected) => format!("{expected} expected, got {got}"),
+            ConversionError::Invalid(message) => message.clone(),
+        }
+    }
+
+    /// The error of a value found at `place` inside another one, like the
+    /// element of a list.
+    fn inside(self, place: &str, value: &Value) -> Self {
+        match self {
+            ConversionError::Expected(expected) => ConversionError::Invalid(format!(
+                "{expected} expected {place}, got {}",
+                value.type_name()
+            )),
+            ConversionError::Invalid(message) => {
+                ConversionError::Invalid(format!("{message} {place}"))
+            }
+        }
+    }
+}
+
+/// A Rust type that can be made into a Lua value.
+pub trait IntoLua {
+    fn into_lua(self, interpreter: &mut Interpreter) -> Value;
+}
+
+/// A Rust type that can be made from a Lua value.
+pub trait FromLua: Sized {
+    fn from_lua(value: Value, interpreter: &mut Interpreter) -> Result<Self, ConversionError>;
+}
+
+/// Any number of values, as the results of a function. Single values and
+/// tuples convert to as many values, `Variadic` to as many as it holds.
+pub trait IntoLuaMulti {
+    fn into_lua_multi(self, interpreter: &mut Interpreter) -> Vec<Value>;
+}
+
+/// The arguments of a function. Single values take one argument, tuples one
+/// per element but the last, which can be a `Variadic` taking the rest.
+pub trait FromLuaMulti: Sized {
+    /// Converts the arguments from `position` on, counting from one, failing
+    /// with the error of a bad argument to the function `name`.
+    fn from_lua_multi(
+        arguments: &[Value],
+        position: usize,
+        name: &str,
+        interpreter: &mut Interpreter,
+    ) -> Result<Self, LuaError>;
+}
+
+/// Any number of values of the same type, such as the trailing arguments of
+/// a function or the results it gives.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Variadic<T>(pub Vec<T>);
+
+impl<T> Deref for Variadic<T> {
+    type Target = Vec<T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Variadic<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl<T> From<Vec<T>> for Variadic<T> {
+    fn from(values: Vec<T>) -> Self {
+        Variadic(values)
+    }
+}
+
+impl<T> FromIterator<T> for Variadic<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        Variadic(iter.into_iter().collect())
+    }
+}
+
+impl<T> IntoIterator for Variadic<T> {
+    type Item = T;
+    type IntoIter = std::vec::IntoIter<T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl IntoLua for Value {
+    fn into_lua(self, _interpreter: &mut Interpreter) -> Value {
+        self
+    }
+}
+
+impl FromLua for Value {
+    fn from_lua(value: Value, _interpreter: &mut Interpreter) -> Result<Self, ConversionError> {
+        Ok(value)
+    }
+}
+
+macro_rules! integer_conversions {
+    ($($t:ty),*) => {$(
+        impl IntoLua for $t {
+            fn into_lua(self, _interpreter: &mut Interpreter) -> Value {
+                match i64::try_from(self) {
+                    Ok(n) => Value::Integer(n),
+                    // Only the largest unsigned values do not fit
+                    Err(_) => Value::Float(self as f64),
+                }
+            }
+        }
+
+        impl FromLua for $t {
+            fn from_lua(
+                value: Value,
+                _interpreter: &mut Interpreter,
+            ) -> Result<Self, ConversionError> {
+                let Some(n) = value.to_integer() else {
+                    return Err(match value.to_number() {
+                        Some(_) => ConversionError::Invalid(
+                            "number has no integer representation".to_string(),
+                        ),
+                        None => ConversionError::Expected("number"),
+                    });
+                };
+                <$t>::try_from(n).map_err(|_| {
+                    ConversionError::Invalid(format!(
+                        "integer out of range for {}",
+                        stringify!($t)
+                    ))
+                })
+            }
+        }
+    )*};
+}
+
+integer_conversions!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+macro_rules! float_conversions {
+    ($($t:ty),*) => {$(
+        impl IntoLua for $t {
+            fn into_lua(self, _interpreter: &mut Interpreter) -> Value {
+                Value::Float(self as f64)
+            }
+        }
+
+        impl FromLua for $t {
+            fn from_lua(
+                value: Value,
+                _interpreter: &mut Interpreter,
+            ) -> Result<Self, ConversionError> {
+                match value.to_float() {
+                    Some(f) => Ok(f as $t),
+                    None => Err(ConversionError::Expected("number")),
+                }
+            }
+        }
+    )*};
+}
+
+float_conversions!(f32, f64);
+
+impl IntoLua for bool {
+    fn into_lua(self, _interpreter: &mut Interpreter) -> Value {
+        Value::from(self)
+    }
+}
+
+/// Any value converts to a boolean, `nil` and `false` being false.
+impl FromLua for bool {
+    fn from_lua(value: Value, _interpreter: &mut Interpreter) -> Result<Self, ConversionError> {
+        Ok(value.is_truthy())
+    }
+}
+
+impl IntoLua for LuaString {
+    fn into_lua(self, _interpreter: &mut Interpreter) -> Value {
+        Value::String(self)
+    }
+}
+
+/// Strings, and numbers converted to strings.
+impl FromLua for LuaString {
+    fn from_lua(value: Value, _interpreter: &mut Interpreter) -> Result<Self, ConversionError> {
+        value
+            .to_lua_string()
+            .ok_or(ConversionError::Expected("string"))
+    }
+}
+
+impl IntoLua for String {
+    fn into_lua(self, _interpreter: &mut Interpreter) -> Value {
+        Value::String(self.into())
+    }
+}
+
+impl IntoLua for &str {
+    fn into_lua(self, _interpreter: &mut Interpreter) -> Value {
+        Value::String(self.into())
+    }
+}
+
+/// Strings that are valid UTF-8, and numbers converted to strings.
+impl FromLua for String {
+    fn from_lua(value: Value, interpreter: &mut Interpreter) -> Result<Self, ConversionError> {
+        let s = LuaString::from_lua(value, interpreter)?;
+        match s.to_str() {
+            Some(s) => Ok(s.to_string()),
+            None => Err(ConversionError::Invalid(
+                "string is not valid UTF-8".to_string(),
+            )),
+        }
+    }
+}
+
+impl<T: IntoLua> IntoLua for Option<T> {
+    fn into_lua(self, interpreter: &mut Interpreter) -> Value {
+        match self {
+            Some(value) => value.into_lua(interpreter),
+            None => Value::Nil,
+        }
+    }
+}
+
+/// `nil` is `None`, anything else must convert to a `T`.
+impl<T: FromLua> FromLua for Option<T> {
+    fn from_lua(value: Value, interpreter: &mut Interpreter) -> Result<Self, ConversionError> {
+        match value {
+            Value::Nil => Ok(None),
+            value => T::from_lua(value, interpreter).map(Some),
+        }
+    }
+}
+
+/// A sequence, as a table with the keys `1..=n`.
+impl<T: IntoLua> IntoLua for Vec<T> {
+    fn into_lua(self, interpreter: &mut Interpreter) -> Value {
+        let mut table = Table::new();
+        for (i, value) in (1..).zip(self) {
+            let value = value.into_lua(interpreter);
+            table.insert(Value::Integer(i), value);
+        }
+        interpreter.new_table(table)
+    }
+}
+
+/// The sequence `1..=#t` of a table, read without metamethods.
+impl<T: FromLua> FromLua for Vec<T> {
+    fn from_lua(value: Value, interpreter: &mut Interpreter) -> Result<Self, ConversionError> {
+        let Value::Table(table) = value else {
+            return Err(ConversionError::Expected("table"));
+        };
+        let length = table.borrow().length();
+        let mut result = Vec::with_capacity(length as usize);
+        for i in 1..=length {
+            let element = table.borrow().get(&Value::Integer(i));
+            let converted = T::from_lua(element.clone(), interpreter)
+                .map_err(|error| error.inside(&format!("at index {i}"), &element))?;
+            result.push(converted);
+        }
+        Ok(result)
+    }
+}
+
+impl<K: IntoLua, V: IntoLua> IntoLua for HashMap<K, V> {
+    fn into_lua(self, interpreter: &mut Interpreter) -> Value {
+        let mut table = Table::new();
+        for (key, value) in self {
+            let key = key.into_lua(interpreter);
+            let value = value.into_lua(interpreter);
+            // Keys that cannot be in a table, `nil` and NaN, are left out
+            if !key.is_nil() && !matches!(key, Value::Float(f) if f.is_nan()) {
+                table.insert(key, value);
+            }
+        }
+        interpreter.new_table(table)
+    }
+}
+
+/// All the entries of a table, read without metamethods.
+impl<K: FromLua + Eq + Hash, V: FromLua> FromLua for HashMap<K, V> {
+    fn from_lua(value: Value, interpreter: &mut Interpreter) -> Result<Self, ConversionError> {
+        let Value::Table(table) = value else {
+            return Err(ConversionError::Expected("table"));
+        };
+        let mut result = HashMap::new();
+        let mut key = Value::Nil;
+        loop {
+            let entry = table.borrow().next(&key);
+            let Some((next, value)) =
+                entry.map_err(|error| ConversionError::Invalid(error.value.to_string()))?
+            else {
+                break;
+            };
+            let converted_key = K::from_lua(next.clone(), interpreter)
+                .map_err(|error| error.inside("as key", &next))?;
+            let converted_value = V::from_lua(value.clone(), interpreter)
+                .map_err(|error| error.inside(&format!("at key '{next}'"), &value))?;
+            result.insert(converted_key, converted_value);
+            key = next;
+        }
+        Ok(result)
+    }
+}
+
+impl<T: IntoLua> IntoLuaMulti for T {
+    fn into_lua_multi(self, interpreter: &mut Interpreter) -> Vec<Value> {
+        vec![self.into_lua(interpreter)]
+    }
+}
+
+impl<T: IntoLua> IntoLuaMulti for Variadic<T> {
+    fn into_lua_multi(self, interpreter: &mut Interpreter) -> Vec<Value> {
+        self.0
+            .into_iter()
+            .map(|value| value.into_lua(interpreter))
+            .collect()
+    }
+}
+
+impl<T: FromLua> FromLuaMulti for T {
+    fn from_lua_multi(
+        arguments: &[Value],
+        position: usize,
+        name: &str,
+        interpreter: &mut Interpreter,
+    ) -> Result<Self, LuaError> {
+        let value = argument(arguments, position);
+        T::from_lua(value.clone(), interpreter).map_err(|error| {
+            let got = type_name(arguments, position, &value);
+            argument_error(position, name, &error.message(got))
+        })
+    }
+}
+
+impl<T: FromLua> FromLuaMulti for Variadic<T> {
+    fn from_lua_multi(
+        arguments: &[Value],
+        position: usize,
+        name: &str,
+        interpreter: &mut Interpreter,
+    ) -> Result<Self, LuaError> {
+        (position..=arguments.len())
+            .map(|position| T::from_lua_multi(arguments, position, name, interpreter))
+            .collect()
+    }
+}
+
+impl IntoLuaMulti for () {
+    fn into_lua_multi(self, _interpreter: &mut Interpreter) -> Vec<Value> {
+        vec![]
+    }
+}
+
+/// No arguments, ignoring any given.
+impl FromLuaMulti for () {
+    fn from_lua_multi(
+        _arguments: &[Value],
+        _position: usize,
+        _name: &str,
+        _interpreter: &mut Interpreter,
+    ) -> Result<Self, LuaError> {
+        Ok(())
+    }
+}
+
+macro_rules! tuple_conversions {
+    ($($name:ident),* ; $last:ident) => {
+        impl<$($name: IntoLua,)* $last: IntoLuaMulti> IntoLuaMulti for ($($name,)* $last,) {
+            #[allow(non_snake_case)]
+            fn into_lua_multi(self, interpreter: &mut Interpreter) -> Vec<Value> {
+                let ($($name,)* $last,) = self;
+                let mut values = vec![$($name.into_lua(interpreter),)*];
+                values.extend($last.into_lua_multi(interpreter));
+                values
+            }
+        }
+
+        impl<$($name: FromLua,)* $last: FromLuaMulti> FromLuaMulti for ($($name,)* $last,) {
+            #[allow(non_snake_case, unused_mut, unused_variables)]
+            fn from_lua_multi(
+                arguments: &[Value],
+                position: usize,
+                name: &str,
+                interpreter: &mut Interpreter,
+            ) -> Result<Self, LuaError> {
+                let mut position = position;
+                $(
+                    let $name = $name::from_lua_multi(arguments, position, name, interpreter)?;
+                    position += 1;
+                )*
+                let $last = $last::from_lua_multi(arguments, position, name, interpreter)?;
+                Ok(($($name,)* $last,))
+            }
+        }
+    };
+}
+
+tuple_conversions!(; A);
+tuple_conversions!(A; B);
+tuple_conversions!(A, B; C);
+tuple_conversions!(A, B, C; D);
+tuple_conversions!(A, B, C, D; E);
+tuple_conversions!(A, B, C, D, E; F);
+tuple_conversions!(A, B, C, D, E, F; G);
+tuple_conversions!(A, B, C, D, E, F, G; H);
@@ -0,0 +1,220 @@
+use std::{cell::RefCell, mem, rc::Rc};
+
+use super::{value::Value, Frame, Interpreter, LuaError};
+
+pub type ThreadRef = Rc<RefCell<Coroutine>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoroutineStatus {
+    Suspended,
+    Running,
+    Normal,
+    Dead,
+}
+
+impl CoroutineStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CoroutineStatus::Suspended => "suspended",
+            CoroutineStatus::Running => "running",
+            CoroutineStatus::Normal => "normal",
+            CoroutineStatus::Dead => "dead",
+        }
+    }
+}
+
+/// A thread of execution with its own stack, which keeps the state of its
+/// calls while it is not running.
+#[derive(Debug)]
+pub struct Coroutine {
+    pub(super) status: CoroutineStatus,
+    pub(super) stack: Vec<Value>,
+    pub(super) frames: Vec<Frame>,
+    pub(super) is_started: bool,
+    /// Number of results wanted by the call that yielded.
+    pub(super) pending: Option<usize>,
+    /// Number of `pcall`s the yield went through, whose status comes first in
+    /// the results of the pending call.
+    pub(super) protected: usize,
+    /// The error that killed the coroutine.
+    pub(super) error: Option<Value>,
+    /// The coroutine that resumed this one, while it runs.
+    pub(super) resumer: Option<ThreadRef>,
+    /// Native depth at which the coroutine was resumed, while another one runs.
+    pub(super) native_base: usize,
+}
+
+impl Coroutine {
+    /// A coroutine that runs `function` when first resumed.
+    pub fn new(function: Value) -> Self {
+        Self {
+            status: CoroutineStatus::Suspended,
+            stack: vec![function],
+            frames: vec![],
+            is_started: false,
+            pending: None,
+            protected: 0,
+            error: None,
+            resumer: None,
+            native_base: 0,
+        }
+    }
+
+    /// The thread of the interpreter itself, which is always running.
+    pub(super) fn main() -> Self {
+        Self {
+            status: CoroutineStatus::Running,
+            stack: vec![],
+            frames: vec![],
+            is_started: true,
+            pending: None,
+            protected: 0,
+            error: None,
+            resumer: None,
+            native_base: 0,
+        }
+    }
+
+    pub fn status(&self) -> CoroutineStatus {
+        self.status
+    }
+}
+
+impl Interpreter {
+    /// The running coroutine and whether it is the main thread.
+    pub fn running(&self) -> (ThreadRef, bool) {
+        (self.current.clone(), Rc::ptr_eq(&self.current, &self.main))
+    }
+
+    /// Whether the running coroutine can yield: it is not the main thread and
+    /// there is no Rust code between it and the resume.
+    pub fn is_yieldable(&self) -> bool {
+        !Rc::ptr_eq(&self.current, &self.main) && self.native_depth == self.native_base
+    }
+
+    /// Runs a coroutine until it yields or returns, giving the values it
+    /// yields or returns.
+    pub fn resume(
+        &mut self,
+        thread: ThreadRef,
+        arguments: Vec<Value>,
+    ) -> Result<Vec<Value>, LuaError> {
+        match thread.borrow().status {
+            CoroutineStatus::Suspended => {}
+            CoroutineStatus::Dead => return Err(LuaError::new("cannot resume dead coroutine")),
+            _ => return Err(LuaError::new("cannot resume non-suspended coroutine")),
+        }
+        if self.native_depth >= self.native_depth_limit {
+            return Err(LuaError::new("C stack overflow"));
+        }
+        let (is_started, pending) = self.enter(&thread);
+        let result = if is_started {
+            let protected = mem::take(&mut thread.borrow_mut().protected);
+            let mut arguments = arguments;
+            arguments.splice(0..0, vec![Value::True; protected]);
+            self.push_results(arguments, pending);
+            Ok(())
+        } else {
+            self.stack.extend(arguments);
+            self.call_value(0, None)
+        };
+        let result = result.and_then(|_| {
+            if self.frames.is_empty() {
+                Ok(())
+            } else {
+                self.execute(0)
+            }
+        });
+        let (status, result) = match result {
+            Ok(()) => (CoroutineStatus::Dead, Ok(mem::take(&mut self.stack))),
+            Err(_) if self.yielded.is_some() => {
+                (CoroutineStatus::Suspended, Ok(self.yielded.take().unwrap()))
+            }
+            Err(error) => {
+                thread.borrow_mut().error = Some(error.value.clone());
+                (CoroutineStatus::Dead, Err(error))
+            }
+        };
+        self.leave(&thread, status);
+        result
+    }
+
+    /// Suspends the running coroutine, making `resume` return `values`.
+    ///
+    /// Must be called by a builtin, whose error is passed on up to the resume.
+    pub fn yield_values(&mut self, values: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+        if Rc::ptr_eq(&self.current, &self.main) {
+            return Err(LuaError::new("attempt to yield from outside a coroutine"));
+        }
+        if self.native_depth != self.native_base {
+            return Err(LuaError::new("attempt to yield across a C-call boundary"));
+        }
+        self.yielded = Some(values);
+        Err(LuaError::new("attempt to yield"))
+    }
+
+    /// Closes the pending to-be-closed variables of a suspended coroutine and
+    /// kills it. Fails with the error that killed it, if any.
+    pub fn close_coroutine(&mut self, thread: ThreadRef) -> Result<(), LuaError> {
+        match thread.borrow().status {
+            CoroutineStatus::Suspended | CoroutineStatus::Dead => {}
+            CoroutineStatus::Running => {
+                return Err(LuaError::new("cannot close a running coroutine"))
+            }
+            CoroutineStatus::Normal => {
+                return Err(LuaError::new("cannot close a normal coroutine"))
+            }
+        }
+        let mut error = thread.borrow_mut().error.take();
+        self.enter(&thread);
+        while !self.frames.is_empty() {
+            while let Err(e) = self.close_variables(0, error.clone()) {
+                error = Some(e.value);
+            }
+            self.frames.pop();
+        }
+        self.stack.clear();
+        self.leave(&thread, CoroutineStatus::Dead);
+        match error {
+            Some(value) => Err(LuaError::from(value)),
+            None => Ok(()),
+        }
+    }
+
+    /// Makes `thread` the running coroutine, returning whether it had already
+    /// started and the number of results its pending call wants.
+    fn enter(&mut self, thread: &ThreadRef) -> (bool, Option<usize>) {
+        self.native_depth += 1;
+        let previous = mem::replace(&mut self.current, thread.clone());
+        previous.borrow_mut().status = CoroutineStatus::Normal;
+        let mut coroutine = thread.borrow_mut();
+        coroutine.status = CoroutineStatus::Running;
+        coroutine.resumer = Some(previous.clone());
+        // The state of the previous coroutine is kept in its place meanwhile
+        let mut previous = previous.borrow_mut();
+        previous.stack = mem::replace(&mut self.stack, mem::take(&mut coroutine.stack));
+        previous.frames = mem::replace(&mut self.frames, mem::take(&mut coroutine.frames));
+        previous.native_base = mem::replace(&mut self.native_base, self.native_depth);
+        let is_started = mem::replace(&mut coroutine.is_started, true);
+        (is_started, coroutine.pending)
+    }
+
+    /// Gives the control back to the coroutine that resumed `thread`.
+    fn leave(&mut self, thread: &ThreadRef, status: CoroutineStatus) {
+        let mut coroutine = thread.borrow_mut();
+        let previous = coroutine.resumer.take().unwrap();
+        coroutine.status = status;
+        let mut previous_state = previous.borrow_mut();
+        coroutine.stack = mem::replace(&mut self.stack, mem::take(&mut previous_state.stack));
+        coroutine.frames = mem::replace(&mut self.frames, mem::take(&mut previous_state.frames));
+        if status == CoroutineStatus::Dead {
+            coroutine.stack.clear();
+            coroutine.frames.clear();
+        }
+        self.native_base = previous_state.native_base;
+        previous_state.status = CoroutineStatus::Running;
+        drop(previous_state);
+        self.current = previous;
+        self.native_depth -= 1;
+    }
+}
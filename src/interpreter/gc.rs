@@ -0,0 +1,698 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    mem,
+    rc::{Rc, Weak},
+};
+
+use super::{
+    coroutine::{Coroutine, ThreadRef},
+    value::{Closure, Table, TableRef, Value},
+    Completion, Interpreter,
+};
+
+/// Fewest new objects before a collection, or before forgetting dead objects.
+const MINIMUM_GROWTH: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GcMode {
+    Incremental,
+    Generational,
+}
+
+impl GcMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            GcMode::Incremental => "incremental",
+            GcMode::Generational => "generational",
+        }
+    }
+}
+
+/// An object tracked by the collector, without keeping it alive.
+#[derive(Debug)]
+enum Tracked {
+    Table(Weak<RefCell<Table>>),
+    Closure(Weak<Closure>),
+    Cell(Weak<RefCell<Value>>),
+    Thread(Weak<RefCell<Coroutine>>),
+}
+
+/// A tracked object kept alive during a collection.
+enum Object {
+    Table(TableRef),
+    Closure(Rc<Closure>),
+    Cell(Rc<RefCell<Value>>),
+    Thread(ThreadRef),
+}
+
+/// The objects that can be part of reference cycles, that is tables, closures,
+/// the cells of captured locals and coroutines.
+///
+/// Objects are shared with reference counting, which frees them as soon as
+/// they are no longer used except when they reference each other. The
+/// collector finds those cycles by subtracting the references the objects
+/// hold to each other from their counts: objects left with references are
+/// used from outside, by the interpreter or Rust code, and anything they reach
+/// is alive. The rest is cleared, breaking the cycles.
+///
+/// Objects marked for finalization are kept alive by the heap until a
+/// collection finds them unreachable otherwise. They then survive it along
+/// with everything they reference, and their `__gc` metamethod is called.
+///
+/// Collections run whole at once, automatically when enough objects outlived
+/// their creation since the previous one. The mode and its parameters only
+/// change how often that happens.
+#[derive(Debug)]
+pub struct Heap {
+    objects: Vec<Tracked>,
+    /// Objects marked for finalization, in the order they were marked.
+    finalizers: Vec<Value>,
+    /// Addresses of the objects marked for finalization.
+    finalizable: HashSet<usize>,
+    /// Number of tracked objects at which the dead ones are forgotten.
+    forget_at: usize,
+    /// Objects that survived the last collection.
+    live: usize,
+    is_running: bool,
+    /// Whether the interpreter is being dropped, after which objects are no
+    /// longer marked for finalization, so finalizers cannot run forever.
+    is_closing: bool,
+    mode: GcMode,
+    /// How much the heap grows before an incremental collection, as a
+    /// percentage of the live objects.
+    pause: usize,
+    step_multiplier: usize,
+    step_size: usize,
+    /// How much the heap grows before a generational collection, as a
+    /// percentage of the live objects.
+    minor_multiplier: usize,
+    major_multiplier: usize,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self {
+            objects: vec![],
+            finalizers: vec![],
+            finalizable: HashSet::new(),
+            forget_at: MINIMUM_GROWTH,
+            live: 0,
+            is_running: true,
+            is_closing: false,
+            mode: GcMode::Incremental,
+            pause: 200,
+            step_multiplier: 100,
+            step_size: 13,
+            minor_multiplier: 20,
+            major_multiplier: 100,
+        }
+    }
+}
+
+impl Heap {
+    pub fn is_running(&self) -> bool {
+        self.is_running
+    }
+
+    /// Stops or restarts automatic collections.
+    pub fn set_running(&mut self, is_running: bool) {
+        self.is_running = is_running;
+    }
+
+    pub fn mode(&self) -> GcMode {
+        self.mode
+    }
+
+    /// Switches to incremental mode, changing the parameters that are not
+    /// zero. Gives the previous mode.
+    pub fn set_incremental(
+        &mut self,
+        pause: usize,
+        step_multiplier: usize,
+        step_size: usize,
+    ) -> GcMode {
+        for (parameter, value) in [
+            (&mut self.pause, pause),
+            (&mut self.step_multiplier, step_multiplier),
+            (&mut self.step_size, step_size),
+        ] {
+            if value != 0 {
+                *parameter = value;
+            }
+        }
+        mem::replace(&mut self.mode, GcMode::Incremental)
+    }
+
+    /// Switches to generational mode, changing the parameters that are not
+    /// zero. Gives the previous mode.
+    pub fn set_generational(&mut self, minor_multiplier: usize, major_multiplier: usize) -> GcMode {
+        if minor_multiplier != 0 {
+            self.minor_multiplier = minor_multiplier;
+        }
+        if major_multiplier != 0 {
+            self.major_multiplier = major_multiplier;
+        }
+        mem::replace(&mut self.mode, GcMode::Generational)
+    }
+
+    /// Rough number of bytes used by the objects alive.
+    pub fn memory(&self) -> usize {
+        self.objects.iter().map(Tracked::memory).sum()
+    }
+
+    /// Keeps an object alive until a collection finds it unreachable, then
+    /// calls its `__gc` metamethod. Objects are only marked once.
+    fn mark_for_finalization(&mut self, object: Value) {
+        if self.is_closing {
+            return;
+        }
+        let mut address = None;
+        visit_value(&object, &mut |a| address = Some(a));
+        if let Some(address) = address {
+            if self.finalizable.insert(address) {
+                self.finalizers.push(object);
+            }
+        }
+    }
+
+    /// Starts tracking a new object, returning whether a collection is due.
+    ///
+    /// Most objects are freed by reference counting, so the ones already gone
+    /// are forgotten first and only the growth of the others counts.
+    fn track(&mut self, object: Tracked) -> bool {
+        self.objects.push(object);
+        if self.objects.len() < self.forget_at {
+            return false;
+        }
+        self.objects.retain(Tracked::is_alive);
+        self.forget_at = MINIMUM_GROWTH.max(self.objects.len() * 2);
+        self.is_running && self.objects.len() >= self.live + self.threshold()
+    }
+
+    /// Number of new objects that makes a collection due.
+    fn threshold(&self) -> usize {
+        let growth = match self.mode {
+            GcMode::Incremental => self.pause.saturating_sub(100),
+            GcMode::Generational => self.minor_multiplier,
+        };
+        MINIMUM_GROWTH.max(self.live * growth / 100)
+    }
+}
+
+impl Tracked {
+    fn upgrade(&self) -> Option<Object> {
+        match self {
+            Tracked::Table(table) => table.upgrade().map(Object::Table),
+            Tracked::Closure(closure) => closure.upgrade().map(Object::Closure),
+            Tracked::Cell(cell) => cell.upgrade().map(Object::Cell),
+            Tracked::Thread(thread) => thread.upgrade().map(Object::Thread),
+        }
+    }
+
+    fn is_alive(&self) -> bool {
+        match self {
+            Tracked::Table(table) => table.strong_count() > 0,
+            Tracked::Closure(closure) => closure.strong_count() > 0,
+            Tracked::Cell(cell) => cell.strong_count() > 0,
+            Tracked::Thread(thread) => thread.strong_count() > 0,
+        }
+    }
+
+    fn memory(&self) -> usize {
+        self.upgrade().map_or(0, |object| object.memory())
+    }
+}
+
+impl Object {
+    fn from_value(value: &Value) -> Option<Object> {
+        match value {
+            Value::Table(table) => Some(Object::Table(table.clone())),
+            Value::Lambda(closure) => Some(Object::Closure(closure.clone())),
+            Value::Thread(thread) => Some(Object::Thread(thread.clone())),
+            _ => None,
+        }
+    }
+
+    fn downgrade(&self) -> Tracked {
+        match self {
+            Object::Table(table) => Tracked::Table(Rc::downgrade(table)),
+            Object::Closure(closure) => Tracked::Closure(Rc::downgrade(closure)),
+            Object::Cell(cell) => Tracked::Cell(Rc::downgrade(cell)),
+            Object::Thread(thread) => Tracked::Thread(Rc::downgrade(thread)),
+        }
+    }
+
+    fn address(&self) -> usize {
+        match self {
+            Object::Table(table) => address(table),
+            Object::Closure(closure) => address(closure),
+            Object::Cell(cell) => address(cell),
+            Object::Thread(thread) => address(thread),
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Object::Table(table) => Rc::strong_count(table),
+            Object::Closure(closure) => Rc::strong_count(closure),
+            Object::Cell(cell) => Rc::strong_count(cell),
+            Object::Thread(thread) => Rc::strong_count(thread),
+        }
+    }
+
+    /// Calls `visit` with the address of every object this one references, as
+    /// many times as it references it. Returns false if the object is in use
+    /// and cannot be inspected.
+    fn references(&self, visit: &mut dyn FnMut(usize)) -> bool {
+        match self {
+            Object::Table(table) => {
+                let Ok(table) = table.try_borrow() else {
+                    return false;
+                };
+                table
+                    .references()
+                    .for_each(|value| visit_value(value, visit));
+                if let Some(metatable) = table.metatable() {
+                    visit(address(&metatable));
+                }
+            }
+            Object::Closure(closure) => {
+                for cell in &closure.upvalues {
+                    visit(address(cell));
+                }
+            }
+            Object::Cell(cell) => {
+                let Ok(cell) = cell.try_borrow() else {
+                    return false;
+                };
+                visit_value(&cell, visit);
+            }
+            Object::Thread(thread) => {
+                let Ok(thread) = thread.try_borrow() else {
+                    return false;
+                };
+                thread
+                    .stack
+                    .iter()
+                    .for_each(|value| visit_value(value, visit));
+                if let Some(error) = &thread.error {
+                    visit_value(error, visit);
+                }
+                if let Some(resumer) = &thread.resumer {
+                    visit(address(resumer));
+                }
+                for frame in &thread.frames {
+                    visit(address(&frame.closure));
+                    frame
+                        .varargs
+                        .iter()
+                        .for_each(|value| visit_value(value, visit));
+                    for cell in frame.cells.iter().flatten() {
+                        visit(address(cell));
+                    }
+                    if let Some(Completion::Method(object)) = &frame.completion {
+                        visit_value(object, visit);
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    /// Takes everything out of the object so it no longer references others.
+    fn clear(&self) {
+        match self {
+            Object::Table(table) => {
+                let contents = table
+                    .try_borrow_mut()
+                    .map(|mut table| mem::take(&mut *table));
+                drop(contents);
+            }
+            // Closures only reference cells, which are cleared themselves
+            Object::Closure(_) => {}
+            Object::Cell(cell) => {
+                let value = cell
+                    .try_borrow_mut()
+                    .map(|mut cell| mem::replace(&mut *cell, Value::Nil));
+                drop(value);
+            }
+            Object::Thread(thread) => {
+                let contents = thread.try_borrow_mut().map(|mut thread| {
+                    (
+                        mem::take(&mut thread.stack),
+                        mem::take(&mut thread.frames),
+                        thread.resumer.take(),
+                        thread.error.take(),
+                    )
+                });
+                drop(contents);
+            }
+        }
+    }
+
+    fn memory(&self) -> usize {
+        let value = mem::size_of::<Value>();
+        match self {
+            Object::Table(table) => table.try_borrow().map_or(0, |table| table.memory()),
+            Object::Closure(closure) => {
+                mem::size_of::<Closure>() + closure.upvalues.capacity() * mem::size_of::<usize>()
+            }
+            Object::Cell(_) => value,
+            Object::Thread(thread) => thread.try_borrow().map_or(0, |thread| {
+                mem::size_of::<Coroutine>()
+                    + thread.stack.capacity() * value
+                    + thread.frames.capacity() * mem::size_of::<super::Frame>()
+            }),
+        }
+    }
+}
+
+/// Identifies a shared object.
+fn address<T>(object: &Rc<T>) -> usize {
+    Rc::as_ptr(object) as *const () as usize
+}
+
+/// Calls `visit` with the address of the object a value holds, if any.
+fn visit_value(value: &Value, visit: &mut dyn FnMut(usize)) {
+    match value {
+        Value::Table(table) => visit(address(table)),
+        Value::Lambda(closure) => visit(address(closure)),
+        Value::Thread(thread) => visit(address(thread)),
+        _ => {}
+    }
+}
+
+impl Interpreter {
+    pub fn heap(&mut self) -> &mut Heap {
+        &mut self.heap
+    }
+
+    /// Makes a table whose cycles are collected.
+    pub fn new_table(&mut self, table: Table) -> Value {
+        let table = Rc::new(RefCell::new(table));
+        self.track(Tracked::Table(Rc::downgrade(&table)));
+        Value::Table(table)
+    }
+
+    /// Makes a coroutine running `function` whose cycles are collected.
+    pub fn new_thread(&mut self, function: Value) -> Value {
+        let thread = Rc::new(RefCell::new(Coroutine::new(function)));
+        self.track(Tracked::Thread(Rc::downgrade(&thread)));
+        Value::Thread(thread)
+    }
+
+    pub(super) fn new_closure(&mut self, closure: Closure) -> Value {
+        let closure = Rc::new(closure);
+        self.track(Tracked::Closure(Rc::downgrade(&closure)));
+        Value::Lambda(closure)
+    }
+
+    pub(super) fn new_cell(&mut self, value: Value) -> Rc<RefCell<Value>> {
+        let cell = Rc::new(RefCell::new(value));
+        self.track(Tracked::Cell(Rc::downgrade(&cell)));
+        cell
+    }
+
+    fn track(&mut self, object: Tracked) {
+        if self.heap.track(object) {
+            self.collect_garbage();
+        }
+    }
+
+    /// Sets the metatable of a table, marking the table for finalization if
+    /// the metatable has a `__gc` field.
+    pub fn set_metatable(&mut self, table: &TableRef, metatable: Option<TableRef>) {
+        let finalized = metatable
+            .as_ref()
+            .is_some_and(|metatable| !metatable.borrow().get_str("__gc").is_nil());
+        table.borrow_mut().set_metatable(metatable);
+        if finalized {
+            self.heap.mark_for_finalization(Value::Table(table.clone()));
+        }
+    }
+
+    /// Runs a full collection, freeing the objects only referenced by cycles
+    /// and clearing the entries of weak tables that referenced them. Then
+    /// calls the finalizers of the objects found unreachable.
+    pub fn collect_garbage(&mut self) {
+        let finalized = self.collect_cycles();
+        self.finalize(finalized);
+    }
+
+    /// Calls the `__gc` metamethods of objects in reverse order, reporting
+    /// their errors as warnings.
+    fn finalize(&mut self, objects: Vec<Value>) {
+        for object in objects.into_iter().rev() {
+            let finalizer = self.metamethod(&object, "__gc");
+            if finalizer.is_nil() {
+                continue;
+            }
+            if let Err(error) = self.call(finalizer, vec![object]) {
+                let message = match &error.value {
+                    Value::String(s) => s.as_bytes(),
+                    _ => b"error object is not a string",
+                };
+                let warning = [b"error in __gc (", message, b")"].concat();
+                let _ = crate::std::global::warn(self, vec![Value::String(warning.into())]);
+            }
+        }
+    }
+
+    /// Clears the unreachable objects, except the ones marked for
+    /// finalization which are resurrected and returned.
+    fn collect_cycles(&mut self) -> Vec<Value> {
+        let mut objects: Vec<Object> = self
+            .heap
+            .objects
+            .iter()
+            .filter_map(Tracked::upgrade)
+            .collect();
+        let tracked: HashSet<usize> = objects.iter().map(Object::address).collect();
+        let finalizers = self.heap.finalizers.iter().filter_map(Object::from_value);
+        objects.extend(finalizers.filter(|object| !tracked.contains(&object.address())));
+        let mut marker = Marker::new(&objects);
+        // References from outside the tracked objects, not counting the one
+        // held here nor the ones held for finalization
+        let mut external: Vec<usize> = objects.iter().map(|o| o.strong_count() - 1).collect();
+        for &address in &self.heap.finalizable {
+            external[marker.indices[&address]] -= 1;
+        }
+        for (i, object) in objects.iter().enumerate() {
+            let is_opaque = !object.references(&mut |address| {
+                if let Some(&j) = marker.indices.get(&address) {
+                    external[j] = external[j].saturating_sub(1);
+                }
+            });
+            if is_opaque {
+                external[i] = usize::MAX;
+            }
+        }
+        marker.pending = (0..objects.len()).filter(|&i| external[i] > 0).collect();
+        marker.propagate();
+        // Weak values referencing objects about to be finalized are cleared
+        // before resurrecting them, weak keys only once they are collected
+        let finalized: Vec<Value>;
+        (finalized, self.heap.finalizers) = mem::take(&mut self.heap.finalizers)
+            .into_iter()
+            .partition(|object| !marker.is_alive(object));
+        marker.clear_weak_entries(false, true, None);
+        let reachable = marker.marked.clone();
+        for object in &finalized {
+            marker.mark_value(object);
+            visit_value(object, &mut |address| {
+                self.heap.finalizable.remove(&address);
+            });
+        }
+        marker.propagate();
+        marker.clear_weak_entries(true, false, None);
+        marker.clear_weak_entries(false, true, Some(&reachable));
+        for (object, _) in objects
+            .iter()
+            .zip(&marker.marked)
+            .filter(|(_, &marked)| !marked)
+        {
+            object.clear();
+        }
+        self.heap.objects = objects
+            .iter()
+            .zip(&marker.marked)
+            .filter(|(_, &marked)| marked)
+            .map(|(object, _)| object.downgrade())
+            .collect();
+        self.heap.live = self.heap.objects.len();
+        self.heap.forget_at = MINIMUM_GROWTH.max(self.heap.live * 2);
+        finalized
+    }
+}
+
+impl Drop for Interpreter {
+    /// Calls the finalizers of all objects still marked for finalization, as
+    /// closing a Lua state does, then frees the cycles left.
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            return;
+        }
+        // Finalizers run as usual after unwinding for `os.exit`
+        self.exit_status = None;
+        self.heap.is_closing = true;
+        let finalized = mem::take(&mut self.heap.finalizers);
+        self.heap.finalizable.clear();
+        self.finalize(finalized);
+        let globals = mem::take(&mut *self.globals.borrow_mut());
+        drop(globals);
+        let registry = mem::take(&mut *self.registry.borrow_mut());
+        drop(registry);
+        self.stack.clear();
+        self.frames.clear();
+        self.collect_cycles();
+    }
+}
+
+/// Finds the objects reachable from the ones used from outside.
+struct Marker<'a> {
+    objects: &'a [Object],
+    indices: HashMap<usize, usize>,
+    marked: Vec<bool>,
+    pending: Vec<usize>,
+    /// Tables with weak keys and strong values, whose values are only
+    /// reachable through them once their keys are.
+    ephemerons: Vec<usize>,
+}
+
+impl<'a> Marker<'a> {
+    fn new(objects: &'a [Object]) -> Self {
+        Self {
+            objects,
+            indices: objects
+                .iter()
+                .enumerate()
+                .map(|(i, object)| (object.address(), i))
+                .collect(),
+            marked: vec![false; objects.len()],
+            pending: vec![],
+            ephemerons: vec![],
+        }
+    }
+
+    fn index(&self, value: &Value) -> Option<usize> {
+        let mut index = None;
+        visit_value(value, &mut |address| {
+            index = self.indices.get(&address).copied()
+        });
+        index
+    }
+
+    /// Whether a value is not a tracked object or one found reachable.
+    fn is_alive(&self, value: &Value) -> bool {
+        self.index(value).is_none_or(|i| self.marked[i])
+    }
+
+    fn mark(&mut self, address: usize) {
+        if let Some(&i) = self.indices.get(&address) {
+            if !self.marked[i] {
+                self.pending.push(i);
+            }
+        }
+    }
+
+    fn mark_value(&mut self, value: &Value) {
+        visit_value(value, &mut |address| self.mark(address));
+    }
+
+    fn propagate(&mut self) {
+        loop {
+            while let Some(i) = self.pending.pop() {
+                if !mem::replace(&mut self.marked[i], true) {
+                    self.traverse(i);
+                }
+            }
+            // Values of ephemerons whose keys turned out reachable, which can
+            // make other keys reachable in turn
+            let objects = self.objects;
+            for i in self.ephemerons.clone() {
+                let Object::Table(table) = &objects[i] else {
+                    unreachable!("Expected ephemeron table");
+                };
+                for (key, value) in table.borrow().entries() {
+                    if self.is_alive(key) {
+                        self.mark_value(value);
+                    }
+                }
+            }
+            if self.pending.is_empty() {
+                return;
+            }
+        }
+    }
+
+    fn traverse(&mut self, i: usize) {
+        let objects = self.objects;
+        let Object::Table(table) = &objects[i] else {
+            objects[i].references(&mut |address| self.mark(address));
+            return;
+        };
+        let Ok(table) = table.try_borrow() else {
+            return;
+        };
+        if let Some(metatable) = table.metatable() {
+            self.mark(address(&metatable));
+        }
+        match table.weakness() {
+            (false, false) => table.references().for_each(|value| self.mark_value(value)),
+            (false, true) => table.entries().for_each(|(key, _)| self.mark_value(key)),
+            (true, false) => {
+                table
+                    .array()
+                    .iter()
+                    .for_each(|value| self.mark_value(value));
+                for (key, value) in table.entries() {
+                    if self.is_alive(key) {
+                        self.mark_value(value);
+                    }
+                }
+                self.ephemerons.push(i);
+            }
+            (true, true) => {}
+        }
+    }
+
+    /// Removes the entries of reachable weak tables whose weak keys or weak
+    /// values are unreachable objects, skipping the tables in `skip`.
+    fn clear_weak_entries(&self, by_keys: bool, by_values: bool, skip: Option<&[bool]>) {
+        for (i, object) in self.objects.iter().enumerate() {
+            if !self.marked[i] || skip.is_some_and(|skip| skip[i]) {
+                continue;
+            }
+            let Object::Table(table) = object else {
+                continue;
+            };
+            let Ok(mut table) = table.try_borrow_mut() else {
+                continue;
+            };
+            let (weak_keys, weak_values) = table.weakness();
+            let (weak_keys, weak_values) = (weak_keys && by_keys, weak_values && by_values);
+            if !weak_keys && !weak_values {
+                continue;
+            }
+            let mut dead: Vec<Value> = table
+                .entries()
+                .filter(|(key, value)| {
+                    (weak_keys && !self.is_alive(key)) || (weak_values && !self.is_alive(value))
+                })
+                .map(|(key, _)| key.clone())
+                .collect();
+            if weak_values {
+                let array = table.array().iter().enumerate();
+                dead.extend(
+                    array
+                        .filter(|(_, value)| !self.is_alive(value))
+                        .map(|(i, _)| Value::Integer(i as i64 + 1)),
+                );
+            }
+            for key in dead {
+                table.insert(key, Value::Nil);
+            }
+        }
+    }
+}
@@ -1,566 +1,1581 @@
+pub mod compiler;
+pub mod convert;
+pub mod coroutine;
+pub mod gc;
 pub mod value;
 
-use std::{collections::HashMap, error::Error, mem, vec};
+use std::{cell::RefCell, error::Error, fmt, rc::Rc};
 
-use crate::parser::{
-    expression::Expression,
-    prefix_expression::{Argument, CallSuffix, PExprAction, PrefixExpression, Primary, Selector},
-    statement::{Block, FunctionName, If, Parameters, Statement, Variable},
+use pest::{
+    error::{InputLocation, LineColLocation},
+    Parser,
 };
 
-use self::value::{Table, Value};
+use crate::{
+    parser::{ast::build_ast, check_nesting, statement::Block, LuaParser, Rule},
+    std::{random::Random, Builtin},
+};
 
-#[derive(Debug)]
-pub struct Interpreter<'a> {
-    pub scopes: Vec<Scope<'a>>,
-}
+use self::{
+    compiler::{BinaryOperator, Capture, Instruction, UnaryOperator},
+    coroutine::{Coroutine, ThreadRef},
+    gc::Heap,
+    value::{float_to_integer, Closure, Table, TableRef, Value},
+};
 
-#[derive(Debug)]
-pub struct Scope<'a> {
-    block: &'a Block,
-    table: HashMap<String, Value>,
-    labels: HashMap<String, usize>,
+/// An error raised by Lua code, carrying any value.
+#[derive(Debug, Clone)]
+pub struct LuaError {
+    pub value: Value,
 }
 
-impl<'a> Scope<'a> {
-    fn new(block: &'a Block) -> Self {
+impl LuaError {
+    pub fn new(message: impl Into<String>) -> Self {
         Self {
-            block,
-            table: HashMap::new(),
-            labels: HashMap::new(),
+            value: Value::String(message.into().into()),
         }
     }
+}
 
-    fn look_for_labels(&mut self) {
-        for (i, statement) in self.block.statements.iter().enumerate() {
-            if let Statement::Label(name) = statement {
-                self.labels.insert(name.clone(), i);
-            }
-        }
+impl From<Value> for LuaError {
+    fn from(value: Value) -> Self {
+        Self { value }
     }
+}
 
-    pub fn insert(&mut self, name: String, value: Value) {
-        self.table.insert(name, value);
+impl fmt::Display for LuaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.value {
+            Value::String(_) | Value::Integer(_) | Value::Float(_) => write!(f, "{}", self.value),
+            value => write!(f, "(error object is a {} value)", value.type_name()),
+        }
     }
+}
 
-    fn get(&self, name: &str) -> Option<&Value> {
-        self.table.get(name)
-    }
+impl Error for LuaError {}
+
+#[derive(Debug)]
+struct Frame {
+    closure: Rc<Closure>,
+    pc: usize,
+    /// Start of the window of the stack used by the function, the function
+    /// itself being right below it.
+    base: usize,
+    results: Option<usize>,
+    varargs: Vec<Value>,
+    cells: Vec<Option<Rc<RefCell<Value>>>>,
+    to_be_closed: Vec<usize>,
+    /// Whether the function was called by `pcall`, which catches its errors.
+    protected: bool,
+    /// What becomes of the result of a metamethod called by an instruction.
+    completion: Option<Completion>,
+}
+
+/// How the first result of a metamethod completes the instruction that
+/// called it.
+#[derive(Debug, Clone)]
+enum Completion {
+    /// The result is the value of the operation.
+    Value,
+    /// The truthiness of the result is the value of a comparison, negated
+    /// for `~=`.
+    Truth { negated: bool },
+    /// The result is a method, called with the object as first argument.
+    Method(Value),
+}
 
-    fn get_mut(&mut self, name: &str) -> Option<&mut Value> {
-        self.table.get_mut(name)
+/// The value of an operation, or the metamethod to call to get it.
+enum Outcome {
+    Value(Value),
+    Metamethod {
+        handler: Value,
+        arguments: Vec<Value>,
+        completion: Completion,
+    },
+}
+
+impl fmt::Debug for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Closure({})", self.proto.name)
     }
 }
 
+/// Default limit of nested calls of Lua functions.
+pub const CALL_DEPTH_LIMIT: usize = 200_000;
+/// Default limit of nested calls from Rust back into Lua, like `LUAI_MAXCCALLS`.
+pub const NATIVE_DEPTH_LIMIT: usize = 200;
+
 #[derive(Debug)]
-enum Command {
-    Continue,
-    Goto(String),
-    Break,
-    Error(Box<dyn Error>),
+pub struct Interpreter {
+    globals: TableRef,
+    /// Values the libraries keep out of reach of Lua code.
+    registry: TableRef,
+    /// The metatable shared by all strings.
+    string_metatable: Option<TableRef>,
+    /// The generator of `math.random`.
+    random: Random,
+    stack: Vec<Value>,
+    frames: Vec<Frame>,
+    native_depth: usize,
+    call_depth_limit: usize,
+    native_depth_limit: usize,
+    main: ThreadRef,
+    current: ThreadRef,
+    /// Native depth at which the current coroutine was resumed.
+    native_base: usize,
+    /// Values passed to `coroutine.yield`, while the coroutine is suspending.
+    yielded: Option<Vec<Value>>,
+    /// Status given to `os.exit` when asked to close the interpreter, while
+    /// the calls unwind up to the host.
+    exit_status: Option<i32>,
+    heap: Heap,
 }
 
-impl<'a> Interpreter<'a> {
+impl Default for Interpreter {
+    fn default() -> Self {
+        let main = Rc::new(RefCell::new(Coroutine::main()));
+        Self {
+            globals: Rc::new(RefCell::new(Table::new())),
+            registry: Rc::new(RefCell::new(Table::new())),
+            string_metatable: None,
+            random: Random::default(),
+            stack: vec![],
+            frames: vec![],
+            native_depth: 0,
+            call_depth_limit: CALL_DEPTH_LIMIT,
+            native_depth_limit: NATIVE_DEPTH_LIMIT,
+            main: main.clone(),
+            current: main,
+            native_base: 0,
+            yielded: None,
+            exit_status: None,
+            heap: Heap::default(),
+        }
+    }
+}
+
+impl Interpreter {
     pub fn new() -> Self {
-        Self { scopes: vec![] }
+        let mut interpreter = Self::default();
+        crate::std::load_std(&mut interpreter);
+        interpreter
+    }
+
+    /// Sets how deep calls of Lua functions can nest before raising a
+    /// "stack overflow" error.
+    pub fn set_call_depth_limit(&mut self, limit: usize) {
+        self.call_depth_limit = limit;
+    }
+
+    /// Sets how deep calls from Rust back into Lua, such as metamethods or
+    /// functions called by `pcall`, can nest before raising a "C stack
+    /// overflow" error. Each of them uses native stack.
+    pub fn set_native_depth_limit(&mut self, limit: usize) {
+        self.native_depth_limit = limit;
+    }
+
+    /// Unwinds every call up to the host, closing their to-be-closed
+    /// variables, with an error no `pcall` catches. The host is expected to
+    /// drop the interpreter, which runs the finalizers, then to end the
+    /// process with `status`, as given by [`Interpreter::exit_status`].
+    pub fn exit(&mut self, status: i32) -> Result<Vec<Value>, LuaError> {
+        self.exit_status = Some(status);
+        Err(LuaError::new(format!("exit with status {status}")))
+    }
+
+    /// The status of the process asked for by `os.exit`, once its calls
+    /// unwound.
+    pub fn exit_status(&self) -> Option<i32> {
+        self.exit_status
     }
 
-    pub fn interpret(&mut self, block: &'a Block) {
-        let global_block = Block {
-            statements: vec![],
-            return_statement: None,
+    /// Runs a chunk, returning the values it returns.
+    pub fn interpret(&mut self, block: &Block) -> Result<Vec<Value>, LuaError> {
+        let function = self.load_block(block, "main chunk", None)?;
+        self.call(function, vec![])
+    }
+
+    /// Compiles a chunk of source code into a function. Its `_ENV` is the
+    /// global environment unless given, and `name` tells where the source
+    /// comes from in error messages, like the chunk names of `load`.
+    pub fn load(
+        &mut self,
+        source: &str,
+        name: &str,
+        environment: Option<Value>,
+    ) -> Result<Value, LuaError> {
+        let chunk = chunk_id(name);
+        let mut pairs = LuaParser::parse(Rule::Chunk, source).map_err(|error| {
+            let (line, offset) = match (error.line_col, error.location) {
+                (LineColLocation::Pos((line, _)), InputLocation::Pos(offset)) => (line, offset),
+                (LineColLocation::Span((line, _), _), InputLocation::Span((offset, _))) => {
+                    (line, offset)
+                }
+                _ => unreachable!("Mismatched error location"),
+            };
+            let near = match source[offset..].split_whitespace().next() {
+                Some(token) => format!("'{}'", token.chars().take(20).collect::<String>()),
+                None => "<eof>".to_string(),
+            };
+            let message = error.variant.message();
+            LuaError::new(format!("{chunk}:{line}: {message} near {near}"))
+        })?;
+        check_nesting(&pairs).map_err(|line| {
+            LuaError::new(format!("{chunk}:{line}: chunk has too many C levels"))
+        })?;
+        let block = build_ast(&mut pairs);
+        self.load_block(&block, &chunk, environment)
+    }
+
+    fn load_block(
+        &mut self,
+        block: &Block,
+        name: &str,
+        environment: Option<Value>,
+    ) -> Result<Value, LuaError> {
+        let proto = compiler::compile(block, name)?;
+        let environment = environment.unwrap_or_else(|| Value::Table(self.globals.clone()));
+        let closure = Closure {
+            proto,
+            upvalues: vec![self.new_cell(environment)],
         };
-        let global_block = Box::new(global_block);
-        let global_block = Box::leak(global_block);
-        let mut scope = Scope::new(global_block);
-        crate::std::load_std(&mut scope);
-        self.scopes.push(scope);
-        match self.evaluate_block(block) {
-            Command::Goto(name) => todo!("No visible label '{name}' for <goto>"),
-            Command::Break => todo!("Break outside a loop"),
-            Command::Error(err) => todo!("Error happened: {err}"),
-            _ => {}
+        Ok(self.new_closure(closure))
+    }
+
+    /// The table of the global environment, `_G`.
+    pub fn globals(&self) -> TableRef {
+        self.globals.clone()
+    }
+
+    pub fn get_global(&self, name: &str) -> Value {
+        self.globals.borrow().get_str(name)
+    }
+
+    /// Sets a global, which can be a function written in Rust given as a
+    /// `NativeFunction`.
+    pub fn set_global(&mut self, name: &str, value: impl Into<Value>) {
+        self.globals.borrow_mut().insert_str(name, value.into());
+    }
+
+    /// The table where the libraries keep their own state.
+    pub fn registry(&self) -> TableRef {
+        self.registry.clone()
+    }
+
+    pub(crate) fn random(&mut self) -> &mut Random {
+        &mut self.random
+    }
+
+    /// Calls any callable value, returning all of its results.
+    pub fn call(&mut self, function: Value, arguments: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+        if self.native_depth >= self.native_depth_limit {
+            return Err(LuaError::new("C stack overflow"));
         }
-        self.scopes.pop();
-    }
-
-    fn evaluate_block(&mut self, block: &'a Block) -> Command {
-        let mut scope = Scope::new(block);
-        scope.look_for_labels();
-        self.scopes.push(scope);
-
-        let mut i = 0;
-        while i < block.statements.len() {
-            let statement = &block.statements[i];
-            let label = match statement {
-                Statement::LocalVariables { .. } => self.evaluate_local_variables(statement),
-                Statement::Assignment { .. } => self.evaluate_global_variables(statement),
-                Statement::Do(_) => self.evaluate_do(statement),
-                Statement::If { .. } => self.evaluate_if(statement),
-                Statement::While { .. } => self.evaluate_while(statement),
-                Statement::Repeat { .. } => self.evaluate_repeat(statement),
-                Statement::LocalFunctionDefinition { .. } => {
-                    self.evaluate_local_function_definition(statement)
-                }
-                Statement::FunctionDefinition { .. } => {
-                    self.evaluate_global_function_definition(statement)
-                }
-                Statement::NumericalFor { .. } => self.evaluate_numerical_for(statement),
-                Statement::FunctionCall { .. } => self.evaluate_function_call(statement),
-                Statement::Goto(name) => Command::Goto(name.clone()),
-                Statement::Label(_) => Command::Continue,
-                Statement::Empty => Command::Continue,
-                Statement::Break => Command::Break,
-                _ => unreachable!("Expected statement, found {:?}", statement),
-            };
-            match label {
-                Command::Goto(name) => {
-                    if let Some(pos) = self.scopes.last().unwrap().labels.get(&name) {
-                        i = *pos;
-                    } else {
-                        let scope = self.scopes.pop().unwrap();
-                        println!("Scope after: {:?}", scope);
-                        return Command::Goto(name);
-                    }
-                }
-                Command::Break => {
-                    self.scopes.pop();
-                    return Command::Break;
-                }
-                Command::Error(err) => {
-                    self.scopes.pop();
-                    return Command::Error(err);
+        let func = self.stack.len();
+        let depth = self.frames.len();
+        self.stack.push(function);
+        self.stack.extend(arguments);
+        self.native_depth += 1;
+        let result = self.call_value(func, None).and_then(|_| {
+            if self.frames.len() > depth {
+                self.execute(depth)
+            } else {
+                Ok(())
+            }
+        });
+        self.native_depth -= 1;
+        match result {
+            Ok(()) => Ok(self.stack.split_off(func)),
+            Err(error) => {
+                self.stack.truncate(func);
+                Err(error)
+            }
+        }
+    }
+
+    pub fn metatable(&self, value: &Value) -> Option<Rc<RefCell<Table>>> {
+        match value {
+            Value::Table(table) => table.borrow().metatable(),
+            Value::String(_) => self.string_metatable.clone(),
+            Value::Userdata(userdata) => userdata.metatable(),
+            _ => None,
+        }
+    }
+
+    pub fn set_string_metatable(&mut self, metatable: Option<TableRef>) {
+        self.string_metatable = metatable;
+    }
+
+    pub fn metamethod(&self, value: &Value, event: &str) -> Value {
+        match self.metatable(value) {
+            Some(metatable) => metatable.borrow().get_str(event),
+            None => Value::Nil,
+        }
+    }
+
+    /// Calls the function at `func` with the values above it. Lua functions
+    /// only get their frame pushed, they run once the interpreter resumes.
+    fn call_value(&mut self, func: usize, results: Option<usize>) -> Result<(), LuaError> {
+        match self.stack[func].clone() {
+            Value::Lambda(closure) => self.push_frame(closure, func, results),
+            Value::Builtin(Builtin::PCall) => self.protected_call(func, results),
+            Value::Builtin(builtin) => self.call_native(func, results, |interpreter, arguments| {
+                builtin.call(interpreter, arguments)
+            }),
+            Value::Native(function) => self.call_native(func, results, |interpreter, arguments| {
+                function.call(interpreter, arguments)
+            }),
+            value => {
+                let handler = self.metamethod(&value, "__call");
+                if handler.is_nil() {
+                    return Err(LuaError::new(format!(
+                        "attempt to call a {} value",
+                        value.type_name()
+                    )));
                 }
-                _ => {}
+                self.stack.insert(func, handler);
+                self.call_value(func, results)
             }
-            i += 1;
         }
-        // println!("[END] Scope after: {:?}", self.scopes.last().unwrap());
-        self.scopes.pop();
-        Command::Continue
     }
 
-    fn evaluate_function_call(&mut self, statement: &'a Statement) -> Command {
-        let Statement::FunctionCall { prefix_exp, call } = statement else {
-            unreachable!("Expected function call, found: {:?}", statement);
-        };
-        let Primary::Name(name) = &prefix_exp.primary else {
-            todo!("Deal with expressions")
-        };
-        let Some(object) = self.get(name) else {
-            todo!("Error if variable is not found");
+    /// Calls a function written in Rust at `func`, which runs to completion.
+    fn call_native(
+        &mut self,
+        func: usize,
+        results: Option<usize>,
+        function: impl FnOnce(&mut Self, Vec<Value>) -> Result<Vec<Value>, LuaError>,
+    ) -> Result<(), LuaError> {
+        let arguments = self.stack.split_off(func + 1);
+        self.stack.truncate(func);
+        let values = match function(self, arguments) {
+            Ok(values) => values,
+            Err(error) => {
+                if self.yielded.is_some() {
+                    self.current.borrow_mut().pending = results;
+                }
+                return Err(error);
+            }
         };
-        if !matches!(object, Value::Builtin(_) | Value::Lambda { .. }) {
-            todo!("Error if variable is not a function");
+        self.push_results(values, results);
+        Ok(())
+    }
+
+    /// Calls `pcall` at `func` without leaving the interpreter loop, so the
+    /// function it calls can yield.
+    fn protected_call(&mut self, func: usize, results: Option<usize>) -> Result<(), LuaError> {
+        if self.stack.len() == func + 1 {
+            return Err(crate::std::argument_error(1, "pcall", "value expected"));
         }
-        let arg = match call {
-            CallSuffix::Simple(arg) => arg,
-            CallSuffix::Method { .. } => todo!("How to call methods"),
-        };
-        let values = match arg {
-            Argument::List(args) => args.iter().map(|x| self.evaluate_expression(x)).collect(),
-            Argument::String(s) => vec![Value::String(s.clone())],
-            Argument::Table(t) => vec![self.evaluate_expression(t)],
+        self.check_native_depth()?;
+        self.stack.remove(func);
+        let depth = self.frames.len();
+        let is_lua = match &self.stack[func] {
+            Value::Lambda(_) => true,
+            Value::Builtin(_) | Value::Native(_) => false,
+            value => matches!(self.metamethod(value, "__call"), Value::Lambda(_)),
         };
-        let object = self.get(name).unwrap().clone();
-        match object {
-            Value::Builtin(f) => f.call(values),
-            Value::Lambda { parameters, body } => {
-                let body = Box::leak(Box::new(body));
-                let block = Box::leak(Box::new(Block::default()));
-                let mut scope = Scope::new(block);
-                let Parameters { name_list, var_arg } = parameters;
-                for (name, value) in name_list.into_iter().zip(values) {
-                    scope.insert(name, value)
-                }
-
-                self.scopes.push(scope);
-                self.evaluate_block(body);
-                self.scopes.pop();
-                Value::Nil
+        let result = self.call_value(func, if is_lua { results } else { None });
+        match result {
+            Ok(()) if self.frames.len() > depth => {
+                self.frames.last_mut().unwrap().protected = true;
             }
-            o => unreachable!("Expected function, found {:?}", o),
-        };
-        Command::Continue
+            Ok(()) => {
+                self.stack.insert(func, Value::True);
+                if let Some(n) = results {
+                    self.stack.resize(func + n, Value::Nil);
+                }
+            }
+            Err(error) if self.exit_status.is_some() => return Err(error),
+            Err(error) if self.yielded.is_some() => {
+                let mut coroutine = self.current.borrow_mut();
+                coroutine.pending = results;
+                coroutine.protected += 1;
+                return Err(error);
+            }
+            Err(error) => {
+                self.stack.truncate(func);
+                self.push_results(vec![Value::False, error.value], results);
+            }
+        }
+        Ok(())
     }
 
-    fn evaluate_global_function_definition(&mut self, statement: &'a Statement) -> Command {
-        let Statement::FunctionDefinition {
-            function_name,
-            parameters,
-            body,
-        } = statement
-        else {
-            unreachable!("Expected function definition, found {:?}", statement);
-        };
-        let parameters = parameters.clone().unwrap_or(Parameters {
-            name_list: vec![],
-            var_arg: false,
-        });
-        let lambda = Value::Lambda {
-            parameters,
-            body: body.clone(),
+    /// Fails if the calls in progress would go over the native depth limit.
+    /// Like calls from native code, each pending pcall or metamethod counts
+    /// towards it.
+    fn check_native_depth(&self) -> Result<(), LuaError> {
+        let pending = self
+            .frames
+            .iter()
+            .filter(|frame| frame.protected || frame.completion.is_some())
+            .count();
+        if self.native_depth + pending >= self.native_depth_limit {
+            return Err(LuaError::new("C stack overflow"));
+        }
+        Ok(())
+    }
+
+    /// Calls the metamethod of an instruction, keeping `results` of its
+    /// results. Lua functions get their frame pushed like other calls, so
+    /// they can yield.
+    fn call_metamethod(
+        &mut self,
+        handler: Value,
+        arguments: Vec<Value>,
+        results: usize,
+        completion: Completion,
+    ) -> Result<(), LuaError> {
+        let Value::Lambda(closure) = &handler else {
+            let values = self.call(handler, arguments)?;
+            self.push_results(values, Some(results));
+            self.complete(completion);
+            return Ok(());
         };
-        let FunctionName { names, method } = function_name;
-        if let Some(method) = method {
-            self.add_method_to_table(&names[0], &names[1..], method, lambda)
-        } else if names.len() == 1 {
-            self.add_function(&names[0], lambda);
-        } else {
-            let last = names.len() - 1;
-            let members = &names[1..last];
-            self.add_function_to_table(&names[0], members, &names[last], lambda);
+        self.check_native_depth()?;
+        let closure = closure.clone();
+        let func = self.stack.len();
+        self.stack.push(handler);
+        self.stack.extend(arguments);
+        self.push_frame(closure, func, Some(results))?;
+        self.frames.last_mut().unwrap().completion = Some(completion);
+        Ok(())
+    }
+
+    /// Pushes the value of an operation, calling its metamethod if needed.
+    fn push_outcome(&mut self, outcome: Outcome) -> Result<(), LuaError> {
+        match outcome {
+            Outcome::Value(value) => {
+                self.stack.push(value);
+                Ok(())
+            }
+            Outcome::Metamethod {
+                handler,
+                arguments,
+                completion,
+            } => self.call_metamethod(handler, arguments, 1, completion),
         }
-        Command::Continue
     }
 
-    fn add_function(&mut self, function_name: &str, body: Value) {
-        self.scopes[0].insert(function_name.to_string(), body)
+    /// Performs an operation whose value is not used, calling its metamethod
+    /// if needed.
+    fn discard_outcome(&mut self, outcome: Outcome) -> Result<(), LuaError> {
+        match outcome {
+            Outcome::Value(_) => Ok(()),
+            Outcome::Metamethod {
+                handler,
+                arguments,
+                completion,
+            } => self.call_metamethod(handler, arguments, 0, completion),
+        }
     }
 
-    fn add_function_to_table(
+    /// Completes the instruction that called a metamethod with its result,
+    /// on top of the stack.
+    fn complete(&mut self, completion: Completion) {
+        match completion {
+            Completion::Value => {}
+            Completion::Truth { negated } => {
+                let value = self.stack.last_mut().unwrap();
+                *value = Value::from(value.is_truthy() != negated);
+            }
+            Completion::Method(object) => self.stack.push(object),
+        }
+    }
+
+    /// The value of an operation, calling its metamethod from Rust if needed.
+    fn resolve(&mut self, outcome: Outcome) -> Result<Value, LuaError> {
+        match outcome {
+            Outcome::Value(value) => Ok(value),
+            Outcome::Metamethod {
+                handler,
+                arguments,
+                completion,
+            } => {
+                let value = self.call(handler, arguments)?.into_iter().next();
+                let value = value.unwrap_or(Value::Nil);
+                Ok(match completion {
+                    Completion::Truth { negated } => Value::from(value.is_truthy() != negated),
+                    _ => value,
+                })
+            }
+        }
+    }
+
+    /// Fails with a description of the value if the value called by the
+    /// instruction at `pc` is not callable.
+    fn check_callable(&self, func: usize, pc: usize) -> Result<(), LuaError> {
+        let value = &self.stack[func];
+        if matches!(
+            value,
+            Value::Lambda(_) | Value::Builtin(_) | Value::Native(_)
+        ) || !self.metamethod(value, "__call").is_nil()
+        {
+            return Ok(());
+        }
+        let frame = self.frames.last().unwrap();
+        let description = frame.closure.proto.descriptions.get(&pc);
+        Err(LuaError::new(format!(
+            "attempt to call a {} value{}",
+            value.type_name(),
+            describe(description)
+        )))
+    }
+
+    fn push_frame(
         &mut self,
-        table_name: &str,
-        members: &[String],
-        function_name: &str,
-        body: Value,
-    ) {
-        let value = self.get_mut(table_name);
-        let Some(Value::Table(t)) = value else {
-            return;
+        closure: Rc<Closure>,
+        func: usize,
+        results: Option<usize>,
+    ) -> Result<(), LuaError> {
+        if self.frames.len() >= self.call_depth_limit {
+            return Err(LuaError::new("stack overflow"));
+        }
+        let proto = &closure.proto;
+        let base = func + 1;
+        let parameters = proto.parameters;
+        let varargs = if self.stack.len() > base + parameters {
+            let extra = self.stack.split_off(base + parameters);
+            if proto.is_vararg {
+                extra
+            } else {
+                vec![]
+            }
+        } else {
+            self.stack.resize(base + parameters, Value::Nil);
+            vec![]
         };
-        let mut table = t;
-        for member in members.iter() {
-            let value = table.get_mut(&Value::String(member.to_string()));
-            let Some(Value::Table(t)) = value else {
-                return;
+        let cells = vec![None; proto.cells];
+        self.frames.push(Frame {
+            closure,
+            pc: 0,
+            base,
+            results,
+            varargs,
+            cells,
+            to_be_closed: vec![],
+            protected: false,
+            completion: None,
+        });
+        Ok(())
+    }
+
+    fn push_results(&mut self, mut values: Vec<Value>, results: Option<usize>) {
+        if let Some(n) = results {
+            values.resize(n, Value::Nil);
+        }
+        self.stack.extend(values);
+    }
+
+    /// Runs until the frame at `stop` returns, unwinding the frames above it on
+    /// errors up to a frame called by `pcall`. Yields leave the frames as they
+    /// are, so the coroutine can be resumed later.
+    fn execute(&mut self, stop: usize) -> Result<(), LuaError> {
+        loop {
+            let mut error = match self.run(stop) {
+                Ok(()) => return Ok(()),
+                Err(error) if self.yielded.is_some() => return Err(error),
+                Err(error) => error,
             };
-            table = t;
+            loop {
+                if self.frames.len() == stop {
+                    return Err(error);
+                }
+                if self.exit_status.is_some() {
+                    // Closing the interpreter closes the variables as on a
+                    // normal exit, whatever their handlers raise
+                    while self.close_variables(0, None).is_err() {}
+                } else {
+                    while let Err(e) = self.close_variables(0, Some(error.value.clone())) {
+                        error = e;
+                    }
+                }
+                let frame = self.frames.pop().unwrap();
+                self.stack.truncate(frame.base - 1);
+                if frame.protected && self.exit_status.is_none() {
+                    self.push_results(vec![Value::False, error.value], frame.results);
+                    break;
+                }
+            }
+            if self.frames.len() == stop {
+                return Ok(());
+            }
         }
-        table.insert(&Value::String(function_name.to_string()), &body);
     }
 
-    fn add_method_to_table(
-        &mut self,
-        table_name: &str,
-        members: &[String],
-        method_name: &str,
-        body: Value,
-    ) {
-        let value = self.get_mut(table_name);
-        let Some(Value::Table(t)) = value else {
-            return;
-        };
-        let mut table = t;
-        for member in members.iter() {
-            let value = table.get_mut(&Value::String(member.to_string()));
-            let Some(Value::Table(t)) = value else {
-                return;
+    /// Closes the to-be-closed variables of the current frame from `level` up,
+    /// in reverse order.
+    fn close_variables(&mut self, level: usize, error: Option<Value>) -> Result<(), LuaError> {
+        loop {
+            let frame = self.frames.last_mut().unwrap();
+            let slot = match frame.to_be_closed.last() {
+                Some(&slot) if slot >= level => slot,
+                _ => return Ok(()),
             };
-            table = t;
-        }
-        let Value::Lambda {
-            mut parameters,
-            body,
-        } = body
-        else {
-            unreachable!("Expected lambda, found {:?}", body);
-        };
-        parameters.name_list.insert(0, "self".to_string());
-        let body = Value::Lambda { parameters, body };
-        table.insert(&Value::String(method_name.to_string()), &body);
-    }
-
-    fn evaluate_local_function_definition(&mut self, statement: &'a Statement) -> Command {
-        let Statement::LocalFunctionDefinition {
-            name,
-            parameters,
-            body,
-        } = statement
-        else {
-            unreachable!("Expected function definition, found {:?}", statement);
+            frame.to_be_closed.pop();
+            let value = self.stack[frame.base + slot].clone();
+            let handler = self.metamethod(&value, "__close");
+            let error = error.clone().unwrap_or(Value::Nil);
+            self.call(handler, vec![value, error])?;
+        }
+    }
+
+    /// Closes the last to-be-closed variable of the current frame from
+    /// `level` up, if any, and runs the instruction at `pc` again for the
+    /// others once its handler returns, so that handlers can yield.
+    fn close_variable(&mut self, level: usize, pc: usize) -> Result<bool, LuaError> {
+        let frame = self.frames.last_mut().unwrap();
+        let slot = match frame.to_be_closed.last() {
+            Some(&slot) if slot >= level => slot,
+            _ => return Ok(false),
         };
-        let parameters = parameters.clone().unwrap_or(Parameters {
-            name_list: vec![],
-            var_arg: false,
-        });
-        let lambda = Value::Lambda {
-            parameters,
-            body: body.clone(),
+        frame.to_be_closed.pop();
+        frame.pc = pc;
+        let value = self.stack[frame.base + slot].clone();
+        let handler = self.metamethod(&value, "__close");
+        let arguments = vec![value, Value::Nil];
+        self.call_metamethod(handler, arguments, 0, Completion::Value)?;
+        Ok(true)
+    }
+
+    fn run(&mut self, stop: usize) -> Result<(), LuaError> {
+        loop {
+            let frame = self.frames.last_mut().unwrap();
+            let pc = frame.pc;
+            let instruction = frame.closure.proto.code[pc];
+            frame.pc += 1;
+            let base = frame.base;
+            match instruction {
+                Instruction::Nil => self.stack.push(Value::Nil),
+                Instruction::True => self.stack.push(Value::True),
+                Instruction::False => self.stack.push(Value::False),
+                Instruction::Constant(k) => {
+                    let value = frame.closure.proto.constants[k].clone();
+                    self.stack.push(value);
+                }
+                Instruction::Truncate(slot) => self.stack.truncate(base + slot),
+                Instruction::Local(slot) => {
+                    let value = self.stack[base + slot].clone();
+                    self.stack.push(value);
+                }
+                Instruction::SetLocal(slot) => {
+                    let value = self.stack.pop().unwrap();
+                    self.stack[base + slot] = value;
+                }
+                Instruction::Cell(cell) => {
+                    let value = frame.cells[cell].as_ref().unwrap().borrow().clone();
+                    self.stack.push(value);
+                }
+                Instruction::SetCell(cell) => {
+                    let value = self.stack.pop().unwrap();
+                    *frame.cells[cell].as_ref().unwrap().borrow_mut() = value;
+                }
+                Instruction::NewCell { cell, slot } => {
+                    let value = self.stack[base + slot].clone();
+                    let value = Some(self.new_cell(value));
+                    self.frames.last_mut().unwrap().cells[cell] = value;
+                }
+                Instruction::Upvalue(index) => {
+                    let value = frame.closure.upvalues[index].borrow().clone();
+                    self.stack.push(value);
+                }
+                Instruction::SetUpvalue(index) => {
+                    let value = self.stack.pop().unwrap();
+                    *frame.closure.upvalues[index].borrow_mut() = value;
+                }
+                Instruction::UpvalueField { upvalue, key } => {
+                    self.upvalue_field(upvalue, key, pc)?
+                }
+                Instruction::SetUpvalueField { upvalue, key } => {
+                    self.set_upvalue_field(upvalue, key)?
+                }
+                Instruction::Index => {
+                    let key = self.stack.pop().unwrap();
+                    let object = self.stack.pop().unwrap();
+                    let outcome = self.index_described(object, key, pc)?;
+                    self.push_outcome(outcome)?;
+                }
+                Instruction::Field(k) => {
+                    let key = frame.closure.proto.constants[k].clone();
+                    let object = self.stack.pop().unwrap();
+                    let outcome = self.index_described(object, key, pc)?;
+                    self.push_outcome(outcome)?;
+                }
+                Instruction::Method(k) => {
+                    let key = frame.closure.proto.constants[k].clone();
+                    let object = self.stack.pop().unwrap();
+                    match self.index_described(object.clone(), key, pc)? {
+                        Outcome::Metamethod {
+                            handler, arguments, ..
+                        } => {
+                            let completion = Completion::Method(object);
+                            self.call_metamethod(handler, arguments, 1, completion)?;
+                        }
+                        Outcome::Value(method) => {
+                            self.stack.push(method);
+                            self.stack.push(object);
+                        }
+                    }
+                }
+                Instruction::SetIndex { table, key } => {
+                    let value = self.stack.pop().unwrap();
+                    let table = self.stack[base + table].clone();
+                    let key = self.stack[base + key].clone();
+                    let outcome = self.set_index_outcome(table, key, value)?;
+                    self.discard_outcome(outcome)?;
+                }
+                Instruction::SetField { table, key } => {
+                    let key = frame.closure.proto.constants[key].clone();
+                    let value = self.stack.pop().unwrap();
+                    let table = self.stack[base + table].clone();
+                    let outcome = self.set_index_outcome(table, key, value)?;
+                    self.discard_outcome(outcome)?;
+                }
+                Instruction::NewTable => {
+                    let table = self.new_table(Table::new());
+                    self.stack.push(table);
+                }
+                Instruction::SetList { table } => self.set_list(base + table),
+                Instruction::Closure(index) => self.closure(index),
+                Instruction::VarArg(count) => self.vararg(count),
+                Instruction::Call { func, results } => {
+                    self.check_callable(base + func, pc)?;
+                    self.call_value(base + func, results)?;
+                }
+                Instruction::TailCall { func } => {
+                    self.tail_call(base + func, pc)?;
+                    if self.frames.len() == stop {
+                        return Ok(());
+                    }
+                }
+                Instruction::Return { first } => {
+                    if self.close_variable(0, pc)? {
+                        continue;
+                    }
+                    self.return_values(first);
+                    if self.frames.len() == stop {
+                        return Ok(());
+                    }
+                }
+                Instruction::Jump(target) => frame.pc = target,
+                Instruction::JumpIfFalse(target) => {
+                    if !self.stack.pop().unwrap().is_truthy() {
+                        frame.pc = target;
+                    }
+                }
+                Instruction::And(target) => {
+                    if self.stack.last().unwrap().is_truthy() {
+                        self.stack.pop();
+                    } else {
+                        frame.pc = target;
+                    }
+                }
+                Instruction::Or(target) => {
+                    if self.stack.last().unwrap().is_truthy() {
+                        frame.pc = target;
+                    } else {
+                        self.stack.pop();
+                    }
+                }
+                Instruction::Binary(operator) => {
+                    let rhs = self.stack.pop().unwrap();
+                    let lhs = self.stack.pop().unwrap();
+                    let outcome = self.binary_outcome(operator, lhs, rhs)?;
+                    self.push_outcome(outcome)?;
+                }
+                Instruction::Unary(operator) => {
+                    let operand = self.stack.pop().unwrap();
+                    let outcome = self.unary_outcome(operator, operand)?;
+                    self.push_outcome(outcome)?;
+                }
+                Instruction::MarkClose(slot) => self.mark_close(slot, pc)?,
+                Instruction::Close(level) => {
+                    self.close_variable(level, pc)?;
+                }
+                Instruction::NumericForPrepare { base: slot, exit } => {
+                    if !self.for_prepare(base + slot)? {
+                        self.frames.last_mut().unwrap().pc = exit;
+                    }
+                }
+                Instruction::NumericForLoop { base: slot, body } => {
+                    if self.for_loop(base + slot) {
+                        self.frames.last_mut().unwrap().pc = body;
+                    } else {
+                        self.stack.truncate(base + slot + 3);
+                    }
+                }
+                Instruction::GenericForLoop { base: slot, body } => {
+                    let control = self.stack[base + slot + 4].clone();
+                    if control.is_nil() {
+                        self.stack.truncate(base + slot + 4);
+                    } else {
+                        self.stack[base + slot + 2] = control;
+                        frame.pc = body;
+                    }
+                }
+            }
+        }
+    }
+
+    fn upvalue_field(&mut self, upvalue: usize, key: usize, pc: usize) -> Result<(), LuaError> {
+        let frame = self.frames.last().unwrap();
+        let object = frame.closure.upvalues[upvalue].borrow().clone();
+        let key = frame.closure.proto.constants[key].clone();
+        let outcome = self.index_described(object, key, pc)?;
+        self.push_outcome(outcome)
+    }
+
+    fn set_upvalue_field(&mut self, upvalue: usize, key: usize) -> Result<(), LuaError> {
+        let frame = self.frames.last().unwrap();
+        let object = frame.closure.upvalues[upvalue].borrow().clone();
+        let key = frame.closure.proto.constants[key].clone();
+        let value = self.stack.pop().unwrap();
+        let outcome = self.set_index_outcome(object, key, value)?;
+        self.discard_outcome(outcome)
+    }
+
+    fn set_list(&mut self, slot: usize) {
+        let values = self.stack.split_off(slot + 1);
+        let Value::Table(table) = &self.stack[slot] else {
+            unreachable!("Expected table constructor");
         };
-        self.scopes.last_mut().unwrap().insert(name.clone(), lambda);
-        Command::Continue
-    }
-
-    fn evaluate_numerical_for(&mut self, statement: &'a Statement) -> Command {
-        let Statement::NumericalFor {
-            control,
-            initial,
-            limit,
-            step,
-            block,
-        } = statement
-        else {
-            unreachable!("Expected numerical for, found {:?}", statement);
+        let mut table = table.borrow_mut();
+        for (i, value) in values.into_iter().enumerate() {
+            table.insert(Value::Integer(i as i64 + 1), value);
+        }
+    }
+
+    fn closure(&mut self, index: usize) {
+        let frame = self.frames.last().unwrap();
+        let proto = frame.closure.proto.protos[index].clone();
+        let upvalues = proto
+            .captures
+            .iter()
+            .map(|capture| match *capture {
+                Capture::Cell(cell) => frame.cells[cell].clone().unwrap(),
+                Capture::Upvalue(index) => frame.closure.upvalues[index].clone(),
+            })
+            .collect();
+        let closure = self.new_closure(Closure { proto, upvalues });
+        self.stack.push(closure);
+    }
+
+    fn vararg(&mut self, count: Option<usize>) {
+        let mut values = self.frames.last().unwrap().varargs.clone();
+        if let Some(n) = count {
+            values.resize(n, Value::Nil);
+        }
+        self.stack.extend(values);
+    }
+
+    fn tail_call(&mut self, func: usize, pc: usize) -> Result<(), LuaError> {
+        self.check_callable(func, pc)?;
+        let frame = self.frames.last().unwrap();
+        if frame.protected || frame.completion.is_some() {
+            // The frame must stay to catch errors or complete its
+            // instruction, the following return instruction returns the results
+            return self.call_value(func, None);
+        }
+        // The callee takes the place of the current function
+        let frame = self.frames.pop().unwrap();
+        let target = frame.base - 1;
+        self.stack.drain(target..func);
+        self.call_value(target, frame.results)
+    }
+
+    fn return_values(&mut self, first: usize) {
+        let frame = self.frames.pop().unwrap();
+        let func = frame.base - 1;
+        self.stack.drain(func..(frame.base + first));
+        if frame.protected {
+            self.stack.insert(func, Value::True);
+        }
+        if let Some(n) = frame.results {
+            self.stack.resize(func + n, Value::Nil);
+        }
+        if let Some(completion) = frame.completion {
+            self.complete(completion);
+        }
+    }
+
+    fn mark_close(&mut self, slot: usize, pc: usize) -> Result<(), LuaError> {
+        let frame = self.frames.last().unwrap();
+        let value = self.stack[frame.base + slot].clone();
+        if !value.is_truthy() {
+            return Ok(());
+        }
+        if self.metamethod(&value, "__close").is_nil() {
+            let name = frame
+                .closure
+                .proto
+                .descriptions
+                .get(&pc)
+                .map_or("?", String::as_str);
+            return Err(LuaError::new(format!(
+                "variable '{name}' got a non-closable value"
+            )));
+        }
+        self.frames.last_mut().unwrap().to_be_closed.push(slot);
+        Ok(())
+    }
+
+    /// Checks the values of a numeric for loop and pushes its control variable,
+    /// returning whether the loop runs at all. Integer loops keep the number of
+    /// remaining iterations in place of the limit.
+    fn for_prepare(&mut self, slot: usize) -> Result<bool, LuaError> {
+        let initial = self.stack[slot].clone();
+        let limit = self.stack[slot + 1].clone();
+        let step = self.stack[slot + 2].clone();
+        if let (Value::Integer(initial), Value::Integer(step)) = (&initial, &step) {
+            let (initial, step) = (*initial, *step);
+            if step == 0 {
+                return Err(LuaError::new("'for' step is zero"));
+            }
+            let Some(limit) = for_limit(initial, &limit, step)? else {
+                return Ok(false);
+            };
+            let count = if step > 0 {
+                (limit as u64).wrapping_sub(initial as u64) / step as u64
+            } else {
+                (initial as u64).wrapping_sub(limit as u64) / ((-(step + 1)) as u64 + 1)
+            };
+            self.stack[slot + 1] = Value::Integer(count as i64);
+            self.stack.push(Value::Integer(initial));
+            return Ok(true);
+        }
+        let Some(limit) = limit.to_float() else {
+            return Err(LuaError::new("'for' limit must be a number"));
         };
-        let initial = self.evaluate_expression(initial);
-        let limit = self.evaluate_expression(limit);
-        let step = step
-            .as_ref()
-            .map(|x| self.evaluate_expression(x))
-            .unwrap_or(Value::Integer(1));
-
-        let mut initial = match initial.to_number() {
-            Ok(v) => v,
-            Err(e) => return Command::Error(e),
+        let Some(step) = step.to_float() else {
+            return Err(LuaError::new("'for' step must be a number"));
         };
-        let mut limit = match limit.to_number() {
-            Ok(v) => v,
-            Err(e) => return Command::Error(e),
+        let Some(initial) = initial.to_float() else {
+            return Err(LuaError::new("'for' initial value must be a number"));
         };
-        let mut step = match step.to_number() {
-            Ok(v) => v,
-            Err(e) => return Command::Error(e),
+        if step == 0.0 {
+            return Err(LuaError::new("'for' step is zero"));
+        }
+        let runs = if step > 0.0 {
+            initial <= limit
+        } else {
+            limit <= initial
         };
-        if !matches!(
-            (&initial, &limit, &step),
-            (Value::Integer(_), Value::Integer(_), Value::Integer(_))
-        ) {
-            initial = initial.to_float().unwrap();
-            limit = limit.to_float().unwrap();
-            step = step.to_float().unwrap();
+        if runs {
+            self.stack[slot] = Value::Float(initial);
+            self.stack[slot + 1] = Value::Float(limit);
+            self.stack[slot + 2] = Value::Float(step);
+            self.stack.push(Value::Float(initial));
         }
-        let limit = limit;
-        let step = step;
+        Ok(runs)
+    }
 
-        let for_block = Block {
-            statements: vec![],
-            return_statement: None,
-        };
-        let for_block = Box::new(for_block);
-        let ref_block: &'static _ = Box::leak(for_block);
-        let mut scope = Scope::new(&ref_block);
-        scope.insert(control.clone(), initial);
-        self.scopes.push(scope);
-        let index = self.scopes.len() - 1;
+    /// Advances a numeric for loop, returning whether it continues.
+    fn for_loop(&mut self, slot: usize) -> bool {
+        match (&self.stack[slot + 1], &self.stack[slot + 2]) {
+            (Value::Integer(count), Value::Integer(step)) => {
+                let (count, step) = (*count, *step);
+                if count as u64 == 0 {
+                    return false;
+                }
+                let Value::Integer(control) = self.stack[slot] else {
+                    unreachable!("Expected integer loop");
+                };
+                let control = control.wrapping_add(step);
+                self.stack[slot] = Value::Integer(control);
+                self.stack[slot + 1] = Value::Integer((count as u64 - 1) as i64);
+                self.stack.truncate(slot + 3);
+                self.stack.push(Value::Integer(control));
+                true
+            }
+            (Value::Float(limit), Value::Float(step)) => {
+                let (limit, step) = (*limit, *step);
+                let Value::Float(control) = self.stack[slot] else {
+                    unreachable!("Expected float loop");
+                };
+                let control = control + step;
+                let continues = if step > 0.0 {
+                    control <= limit
+                } else {
+                    limit <= control
+                };
+                if continues {
+                    self.stack[slot] = Value::Float(control);
+                    self.stack.truncate(slot + 3);
+                    self.stack.push(Value::Float(control));
+                }
+                continues
+            }
+            _ => unreachable!("Expected numeric loop"),
+        }
+    }
 
-        let mut i = self.get_from(&control, index).unwrap();
-        while i.is_less_or_equal(&limit).unwrap() {
-            self.evaluate_block(block);
+    fn index_described(
+        &mut self,
+        object: Value,
+        key: Value,
+        pc: usize,
+    ) -> Result<Outcome, LuaError> {
+        if let Value::Table(table) = &object {
+            let value = table.borrow().get(&key);
+            if !value.is_nil() || table.borrow().metatable().is_none() {
+                return Ok(Outcome::Value(value));
+            }
+        }
+        if !matches!(object, Value::Table(_)) && self.metamethod(&object, "__index").is_nil() {
+            let frame = self.frames.last().unwrap();
+            let description = frame.closure.proto.descriptions.get(&pc);
+            return Err(LuaError::new(format!(
+                "attempt to index a {} value{}",
+                object.type_name(),
+                describe(description)
+            )));
+        }
+        self.index_outcome(object, key)
+    }
 
-            let initial = self.get_mut_from(&control, index).unwrap();
-            let r = initial.add(&step).unwrap();
-            let _ = mem::replace(initial, r);
+    /// `object[key]`, going through `__index` metamethods.
+    pub fn index(&mut self, object: Value, key: Value) -> Result<Value, LuaError> {
+        let outcome = self.index_outcome(object, key)?;
+        self.resolve(outcome)
+    }
 
-            i = self.get_from(&control, index).unwrap();
+    fn index_outcome(&mut self, mut object: Value, key: Value) -> Result<Outcome, LuaError> {
+        loop {
+            let handler = match &object {
+                Value::Table(table) => {
+                    let value = table.borrow().get(&key);
+                    if !value.is_nil() {
+                        return Ok(Outcome::Value(value));
+                    }
+                    let handler = self.metamethod(&object, "__index");
+                    if handler.is_nil() {
+                        return Ok(Outcome::Value(Value::Nil));
+                    }
+                    handler
+                }
+                _ => {
+                    let handler = self.metamethod(&object, "__index");
+                    if handler.is_nil() {
+                        return Err(LuaError::new(format!(
+                            "attempt to index a {} value",
+                            object.type_name()
+                        )));
+                    }
+                    handler
+                }
+            };
+            match handler {
+                Value::Lambda(_) | Value::Builtin(_) | Value::Native(_) => {
+                    return Ok(Outcome::Metamethod {
+                        handler,
+                        arguments: vec![object, key],
+                        completion: Completion::Value,
+                    });
+                }
+                handler => object = handler,
+            }
         }
+    }
 
-        self.scopes.pop();
-        Command::Continue
+    /// `object[key] = value`, going through `__newindex` metamethods.
+    pub fn set_index(&mut self, object: Value, key: Value, value: Value) -> Result<(), LuaError> {
+        let outcome = self.set_index_outcome(object, key, value)?;
+        self.resolve(outcome)?;
+        Ok(())
     }
 
-    fn evaluate_while(&mut self, statement: &'a Statement) -> Command {
-        let Statement::While { condition, block } = statement else {
-            unreachable!("Expected while statement, found {:?}", statement);
-        };
-        while self.evaluate_expression(condition).is_truthy() {
-            let label = self.evaluate_block(block);
-            match label {
-                Command::Goto(_) => return label,
-                Command::Break => break,
-                Command::Error(_) => return label,
-                _ => {}
+    fn set_index_outcome(
+        &mut self,
+        mut object: Value,
+        key: Value,
+        value: Value,
+    ) -> Result<Outcome, LuaError> {
+        loop {
+            let handler = match &object {
+                Value::Table(table) => {
+                    let is_present = !table.borrow().get(&key).is_nil();
+                    let handler = if is_present {
+                        Value::Nil
+                    } else {
+                        self.metamethod(&object, "__newindex")
+                    };
+                    if handler.is_nil() {
+                        check_key(&key)?;
+                        table.borrow_mut().insert(key, value);
+                        return Ok(Outcome::Value(Value::Nil));
+                    }
+                    handler
+                }
+                _ => {
+                    let handler = self.metamethod(&object, "__newindex");
+                    if handler.is_nil() {
+                        return Err(LuaError::new(format!(
+                            "attempt to index a {} value",
+                            object.type_name()
+                        )));
+                    }
+                    handler
+                }
+            };
+            match handler {
+                Value::Lambda(_) | Value::Builtin(_) | Value::Native(_) => {
+                    return Ok(Outcome::Metamethod {
+                        handler,
+                        arguments: vec![object, key, value],
+                        completion: Completion::Value,
+                    });
+                }
+                handler => object = handler,
             }
         }
-        Command::Continue
     }
 
-    fn evaluate_repeat(&mut self, statement: &'a Statement) -> Command {
-        let Statement::Repeat { block, condition } = statement else {
-            unreachable!("Expected repeat statement, found {:?}", statement);
-        };
-        let label = self.evaluate_block(block);
-        match label {
-            Command::Goto(_) => return label,
-            Command::Break => return Command::Continue,
-            _ => {}
+    /// The call of the metamethod for `event` of either operand, if any.
+    fn binary_metamethod(
+        &self,
+        event: &str,
+        lhs: &Value,
+        rhs: &Value,
+        completion: Completion,
+    ) -> Option<Outcome> {
+        let mut handler = self.metamethod(lhs, event);
+        if handler.is_nil() {
+            handler = self.metamethod(rhs, event);
         }
-        while !self.evaluate_expression(condition).is_truthy() {
-            let label = self.evaluate_block(block);
-            match label {
-                Command::Goto(_) => return label,
-                Command::Break => break,
-                Command::Error(_) => return label,
-                _ => {}
-            }
+        if handler.is_nil() {
+            return None;
         }
-        Command::Continue
+        Some(Outcome::Metamethod {
+            handler,
+            arguments: vec![lhs.clone(), rhs.clone()],
+            completion,
+        })
     }
 
-    fn evaluate_if(&mut self, statement: &'a Statement) -> Command {
-        let Statement::If { ifs, r#else } = statement else {
-            unreachable!("Expected if statement, found {:?}", statement);
+    pub fn binary(
+        &mut self,
+        operator: BinaryOperator,
+        lhs: Value,
+        rhs: Value,
+    ) -> Result<Value, LuaError> {
+        let outcome = self.binary_outcome(operator, lhs, rhs)?;
+        self.resolve(outcome)
+    }
+
+    fn binary_outcome(
+        &mut self,
+        operator: BinaryOperator,
+        lhs: Value,
+        rhs: Value,
+    ) -> Result<Outcome, LuaError> {
+        use BinaryOperator::*;
+        match operator {
+            Equals => return Ok(self.equals_outcome(&lhs, &rhs, false)),
+            Different => return Ok(self.equals_outcome(&lhs, &rhs, true)),
+            Less => return self.less_than_outcome(&lhs, &rhs),
+            LessOrEqual => return self.less_or_equal_outcome(&lhs, &rhs),
+            Greater => return self.less_than_outcome(&rhs, &lhs),
+            GreaterOrEqual => return self.less_or_equal_outcome(&rhs, &lhs),
+            Concatenation => return self.concatenate_outcome(lhs, rhs),
+            _ => {}
+        }
+        if let Some(value) = arithmetic(operator, &lhs, &rhs)? {
+            return Ok(Outcome::Value(value));
+        }
+        let event = match operator {
+            Addition => "__add",
+            Subtraction => "__sub",
+            Multiplication => "__mul",
+            Division => "__div",
+            IntegerDivision => "__idiv",
+            Modulo => "__mod",
+            Exponentiation => "__pow",
+            BitwiseAnd => "__band",
+            BitwiseOr => "__bor",
+            BitwiseXor => "__bxor",
+            BitwiseLeftShift => "__shl",
+            BitwiseRightShift => "__shr",
+            _ => unreachable!("Expected arithmetic operator"),
         };
-        for r#if in ifs {
-            let If { condition, block } = r#if;
-            let result = self.evaluate_expression(condition);
-            if result.is_truthy() {
-                return self.evaluate_block(block);
-            }
+        if let Some(outcome) = self.binary_metamethod(event, &lhs, &rhs, Completion::Value) {
+            return Ok(outcome);
         }
-        let Some(block) = r#else else {
-            return Command::Continue;
+        let is_bitwise = matches!(
+            operator,
+            BitwiseAnd | BitwiseOr | BitwiseXor | BitwiseLeftShift | BitwiseRightShift
+        );
+        let culprit = if lhs.to_number().is_none() {
+            &lhs
+        } else {
+            &rhs
         };
-        self.evaluate_block(block)
+        if is_bitwise {
+            if lhs.to_number().is_some() && rhs.to_number().is_some() {
+                return Err(LuaError::new("number has no integer representation"));
+            }
+            return Err(LuaError::new(format!(
+                "attempt to perform bitwise operation on a {} value",
+                culprit.type_name()
+            )));
+        }
+        Err(LuaError::new(format!(
+            "attempt to perform arithmetic on a {} value",
+            culprit.type_name()
+        )))
     }
 
-    fn evaluate_do(&mut self, statement: &'a Statement) -> Command {
-        let Statement::Do(block) = statement else {
-            unreachable!("Expected do statement, found {:?}", statement);
-        };
-        return self.evaluate_block(block);
+    pub fn unary(&mut self, operator: UnaryOperator, operand: Value) -> Result<Value, LuaError> {
+        let outcome = self.unary_outcome(operator, operand)?;
+        self.resolve(outcome)
     }
 
-    fn evaluate_global_variables(&mut self, statement: &Statement) -> Command {
-        let Statement::Assignment {
-            variable_list,
-            expr_list,
-        } = statement
-        else {
-            unreachable!("Expected assignment, found {:?}", statement);
-        };
-        let values: Vec<_> = expr_list
-            .iter()
-            .map(|expr| self.evaluate_expression(expr))
-            .collect();
-        let mut values = values.into_iter();
-        for variable in variable_list {
-            let value = values.next().unwrap_or(Value::Nil);
-            match variable {
-                Variable::Name(name) => self.scopes[0].insert(name.clone(), value),
-                Variable::Selector { .. } => todo!(),
+    fn unary_outcome(
+        &mut self,
+        operator: UnaryOperator,
+        operand: Value,
+    ) -> Result<Outcome, LuaError> {
+        match operator {
+            UnaryOperator::BooleanNegation => Ok(Outcome::Value(Value::from(!operand.is_truthy()))),
+            UnaryOperator::Negation => match operand.to_number() {
+                Some(Value::Integer(n)) => Ok(Outcome::Value(Value::Integer(n.wrapping_neg()))),
+                Some(Value::Float(f)) => Ok(Outcome::Value(Value::Float(-f))),
+                _ => match self.binary_metamethod("__unm", &operand, &operand, Completion::Value) {
+                    Some(outcome) => Ok(outcome),
+                    None => Err(LuaError::new(format!(
+                        "attempt to perform arithmetic on a {} value",
+                        operand.type_name()
+                    ))),
+                },
+            },
+            UnaryOperator::BitwiseNegation => {
+                if let Some(n) = operand.to_integer() {
+                    return Ok(Outcome::Value(Value::Integer(!n)));
+                }
+                match self.binary_metamethod("__bnot", &operand, &operand, Completion::Value) {
+                    Some(outcome) => Ok(outcome),
+                    None if operand.to_number().is_some() => {
+                        Err(LuaError::new("number has no integer representation"))
+                    }
+                    None => Err(LuaError::new(format!(
+                        "attempt to perform bitwise operation on a {} value",
+                        operand.type_name()
+                    ))),
+                }
             }
+            UnaryOperator::Length => self.length_outcome(operand),
         }
-        Command::Continue
     }
 
-    fn evaluate_local_variables(&mut self, statement: &Statement) -> Command {
-        let Statement::LocalVariables {
-            variables,
-            expr_list,
-        } = statement
-        else {
-            unreachable!("Expected local variables, found {:?}", statement);
-        };
-        if expr_list.is_none() {
-            for variable in variables {
-                self.scopes
-                    .last_mut()
-                    .unwrap()
-                    .insert(variable.name.clone(), Value::Nil);
-            }
-            return Command::Continue;
+    /// `#value`, going through the `__len` metamethod.
+    pub fn length(&mut self, value: Value) -> Result<Value, LuaError> {
+        let outcome = self.length_outcome(value)?;
+        self.resolve(outcome)
+    }
+
+    fn length_outcome(&mut self, value: Value) -> Result<Outcome, LuaError> {
+        if let Value::String(s) = &value {
+            return Ok(Outcome::Value(Value::Integer(s.len() as i64)));
         }
-        let values: Vec<_> = expr_list
-            .as_ref()
-            .unwrap()
-            .iter()
-            .map(|expr| self.evaluate_expression(expr))
-            .collect();
-        let mut values = values.into_iter();
-        for variable in variables {
-            let value = values.next().unwrap_or(Value::Nil);
-            self.scopes
-                .last_mut()
-                .unwrap()
-                .insert(variable.name.clone(), value);
-        }
-        Command::Continue
-    }
-
-    fn evaluate_expression(&mut self, expression: &Expression) -> Value {
-        match expression {
-            Expression::Integer(n) => Value::Integer(*n),
-            Expression::Float(n) => Value::Float(*n),
-            Expression::True => Value::True,
-            Expression::False => Value::False,
-            Expression::Nil => Value::Nil,
-            Expression::String(str) => Value::String(str.clone()),
-            Expression::Lambda { parameters, body } => Value::Lambda {
-                parameters: parameters.clone().unwrap_or(Parameters {
-                    name_list: vec![],
-                    var_arg: false,
-                }),
-                body: body.clone(),
-            },
-            Expression::Table(fields) => {
-                Value::Table(Table::from_fields(fields, |e| self.evaluate_expression(e)))
+        let handler = self.metamethod(&value, "__len");
+        if !handler.is_nil() {
+            return Ok(Outcome::Metamethod {
+                handler,
+                arguments: vec![value],
+                completion: Completion::Value,
+            });
+        }
+        match &value {
+            Value::Table(table) => Ok(Outcome::Value(Value::Integer(table.borrow().length()))),
+            _ => Err(LuaError::new(format!(
+                "attempt to get length of a {} value",
+                value.type_name()
+            ))),
+        }
+    }
+
+    pub fn equals(&mut self, lhs: &Value, rhs: &Value) -> Result<bool, LuaError> {
+        let outcome = self.equals_outcome(lhs, rhs, false);
+        Ok(self.resolve(outcome)?.is_truthy())
+    }
+
+    fn equals_outcome(&mut self, lhs: &Value, rhs: &Value, negated: bool) -> Outcome {
+        if lhs == rhs {
+            return Outcome::Value(Value::from(!negated));
+        }
+        if matches!(
+            (lhs, rhs),
+            (Value::Table(_), Value::Table(_)) | (Value::Userdata(_), Value::Userdata(_))
+        ) {
+            let completion = Completion::Truth { negated };
+            if let Some(outcome) = self.binary_metamethod("__eq", lhs, rhs, completion) {
+                return outcome;
             }
-            _ => todo!("Evaluate expression: {:?}", expression),
         }
+        Outcome::Value(Value::from(negated))
     }
 
-    fn evaluate_expression_for_reference(&mut self, expression: &Expression) -> Option<&mut Value> {
-        match expression {
-            Expression::PrefixExpression(p) => self.evaluate_prefix_expression(p),
-            Expression::Lambda { parameters, body } => todo!(),
-            _ => None,
+    pub fn less_than(&mut self, lhs: &Value, rhs: &Value) -> Result<bool, LuaError> {
+        let outcome = self.less_than_outcome(lhs, rhs)?;
+        Ok(self.resolve(outcome)?.is_truthy())
+    }
+
+    fn less_than_outcome(&mut self, lhs: &Value, rhs: &Value) -> Result<Outcome, LuaError> {
+        if let Some(ordering) = raw_compare(lhs, rhs) {
+            let is_less = ordering == Some(std::cmp::Ordering::Less);
+            return Ok(Outcome::Value(Value::from(is_less)));
         }
+        let completion = Completion::Truth { negated: false };
+        self.binary_metamethod("__lt", lhs, rhs, completion)
+            .ok_or_else(|| compare_error(lhs, rhs))
     }
 
-    fn evaluate_prefix_expression(&mut self, prefix_expr: &PrefixExpression) -> Option<&mut Value> {
-        todo!("Damm fucking mutability");
-        let PrefixExpression { primary, actions } = prefix_expr;
-        let mut reference = match primary {
-            Primary::Name(name) => self.get_mut(name),
-            Primary::Expression(expression) => self.evaluate_expression_for_reference(expression),
-        }?;
-        for action in actions.iter() {
-            let x = match action {
-                PExprAction::Selector(selector) => match selector {
-                    Selector::Dot(name) => Value::String(name.to_string()),
-                    Selector::Key(expr) => self.evaluate_expression(expr),
-                },
-                PExprAction::Call(call) => match call {
-                    CallSuffix::Simple(arg) => todo!(),
-                    CallSuffix::Method { name, argument } => todo!(),
-                },
-            };
-            let Value::Table(table) = reference else {
-                return None;
-            };
-            reference = table.get_mut(&x)?;
+    pub fn less_or_equal(&mut self, lhs: &Value, rhs: &Value) -> Result<bool, LuaError> {
+        let outcome = self.less_or_equal_outcome(lhs, rhs)?;
+        Ok(self.resolve(outcome)?.is_truthy())
+    }
+
+    fn less_or_equal_outcome(&mut self, lhs: &Value, rhs: &Value) -> Result<Outcome, LuaError> {
+        if let Some(ordering) = raw_compare(lhs, rhs) {
+            let is_less_or_equal = matches!(
+                ordering,
+                Some(std::cmp::Ordering::Less | std::cmp::Ordering::Equal)
+            );
+            return Ok(Outcome::Value(Value::from(is_less_or_equal)));
+        }
+        let completion = Completion::Truth { negated: false };
+        self.binary_metamethod("__le", lhs, rhs, completion)
+            .ok_or_else(|| compare_error(lhs, rhs))
+    }
+
+    pub fn concatenate(&mut self, lhs: Value, rhs: Value) -> Result<Value, LuaError> {
+        let outcome = self.concatenate_outcome(lhs, rhs)?;
+        self.resolve(outcome)
+    }
+
+    fn concatenate_outcome(&mut self, lhs: Value, rhs: Value) -> Result<Outcome, LuaError> {
+        if let (Some(a), Some(b)) = (lhs.to_lua_string(), rhs.to_lua_string()) {
+            let value = Value::String([a.as_bytes(), b.as_bytes()].concat().into());
+            return Ok(Outcome::Value(value));
         }
-        Some(reference)
+        if let Some(outcome) = self.binary_metamethod("__concat", &lhs, &rhs, Completion::Value) {
+            return Ok(outcome);
+        }
+        let culprit = if lhs.to_lua_string().is_some() {
+            &rhs
+        } else {
+            &lhs
+        };
+        Err(LuaError::new(format!(
+            "attempt to concatenate a {} value",
+            culprit.type_name()
+        )))
     }
+}
 
-    fn get_from(&self, name: &str, index: usize) -> Option<&Value> {
-        self.scopes[index].get(name)
+/// How a chunk is named in messages: as given after `=` or `@`, or quoting
+/// the start of its source.
+fn chunk_id(name: &str) -> String {
+    if let Some(name) = name.strip_prefix('=').or_else(|| name.strip_prefix('@')) {
+        return name.to_string();
+    }
+    let line = name.lines().next().unwrap_or("");
+    if line.len() < name.len() || line.chars().count() > 45 {
+        let start: String = line.chars().take(45).collect();
+        format!("[string \"{start}...\"]")
+    } else {
+        format!("[string \"{line}\"]")
     }
+}
 
-    fn get_mut_from(&mut self, name: &str, index: usize) -> Option<&mut Value> {
-        self.scopes[index].get_mut(name)
+fn describe(description: Option<&String>) -> String {
+    match description {
+        Some(description) => format!(" ({description})"),
+        None => String::new(),
     }
+}
 
-    fn get(&self, name: &str) -> Option<&Value> {
-        let mut value = None;
-        for scope in self.scopes.iter().rev() {
-            value = scope.get(name);
-            if value.is_some() {
-                break;
+pub(crate) fn check_key(key: &Value) -> Result<(), LuaError> {
+    match key {
+        Value::Nil => Err(LuaError::new("table index is nil")),
+        Value::Float(f) if f.is_nan() => Err(LuaError::new("table index is NaN")),
+        _ => Ok(()),
+    }
+}
+
+/// The limit of an integer loop, `None` if the loop must not run.
+fn for_limit(initial: i64, limit: &Value, step: i64) -> Result<Option<i64>, LuaError> {
+    let limit = match limit.to_number() {
+        Some(Value::Integer(n)) => n,
+        Some(Value::Float(f)) => {
+            let rounded = if step < 0 { f.ceil() } else { f.floor() };
+            match float_to_integer(rounded) {
+                Some(n) => n,
+                None if f > 0.0 => {
+                    if step < 0 {
+                        return Ok(None);
+                    }
+                    i64::MAX
+                }
+                None => {
+                    if step > 0 {
+                        return Ok(None);
+                    }
+                    i64::MIN
+                }
             }
         }
-        value
+        _ => return Err(LuaError::new("'for' limit must be a number")),
+    };
+    let skips = if step > 0 {
+        initial > limit
+    } else {
+        initial < limit
+    };
+    Ok(if skips { None } else { Some(limit) })
+}
+
+/// Compares numbers and strings, `None` if the operands are of other types.
+fn raw_compare(lhs: &Value, rhs: &Value) -> Option<Option<std::cmp::Ordering>> {
+    match (lhs, rhs) {
+        (Value::Integer(a), Value::Integer(b)) => Some(Some(a.cmp(b))),
+        (Value::Float(a), Value::Float(b)) => Some(a.partial_cmp(b)),
+        (Value::Integer(a), Value::Float(b)) => Some(compare_integer_float(*a, *b)),
+        (Value::Float(a), Value::Integer(b)) => {
+            Some(compare_integer_float(*b, *a).map(|o| o.reverse()))
+        }
+        (Value::String(a), Value::String(b)) => Some(Some(a.as_bytes().cmp(b.as_bytes()))),
+        _ => None,
+    }
+}
+
+/// Compares an integer with a float exactly, without rounding the integer.
+fn compare_integer_float(i: i64, f: f64) -> Option<std::cmp::Ordering> {
+    if f.is_nan() {
+        return None;
+    }
+    let min = i64::MIN as f64;
+    if f >= -min {
+        return Some(std::cmp::Ordering::Less);
     }
+    if f < min {
+        return Some(std::cmp::Ordering::Greater);
+    }
+    let truncated = f.trunc();
+    match i.cmp(&(truncated as i64)) {
+        std::cmp::Ordering::Equal => 0.0.partial_cmp(&(f - truncated)),
+        ordering => Some(ordering),
+    }
+}
 
-    fn get_mut(&mut self, name: &str) -> Option<&mut Value> {
-        let mut value = None;
-        for scope in self.scopes.iter_mut().rev() {
-            value = scope.get_mut(name);
-            if value.is_some() {
-                break;
+fn compare_error(lhs: &Value, rhs: &Value) -> LuaError {
+    let (a, b) = (lhs.type_name(), rhs.type_name());
+    if a == b {
+        LuaError::new(format!("attempt to compare two {a} values"))
+    } else {
+        LuaError::new(format!("attempt to compare {a} with {b}"))
+    }
+}
+
+/// Arithmetic and bitwise operations on numbers and strings convertible to
+/// numbers, `None` if an operand is not one of them.
+fn arithmetic(
+    operator: BinaryOperator,
+    lhs: &Value,
+    rhs: &Value,
+) -> Result<Option<Value>, LuaError> {
+    use BinaryOperator::*;
+    match (operator, lhs, rhs) {
+        (Addition, Value::Integer(x), Value::Integer(y)) => {
+            return Ok(Some(Value::Integer(x.wrapping_add(*y))))
+        }
+        (Subtraction, Value::Integer(x), Value::Integer(y)) => {
+            return Ok(Some(Value::Integer(x.wrapping_sub(*y))))
+        }
+        (Addition, Value::Float(x), Value::Float(y)) => return Ok(Some(Value::Float(x + y))),
+        (Subtraction, Value::Float(x), Value::Float(y)) => return Ok(Some(Value::Float(x - y))),
+        (Multiplication, Value::Float(x), Value::Float(y)) => return Ok(Some(Value::Float(x * y))),
+        _ => {}
+    }
+    let (Some(a), Some(b)) = (lhs.to_number(), rhs.to_number()) else {
+        return Ok(None);
+    };
+    let value = match operator {
+        Addition | Subtraction | Multiplication | IntegerDivision | Modulo => match (&a, &b) {
+            (Value::Integer(x), Value::Integer(y)) => {
+                let (x, y) = (*x, *y);
+                Value::Integer(match operator {
+                    Addition => x.wrapping_add(y),
+                    Subtraction => x.wrapping_sub(y),
+                    Multiplication => x.wrapping_mul(y),
+                    IntegerDivision => {
+                        if y == 0 {
+                            return Err(LuaError::new("attempt to perform 'n//0'"));
+                        }
+                        let q = x.wrapping_div(y);
+                        if x.wrapping_rem(y) != 0 && (x ^ y) < 0 {
+                            q - 1
+                        } else {
+                            q
+                        }
+                    }
+                    _ => {
+                        if y == 0 {
+                            return Err(LuaError::new("attempt to perform 'n%%0'"));
+                        }
+                        let r = x.wrapping_rem(y);
+                        if r != 0 && (r ^ y) < 0 {
+                            r + y
+                        } else {
+                            r
+                        }
+                    }
+                })
             }
+            _ => {
+                let (x, y) = (a.to_float().unwrap(), b.to_float().unwrap());
+                Value::Float(match operator {
+                    Addition => x + y,
+                    Subtraction => x - y,
+                    Multiplication => x * y,
+                    IntegerDivision => (x / y).floor(),
+                    _ => float_modulo(x, y),
+                })
+            }
+        },
+        Division => Value::Float(a.to_float().unwrap() / b.to_float().unwrap()),
+        Exponentiation => Value::Float(a.to_float().unwrap().powf(b.to_float().unwrap())),
+        _ => {
+            let (Some(x), Some(y)) = (a.to_integer(), b.to_integer()) else {
+                return Err(LuaError::new("number has no integer representation"));
+            };
+            Value::Integer(match operator {
+                BitwiseAnd => x & y,
+                BitwiseOr => x | y,
+                BitwiseXor => x ^ y,
+                BitwiseLeftShift => shift_left(x, y),
+                BitwiseRightShift => shift_left(x, y.wrapping_neg()),
+                _ => unreachable!("Expected arithmetic operator"),
+            })
         }
-        value
+    };
+    Ok(Some(value))
+}
+
+fn float_modulo(x: f64, y: f64) -> f64 {
+    let m = x % y;
+    if (m > 0.0 && y < 0.0) || (m < 0.0 && y > 0.0) {
+        m + y
+    } else {
+        m
+    }
+}
+
+fn shift_left(x: i64, y: i64) -> i64 {
+    if y <= -64 || y >= 64 {
+        0
+    } else if y >= 0 {
+        ((x as u64) << y) as i64
+    } else {
+        ((x as u64) >> -y) as i64
     }
 }
@@ -1,242 +1,754 @@
-use std::{collections::HashMap, error::Error, fmt::Debug, hash::Hash};
-
-use pest::Parser;
+use std::{
+    any::Any,
+    borrow::Cow,
+    cell::{Ref, RefCell, RefMut},
+    collections::HashMap,
+    fmt::{self, Debug, Display},
+    hash::Hash,
+    mem,
+    ops::Deref,
+    rc::Rc,
+};
 
 use crate::{
-    parser::{
-        expression::{self, Expression, Field},
-        statement::{Block, Parameters},
-        LuaParser, Rule,
+    interpreter::{
+        compiler::Proto,
+        convert::{FromLuaMulti, IntoLuaMulti},
+        coroutine::ThreadRef,
+        Interpreter, LuaError,
     },
+    parser::expression::{parse_float, parse_hex_float, parse_hex_integer},
     std::Builtin,
 };
 
-#[derive(Debug, Clone)]
+pub type TableRef = Rc<RefCell<Table>>;
+
+/// A Lua table, split into an array part holding the keys `1..=n` and a hash part.
+///
+/// The hash part keeps its entries in insertion order so `next` can resume a
+/// traversal from any key. Removed entries are left behind as `nil` until the
+/// next insertion of a new key, which allows clearing fields while traversing.
+#[derive(Default)]
 pub struct Table {
-    counter: usize,
-    table: HashMap<Value, Value>,
+    array: Vec<Value>,
+    indices: HashMap<Value, usize>,
+    entries: Vec<(Value, Value)>,
+    removed: usize,
+    metatable: Option<TableRef>,
 }
 
 impl Table {
-    pub fn insert(&mut self, key: &Value, value: &Value) {
-        self.table.insert(key.clone(), value.clone());
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    pub fn get(&self, key: &Value) -> Option<&Value> {
-        self.table.get(key)
+    pub fn get(&self, key: &Value) -> Value {
+        if let Some(index) = self.array_index(key) {
+            return self.array[index].clone();
+        }
+        // Integral floats compare and hash like integers, so no normalization is needed
+        match self.indices.get(key) {
+            Some(&index) => self.entries[index].1.clone(),
+            None => Value::Nil,
+        }
     }
 
-    pub fn get_mut(&mut self, key: &Value) -> Option<&mut Value> {
-        self.table.get_mut(key)
+    pub fn get_str(&self, key: &str) -> Value {
+        self.get(&Value::String(key.into()))
     }
-}
 
-impl Table {
-    pub fn from_fields<F>(fields: &[Field], mut expr_evaluator: F) -> Table
-    where
-        F: FnMut(&Expression) -> Value,
-    {
-        let mut table = Table {
-            counter: 1,
-            table: HashMap::new(),
-        };
-        for field in fields {
-            let (key, value) = match field {
-                Field::ExprKey { key, value } => {
-                    let key = expr_evaluator(key);
-                    let value = expr_evaluator(value);
-                    (key, value)
+    /// Sets `key` to `value`, removing the entry when `value` is `nil`.
+    ///
+    /// The key must be a valid table key, that is, neither `nil` nor NaN.
+    pub fn insert(&mut self, key: Value, value: Value) {
+        let key = key.normalized();
+        if let Value::Integer(n) = key {
+            let length = self.array.len() as i64;
+            if 1 <= n && n <= length {
+                self.array[(n - 1) as usize] = value;
+                if n == length {
+                    while matches!(self.array.last(), Some(Value::Nil)) {
+                        self.array.pop();
+                    }
                 }
-                Field::NameKey { name, value } => {
-                    let key = Value::String(name.clone());
-                    let value = expr_evaluator(value);
-                    (key, value)
+                return;
+            }
+            if n == length + 1 && !value.is_nil() {
+                self.array.push(value);
+                self.migrate_to_array();
+                return;
+            }
+        }
+        match self.indices.get(&key) {
+            Some(&index) => {
+                let entry = &mut self.entries[index].1;
+                match (entry.is_nil(), value.is_nil()) {
+                    (true, false) => self.removed -= 1,
+                    (false, true) => self.removed += 1,
+                    _ => {}
                 }
-                Field::Expr(expr) => {
-                    let key = Value::Integer(table.counter as i64);
-                    let value = expr_evaluator(expr);
-                    table.counter += 1;
-                    (key, value)
+                *entry = value;
+            }
+            None if value.is_nil() => {}
+            None => {
+                if self.removed > 0 && self.removed * 2 >= self.entries.len() {
+                    self.compact();
+                }
+                self.indices.insert(key.clone(), self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+    }
+
+    pub fn insert_str(&mut self, key: &str, value: Value) {
+        self.insert(Value::String(key.into()), value)
+    }
+
+    /// Length of the table, a border as defined by the manual.
+    pub fn length(&self) -> i64 {
+        self.array.len() as i64
+    }
+
+    /// The entry that follows `key` in a traversal, `nil` starting it.
+    ///
+    /// Fails if `key` is not present in the table.
+    pub fn next(&self, key: &Value) -> Result<Option<(Value, Value)>, LuaError> {
+        let (mut array_index, mut entry_index) = match key.normalized() {
+            Value::Nil => (0, 0),
+            Value::Integer(n) if 1 <= n && n <= self.array.len() as i64 => (n as usize, 0),
+            key => match self.indices.get(&key) {
+                Some(&index) => (self.array.len(), index + 1),
+                // The key was the last item of the array part, which shrank after
+                // it was set to nil during the traversal
+                None if matches!(key, Value::Integer(n) if n > self.array.len() as i64) => {
+                    (self.array.len(), 0)
                 }
+                None => return Err(LuaError::new("invalid key to 'next'")),
+            },
+        };
+        while array_index < self.array.len() {
+            let value = &self.array[array_index];
+            array_index += 1;
+            if !value.is_nil() {
+                return Ok(Some((Value::Integer(array_index as i64), value.clone())));
+            }
+        }
+        while entry_index < self.entries.len() {
+            let (key, value) = &self.entries[entry_index];
+            entry_index += 1;
+            if !value.is_nil() {
+                return Ok(Some((key.clone(), value.clone())));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn metatable(&self) -> Option<TableRef> {
+        self.metatable.clone()
+    }
+
+    pub fn set_metatable(&mut self, metatable: Option<TableRef>) {
+        self.metatable = metatable;
+    }
+
+    /// Whether the table holds its keys and its values weakly, as set by the
+    /// `__mode` field of its metatable.
+    pub fn weakness(&self) -> (bool, bool) {
+        let Some(metatable) = &self.metatable else {
+            return (false, false);
+        };
+        match metatable.borrow().get_str("__mode") {
+            Value::String(mode) => (mode.contains(&b'k'), mode.contains(&b'v')),
+            _ => (false, false),
+        }
+    }
+
+    pub(super) fn array(&self) -> &[Value] {
+        &self.array
+    }
+
+    /// The entries of the hash part, including removed ones.
+    pub(super) fn entries(&self) -> impl Iterator<Item = (&Value, &Value)> {
+        self.entries.iter().map(|(key, value)| (key, value))
+    }
+
+    /// Every value the table holds, including the keys of its hash part once
+    /// for each copy kept.
+    pub(super) fn references(&self) -> impl Iterator<Item = &Value> {
+        let entries = self.entries.iter().flat_map(|(key, value)| [key, value]);
+        self.array.iter().chain(entries).chain(self.indices.keys())
+    }
+
+    /// Rough number of bytes used by the table.
+    pub(super) fn memory(&self) -> usize {
+        let value = mem::size_of::<Value>();
+        mem::size_of::<Table>()
+            + self.array.capacity() * value
+            + self.entries.capacity() * 2 * value
+            + self.indices.capacity() * (value + mem::size_of::<usize>())
+    }
+
+    fn array_index(&self, key: &Value) -> Option<usize> {
+        let n = match key {
+            Value::Integer(n) => *n,
+            Value::Float(f) => float_to_integer(*f)?,
+            _ => return None,
+        };
+        if 1 <= n && n <= self.array.len() as i64 {
+            Some((n - 1) as usize)
+        } else {
+            None
+        }
+    }
+
+    fn migrate_to_array(&mut self) {
+        loop {
+            let key = Value::Integer(self.array.len() as i64 + 1);
+            let Some(&index) = self.indices.get(&key) else {
+                return;
             };
-            table.table.insert(key, value);
+            let value = std::mem::replace(&mut self.entries[index].1, Value::Nil);
+            if value.is_nil() {
+                return;
+            }
+            self.removed += 1;
+            self.array.push(value);
         }
-        table
+    }
+
+    fn compact(&mut self) {
+        self.entries.retain(|(_, value)| !value.is_nil());
+        self.indices = self
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(i, (key, _))| (key.clone(), i))
+            .collect();
+        self.removed = 0;
     }
 }
 
-#[derive(Debug, Clone)]
+impl Debug for Table {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Table")
+            .field("array", &self.array)
+            .field("entries", &self.entries)
+            .field("metatable", &self.metatable.as_ref().map(Rc::as_ptr))
+            .finish()
+    }
+}
+
+impl Drop for Table {
+    fn drop(&mut self) {
+        let keys = mem::take(&mut self.indices).into_keys();
+        let entries = mem::take(&mut self.entries)
+            .into_iter()
+            .flat_map(|(key, value)| [key, value]);
+        let metatable = self.metatable.take().map(Value::Table);
+        release(
+            mem::take(&mut self.array)
+                .into_iter()
+                .chain(entries)
+                .chain(keys)
+                .chain(metatable),
+        );
+    }
+}
+
+pub struct Closure {
+    pub proto: Rc<Proto>,
+    pub upvalues: Vec<Rc<RefCell<Value>>>,
+}
+
+impl Drop for Closure {
+    fn drop(&mut self) {
+        // Only the cells no other closure shares are freed with this one
+        let values = mem::take(&mut self.upvalues)
+            .into_iter()
+            .filter_map(|cell| Rc::try_unwrap(cell).ok())
+            .map(RefCell::into_inner);
+        release(values);
+    }
+}
+
+thread_local! {
+    /// Values waiting to be dropped by the release in progress, if any.
+    static RELEASED: RefCell<Option<Vec<Value>>> = const { RefCell::new(None) };
+}
+
+/// Drops values held by an object being freed without recursing into the
+/// objects they hold in turn, so freeing a long chain of tables does not
+/// overflow the stack. A release started while another one runs leaves its
+/// values to the outer one.
+fn release(values: impl Iterator<Item = Value>) {
+    // Other values, and those still used elsewhere, are dropped right away,
+    // before the list is borrowed, as they may free tables themselves
+    let values: Vec<Value> = values
+        .filter(|value| match value {
+            Value::Table(table) => Rc::strong_count(table) == 1,
+            Value::Lambda(closure) => Rc::strong_count(closure) == 1,
+            _ => false,
+        })
+        .collect();
+    if values.is_empty() {
+        return;
+    }
+    let is_running = RELEASED.with_borrow_mut(|released| match released {
+        Some(released) => {
+            released.extend(values);
+            true
+        }
+        None => {
+            *released = Some(values);
+            false
+        }
+    });
+    if is_running {
+        return;
+    }
+    while let Some(value) = RELEASED.with_borrow_mut(|released| released.as_mut().unwrap().pop()) {
+        drop(value);
+    }
+    RELEASED.set(None);
+}
+
+/// A Lua string: an immutable sequence of bytes, shared between its copies.
+///
+/// Strings are not necessarily valid UTF-8, so they are only converted to text
+/// when shown, with invalid sequences replaced.
+#[derive(Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct LuaString(Rc<[u8]>);
+
+impl LuaString {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// The string as text, if it is valid UTF-8.
+    pub fn to_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.0).ok()
+    }
+
+    pub fn to_str_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.0)
+    }
+}
+
+impl Deref for LuaString {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<&[u8]> for LuaString {
+    fn from(bytes: &[u8]) -> Self {
+        Self(bytes.into())
+    }
+}
+
+impl From<Vec<u8>> for LuaString {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self(bytes.into())
+    }
+}
+
+impl From<&str> for LuaString {
+    fn from(s: &str) -> Self {
+        Self(s.as_bytes().into())
+    }
+}
+
+impl From<String> for LuaString {
+    fn from(s: String) -> Self {
+        Self(s.into_bytes().into())
+    }
+}
+
+impl Display for LuaString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_str_lossy())
+    }
+}
+
+impl Debug for LuaString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{}\"", self.0.escape_ascii())
+    }
+}
+
+pub type UserdataRef = Rc<Userdata>;
+
+/// Data owned by Rust code, such as a file handle, with a metatable giving it
+/// its behaviour in Lua.
+pub struct Userdata {
+    data: RefCell<Box<dyn Any>>,
+    metatable: Option<TableRef>,
+}
+
+impl Userdata {
+    pub fn new<T: Any>(data: T, metatable: Option<TableRef>) -> Self {
+        Self {
+            data: RefCell::new(Box::new(data)),
+            metatable,
+        }
+    }
+
+    /// The data, if it is a `T`.
+    pub fn borrow<T: Any>(&self) -> Option<Ref<'_, T>> {
+        Ref::filter_map(self.data.borrow(), |data| data.downcast_ref()).ok()
+    }
+
+    pub fn borrow_mut<T: Any>(&self) -> Option<RefMut<'_, T>> {
+        RefMut::filter_map(self.data.borrow_mut(), |data| data.downcast_mut()).ok()
+    }
+
+    pub fn metatable(&self) -> Option<TableRef> {
+        self.metatable.clone()
+    }
+}
+
+/// The signature of functions written in Rust, which get the interpreter and
+/// their arguments and give their results.
+pub type NativeFn = dyn Fn(&mut Interpreter, Vec<Value>) -> Result<Vec<Value>, LuaError>;
+
+/// A function written in Rust, such as one an embedding application exposes
+/// to scripts. It can capture state, which needs a `Cell` or a `RefCell` to
+/// change since the function can be called again while it runs.
+pub struct NativeFunction {
+    name: String,
+    function: Box<NativeFn>,
+}
+
+impl NativeFunction {
+    pub fn new<F>(name: &str, function: F) -> Self
+    where
+        F: Fn(&mut Interpreter, Vec<Value>) -> Result<Vec<Value>, LuaError> + 'static,
+    {
+        Self {
+            name: name.to_string(),
+            function: Box::new(function),
+        }
+    }
+
+    /// A function taking and giving Rust values, which fails with a bad
+    /// argument error when its arguments cannot be converted.
+    pub fn typed<A, R, F>(name: &str, function: F) -> Self
+    where
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+        F: Fn(&mut Interpreter, A) -> Result<R, LuaError> + 'static,
+    {
+        let function_name = name.to_string();
+        Self::new(name, move |interpreter, arguments| {
+            let arguments = A::from_lua_multi(&arguments, 1, &function_name, interpreter)?;
+            let results = function(interpreter, arguments)?;
+            Ok(results.into_lua_multi(interpreter))
+        })
+    }
+
+    /// The name of the function in error messages.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        arguments: Vec<Value>,
+    ) -> Result<Vec<Value>, LuaError> {
+        (self.function)(interpreter, arguments)
+    }
+}
+
+impl From<NativeFunction> for Value {
+    fn from(function: NativeFunction) -> Self {
+        Value::Native(Rc::new(function))
+    }
+}
+
+#[derive(Clone)]
 pub enum Value {
     Nil,
     False,
     True,
     Integer(i64),
     Float(f64),
-    String(String),
-    Table(Table),
-    Lambda { parameters: Parameters, body: Block },
+    String(LuaString),
+    Table(TableRef),
+    Lambda(Rc<Closure>),
     Builtin(Builtin),
+    Native(Rc<NativeFunction>),
+    Thread(ThreadRef),
+    Userdata(UserdataRef),
 }
 
 impl Value {
-    pub fn is_truthy(self) -> bool {
+    pub fn new_table(table: Table) -> Self {
+        Value::Table(Rc::new(RefCell::new(table)))
+    }
+
+    pub fn is_truthy(&self) -> bool {
         !matches!(self, Value::False | Value::Nil)
     }
 
-    pub fn to_number(&self) -> Result<Self, Box<dyn Error>> {
+    pub fn is_nil(&self) -> bool {
+        matches!(self, Value::Nil)
+    }
+
+    pub fn type_name(&self) -> &'static str {
         match self {
-            Value::Float(_) | Value::Integer(_) => Ok(self.clone()),
-            Value::String(s) => {
-                let pairs = LuaParser::parse(Rule::Number, &s)?;
-                let pair = pairs.into_iter().next().unwrap();
-                match pair.as_rule() {
-                    Rule::Integer => Ok(Value::Integer(expression::parse_integer(&s))),
-                    Rule::HexInteger => Ok(Value::Integer(expression::parse_hex_integer(&s))),
-                    Rule::Float => Ok(Value::Float(expression::parse_float(&s))),
-                    Rule::HexFloat => Ok(Value::Float(expression::parse_hex_float(&s))),
-                    p => unreachable!("Expected number, found {:?}", p),
-                }
-            }
-            _ => Err(String::from(""))?,
+            Value::Nil => "nil",
+            Value::False | Value::True => "boolean",
+            Value::Integer(_) | Value::Float(_) => "number",
+            Value::String(_) => "string",
+            Value::Table(_) => "table",
+            Value::Lambda(_) | Value::Builtin(_) | Value::Native(_) => "function",
+            Value::Thread(_) => "thread",
+            Value::Userdata(_) => "userdata",
+        }
+    }
+
+    /// Converts numbers and strings convertible to numbers to a number.
+    pub fn to_number(&self) -> Option<Value> {
+        match self {
+            Value::Integer(_) | Value::Float(_) => Some(self.clone()),
+            Value::String(s) => s.to_str().and_then(str_to_number),
+            _ => None,
+        }
+    }
+
+    /// Converts strings and numbers to a string, as concatenation does.
+    pub fn to_lua_string(&self) -> Option<LuaString> {
+        match self {
+            Value::String(s) => Some(s.clone()),
+            Value::Integer(_) | Value::Float(_) => Some(self.to_string().into()),
+            _ => None,
         }
     }
 
-    pub fn to_float(&self) -> Result<Self, Box<dyn Error>> {
+    pub fn to_float(&self) -> Option<f64> {
         match self.to_number()? {
-            Value::Float(f) => Ok(Value::Float(f)),
-            Value::Integer(n) => Ok(Value::Float(n as f64)),
-            v => unreachable!("Expected number, found {:?}", v),
+            Value::Integer(n) => Some(n as f64),
+            Value::Float(f) => Some(f),
+            _ => None,
         }
     }
 
-    pub fn to_string(&self) -> String {
+    /// Converts the value to an integer if it has an exact integer representation.
+    pub fn to_integer(&self) -> Option<i64> {
+        match self.to_number()? {
+            Value::Integer(n) => Some(n),
+            Value::Float(f) => float_to_integer(f),
+            _ => None,
+        }
+    }
+
+    /// The value used as a table key: floats with an integral value become integers.
+    pub fn normalized(&self) -> Value {
         match self {
-            Value::Nil => "nil".to_string(),
-            Value::False => "false".to_string(),
-            Value::True => "true".to_string(),
-            Value::Integer(n) => n.to_string(),
-            Value::Float(f) => f.to_string(),
-            Value::String(s) => s.clone(),
-            Value::Table(_) => todo!(),
-            Value::Lambda { parameters, body } => todo!(),
-            Value::Builtin(_) => todo!(),
+            Value::Float(f) => match float_to_integer(*f) {
+                Some(n) => Value::Integer(n),
+                None => self.clone(),
+            },
+            _ => self.clone(),
         }
     }
 }
 
-impl Value {
-    pub fn add(&self, rhs: &Self) -> Result<Value, Box<dyn Error>> {
-        let lhs = self.to_number()?;
-        let rhs = rhs.to_number()?;
-        let v = match (lhs, rhs) {
-            (Value::Integer(a), Value::Integer(b)) => Value::Integer(a + b),
-            (Value::Integer(a), Value::Float(b)) => Value::Float(a as f64 + b),
-            (Value::Float(a), Value::Integer(b)) => Value::Float(a + b as f64),
-            (Value::Float(a), Value::Float(b)) => Value::Float(a + b),
-            (lhs, rhs) => unreachable!("Expected numbers, found {:?} and {:?}", lhs, rhs),
-        };
-        Ok(v)
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        if value {
+            Value::True
+        } else {
+            Value::False
+        }
     }
+}
 
-    pub fn is_equal(&self, rhs: &Self) -> bool {
-        match (self, rhs) {
-            (Value::Nil, Value::Nil) => true,
-            (Value::False, Value::False) => true,
-            (Value::True, Value::True) => true,
-            (Value::Integer(a), Value::Integer(b)) => a == b,
-            (Value::Integer(a), Value::Float(b)) => &(*a as f64) == b,
-            (Value::Float(a), Value::Integer(b)) => a == &(*b as f64),
-            (Value::Float(a), Value::Float(b)) => a == b,
-            (Value::String(a), Value::String(b)) => a == b,
-            (Value::Table(_), Value::Table(_)) => todo!(),
-            (Value::Lambda { .. }, Value::Lambda { .. }) => todo!(),
-            (_, _) => false,
-        }
+pub fn float_to_integer(f: f64) -> Option<i64> {
+    // -2^63 is exactly representable, while i64::MAX is not
+    let min = i64::MIN as f64;
+    if f.fract() == 0.0 && (min..-min).contains(&f) {
+        Some(f as i64)
+    } else {
+        None
     }
+}
 
-    pub fn is_less_than(&self, rhs: &Self) -> Result<bool, Box<dyn Error>> {
-        let r = match (self, rhs) {
-            (Value::Integer(a), Value::Integer(b)) => a < b,
-            (Value::Integer(a), Value::Float(b)) => &(*a as f64) < b,
-            (Value::Float(a), Value::Integer(b)) => a < &(*b as f64),
-            (Value::Float(a), Value::Float(b)) => a < b,
-            (Value::String(a), Value::String(b)) => a < b,
-            (_, _) => return Err("")?,
-        };
-        Ok(r)
+/// Converts a string to a number following the lexical rules of Lua numerals,
+/// allowing surrounding whitespace and a sign.
+pub fn str_to_number(s: &str) -> Option<Value> {
+    let s = s.trim_matches(|c| " \t\n\r\x0b\x0c".contains(c));
+    let (negative, s) = match s.strip_prefix('-') {
+        Some(s) => (true, s),
+        None => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+    let is_hex = s.starts_with("0x") || s.starts_with("0X");
+    let (digits, exponent_marker) = if is_hex {
+        (&s[2..], ['p', 'P'])
+    } else {
+        (s, ['e', 'E'])
+    };
+    let (mantissa, exponent) = match digits.find(exponent_marker) {
+        Some(i) => (&digits[..i], Some(&digits[(i + 1)..])),
+        None => (digits, None),
+    };
+    let is_digit = |c: char| {
+        if is_hex {
+            c.is_ascii_hexdigit()
+        } else {
+            c.is_ascii_digit()
+        }
+    };
+    let mut parts = mantissa.splitn(2, '.');
+    let integer = parts.next().unwrap_or("");
+    let fraction = parts.next();
+    let is_valid_mantissa = integer.chars().all(is_digit)
+        && fraction.is_none_or(|f| f.chars().all(is_digit))
+        && (integer.len() + fraction.map_or(0, str::len)) > 0;
+    let is_valid_exponent = exponent.is_none_or(|e| {
+        let e = e.strip_prefix(['+', '-']).unwrap_or(e);
+        !e.is_empty() && e.chars().all(|c| c.is_ascii_digit())
+    });
+    if !is_valid_mantissa || !is_valid_exponent {
+        return None;
     }
+    let is_integer = fraction.is_none() && exponent.is_none();
+    let number = match (is_hex, is_integer) {
+        (true, true) => Value::Integer(parse_hex_integer(s)),
+        (true, false) => Value::Float(parse_hex_float(s)),
+        (false, true) => match s.parse::<i64>() {
+            Ok(n) => Value::Integer(n),
+            Err(_) => Value::Float(parse_float(s)),
+        },
+        (false, false) => Value::Float(parse_float(s)),
+    };
+    Some(match (number, negative) {
+        (Value::Integer(n), true) => Value::Integer(n.wrapping_neg()),
+        (Value::Float(f), true) => Value::Float(-f),
+        (number, _) => number,
+    })
+}
 
-    pub fn is_less_or_equal(&self, rhs: &Self) -> Result<bool, Box<dyn Error>> {
-        let r = match (self, rhs) {
-            (Value::Integer(a), Value::Integer(b)) => a <= b,
-            (Value::Integer(a), Value::Float(b)) => &(*a as f64) <= b,
-            (Value::Float(a), Value::Integer(b)) => a <= &(*b as f64),
-            (Value::Float(a), Value::Float(b)) => a <= b,
-            (Value::String(a), Value::String(b)) => a <= b,
-            (_, _) => return Err("")?,
-        };
-        Ok(r)
+/// Formats a float the way `%.14g` does, keeping a `.0` suffix on integral
+/// values so they can be told apart from integers.
+pub fn format_float(f: f64) -> String {
+    if f.is_infinite() {
+        return if f > 0.0 { "inf" } else { "-inf" }.to_string();
+    }
+    if f.is_nan() {
+        return if f.is_sign_negative() { "-nan" } else { "nan" }.to_string();
+    }
+    let mut s = format_g(f, 14, false);
+    if s.chars().all(|c| c.is_ascii_digit() || c == '-') {
+        s.push_str(".0");
     }
+    s
 }
 
-impl PartialEq for Table {
-    fn eq(&self, other: &Self) -> bool {
-        if self.counter != other.counter {
-            return false;
+/// Formats a finite float like C's `%g` with the given precision.
+pub fn format_g(f: f64, precision: usize, alternate: bool) -> String {
+    let precision = precision.max(1);
+    let scientific = format!("{:.*e}", precision - 1, f);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+    let strip = |s: &str| -> String {
+        if alternate || !s.contains('.') {
+            s.to_string()
+        } else {
+            s.trim_end_matches('0').trim_end_matches('.').to_string()
         }
-        if self.table.len() != other.table.len() {
-            return false;
+    };
+    if exponent < -4 || exponent >= precision as i32 {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", strip(mantissa), sign, exponent.abs())
+    } else {
+        let decimals = (precision as i32 - 1 - exponent) as usize;
+        strip(&format!("{:.*}", decimals, f))
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Nil => write!(f, "nil"),
+            Value::False => write!(f, "false"),
+            Value::True => write!(f, "true"),
+            Value::Integer(n) => write!(f, "{n}"),
+            Value::Float(n) => write!(f, "{}", format_float(*n)),
+            Value::String(s) => write!(f, "{s}"),
+            Value::Table(t) => write!(f, "table: {:p}", Rc::as_ptr(t)),
+            Value::Lambda(l) => write!(f, "function: {:p}", Rc::as_ptr(l)),
+            Value::Builtin(b) => write!(f, "function: builtin: {}", b.name()),
+            Value::Native(n) => write!(f, "function: {:p}", Rc::as_ptr(n)),
+            Value::Thread(t) => write!(f, "thread: {:p}", Rc::as_ptr(t)),
+            Value::Userdata(u) => write!(f, "userdata: {:p}", Rc::as_ptr(u)),
         }
-        for (key, value) in self.table.iter() {
-            let Some(v) = other.table.get(key) else {
-                return false;
-            };
-            if v != value {
-                return false;
-            };
+    }
+}
+
+impl Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Nil => write!(f, "Nil"),
+            Value::False => write!(f, "False"),
+            Value::True => write!(f, "True"),
+            Value::Integer(n) => write!(f, "Integer({n})"),
+            Value::Float(n) => write!(f, "Float({n:?})"),
+            Value::String(s) => write!(f, "String({s:?})"),
+            Value::Table(t) => write!(f, "Table({:p})", Rc::as_ptr(t)),
+            Value::Lambda(l) => write!(f, "Lambda({:p})", Rc::as_ptr(l)),
+            Value::Builtin(b) => write!(f, "Builtin({b:?})"),
+            Value::Native(n) => write!(f, "Native({:?}, {:p})", n.name(), Rc::as_ptr(n)),
+            Value::Thread(t) => write!(f, "Thread({:p})", Rc::as_ptr(t)),
+            Value::Userdata(u) => write!(f, "Userdata({:p})", Rc::as_ptr(u)),
         }
-        true
     }
 }
 
+/// Raw equality, without calling the `__eq` metamethod.
 impl PartialEq for Value {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
-            (Self::Integer(l0), Self::Integer(r0)) => l0 == r0,
-            (Self::Float(l0), Self::Float(r0)) => l0 == r0,
-            (Self::String(l0), Self::String(r0)) => l0 == r0,
-            (Self::Table(l0), Self::Table(r0)) => l0 == r0,
-            (
-                Self::Lambda {
-                    parameters: l_parameters,
-                    body: l_body,
-                },
-                Self::Lambda {
-                    parameters: r_parameters,
-                    body: r_body,
-                },
-            ) => l_parameters == r_parameters && l_body == r_body,
-            _ => core::mem::discriminant(self) == core::mem::discriminant(other),
+            (Value::Nil, Value::Nil) => true,
+            (Value::False, Value::False) => true,
+            (Value::True, Value::True) => true,
+            (Value::Integer(a), Value::Integer(b)) => a == b,
+            (Value::Integer(a), Value::Float(b)) => float_to_integer(*b) == Some(*a),
+            (Value::Float(a), Value::Integer(b)) => float_to_integer(*a) == Some(*b),
+            (Value::Float(a), Value::Float(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Table(a), Value::Table(b)) => Rc::ptr_eq(a, b),
+            (Value::Lambda(a), Value::Lambda(b)) => Rc::ptr_eq(a, b),
+            (Value::Builtin(a), Value::Builtin(b)) => a == b,
+            (Value::Native(a), Value::Native(b)) => Rc::ptr_eq(a, b),
+            (Value::Thread(a), Value::Thread(b)) => Rc::ptr_eq(a, b),
+            (Value::Userdata(a), Value::Userdata(b)) => Rc::ptr_eq(a, b),
+            (_, _) => false,
         }
     }
 }
 
-impl Eq for Table {}
-
 impl Eq for Value {}
 
 impl Hash for Value {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        core::mem::discriminant(self).hash(state);
-    }
-}
-
-impl Hash for Table {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.counter.hash(state);
-        for (key, value) in self.table.iter() {
-            key.hash(state);
-            value.hash(state);
+        match self {
+            Value::Integer(n) => n.hash(state),
+            Value::Float(f) => match float_to_integer(*f) {
+                Some(n) => n.hash(state),
+                None => f.to_bits().hash(state),
+            },
+            Value::String(s) => s.hash(state),
+            Value::Table(t) => Rc::as_ptr(t).hash(state),
+            Value::Lambda(l) => Rc::as_ptr(l).hash(state),
+            Value::Builtin(b) => b.hash(state),
+            Value::Native(n) => Rc::as_ptr(n).hash(state),
+            Value::Thread(t) => Rc::as_ptr(t).hash(state),
+            Value::Userdata(u) => Rc::as_ptr(u).hash(state),
+            v => core::mem::discriminant(v).hash(state),
         }
     }
 }
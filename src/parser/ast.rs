@@ -27,7 +27,7 @@ pub fn build_ast(pairs: &mut Pairs<Rule>) -> Block {
             _ => unreachable!("Expected statement, found {:?}", pair),
         };
     }
-    return block;
+    block
 }
 
 fn parse_statement(mut pairs: Pairs<Rule>) -> Statement {
@@ -158,12 +158,9 @@ fn parse_parameters(mut pairs: Pairs<Rule>) -> Parameters {
 }
 
 pub fn parse_function_body(mut pairs: Pairs<Rule>) -> (Option<Parameters>, Block) {
-    match pairs.peek().unwrap().as_rule() {
-        Rule::Block => {
-            let block = build_ast(&mut pairs);
-            return (None, block);
-        }
-        _ => {}
+    if pairs.peek().unwrap().as_rule() == Rule::Block {
+        let block = build_ast(&mut pairs);
+        return (None, block);
     }
     let parameters = parse_parameters(pairs.next().unwrap().into_inner());
     let block = build_ast(&mut pairs);
@@ -193,10 +190,7 @@ fn parse_function_definition(mut pairs: Pairs<Rule>) -> Statement {
 fn parse_if(mut pairs: Pairs<Rule>) -> Statement {
     let mut ifs = vec![];
     let mut r#else = None;
-    loop {
-        let Some(first) = pairs.peek() else {
-            break;
-        };
+    while let Some(first) = pairs.peek() {
         if first.as_rule() == Rule::Block {
             r#else = Some(build_ast(&mut pairs));
             break;
@@ -235,9 +229,8 @@ fn parse_numerical_for(mut pairs: Pairs<Rule>) -> Statement {
     let initial = parse_expr(pairs.next().unwrap().into_inner());
     let limit = parse_expr(pairs.next().unwrap().into_inner());
     let mut step = None;
-    match pairs.peek().unwrap().as_rule() {
-        Rule::Expression => step = Some(parse_expr(pairs.next().unwrap().into_inner())),
-        _ => {}
+    if pairs.peek().unwrap().as_rule() == Rule::Expression {
+        step = Some(parse_expr(pairs.next().unwrap().into_inner()));
     }
     let block = build_ast(&mut pairs);
     Statement::NumericalFor {
@@ -7,7 +7,7 @@ use crate::{
     parser::ast::parse_function_body,
     parser::prefix_expression::{parse_prefix_expr, PrefixExpression},
     parser::statement::{Block, Parameters},
-    parser::Rule,
+    parser::{source_bytes, Rule},
 };
 
 #[derive(Debug, PartialEq, Clone)]
@@ -21,7 +21,7 @@ pub enum Field {
 pub enum Expression {
     Integer(i64),
     Float(f64),
-    String(String),
+    String(Vec<u8>),
     True,
     False,
     Nil,
@@ -189,7 +189,11 @@ pub fn parse_integer(n: &str) -> i64 {
 }
 
 pub fn parse_hex_integer(n: &str) -> i64 {
-    i64::from_str_radix(&n[2..], 16).unwrap()
+    // Hexadecimal integers wrap around on overflow, as in the reference implementation
+    n[2..].chars().fold(0i64, |acc, c| {
+        acc.wrapping_mul(16)
+            .wrapping_add(c.to_digit(16).unwrap() as i64)
+    })
 }
 
 pub fn parse_float(n: &str) -> f64 {
@@ -198,74 +202,116 @@ pub fn parse_float(n: &str) -> f64 {
 
 pub fn parse_hex_float(n: &str) -> f64 {
     let n = &n[2..];
-    let dot = n.find('.');
-    let p = n.find('p');
-
-    let integer;
-    let decimal;
-    let decimal_size;
-    let exponent;
+    let (mantissa, mut exponent) = match n.find(['p', 'P']) {
+        Some(p) => (&n[..p], n[(p + 1)..].parse::<i32>().unwrap_or(0)),
+        None => (n, 0),
+    };
     let mut number = 0f64;
-    if let Some(dot) = dot {
-        if let Some(p) = p {
-            integer = i64::from_str_radix(&n[..dot], 16).unwrap_or(0);
-            decimal = i64::from_str_radix(&n[(dot + 1)..p], 16).unwrap_or(0);
-            decimal_size = p - dot - 1;
-            exponent = i64::from_str_radix(&n[(p + 1)..], 16).unwrap_or(0);
-        } else {
-            integer = i64::from_str_radix(&n[..dot], 16).unwrap_or(0);
-            decimal = i64::from_str_radix(&n[(dot + 1)..], 16).unwrap_or(0);
-            decimal_size = n.len() - dot - 1;
-            exponent = 0;
+    let mut is_decimal = false;
+    for c in mantissa.chars() {
+        if c == '.' {
+            is_decimal = true;
+            continue;
         }
-    } else {
-        if let Some(p) = p {
-            integer = i64::from_str_radix(&n[..p], 16).unwrap_or(0);
-            decimal = 0;
-            decimal_size = 0;
-            exponent = i64::from_str_radix(&n[(p + 1)..], 16).unwrap_or(0);
-        } else {
-            integer = i64::from_str_radix(&n[..], 16).unwrap_or(0);
-            decimal = 0;
-            decimal_size = 0;
-            exponent = 0;
+        number = number * 16.0 + c.to_digit(16).unwrap() as f64;
+        if is_decimal {
+            exponent -= 4;
         }
     }
-    number += integer as f64;
-    if decimal_size > 0 {
-        number += decimal as f64 / 16f64.powf(decimal_size as f64);
-    }
-    number = number * 2f64.powf(exponent as f64);
-    number
+    number * 2f64.powi(exponent)
 }
 
-pub fn parse_string(text: &str) -> String {
-    let mut result = String::with_capacity(text.len());
-    let mut is_escaped = false;
-    for c in text.chars() {
-        if is_escaped {
-            let cc = match c {
-                'n' => '\n',
-                't' => '\t',
-                '\\' => '\\',
-                '\'' => '\'',
-                '"' => '"',
-                _ => todo!("Unknown escapes character '\\{c}'"),
-            };
-            result.push(cc);
-            is_escaped = false;
-        } else if c == '\\' {
-            is_escaped = true;
-        } else {
+/// Decodes the escape sequences of a short string, which the grammar has
+/// already checked.
+pub fn parse_string(text: &str) -> Vec<u8> {
+    let bytes = source_bytes(text);
+    // Escapes are ASCII, which raw bytes never are
+    let ascii = |range: std::ops::Range<usize>| std::str::from_utf8(&bytes[range]).unwrap();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        i += 1;
+        if c != b'\\' {
             result.push(c);
+            continue;
+        }
+        let c = bytes[i];
+        i += 1;
+        match c {
+            b'a' => result.push(0x07),
+            b'b' => result.push(0x08),
+            b'f' => result.push(0x0c),
+            b'n' => result.push(b'\n'),
+            b'r' => result.push(b'\r'),
+            b't' => result.push(b'\t'),
+            b'v' => result.push(0x0b),
+            b'\n' | b'\r' => {
+                // A line break, counting `\r\n` and `\n\r` as one
+                if matches!(bytes.get(i), Some(&next) if (next == b'\n' || next == b'\r') && next != c)
+                {
+                    i += 1;
+                }
+                result.push(b'\n');
+            }
+            b'x' => {
+                result.push(u8::from_str_radix(ascii(i..i + 2), 16).unwrap());
+                i += 2;
+            }
+            b'z' => {
+                while bytes
+                    .get(i)
+                    .is_some_and(|&c| c.is_ascii_whitespace() || c == 0x0b)
+                {
+                    i += 1;
+                }
+            }
+            b'u' => {
+                let end = i + bytes[i..].iter().position(|&c| c == b'}').unwrap();
+                let code = u32::from_str_radix(ascii(i + 1..end), 16).unwrap();
+                result.extend_from_slice(&utf8_encode(code));
+                i = end + 1;
+            }
+            b'0'..=b'9' => {
+                let start = i - 1;
+                while i < bytes.len() && i - start < 3 && bytes[i].is_ascii_digit() {
+                    i += 1;
+                }
+                result.push(ascii(start..i).parse().unwrap());
+            }
+            c => result.push(c),
         }
     }
-    // println!("{}:{}:{}: {result}", file!(), line!(), column!());
     result
 }
 
-pub fn parse_raw_string(text: &str) -> String {
-    text.into()
+/// Encodes a code point in UTF-8, extended like Lua's to values of up to 31
+/// bits with sequences of up to six bytes.
+pub fn utf8_encode(code: u32) -> Vec<u8> {
+    if code < 0x80 {
+        return vec![code as u8];
+    }
+    let mut continuation = vec![];
+    let mut code = code;
+    // The largest value that fits in the first byte
+    let mut first_maximum = 0x3f;
+    while code > first_maximum {
+        continuation.push(0x80 | (code & 0x3f) as u8);
+        code >>= 6;
+        first_maximum >>= 1;
+    }
+    let mut result = vec![((!first_maximum << 1) as u8) | code as u8];
+    result.extend(continuation.iter().rev());
+    result
+}
+
+pub fn parse_raw_string(text: &str) -> Vec<u8> {
+    // A newline right after the opening bracket is not part of the string
+    let text = text
+        .strip_prefix("\r\n")
+        .or_else(|| text.strip_prefix('\n'))
+        .unwrap_or(text);
+    source_bytes(text).into_owned()
 }
 
 pub fn parse_expr(pairs: Pairs<Rule>) -> Expression {
@@ -275,7 +321,10 @@ pub fn parse_expr(pairs: Pairs<Rule>) -> Expression {
             Rule::False => Expression::False,
             Rule::VarArg => Expression::VarArg,
             Rule::Nil => Expression::Nil,
-            Rule::Integer => Expression::Integer(parse_integer(primary.as_str())),
+            Rule::Integer => match primary.as_str().parse() {
+                Ok(n) => Expression::Integer(n),
+                Err(_) => Expression::Float(parse_float(primary.as_str())),
+            },
             Rule::HexInteger => Expression::Integer(parse_hex_integer(primary.as_str())),
             Rule::Float => Expression::Float(parse_float(primary.as_str())),
             Rule::HexFloat => Expression::Float(parse_hex_float(primary.as_str())),
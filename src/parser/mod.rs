@@ -3,8 +3,110 @@ pub mod expression;
 pub mod prefix_expression;
 pub mod statement;
 
+use std::borrow::Cow;
+
+use pest::iterators::{Pair, Pairs};
 use pest_derive::Parser;
 
 #[derive(Debug, Parser)]
 #[grammar = "parser/lua.pest"]
 pub struct LuaParser;
+
+/// Most levels of nesting of a chunk, as `LUAI_MAXCCALLS` in the reference
+/// implementation.
+pub const MAXIMUM_LEVELS: usize = 200;
+
+/// Checks that a parsed chunk nests at most [`MAXIMUM_LEVELS`] blocks and
+/// operators, since building and compiling its syntax tree recurses through
+/// them. Gives the line of the first construct too deep otherwise.
+///
+/// Each block, expression and operator counts as a level, including those of
+/// left associative operators, whose trees nest as deep.
+pub fn check_nesting(pairs: &Pairs<Rule>) -> Result<(), usize> {
+    let mut pending: Vec<(Pair<Rule>, usize)> = pairs.clone().map(|pair| (pair, 0)).collect();
+    while let Some((pair, depth)) = pending.pop() {
+        let depth = match pair.as_rule() {
+            Rule::Block => depth + 1,
+            Rule::Expression => {
+                let operators = pair
+                    .clone()
+                    .into_inner()
+                    .filter(|pair| !is_operand(pair.as_rule()));
+                depth + 1 + operators.count()
+            }
+            _ => depth,
+        };
+        if depth > MAXIMUM_LEVELS {
+            return Err(pair.line_col().0);
+        }
+        pending.extend(pair.into_inner().map(|pair| (pair, depth)));
+    }
+    Ok(())
+}
+
+fn is_operand(rule: Rule) -> bool {
+    matches!(
+        rule,
+        Rule::SqString
+            | Rule::DqString
+            | Rule::RawString
+            | Rule::Integer
+            | Rule::Float
+            | Rule::HexInteger
+            | Rule::HexFloat
+            | Rule::True
+            | Rule::False
+            | Rule::Nil
+            | Rule::VarArg
+            | Rule::Lambda
+            | Rule::Table
+            | Rule::PrefixExpression
+    )
+}
+
+/// The first of the private use characters standing for the bytes of a chunk
+/// that are not valid UTF-8, as the grammar only works on text.
+const RAW_BYTES: u32 = 0x10_ff00;
+
+fn raw_byte(c: char) -> Option<u8> {
+    (c as u32).checked_sub(RAW_BYTES).map(|b| b as u8)
+}
+
+/// Converts the bytes of a chunk to text the parser accepts. Bytes that are
+/// not valid UTF-8, and the characters that stand for bytes themselves, are
+/// replaced by one character per byte, which [`source_bytes`] turns back.
+pub fn source_text(bytes: &[u8]) -> Cow<'_, str> {
+    if let Ok(text) = std::str::from_utf8(bytes) {
+        if !text.chars().any(|c| raw_byte(c).is_some()) {
+            return Cow::Borrowed(text);
+        }
+    }
+    let raw = |b: u8| char::from_u32(RAW_BYTES + b as u32).unwrap();
+    let mut text = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            if raw_byte(c).is_some() {
+                text.extend(c.to_string().bytes().map(raw));
+            } else {
+                text.push(c);
+            }
+        }
+        text.extend(chunk.invalid().iter().map(|&b| raw(b)));
+    }
+    Cow::Owned(text)
+}
+
+/// Gives back the bytes of a piece of text made by [`source_text`].
+pub fn source_bytes(text: &str) -> Cow<'_, [u8]> {
+    if !text.chars().any(|c| raw_byte(c).is_some()) {
+        return Cow::Borrowed(text.as_bytes());
+    }
+    let mut bytes = Vec::with_capacity(text.len());
+    for c in text.chars() {
+        match raw_byte(c) {
+            Some(b) => bytes.push(b),
+            None => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+    Cow::Owned(bytes)
+}
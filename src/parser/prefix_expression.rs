@@ -5,7 +5,7 @@ use crate::{
     parser::Rule,
 };
 
-use super::expression::parse_table;
+use super::expression::{parse_raw_string, parse_string, parse_table};
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct PrefixExpression {
@@ -22,7 +22,7 @@ pub enum Primary {
 #[derive(Debug, PartialEq, Clone)]
 pub enum Argument {
     List(Vec<Expression>),
-    String(String),
+    String(Vec<u8>),
     Table(Expression),
 }
 
@@ -70,9 +70,9 @@ fn parse_arguments(mut pairs: Pairs<Rule>) -> Argument {
         return Argument::List(vec![]);
     };
     match pair.as_rule() {
-        Rule::SqString => Argument::String(pair.as_str().into()),
-        Rule::DqString => Argument::String(pair.as_str().into()),
-        Rule::RawString => Argument::String(pair.as_str().into()),
+        Rule::SqString => Argument::String(parse_string(pair.into_inner().as_str())),
+        Rule::DqString => Argument::String(parse_string(pair.into_inner().as_str())),
+        Rule::RawString => Argument::String(parse_raw_string(pair.into_inner().as_str())),
         Rule::Table => Argument::Table(parse_table(pair.into_inner())),
         Rule::ExpressionList => {
             let exprs = pair
@@ -3,21 +3,12 @@ use crate::{
     parser::prefix_expression::{CallSuffix, PrefixExpression, Selector},
 };
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Default)]
 pub struct Block {
     pub statements: Vec<Statement>,
     pub return_statement: Option<Return>,
 }
 
-impl Default for Block {
-    fn default() -> Self {
-        Self {
-            statements: vec![],
-            return_statement: None,
-        }
-    }
-}
-
 #[derive(Debug, PartialEq, Clone)]
 pub struct Return(pub Option<Vec<Expression>>);
 
@@ -2,21 +2,33 @@ use std::io::{stdin, Read};
 
 use lust::{
     interpreter::Interpreter,
-    parser::{ast::build_ast, LuaParser, Rule},
+    parser::{ast::build_ast, check_nesting, source_text, LuaParser, Rule},
 };
 use pest::Parser;
 
 fn main() {
-    let mut stdin = stdin().lock();
-    let mut content = String::new();
-    stdin.read_to_string(&mut content).unwrap();
+    // The lock is released right away, since scripts read through io.stdin
+    let mut content = vec![];
+    stdin().lock().read_to_end(&mut content).unwrap();
+    let content = source_text(&content);
     let pairs = LuaParser::parse(Rule::Chunk, &content);
     let Ok(mut pairs) = pairs else {
         println!("Error: {}", pairs.err().unwrap());
         return;
     };
+    if let Err(line) = check_nesting(&pairs) {
+        println!("Error: {line}: chunk has too many C levels");
+        return;
+    }
     let program = build_ast(&mut pairs);
     // let symbol_table = SymbolTable::new(&program);
     let mut interpreter = Interpreter::new();
-    interpreter.interpret(&program);
+    if let Err(error) = interpreter.interpret(&program) {
+        if let Some(status) = interpreter.exit_status() {
+            // Dropping the interpreter runs the finalizers and closes the files
+            drop(interpreter);
+            std::process::exit(status);
+        }
+        println!("Error: {}", error);
+    }
 }
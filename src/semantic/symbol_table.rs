@@ -40,14 +40,9 @@ impl SymbolTable {
     }
 
     fn deal_with_local_variables(&mut self, statement: &Statement) {
-        let Statement::LocalVariables {
-            variables,
-            expr_list,
-        } = statement
-        else {
+        let Statement::LocalVariables { variables, .. } = statement else {
             unreachable!("Expected local variables, got {:?}", statement);
         };
-        if let Some(_) = expr_list {}
         for _ in variables {}
     }
 }
@@ -0,0 +1,117 @@
+use crate::interpreter::{
+    coroutine::{CoroutineStatus, ThreadRef},
+    value::Value,
+    Interpreter, LuaError,
+};
+
+use super::{argument, argument_error, type_name};
+
+/// `coroutine.wrap`, which only needs the other functions of the library.
+pub const WRAP: &str = "
+    local create, resume, error = coroutine.create, coroutine.resume, error
+    local function check(ok, ...)
+        if ok then return ... end
+        error((...), 0)
+    end
+    return function(f)
+        local co = create(f)
+        return function(...) return check(resume(co, ...)) end
+    end
+";
+
+fn check_thread(parameters: &[Value], position: usize, name: &str) -> Result<ThreadRef, LuaError> {
+    match argument(parameters, position) {
+        Value::Thread(thread) => Ok(thread),
+        value => Err(argument_error(
+            position,
+            name,
+            &format!(
+                "coroutine expected, got {}",
+                type_name(parameters, position, &value)
+            ),
+        )),
+    }
+}
+
+pub fn create(
+    interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    match argument(&parameters, 1) {
+        function @ (Value::Lambda(_) | Value::Builtin(_) | Value::Native(_)) => {
+            Ok(vec![interpreter.new_thread(function)])
+        }
+        value => Err(argument_error(
+            1,
+            "create",
+            &format!(
+                "function expected, got {}",
+                type_name(&parameters, 1, &value)
+            ),
+        )),
+    }
+}
+
+pub fn resume(
+    interpreter: &mut Interpreter,
+    mut parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let thread = check_thread(&parameters, 1, "resume")?;
+    parameters.remove(0);
+    match interpreter.resume(thread, parameters) {
+        Ok(mut values) => {
+            values.insert(0, Value::True);
+            Ok(values)
+        }
+        Err(error) if interpreter.exit_status().is_some() => Err(error),
+        Err(error) => Ok(vec![Value::False, error.value]),
+    }
+}
+
+pub fn r#yield(
+    interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    interpreter.yield_values(parameters)
+}
+
+pub fn status(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let thread = check_thread(&parameters, 1, "status")?;
+    let status = thread.borrow().status();
+    Ok(vec![Value::String(status.name().into())])
+}
+
+pub fn running(
+    interpreter: &mut Interpreter,
+    _parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let (thread, is_main) = interpreter.running();
+    Ok(vec![Value::Thread(thread), Value::from(is_main)])
+}
+
+pub fn isyieldable(
+    interpreter: &mut Interpreter,
+    _parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    Ok(vec![Value::from(interpreter.is_yieldable())])
+}
+
+pub fn close(
+    interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let thread = check_thread(&parameters, 1, "close")?;
+    let status = thread.borrow().status();
+    if matches!(status, CoroutineStatus::Running | CoroutineStatus::Normal) {
+        let message = format!("cannot close a {} coroutine", status.name());
+        return Err(LuaError::new(message));
+    }
+    match interpreter.close_coroutine(thread) {
+        Ok(()) => Ok(vec![Value::True]),
+        Err(error) if interpreter.exit_status().is_some() => Err(error),
+        Err(error) => Ok(vec![Value::False, error.value]),
+    }
+}
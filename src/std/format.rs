@@ -0,0 +1,373 @@
+//! `string.format`, following the conversions of C's `printf`.
+
+use crate::interpreter::{
+    value::{format_g, Value},
+    Interpreter, LuaError,
+};
+
+use super::{argument, argument_error, check_integer, check_string, tostring, type_name};
+
+/// Flags, width and precision of a conversion longer than this are invalid.
+const MAXIMUM_FORMAT: usize = 22;
+
+/// The flags, width and precision of a conversion.
+#[derive(Debug, Default)]
+struct Spec {
+    left: bool,
+    plus: bool,
+    space: bool,
+    alternate: bool,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+impl Spec {
+    /// Parses the conversion `form`, without its `%`, checking that it only
+    /// uses `flags`, a width and a precision if `precision` is allowed.
+    fn parse(form: &str, flags: &str, precision: bool) -> Result<Self, LuaError> {
+        let mut spec = Spec::default();
+        let bytes = form.as_bytes();
+        let mut i = 0;
+        while i < bytes.len() && flags.as_bytes().contains(&bytes[i]) {
+            match bytes[i] {
+                b'-' => spec.left = true,
+                b'+' => spec.plus = true,
+                b' ' => spec.space = true,
+                b'#' => spec.alternate = true,
+                _ => spec.zero = true,
+            }
+            i += 1;
+        }
+        let digits = |i: &mut usize| {
+            let start = *i;
+            while *i < bytes.len() && *i - start < 2 && bytes[*i].is_ascii_digit() {
+                *i += 1;
+            }
+            form[start..*i].parse().unwrap_or(0)
+        };
+        if bytes.get(i) != Some(&b'0') {
+            spec.width = digits(&mut i);
+            if precision && bytes.get(i) == Some(&b'.') {
+                i += 1;
+                spec.precision = Some(digits(&mut i));
+            }
+        }
+        if i + 1 != bytes.len() {
+            return Err(LuaError::new(format!(
+                "invalid conversion specification: '%{form}'"
+            )));
+        }
+        Ok(spec)
+    }
+
+    /// The sign of a number, if it needs one.
+    fn sign(&self, negative: bool) -> &'static str {
+        if negative {
+            "-"
+        } else if self.plus {
+            "+"
+        } else if self.space {
+            " "
+        } else {
+            ""
+        }
+    }
+
+    /// Pads `prefix` and `body` to the width, with zeros between them if
+    /// `zeros` is allowed and asked for.
+    fn pad(&self, prefix: &str, body: &str, zeros: bool) -> String {
+        let length = prefix.len() + body.len();
+        let padding = self.width.saturating_sub(length);
+        if self.left {
+            format!("{prefix}{body}{}", " ".repeat(padding))
+        } else if self.zero && zeros {
+            format!("{prefix}{}{body}", "0".repeat(padding))
+        } else {
+            format!("{}{prefix}{body}", " ".repeat(padding))
+        }
+    }
+
+    /// Adds `body` to `result`, padded with spaces to the width.
+    fn pad_bytes(&self, body: &[u8], result: &mut Vec<u8>) {
+        let padding = vec![b' '; self.width.saturating_sub(body.len())];
+        if self.left {
+            result.extend_from_slice(body);
+            result.extend_from_slice(&padding);
+        } else {
+            result.extend_from_slice(&padding);
+            result.extend_from_slice(body);
+        }
+    }
+
+    /// Formats the digits of an integer, keeping at least `precision` of them.
+    fn integer(&self, prefix: &str, digits: String) -> String {
+        let digits = match self.precision {
+            Some(0) if digits == "0" => String::new(),
+            Some(precision) if digits.len() < precision => {
+                format!("{}{digits}", "0".repeat(precision - digits.len()))
+            }
+            _ => digits,
+        };
+        self.pad(prefix, &digits, self.precision.is_none())
+    }
+}
+
+/// Formats a float like C's `%a`, with `precision` hexadecimal digits after
+/// the point or as many as needed.
+fn format_hex_float(f: f64, precision: Option<usize>, alternate: bool) -> String {
+    let bits = f.abs().to_bits();
+    let exponent = (bits >> 52) as i64;
+    let mut mantissa = bits & ((1 << 52) - 1);
+    let (mut lead, exponent) = match (exponent, mantissa) {
+        (0, 0) => (0, 0),
+        (0, _) => (0, -1022),
+        _ => (1, exponent - 1023),
+    };
+    let mut digits = 13;
+    if let Some(precision) = precision.filter(|&precision| precision < 13) {
+        // Round to nearest, ties to even, on the dropped bits
+        let shift = 4 * (13 - precision);
+        let dropped = mantissa & ((1 << shift) - 1);
+        let half = 1 << (shift - 1);
+        mantissa >>= shift;
+        if dropped > half || (dropped == half && mantissa & 1 == 1) {
+            mantissa += 1;
+            if mantissa >> (4 * precision) != 0 {
+                mantissa &= (1 << (4 * precision)) - 1;
+                lead += 1;
+            }
+        }
+        digits = precision;
+    }
+    let mut hex = if digits == 0 {
+        String::new()
+    } else {
+        format!("{mantissa:0digits$x}")
+    };
+    match precision {
+        None => hex.truncate(hex.trim_end_matches('0').len()),
+        Some(precision) if precision > 13 => hex.push_str(&"0".repeat(precision - 13)),
+        _ => {}
+    }
+    let point = if hex.is_empty() && !alternate {
+        ""
+    } else {
+        "."
+    };
+    format!("{lead}{point}{hex}p{exponent:+}")
+}
+
+/// Formats a float like C's `%e`.
+fn format_exponent(f: f64, precision: usize, alternate: bool) -> String {
+    let s = format!("{f:.precision$e}");
+    let (mantissa, exponent) = s.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+    let point = if alternate && precision == 0 { "." } else { "" };
+    let sign = if exponent < 0 { '-' } else { '+' };
+    format!("{mantissa}{point}e{sign}{:02}", exponent.abs())
+}
+
+fn format_float(spec: &Spec, conversion: u8, f: f64) -> String {
+    let prefix = spec.sign(f.is_sign_negative());
+    let f = f.abs();
+    if !f.is_finite() {
+        let body = if f.is_nan() { "nan" } else { "inf" };
+        let body = if conversion.is_ascii_uppercase() {
+            body.to_ascii_uppercase()
+        } else {
+            body.to_string()
+        };
+        return spec.pad(prefix, &body, false);
+    }
+    let precision = spec.precision.unwrap_or(6);
+    let (prefix, body) = match conversion.to_ascii_lowercase() {
+        b'f' => {
+            let point = if spec.alternate && precision == 0 {
+                "."
+            } else {
+                ""
+            };
+            (prefix.to_string(), format!("{f:.precision$}{point}"))
+        }
+        b'e' => (
+            prefix.to_string(),
+            format_exponent(f, precision, spec.alternate),
+        ),
+        b'g' => (prefix.to_string(), format_g(f, precision, spec.alternate)),
+        _ => (
+            format!("{prefix}0x"),
+            format_hex_float(f, spec.precision, spec.alternate),
+        ),
+    };
+    if conversion.is_ascii_uppercase() {
+        spec.pad(
+            &prefix.to_ascii_uppercase(),
+            &body.to_ascii_uppercase(),
+            true,
+        )
+    } else {
+        spec.pad(&prefix, &body, true)
+    }
+}
+
+/// Adds the string `s` as a Lua literal that reads back the same.
+fn quote_string(s: &[u8], result: &mut Vec<u8>) {
+    result.push(b'"');
+    for (i, &c) in s.iter().enumerate() {
+        match c {
+            b'"' | b'\\' | b'\n' => result.extend_from_slice(&[b'\\', c]),
+            c if c.is_ascii_control() => {
+                let escape = if s.get(i + 1).is_some_and(u8::is_ascii_digit) {
+                    format!("\\{c:03}")
+                } else {
+                    format!("\\{c}")
+                };
+                result.extend_from_slice(escape.as_bytes());
+            }
+            c => result.push(c),
+        }
+    }
+    result.push(b'"');
+}
+
+fn quote(parameters: &[Value], position: usize, result: &mut Vec<u8>) -> Result<(), LuaError> {
+    let literal = match argument(parameters, position) {
+        Value::String(s) => {
+            quote_string(s.as_bytes(), result);
+            return Ok(());
+        }
+        Value::Integer(i64::MIN) => "0x8000000000000000".to_string(),
+        Value::Integer(n) => n.to_string(),
+        Value::Float(f) if f == f64::INFINITY => "1e9999".to_string(),
+        Value::Float(f) if f == f64::NEG_INFINITY => "-1e9999".to_string(),
+        Value::Float(f) if f.is_nan() => "(0/0)".to_string(),
+        Value::Float(f) => {
+            let sign = if f.is_sign_negative() { "-" } else { "" };
+            format!("{sign}0x{}", format_hex_float(f, None, false))
+        }
+        value @ (Value::Nil | Value::False | Value::True) => value.to_string(),
+        _ => {
+            return Err(argument_error(
+                position,
+                "format",
+                "value has no literal form",
+            ))
+        }
+    };
+    result.extend_from_slice(literal.as_bytes());
+    Ok(())
+}
+
+pub fn format(
+    interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let format = check_string(&parameters, 1, "format")?;
+    let format = format.as_bytes();
+    let mut result = Vec::with_capacity(format.len());
+    let mut position = 1;
+    let mut i = 0;
+    while i < format.len() {
+        let c = format[i];
+        i += 1;
+        if c != b'%' {
+            result.push(c);
+            continue;
+        }
+        if format.get(i) == Some(&b'%') {
+            result.push(b'%');
+            i += 1;
+            continue;
+        }
+        position += 1;
+        if position > parameters.len() {
+            return Err(argument_error(position, "format", "no value"));
+        }
+        let start = i;
+        while i < format.len() && b"-+ #0123456789.".contains(&format[i]) {
+            i += 1;
+        }
+        let end = (i + 1).min(format.len());
+        let form = String::from_utf8_lossy(&format[start..end]).into_owned();
+        if form.len() >= MAXIMUM_FORMAT {
+            return Err(LuaError::new("invalid format string to 'format'"));
+        }
+        let conversion = format.get(i).copied().unwrap_or(0);
+        i = end;
+        let formatted = match conversion {
+            b'c' => {
+                let spec = Spec::parse(&form, "-", false)?;
+                let c = check_integer(&parameters, position, "format")? as u8;
+                spec.pad_bytes(&[c], &mut result);
+                continue;
+            }
+            b'd' | b'i' => {
+                let spec = Spec::parse(&form, "-+0 ", true)?;
+                let n = check_integer(&parameters, position, "format")?;
+                spec.integer(spec.sign(n < 0), n.unsigned_abs().to_string())
+            }
+            b'u' => {
+                let spec = Spec::parse(&form, "-0", true)?;
+                let n = check_integer(&parameters, position, "format")?;
+                spec.integer("", (n as u64).to_string())
+            }
+            b'o' | b'x' | b'X' => {
+                let spec = Spec::parse(&form, "-#0", true)?;
+                let n = check_integer(&parameters, position, "format")? as u64;
+                let (prefix, digits) = match conversion {
+                    b'o' if spec.alternate => ("", format!("0{n:o}")),
+                    b'o' => ("", format!("{n:o}")),
+                    b'x' => (
+                        if spec.alternate && n != 0 { "0x" } else { "" },
+                        format!("{n:x}"),
+                    ),
+                    _ => (
+                        if spec.alternate && n != 0 { "0X" } else { "" },
+                        format!("{n:X}"),
+                    ),
+                };
+                spec.integer(prefix, digits)
+            }
+            b'a' | b'A' | b'e' | b'E' | b'f' | b'F' | b'g' | b'G' => {
+                let spec = Spec::parse(&form, "-+ #0", true)?;
+                let value = argument(&parameters, position);
+                let Some(f) = value.to_float() else {
+                    return Err(argument_error(
+                        position,
+                        "format",
+                        &format!(
+                            "number expected, got {}",
+                            type_name(&parameters, position, &value)
+                        ),
+                    ));
+                };
+                format_float(&spec, conversion, f)
+            }
+            b'q' => {
+                if form.len() > 1 {
+                    return Err(LuaError::new("specifier '%q' cannot have modifiers"));
+                }
+                quote(&parameters, position, &mut result)?;
+                continue;
+            }
+            b's' => {
+                let spec = Spec::parse(&form, "-", true)?;
+                let s = tostring(interpreter, &argument(&parameters, position))?;
+                let s = match spec.precision {
+                    Some(precision) if precision < s.len() => &s[..precision],
+                    _ => &s[..],
+                };
+                spec.pad_bytes(s, &mut result);
+                continue;
+            }
+            _ => {
+                return Err(LuaError::new(format!(
+                    "invalid conversion '%{form}' to 'format'"
+                )))
+            }
+        };
+        result.extend_from_slice(formatted.as_bytes());
+    }
+    Ok(vec![Value::String(result.into())])
+}
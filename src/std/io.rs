@@ -0,0 +1,934 @@
+//! The `io` library, with files as userdata whose metatable is kept in the
+//! registry under `FILE*`.
+
+use std::{
+    cell::RefCell,
+    fs::{self, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Stderr, Stdin, Stdout, Write},
+    os::unix::ffi::OsStrExt,
+    process::{Child, Command, ExitStatus, Stdio},
+    rc::{Rc, Weak},
+};
+
+use crate::interpreter::{
+    value::{format_g, str_to_number, Table, TableRef, Userdata, UserdataRef, Value},
+    Interpreter, LuaError,
+};
+
+use super::{
+    argument, argument_error, check_integer, check_string, exec_result, file_error, library,
+    optional_integer, optional_string, run_prelude, type_name, Builtin,
+};
+
+/// The registry key of the metatable of files, also their `__name`.
+pub const FILE_HANDLE: &str = "FILE*";
+/// Registry keys of the default files.
+const INPUT: &str = "_IO_input";
+const OUTPUT: &str = "_IO_output";
+/// The registry key of the function making the iterators of `lines`.
+const LINES: &str = "_IO_lines";
+
+/// Size of the buffers of files, like C's `BUFSIZ`.
+const BUFFER_SIZE: usize = 8192;
+/// Longest numeral `read("n")` accepts.
+const MAXIMUM_NUMERAL: usize = 200;
+/// Most formats `lines` can be given.
+const MAXIMUM_LINES_FORMATS: usize = 250;
+
+const EBADF: i32 = 9;
+const EINVAL: i32 = 22;
+const ESPIPE: i32 = 29;
+
+/// `lines`, an iterator calling `step` with the file, whether to close it at
+/// its end and the `n` formats.
+pub const LINES_ITERATOR: &str = "
+    local step, unpack = ...
+    return function(file, close, n, ...)
+        local formats = {...}
+        return function()
+            return step(file, close, unpack(formats, 1, n))
+        end
+    end
+";
+
+enum Stream {
+    Stdin(Stdin),
+    Stdout(Stdout),
+    Stderr(Stderr),
+    File(fs::File),
+    /// A command run by `io.popen`, whose input or output is piped.
+    Pipe(Child),
+}
+
+impl Stream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Stdin(stdin) => stdin.read(buffer),
+            Stream::File(file) => file.read(buffer),
+            Stream::Pipe(Child {
+                stdout: Some(stdout),
+                ..
+            }) => stdout.read(buffer),
+            _ => Err(io::Error::from_raw_os_error(EBADF)),
+        }
+    }
+
+    fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match self {
+            Stream::Stdout(stdout) => stdout.write_all(bytes),
+            Stream::Stderr(stderr) => stderr.write_all(bytes),
+            Stream::File(file) => file.write_all(bytes),
+            Stream::Pipe(Child {
+                stdin: Some(stdin), ..
+            }) => stdin.write_all(bytes),
+            Stream::Stdin(_) | Stream::Pipe(_) => Err(io::Error::from_raw_os_error(EBADF)),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Stdout(stdout) => stdout.flush(),
+            Stream::Stderr(stderr) => stderr.flush(),
+            Stream::File(file) => file.flush(),
+            Stream::Pipe(Child {
+                stdin: Some(stdin), ..
+            }) => stdin.flush(),
+            Stream::Stdin(_) | Stream::Pipe(_) => Ok(()),
+        }
+    }
+
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        match self {
+            Stream::File(file) => file.seek(position),
+            _ => Err(io::Error::from_raw_os_error(ESPIPE)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Buffering {
+    No,
+    Full,
+    Line,
+}
+
+/// An open or closed file, buffering what it reads ahead and what it writes.
+pub struct LuaFile {
+    /// `None` once closed.
+    stream: Option<Stream>,
+    read_buffer: Vec<u8>,
+    read_position: usize,
+    write_buffer: Vec<u8>,
+    buffering: Buffering,
+    /// Whether this is one of the standard files, which cannot be closed.
+    is_standard: bool,
+}
+
+impl LuaFile {
+    fn new(stream: Stream, is_standard: bool) -> Self {
+        Self {
+            stream: Some(stream),
+            read_buffer: vec![],
+            read_position: 0,
+            write_buffer: vec![],
+            buffering: Buffering::Full,
+            is_standard,
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.stream.is_none()
+    }
+
+    fn stream(&mut self) -> io::Result<&mut Stream> {
+        self.stream
+            .as_mut()
+            .ok_or_else(|| io::Error::from_raw_os_error(EBADF))
+    }
+
+    fn unread(&self) -> usize {
+        self.read_buffer.len() - self.read_position
+    }
+
+    /// Makes sure there are bytes read ahead, giving false at the end of the file.
+    fn fill(&mut self) -> io::Result<bool> {
+        if self.unread() > 0 {
+            return Ok(true);
+        }
+        self.flush()?;
+        self.read_buffer.resize(BUFFER_SIZE, 0);
+        self.read_position = 0;
+        let result = loop {
+            match self.stream.as_mut() {
+                Some(stream) => match stream.read(&mut self.read_buffer) {
+                    Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                    result => break result,
+                },
+                None => break Err(io::Error::from_raw_os_error(EBADF)),
+            }
+        };
+        let count = result.inspect_err(|_| self.read_buffer.clear())?;
+        self.read_buffer.truncate(count);
+        Ok(count > 0)
+    }
+
+    fn peek(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.fill()?.then(|| self.read_buffer[self.read_position]))
+    }
+
+    fn read_line(&mut self, keep_newline: bool) -> io::Result<Option<Vec<u8>>> {
+        let mut line = vec![];
+        while self.fill()? {
+            let available = &self.read_buffer[self.read_position..];
+            match available.iter().position(|&c| c == b'\n') {
+                Some(i) => {
+                    let end = if keep_newline { i + 1 } else { i };
+                    line.extend_from_slice(&available[..end]);
+                    self.read_position += i + 1;
+                    return Ok(Some(line));
+                }
+                None => {
+                    line.extend_from_slice(available);
+                    self.read_position = self.read_buffer.len();
+                }
+            }
+        }
+        // A last line without newline still counts, but not an empty one
+        Ok((!line.is_empty()).then_some(line))
+    }
+
+    fn read_all(&mut self) -> io::Result<Vec<u8>> {
+        let mut result = vec![];
+        while self.fill()? {
+            result.extend_from_slice(&self.read_buffer[self.read_position..]);
+            self.read_position = self.read_buffer.len();
+        }
+        Ok(result)
+    }
+
+    /// Reads up to `count` bytes, or checks for the end of the file when
+    /// `count` is zero.
+    fn read_bytes(&mut self, count: usize) -> io::Result<Option<Vec<u8>>> {
+        if count == 0 {
+            return Ok(self.fill()?.then(Vec::new));
+        }
+        let mut result = vec![];
+        while result.len() < count && self.fill()? {
+            let available = &self.read_buffer[self.read_position..];
+            let length = available.len().min(count - result.len());
+            result.extend_from_slice(&available[..length]);
+            self.read_position += length;
+        }
+        Ok((!result.is_empty()).then_some(result))
+    }
+
+    /// Reads the longest prefix of a numeral, like `liolib`'s `read_number`.
+    fn read_number(&mut self) -> io::Result<Option<Value>> {
+        let mut numeral = vec![];
+        while self
+            .peek()?
+            .is_some_and(|c| c.is_ascii_whitespace() || c == 0x0b)
+        {
+            self.read_position += 1;
+        }
+        let mut accept = |file: &mut Self, set: &[u8]| -> io::Result<bool> {
+            match file.peek()? {
+                Some(c) if set.contains(&c) && numeral.len() < MAXIMUM_NUMERAL => {
+                    numeral.push(c);
+                    file.read_position += 1;
+                    Ok(true)
+                }
+                _ => Ok(false),
+            }
+        };
+        accept(self, b"-+")?;
+        let mut count = 0;
+        let mut is_hex = false;
+        if accept(self, b"0")? {
+            if accept(self, b"xX")? {
+                is_hex = true;
+            } else {
+                count = 1;
+            }
+        }
+        let digits: &[u8] = if is_hex {
+            b"0123456789abcdefABCDEF"
+        } else {
+            b"0123456789"
+        };
+        while accept(self, digits)? {
+            count += 1;
+        }
+        if accept(self, b".")? {
+            while accept(self, digits)? {
+                count += 1;
+            }
+        }
+        if count > 0 && accept(self, if is_hex { b"pP" } else { b"eE" })? {
+            accept(self, b"-+")?;
+            while accept(self, b"0123456789")? {}
+        }
+        Ok(std::str::from_utf8(&numeral).ok().and_then(str_to_number))
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        // Drops what was read ahead, going back to where reading stopped
+        let unread = self.unread();
+        if unread > 0 {
+            self.stream()?.seek(SeekFrom::Current(-(unread as i64)))?;
+        }
+        self.read_buffer.clear();
+        self.read_position = 0;
+        let buffering = self.buffering;
+        match self.stream()? {
+            stream @ (Stream::Stdout(_) | Stream::Stderr(_)) => {
+                stream.write_all(bytes)?;
+                if buffering == Buffering::No {
+                    stream.flush()?;
+                }
+                return Ok(());
+            }
+            Stream::Stdin(_) => return Err(io::Error::from_raw_os_error(EBADF)),
+            Stream::File(_) | Stream::Pipe(_) => {}
+        }
+        self.write_buffer.extend_from_slice(bytes);
+        let must_flush = match buffering {
+            Buffering::No => true,
+            Buffering::Line => bytes.contains(&b'\n'),
+            Buffering::Full => self.write_buffer.len() >= BUFFER_SIZE,
+        };
+        if must_flush {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.write_buffer.is_empty() {
+            return match self.stream.as_mut() {
+                Some(stream) => stream.flush(),
+                None => Ok(()),
+            };
+        }
+        let buffer = std::mem::take(&mut self.write_buffer);
+        let stream = self.stream()?;
+        stream.write_all(&buffer)?;
+        stream.flush()
+    }
+
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        self.flush()?;
+        let position = match position {
+            SeekFrom::Current(offset) => SeekFrom::Current(offset - self.unread() as i64),
+            SeekFrom::Start(_) | SeekFrom::End(_) => position,
+        };
+        let result = self.stream()?.seek(position);
+        self.read_buffer.clear();
+        self.read_position = 0;
+        result
+    }
+
+    /// Closes the file, giving the exit status of the command of a pipe.
+    fn close(&mut self) -> io::Result<Option<ExitStatus>> {
+        let result = self.flush();
+        let status = match self.stream.take() {
+            Some(Stream::Pipe(mut child)) => {
+                // Closing both ends lets the command end, with a broken pipe
+                // if it still has output, as `pclose` does
+                drop(child.stdin.take());
+                drop(child.stdout.take());
+                Some(child.wait()?)
+            }
+            _ => None,
+        };
+        result.map(|()| status)
+    }
+}
+
+impl Drop for LuaFile {
+    fn drop(&mut self) {
+        // Errors have nowhere to go once the file is unreachable
+        let _ = self.close();
+    }
+}
+
+thread_local! {
+    /// Every file handle made, to flush them all when the process exits.
+    static FILES: RefCell<Vec<Weak<Userdata>>> = const { RefCell::new(vec![]) };
+}
+
+/// Makes a file handle from a stream.
+fn new_file(interpreter: &mut Interpreter, stream: Stream, is_standard: bool) -> Value {
+    let metatable = match interpreter.registry().borrow().get_str(FILE_HANDLE) {
+        Value::Table(metatable) => Some(metatable),
+        _ => None,
+    };
+    let file = Rc::new(Userdata::new(LuaFile::new(stream, is_standard), metatable));
+    FILES.with_borrow_mut(|files| {
+        files.retain(|file| file.strong_count() > 0);
+        files.push(Rc::downgrade(&file));
+    });
+    Value::Userdata(file)
+}
+
+/// Flushes every open file, as the C library does when the process exits.
+pub fn flush_files() {
+    let files: Vec<UserdataRef> =
+        FILES.with_borrow(|files| files.iter().filter_map(Weak::upgrade).collect());
+    for file in files {
+        if let Some(mut file) = file.borrow_mut::<LuaFile>() {
+            let _ = file.flush();
+        }
+    }
+}
+
+/// Sets up the metatable of files and the default files, giving the `io` table.
+pub fn init(interpreter: &mut Interpreter, io: &TableRef) {
+    let methods = library(interpreter, &METHODS);
+    let mut metatable = Table::new();
+    metatable.insert_str("__index", methods);
+    metatable.insert_str("__name", Value::String(FILE_HANDLE.into()));
+    metatable.insert_str("__tostring", Value::Builtin(Builtin::FileToString));
+    metatable.insert_str("__close", Value::Builtin(Builtin::FileGc));
+    metatable.insert_str("__gc", Value::Builtin(Builtin::FileGc));
+    let metatable = interpreter.new_table(metatable);
+    let registry = interpreter.registry();
+    registry.borrow_mut().insert_str(FILE_HANDLE, metatable);
+    let stdin = new_file(interpreter, Stream::Stdin(io::stdin()), true);
+    let stdout = new_file(interpreter, Stream::Stdout(io::stdout()), true);
+    let stderr = new_file(interpreter, Stream::Stderr(io::stderr()), true);
+    {
+        let mut registry = registry.borrow_mut();
+        registry.insert_str(INPUT, stdin.clone());
+        registry.insert_str(OUTPUT, stdout.clone());
+    }
+    let mut io = io.borrow_mut();
+    io.insert_str("stdin", stdin);
+    io.insert_str("stdout", stdout);
+    io.insert_str("stderr", stderr);
+    drop(io);
+    let step = Value::Builtin(Builtin::IoLinesStep);
+    let unpack = Value::Builtin(Builtin::TableUnpack);
+    let lines = run_prelude(interpreter, LINES_ITERATOR, vec![step, unpack]);
+    registry.borrow_mut().insert_str(LINES, lines);
+}
+
+const METHODS: [Builtin; 7] = [
+    Builtin::FileClose,
+    Builtin::FileFlush,
+    Builtin::FileLines,
+    Builtin::FileRead,
+    Builtin::FileSeek,
+    Builtin::FileSetvbuf,
+    Builtin::FileWrite,
+];
+
+/// Flushes the default output file, as the process is about to end.
+pub fn flush_output(interpreter: &Interpreter) {
+    if let Value::Userdata(output) = interpreter.registry().borrow().get_str(OUTPUT) {
+        if let Some(mut file) = output.borrow_mut::<LuaFile>() {
+            let _ = file.flush();
+        }
+    }
+}
+
+/// A file handle argument, open or closed.
+fn check_file(parameters: &[Value], position: usize, name: &str) -> Result<UserdataRef, LuaError> {
+    match argument(parameters, position) {
+        Value::Userdata(userdata) if userdata.borrow::<LuaFile>().is_some() => Ok(userdata),
+        value => Err(argument_error(
+            position,
+            name,
+            &format!(
+                "{FILE_HANDLE} expected, got {}",
+                type_name(parameters, position, &value)
+            ),
+        )),
+    }
+}
+
+/// An open file handle argument.
+fn check_open_file(
+    parameters: &[Value],
+    position: usize,
+    name: &str,
+) -> Result<UserdataRef, LuaError> {
+    let file = check_file(parameters, position, name)?;
+    if is_closed(&file) {
+        return Err(LuaError::new("attempt to use a closed file"));
+    }
+    Ok(file)
+}
+
+fn is_closed(file: &UserdataRef) -> bool {
+    file.borrow::<LuaFile>().is_none_or(|file| file.is_closed())
+}
+
+/// Runs an operation on the file held by a userdata.
+fn with_file<T>(file: &UserdataRef, operation: impl FnOnce(&mut LuaFile) -> T) -> T {
+    let mut file = file.borrow_mut::<LuaFile>().expect("Not a file");
+    operation(&mut file)
+}
+
+/// The default input or output file, which must still be open.
+fn default_file(interpreter: &Interpreter, key: &str) -> Result<UserdataRef, LuaError> {
+    match interpreter.registry().borrow().get_str(key) {
+        Value::Userdata(file) if !is_closed(&file) => Ok(file),
+        _ => {
+            let kind = if key == INPUT { "input" } else { "output" };
+            Err(LuaError::new(format!("default {kind} file is closed")))
+        }
+    }
+}
+
+/// Whether `mode` is a valid mode for `io.open`, like `r`, `w+` or `ab`.
+fn is_valid_mode(mode: &str) -> bool {
+    let mode = mode.as_bytes();
+    matches!(mode.first(), Some(b'r' | b'w' | b'a'))
+        && mode[1..]
+            .strip_prefix(b"+")
+            .unwrap_or(&mode[1..])
+            .iter()
+            .all(|&c| c == b'b')
+}
+
+fn open(filename: &str, mode: &str) -> io::Result<fs::File> {
+    let mut options = OpenOptions::new();
+    let update = mode.contains('+');
+    match mode.as_bytes()[0] {
+        b'r' => options.read(true).write(update),
+        b'w' => options.write(true).create(true).truncate(true).read(update),
+        _ => options.append(true).create(true).read(update),
+    };
+    options.open(filename)
+}
+
+/// Opens a file for `io.lines`, `io.input` and `io.output`, raising an error
+/// when it cannot be opened.
+fn open_checked(
+    interpreter: &mut Interpreter,
+    filename: &str,
+    mode: &str,
+) -> Result<Value, LuaError> {
+    match open(filename, mode) {
+        Ok(file) => Ok(new_file(interpreter, Stream::File(file), false)),
+        Err(error) => Err(LuaError::new(format!(
+            "cannot open file '{filename}' ({})",
+            super::os_error_message(&error)
+        ))),
+    }
+}
+
+/// Reads from `file` with the formats from `first` on, giving `nil` for the
+/// first one that fails and stopping there.
+fn read(
+    file: &UserdataRef,
+    parameters: &[Value],
+    first: usize,
+    name: &str,
+) -> Result<Vec<Value>, LuaError> {
+    let mut file = file.borrow_mut::<LuaFile>().expect("Not a file");
+    if parameters.len() < first {
+        return match file.read_line(false) {
+            Ok(line) => Ok(vec![
+                line.map_or(Value::Nil, |line| Value::String(line.into()))
+            ]),
+            Err(error) => Ok(file_error(&error, None)),
+        };
+    }
+    let mut results = vec![];
+    for position in first..=parameters.len() {
+        let result = match argument(parameters, position) {
+            Value::Integer(_) | Value::Float(_) => {
+                let count = check_integer(parameters, position, name)?;
+                file.read_bytes(count.max(0) as usize)
+                    .map(|bytes| bytes.map(|bytes| Value::String(bytes.into())))
+            }
+            _ => {
+                let format = check_string(parameters, position, name)?;
+                let format = format.strip_prefix(b"*").unwrap_or(&format);
+                match format.first() {
+                    Some(b'n') => file.read_number(),
+                    Some(b'l') => file
+                        .read_line(false)
+                        .map(|line| line.map(|line| Value::String(line.into()))),
+                    Some(b'L') => file
+                        .read_line(true)
+                        .map(|line| line.map(|line| Value::String(line.into()))),
+                    Some(b'a') => file.read_all().map(|all| Some(Value::String(all.into()))),
+                    _ => return Err(argument_error(position, name, "invalid format")),
+                }
+            }
+        };
+        match result {
+            Ok(Some(value)) => results.push(value),
+            Ok(None) => {
+                results.push(Value::Nil);
+                break;
+            }
+            Err(error) => return Ok(file_error(&error, None)),
+        }
+    }
+    Ok(results)
+}
+
+/// Writes the arguments from `first` on to `file`, giving the file.
+fn write(
+    file: UserdataRef,
+    parameters: &[Value],
+    first: usize,
+    name: &str,
+) -> Result<Vec<Value>, LuaError> {
+    for position in first..=parameters.len() {
+        let bytes = match argument(parameters, position) {
+            Value::Integer(n) => n.to_string().into_bytes(),
+            // Floats are written with `%.14g`, without the `.0` of `tostring`
+            Value::Float(f) if f.is_finite() => format_g(f, 14, false).into_bytes(),
+            value @ Value::Float(_) => value.to_string().into_bytes(),
+            _ => check_string(parameters, position, name)?.to_vec(),
+        };
+        if let Err(error) = with_file(&file, |file| file.write(&bytes)) {
+            return Ok(file_error(&error, None));
+        }
+    }
+    Ok(vec![Value::Userdata(file)])
+}
+
+/// Closes a file, refusing to close the standard ones.
+fn close_file(file: &UserdataRef) -> Vec<Value> {
+    with_file(file, |file| {
+        if file.is_standard {
+            return vec![
+                Value::Nil,
+                Value::String("cannot close standard file".into()),
+            ];
+        }
+        match file.close() {
+            Ok(Some(status)) => exec_result(status),
+            Ok(None) => vec![Value::True],
+            Err(error) => file_error(&error, None),
+        }
+    })
+}
+
+/// Makes the iterator of `lines` over `file` with the formats from `first` on.
+fn lines_iterator(
+    interpreter: &mut Interpreter,
+    file: Value,
+    close: bool,
+    parameters: &[Value],
+    first: usize,
+) -> Result<Value, LuaError> {
+    let formats = parameters.get(first - 1..).unwrap_or_default();
+    if formats.len() > MAXIMUM_LINES_FORMATS {
+        return Err(argument_error(
+            MAXIMUM_LINES_FORMATS + first,
+            "lines",
+            "too many arguments",
+        ));
+    }
+    let factory = interpreter.registry().borrow().get_str(LINES);
+    let mut arguments = vec![
+        file,
+        Value::from(close),
+        Value::Integer(formats.len() as i64),
+    ];
+    arguments.extend_from_slice(formats);
+    let values = interpreter.call(factory, arguments)?;
+    Ok(values.into_iter().next().unwrap_or(Value::Nil))
+}
+
+pub fn close(
+    interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    if argument(&parameters, 1).is_nil() {
+        let output = interpreter.registry().borrow().get_str(OUTPUT);
+        return file_close(interpreter, vec![output]);
+    }
+    file_close(interpreter, parameters)
+}
+
+pub fn flush(
+    interpreter: &mut Interpreter,
+    _parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let output = default_file(interpreter, OUTPUT)?;
+    match with_file(&output, LuaFile::flush) {
+        Ok(()) => Ok(vec![Value::True]),
+        Err(error) => Ok(file_error(&error, None)),
+    }
+}
+
+/// `io.input` and `io.output`, setting the default file from a file name or
+/// a handle and giving the current one.
+fn default_file_function(
+    interpreter: &mut Interpreter,
+    parameters: &[Value],
+    key: &str,
+    mode: &str,
+    name: &str,
+) -> Result<Vec<Value>, LuaError> {
+    let file = match argument(parameters, 1) {
+        Value::Nil => None,
+        value @ (Value::String(_) | Value::Integer(_) | Value::Float(_)) => {
+            Some(open_checked(interpreter, &value.to_string(), mode)?)
+        }
+        _ => {
+            check_open_file(parameters, 1, name)?;
+            Some(argument(parameters, 1))
+        }
+    };
+    let registry = interpreter.registry();
+    if let Some(file) = file {
+        registry.borrow_mut().insert_str(key, file);
+    }
+    let current = registry.borrow().get_str(key);
+    Ok(vec![current])
+}
+
+pub fn input(
+    interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    default_file_function(interpreter, &parameters, INPUT, "r", "input")
+}
+
+pub fn output(
+    interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    default_file_function(interpreter, &parameters, OUTPUT, "w", "output")
+}
+
+pub fn lines(
+    interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    if argument(&parameters, 1).is_nil() {
+        let input = interpreter.registry().borrow().get_str(INPUT);
+        check_open_file(std::slice::from_ref(&input), 1, "lines")?;
+        let iterator = lines_iterator(interpreter, input, false, &parameters, 2)?;
+        return Ok(vec![iterator]);
+    }
+    let filename = check_string(&parameters, 1, "lines")?.to_string();
+    let file = open_checked(interpreter, &filename, "r")?;
+    let iterator = lines_iterator(interpreter, file.clone(), true, &parameters, 2)?;
+    // The file is also the to-be-closed value of a generic `for`
+    Ok(vec![iterator, Value::Nil, Value::Nil, file])
+}
+
+pub fn lines_step(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let file = check_file(&parameters, 1, "lines")?;
+    if is_closed(&file) {
+        return Err(LuaError::new("file is already closed"));
+    }
+    let mut values = read(&file, &parameters, 3, "lines")?;
+    if values.first().is_some_and(Value::is_truthy) {
+        return Ok(values);
+    }
+    if values.len() > 1 {
+        return Err(LuaError::new(values.swap_remove(1).to_string()));
+    }
+    if argument(&parameters, 2).is_truthy() {
+        close_file(&file);
+    }
+    Ok(vec![])
+}
+
+pub fn open_file(
+    interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let filename = check_string(&parameters, 1, "open")?.to_string();
+    let mode = optional_string(&parameters, 2, "open", "r")?;
+    if !is_valid_mode(&mode) {
+        return Err(argument_error(2, "open", "invalid mode"));
+    }
+    match open(&filename, &mode) {
+        Ok(file) => Ok(vec![new_file(interpreter, Stream::File(file), false)]),
+        Err(error) => Ok(file_error(&error, Some(&filename))),
+    }
+}
+
+pub fn popen(
+    interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let command = check_string(&parameters, 1, "popen")?;
+    let mode = optional_string(&parameters, 2, "popen", "r")?;
+    let mut shell = Command::new("/bin/sh");
+    shell.arg("-c").arg(std::ffi::OsStr::from_bytes(&command));
+    match mode.as_str() {
+        "r" => shell.stdout(Stdio::piped()),
+        "w" => shell.stdin(Stdio::piped()),
+        _ => return Err(argument_error(2, "popen", "invalid mode")),
+    };
+    match shell.spawn() {
+        Ok(child) => Ok(vec![new_file(interpreter, Stream::Pipe(child), false)]),
+        Err(error) => Ok(file_error(&error, Some(&command.to_str_lossy()))),
+    }
+}
+
+pub fn read_default(
+    interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let input = default_file(interpreter, INPUT)?;
+    read(&input, &parameters, 1, "read")
+}
+
+pub fn r#type(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    if parameters.is_empty() {
+        return Err(argument_error(1, "type", "value expected"));
+    }
+    let kind = match argument(&parameters, 1) {
+        Value::Userdata(userdata) => match userdata.borrow::<LuaFile>() {
+            Some(file) if file.is_closed() => "closed file",
+            Some(_) => "file",
+            None => return Ok(vec![Value::Nil]),
+        },
+        _ => return Ok(vec![Value::Nil]),
+    };
+    Ok(vec![Value::String(kind.into())])
+}
+
+pub fn write_default(
+    interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let output = default_file(interpreter, OUTPUT)?;
+    write(output, &parameters, 1, "write")
+}
+
+pub fn file_close(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let file = check_open_file(&parameters, 1, "close")?;
+    Ok(close_file(&file))
+}
+
+pub fn file_flush(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let file = check_open_file(&parameters, 1, "flush")?;
+    match with_file(&file, LuaFile::flush) {
+        Ok(()) => Ok(vec![Value::True]),
+        Err(error) => Ok(file_error(&error, None)),
+    }
+}
+
+pub fn file_lines(
+    interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let file = check_open_file(&parameters, 1, "lines")?;
+    let iterator = lines_iterator(interpreter, Value::Userdata(file), false, &parameters, 2)?;
+    Ok(vec![iterator])
+}
+
+pub fn file_read(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let file = check_open_file(&parameters, 1, "read")?;
+    read(&file, &parameters, 2, "read")
+}
+
+pub fn file_seek(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let file = check_open_file(&parameters, 1, "seek")?;
+    let whence = optional_string(&parameters, 2, "seek", "cur")?;
+    let offset = optional_integer(&parameters, 3, "seek", 0)?;
+    let position = match whence.as_str() {
+        "set" => match u64::try_from(offset) {
+            Ok(offset) => SeekFrom::Start(offset),
+            Err(_) => return Ok(file_error(&io::Error::from_raw_os_error(EINVAL), None)),
+        },
+        "cur" => SeekFrom::Current(offset),
+        "end" => SeekFrom::End(offset),
+        _ => {
+            return Err(argument_error(
+                2,
+                "seek",
+                &format!("invalid option '{whence}'"),
+            ))
+        }
+    };
+    match with_file(&file, |file| file.seek(position)) {
+        Ok(position) => Ok(vec![Value::Integer(position as i64)]),
+        Err(error) => Ok(file_error(&error, None)),
+    }
+}
+
+pub fn file_setvbuf(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let file = check_open_file(&parameters, 1, "setvbuf")?;
+    let mode = check_string(&parameters, 2, "setvbuf")?;
+    let buffering = match mode.as_bytes() {
+        b"no" => Buffering::No,
+        b"full" => Buffering::Full,
+        b"line" => Buffering::Line,
+        _ => {
+            return Err(argument_error(
+                2,
+                "setvbuf",
+                &format!("invalid option '{mode}'"),
+            ))
+        }
+    };
+    // The size of the buffer is only a hint
+    optional_integer(&parameters, 3, "setvbuf", BUFFER_SIZE as i64)?;
+    match with_file(&file, |file| {
+        file.buffering = buffering;
+        file.flush()
+    }) {
+        Ok(()) => Ok(vec![Value::True]),
+        Err(error) => Ok(file_error(&error, None)),
+    }
+}
+
+pub fn file_write(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let file = check_open_file(&parameters, 1, "write")?;
+    write(file, &parameters, 2, "write")
+}
+
+/// `__gc` and `__close` of files, closing them unless already closed.
+pub fn file_gc(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let file = check_file(&parameters, 1, "__gc")?;
+    if !is_closed(&file) {
+        close_file(&file);
+    }
+    Ok(vec![])
+}
+
+pub fn file_tostring(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let file = check_file(&parameters, 1, "tostring")?;
+    let s = if is_closed(&file) {
+        "file (closed)".to_string()
+    } else {
+        format!("file ({:p})", Rc::as_ptr(&file))
+    };
+    Ok(vec![Value::String(s.into())])
+}
@@ -0,0 +1,215 @@
+use crate::interpreter::{
+    value::{float_to_integer, Value},
+    Interpreter, LuaError,
+};
+
+use super::{argument, argument_error, check_any, check_integer, check_number};
+
+/// Converts an integral float to an integer when it fits, as `floor` and
+/// `ceil` do.
+fn integer_or_float(f: f64) -> Value {
+    match float_to_integer(f) {
+        Some(n) => Value::Integer(n),
+        None => Value::Float(f),
+    }
+}
+
+/// A function of one float giving a float.
+fn float_function(
+    parameters: &[Value],
+    name: &str,
+    function: fn(f64) -> f64,
+) -> Result<Vec<Value>, LuaError> {
+    let x = check_number(parameters, 1, name)?;
+    Ok(vec![Value::Float(function(x))])
+}
+
+pub fn abs(_interpreter: &mut Interpreter, parameters: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    match argument(&parameters, 1) {
+        Value::Integer(n) => Ok(vec![Value::Integer(n.wrapping_abs())]),
+        _ => float_function(&parameters, "abs", f64::abs),
+    }
+}
+
+pub fn ceil(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    match argument(&parameters, 1) {
+        n @ Value::Integer(_) => Ok(vec![n]),
+        _ => {
+            let x = check_number(&parameters, 1, "ceil")?;
+            Ok(vec![integer_or_float(x.ceil())])
+        }
+    }
+}
+
+pub fn floor(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    match argument(&parameters, 1) {
+        n @ Value::Integer(_) => Ok(vec![n]),
+        _ => {
+            let x = check_number(&parameters, 1, "floor")?;
+            Ok(vec![integer_or_float(x.floor())])
+        }
+    }
+}
+
+pub fn sqrt(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    float_function(&parameters, "sqrt", f64::sqrt)
+}
+
+pub fn sin(_interpreter: &mut Interpreter, parameters: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    float_function(&parameters, "sin", f64::sin)
+}
+
+pub fn cos(_interpreter: &mut Interpreter, parameters: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    float_function(&parameters, "cos", f64::cos)
+}
+
+pub fn tan(_interpreter: &mut Interpreter, parameters: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    float_function(&parameters, "tan", f64::tan)
+}
+
+pub fn asin(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    float_function(&parameters, "asin", f64::asin)
+}
+
+pub fn acos(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    float_function(&parameters, "acos", f64::acos)
+}
+
+pub fn atan(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let y = check_number(&parameters, 1, "atan")?;
+    let x = match argument(&parameters, 2) {
+        Value::Nil => 1.0,
+        _ => check_number(&parameters, 2, "atan")?,
+    };
+    Ok(vec![Value::Float(y.atan2(x))])
+}
+
+pub fn exp(_interpreter: &mut Interpreter, parameters: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    float_function(&parameters, "exp", f64::exp)
+}
+
+pub fn log(_interpreter: &mut Interpreter, parameters: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let x = check_number(&parameters, 1, "log")?;
+    let result = match argument(&parameters, 2) {
+        Value::Nil => x.ln(),
+        _ => {
+            let base = check_number(&parameters, 2, "log")?;
+            if base == 2.0 {
+                x.log2()
+            } else if base == 10.0 {
+                x.log10()
+            } else {
+                x.ln() / base.ln()
+            }
+        }
+    };
+    Ok(vec![Value::Float(result)])
+}
+
+pub fn fmod(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    match (argument(&parameters, 1), argument(&parameters, 2)) {
+        (Value::Integer(_), Value::Integer(0)) => Err(argument_error(2, "fmod", "zero")),
+        // Avoids the overflow of `mininteger % -1`
+        (Value::Integer(_), Value::Integer(-1)) => Ok(vec![Value::Integer(0)]),
+        (Value::Integer(a), Value::Integer(b)) => Ok(vec![Value::Integer(a % b)]),
+        _ => {
+            let a = check_number(&parameters, 1, "fmod")?;
+            let b = check_number(&parameters, 2, "fmod")?;
+            Ok(vec![Value::Float(a % b)])
+        }
+    }
+}
+
+pub fn modf(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    if let n @ Value::Integer(_) = argument(&parameters, 1) {
+        return Ok(vec![n, Value::Float(0.0)]);
+    }
+    let x = check_number(&parameters, 1, "modf")?;
+    let integral = x.trunc();
+    // Infinities have no fractional part
+    let fraction = if x == integral { 0.0 } else { x - integral };
+    Ok(vec![Value::Float(integral), Value::Float(fraction)])
+}
+
+/// The smallest or largest of the arguments, keeping its type.
+fn extremum(
+    interpreter: &mut Interpreter,
+    parameters: &[Value],
+    name: &str,
+    is_better: fn(&mut Interpreter, &Value, &Value) -> Result<bool, LuaError>,
+) -> Result<Vec<Value>, LuaError> {
+    check_number(parameters, 1, name)?;
+    let mut best = argument(parameters, 1);
+    for position in 2..=parameters.len() {
+        check_number(parameters, position, name)?;
+        let value = argument(parameters, position);
+        if is_better(interpreter, &value, &best)? {
+            best = value;
+        }
+    }
+    Ok(vec![best])
+}
+
+pub fn max(interpreter: &mut Interpreter, parameters: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    extremum(interpreter, &parameters, "max", |interpreter, a, b| {
+        interpreter.less_than(b, a)
+    })
+}
+
+pub fn min(interpreter: &mut Interpreter, parameters: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    extremum(interpreter, &parameters, "min", Interpreter::less_than)
+}
+
+pub fn tointeger(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    check_any(&parameters, 1, "tointeger")?;
+    match argument(&parameters, 1).to_integer() {
+        Some(n) => Ok(vec![Value::Integer(n)]),
+        None => Ok(vec![Value::Nil]),
+    }
+}
+
+pub fn r#type(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    check_any(&parameters, 1, "type")?;
+    let name = match argument(&parameters, 1) {
+        Value::Integer(_) => "integer",
+        Value::Float(_) => "float",
+        _ => return Ok(vec![Value::Nil]),
+    };
+    Ok(vec![Value::String(name.into())])
+}
+
+pub fn ult(_interpreter: &mut Interpreter, parameters: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let a = check_integer(&parameters, 1, "ult")?;
+    let b = check_integer(&parameters, 2, "ult")?;
+    Ok(vec![Value::from((a as u64) < (b as u64))])
+}
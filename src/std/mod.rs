@@ -1,32 +1,1281 @@
+pub mod coroutine;
+mod format;
+mod io;
+mod math;
+mod os;
+mod pack;
+pub mod package;
+mod pattern;
+pub(crate) mod random;
+pub mod string;
+mod table;
+mod utf8;
+
 use std::fmt::Debug;
 
-use crate::interpreter::{value::Value, Scope};
+use crate::interpreter::{
+    value::{LuaString, NativeFunction, Table, TableRef, Value},
+    Interpreter, LuaError,
+};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Builtin {
-    Print,
+    Error,
+    PCall,
+    SetMetatable,
+    GetMetatable,
+    Next,
+    Pairs,
+    RawEqual,
+    RawGet,
+    RawLen,
+    RawSet,
+    CollectGarbage,
+    Load,
+    LoadFile,
+    DoFile,
+    Require,
+    Type,
+    ToNumber,
+    ToString,
+    Select,
+    Assert,
+    Warn,
+    PackageSearchPath,
+    SearchPreload,
+    SearchLua,
+    StringByte,
+    StringChar,
+    StringFind,
+    StringFormat,
+    StringGmatchStep,
+    StringGsub,
+    StringLen,
+    StringLower,
+    StringMatch,
+    StringPack,
+    StringPackSize,
+    StringRep,
+    StringReverse,
+    StringSub,
+    StringUnpack,
+    StringUpper,
+    TableConcat,
+    TableInsert,
+    TableMove,
+    TablePack,
+    TableRemove,
+    TableSort,
+    TableUnpack,
+    MathAbs,
+    MathAcos,
+    MathAsin,
+    MathAtan,
+    MathCeil,
+    MathCos,
+    MathExp,
+    MathFloor,
+    MathFmod,
+    MathLog,
+    MathMax,
+    MathMin,
+    MathModf,
+    MathSin,
+    MathSqrt,
+    MathTan,
+    MathToInteger,
+    MathType,
+    MathUlt,
+    MathRandom,
+    MathRandomSeed,
+    CoroutineCreate,
+    CoroutineResume,
+    CoroutineYield,
+    CoroutineStatus,
+    CoroutineRunning,
+    CoroutineIsYieldable,
+    CoroutineClose,
+    IoClose,
+    IoFlush,
+    IoInput,
+    IoLines,
+    IoLinesStep,
+    IoOpen,
+    IoOutput,
+    IoPopen,
+    IoRead,
+    IoType,
+    IoWrite,
+    FileClose,
+    FileFlush,
+    FileLines,
+    FileRead,
+    FileSeek,
+    FileSetvbuf,
+    FileWrite,
+    FileGc,
+    FileToString,
+    OsClock,
+    OsDate,
+    OsDiffTime,
+    OsExecute,
+    OsExit,
+    OsGetEnv,
+    OsRemove,
+    OsRename,
+    OsTime,
+    OsTmpName,
+    Utf8Char,
+    Utf8Codes,
+    Utf8CodesStep,
+    Utf8CodesStepLax,
+    Utf8CodePoint,
+    Utf8Len,
+    Utf8Offset,
+}
+
+const GLOBALS: [Builtin; 21] = [
+    Builtin::Error,
+    Builtin::PCall,
+    Builtin::SetMetatable,
+    Builtin::GetMetatable,
+    Builtin::Next,
+    Builtin::Pairs,
+    Builtin::RawEqual,
+    Builtin::RawGet,
+    Builtin::RawLen,
+    Builtin::RawSet,
+    Builtin::CollectGarbage,
+    Builtin::Load,
+    Builtin::LoadFile,
+    Builtin::DoFile,
+    Builtin::Require,
+    Builtin::Type,
+    Builtin::ToNumber,
+    Builtin::ToString,
+    Builtin::Select,
+    Builtin::Assert,
+    Builtin::Warn,
+];
+
+const PACKAGE: [Builtin; 1] = [Builtin::PackageSearchPath];
+
+const STRING: [Builtin; 15] = [
+    Builtin::StringByte,
+    Builtin::StringChar,
+    Builtin::StringFind,
+    Builtin::StringFormat,
+    Builtin::StringGsub,
+    Builtin::StringLen,
+    Builtin::StringLower,
+    Builtin::StringMatch,
+    Builtin::StringPack,
+    Builtin::StringPackSize,
+    Builtin::StringRep,
+    Builtin::StringReverse,
+    Builtin::StringSub,
+    Builtin::StringUnpack,
+    Builtin::StringUpper,
+];
+
+const TABLE: [Builtin; 7] = [
+    Builtin::TableConcat,
+    Builtin::TableInsert,
+    Builtin::TableMove,
+    Builtin::TablePack,
+    Builtin::TableRemove,
+    Builtin::TableSort,
+    Builtin::TableUnpack,
+];
+
+const MATH: [Builtin; 21] = [
+    Builtin::MathAbs,
+    Builtin::MathAcos,
+    Builtin::MathAsin,
+    Builtin::MathAtan,
+    Builtin::MathCeil,
+    Builtin::MathCos,
+    Builtin::MathExp,
+    Builtin::MathFloor,
+    Builtin::MathFmod,
+    Builtin::MathLog,
+    Builtin::MathMax,
+    Builtin::MathMin,
+    Builtin::MathModf,
+    Builtin::MathSin,
+    Builtin::MathSqrt,
+    Builtin::MathTan,
+    Builtin::MathToInteger,
+    Builtin::MathType,
+    Builtin::MathUlt,
+    Builtin::MathRandom,
+    Builtin::MathRandomSeed,
+];
+
+const COROUTINE: [Builtin; 7] = [
+    Builtin::CoroutineCreate,
+    Builtin::CoroutineResume,
+    Builtin::CoroutineYield,
+    Builtin::CoroutineStatus,
+    Builtin::CoroutineRunning,
+    Builtin::CoroutineIsYieldable,
+    Builtin::CoroutineClose,
+];
+
+const IO: [Builtin; 10] = [
+    Builtin::IoClose,
+    Builtin::IoFlush,
+    Builtin::IoInput,
+    Builtin::IoLines,
+    Builtin::IoOpen,
+    Builtin::IoOutput,
+    Builtin::IoPopen,
+    Builtin::IoRead,
+    Builtin::IoType,
+    Builtin::IoWrite,
+];
+
+const OS: [Builtin; 10] = [
+    Builtin::OsClock,
+    Builtin::OsDate,
+    Builtin::OsDiffTime,
+    Builtin::OsExecute,
+    Builtin::OsExit,
+    Builtin::OsGetEnv,
+    Builtin::OsRemove,
+    Builtin::OsRename,
+    Builtin::OsTime,
+    Builtin::OsTmpName,
+];
+
+const UTF8: [Builtin; 5] = [
+    Builtin::Utf8Char,
+    Builtin::Utf8Codes,
+    Builtin::Utf8CodePoint,
+    Builtin::Utf8Len,
+    Builtin::Utf8Offset,
+];
+
+pub fn load_std(interpreter: &mut Interpreter) {
+    for builtin in GLOBALS {
+        interpreter.set_global(builtin.name(), Value::Builtin(builtin));
+    }
+    interpreter.set_global("print", NativeFunction::new("print", global::print));
+    let Value::Table(package) = library(interpreter, &PACKAGE) else {
+        unreachable!()
+    };
+    package::init(interpreter, &package);
+    register(interpreter, "_G", Value::Table(interpreter.globals()));
+    register(interpreter, "package", Value::Table(package));
+    let string = library(interpreter, &STRING);
+    let Value::Table(metatable) = interpreter.new_table(Table::new()) else {
+        unreachable!()
+    };
+    metatable.borrow_mut().insert_str("__index", string.clone());
+    let step = Value::Builtin(Builtin::StringGmatchStep);
+    let gmatch = run_prelude(interpreter, string::GMATCH, vec![step]);
+    if let Value::Table(string) = &string {
+        string.borrow_mut().insert_str("gmatch", gmatch);
+    }
+    interpreter.set_string_metatable(Some(metatable));
+    register(interpreter, "string", string);
+    let table = library(interpreter, &TABLE);
+    register(interpreter, "table", table);
+    let math = library(interpreter, &MATH);
+    if let Value::Table(math) = &math {
+        let mut math = math.borrow_mut();
+        math.insert_str("huge", Value::Float(f64::INFINITY));
+        math.insert_str("pi", Value::Float(std::f64::consts::PI));
+        math.insert_str("maxinteger", Value::Integer(i64::MAX));
+        math.insert_str("mininteger", Value::Integer(i64::MIN));
+    }
+    register(interpreter, "math", math);
+    let coroutine = library(interpreter, &COROUTINE);
+    register(interpreter, "coroutine", coroutine);
+    let wrap = run_prelude(interpreter, coroutine::WRAP, vec![]);
+    if let Value::Table(coroutine) = interpreter.get_global("coroutine") {
+        coroutine.borrow_mut().insert_str("wrap", wrap);
+    }
+    let Value::Table(io) = library(interpreter, &IO) else {
+        unreachable!()
+    };
+    io::init(interpreter, &io);
+    register(interpreter, "io", Value::Table(io));
+    let os = library(interpreter, &OS);
+    register(interpreter, "os", os);
+    let utf8 = library(interpreter, &UTF8);
+    if let Value::Table(utf8) = &utf8 {
+        let pattern = Value::String(utf8::CHAR_PATTERN.into());
+        utf8.borrow_mut().insert_str("charpattern", pattern);
+    }
+    register(interpreter, "utf8", utf8);
+}
+
+fn library(interpreter: &mut Interpreter, builtins: &[Builtin]) -> Value {
+    let mut table = Table::new();
+    for &builtin in builtins {
+        table.insert_str(builtin.name(), Value::Builtin(builtin));
+    }
+    interpreter.new_table(table)
+}
+
+/// Makes a library a global and marks it as loaded for `require`.
+fn register(interpreter: &mut Interpreter, name: &str, library: Value) {
+    interpreter.set_global(name, library.clone());
+    if let Value::Table(loaded) = interpreter.registry().borrow().get_str(package::LOADED) {
+        loaded.borrow_mut().insert_str(name, library);
+    }
 }
 
-pub fn load_std(scope: &mut Scope) {
-    scope.insert("print".to_string(), Value::Builtin(Builtin::Print));
+/// Runs a chunk of the library written in Lua with `arguments`, giving the
+/// value it returns.
+fn run_prelude(interpreter: &mut Interpreter, code: &str, arguments: Vec<Value>) -> Value {
+    let chunk = interpreter.load(code, "=prelude", None).unwrap();
+    let values = interpreter.call(chunk, arguments).unwrap();
+    values.into_iter().next().unwrap_or(Value::Nil)
 }
 
 impl Builtin {
-    pub fn call(&self, parameters: Vec<Value>) -> Value {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Builtin::Error => "error",
+            Builtin::PCall => "pcall",
+            Builtin::SetMetatable => "setmetatable",
+            Builtin::GetMetatable => "getmetatable",
+            Builtin::Next => "next",
+            Builtin::Pairs => "pairs",
+            Builtin::RawEqual => "rawequal",
+            Builtin::RawGet => "rawget",
+            Builtin::RawLen => "rawlen",
+            Builtin::RawSet => "rawset",
+            Builtin::CollectGarbage => "collectgarbage",
+            Builtin::Load => "load",
+            Builtin::LoadFile => "loadfile",
+            Builtin::DoFile => "dofile",
+            Builtin::Require => "require",
+            Builtin::Type => "type",
+            Builtin::ToNumber => "tonumber",
+            Builtin::ToString => "tostring",
+            Builtin::Select => "select",
+            Builtin::Assert => "assert",
+            Builtin::Warn => "warn",
+            Builtin::PackageSearchPath => "searchpath",
+            Builtin::SearchPreload => "searcher_preload",
+            Builtin::SearchLua => "searcher_Lua",
+            Builtin::StringByte => "byte",
+            Builtin::StringChar => "char",
+            Builtin::StringFind => "find",
+            Builtin::StringFormat => "format",
+            Builtin::StringGmatchStep => "gmatch",
+            Builtin::StringGsub => "gsub",
+            Builtin::StringLen => "len",
+            Builtin::StringLower => "lower",
+            Builtin::StringMatch => "match",
+            Builtin::StringPack => "pack",
+            Builtin::StringPackSize => "packsize",
+            Builtin::StringRep => "rep",
+            Builtin::StringReverse => "reverse",
+            Builtin::StringSub => "sub",
+            Builtin::StringUnpack => "unpack",
+            Builtin::StringUpper => "upper",
+            Builtin::TableConcat => "concat",
+            Builtin::TableInsert => "insert",
+            Builtin::TableMove => "move",
+            Builtin::TablePack => "pack",
+            Builtin::TableRemove => "remove",
+            Builtin::TableSort => "sort",
+            Builtin::TableUnpack => "unpack",
+            Builtin::MathAbs => "abs",
+            Builtin::MathAcos => "acos",
+            Builtin::MathAsin => "asin",
+            Builtin::MathAtan => "atan",
+            Builtin::MathCeil => "ceil",
+            Builtin::MathCos => "cos",
+            Builtin::MathExp => "exp",
+            Builtin::MathFloor => "floor",
+            Builtin::MathFmod => "fmod",
+            Builtin::MathLog => "log",
+            Builtin::MathMax => "max",
+            Builtin::MathMin => "min",
+            Builtin::MathModf => "modf",
+            Builtin::MathSin => "sin",
+            Builtin::MathSqrt => "sqrt",
+            Builtin::MathTan => "tan",
+            Builtin::MathToInteger => "tointeger",
+            Builtin::MathType => "type",
+            Builtin::MathUlt => "ult",
+            Builtin::MathRandom => "random",
+            Builtin::MathRandomSeed => "randomseed",
+            Builtin::CoroutineCreate => "create",
+            Builtin::CoroutineResume => "resume",
+            Builtin::CoroutineYield => "yield",
+            Builtin::CoroutineStatus => "status",
+            Builtin::CoroutineRunning => "running",
+            Builtin::CoroutineIsYieldable => "isyieldable",
+            Builtin::CoroutineClose => "close",
+            Builtin::IoClose => "close",
+            Builtin::IoFlush => "flush",
+            Builtin::IoInput => "input",
+            Builtin::IoLines => "lines",
+            Builtin::IoLinesStep => "lines",
+            Builtin::IoOpen => "open",
+            Builtin::IoOutput => "output",
+            Builtin::IoPopen => "popen",
+            Builtin::IoRead => "read",
+            Builtin::IoType => "type",
+            Builtin::IoWrite => "write",
+            Builtin::FileClose => "close",
+            Builtin::FileFlush => "flush",
+            Builtin::FileLines => "lines",
+            Builtin::FileRead => "read",
+            Builtin::FileSeek => "seek",
+            Builtin::FileSetvbuf => "setvbuf",
+            Builtin::FileWrite => "write",
+            Builtin::FileGc => "__gc",
+            Builtin::FileToString => "__tostring",
+            Builtin::OsClock => "clock",
+            Builtin::OsDate => "date",
+            Builtin::OsDiffTime => "difftime",
+            Builtin::OsExecute => "execute",
+            Builtin::OsExit => "exit",
+            Builtin::OsGetEnv => "getenv",
+            Builtin::OsRemove => "remove",
+            Builtin::OsRename => "rename",
+            Builtin::OsTime => "time",
+            Builtin::OsTmpName => "tmpname",
+            Builtin::Utf8Char => "char",
+            Builtin::Utf8Codes => "codes",
+            Builtin::Utf8CodesStep => "codes",
+            Builtin::Utf8CodesStepLax => "codes",
+            Builtin::Utf8CodePoint => "codepoint",
+            Builtin::Utf8Len => "len",
+            Builtin::Utf8Offset => "offset",
+        }
+    }
+
+    pub fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        parameters: Vec<Value>,
+    ) -> Result<Vec<Value>, LuaError> {
         match self {
-            Builtin::Print => global::print(parameters),
+            Builtin::Error => global::error(interpreter, parameters),
+            // Calls go through the interpreter, which runs pcall itself
+            Builtin::PCall => interpreter.call(Value::Builtin(Builtin::PCall), parameters),
+            Builtin::SetMetatable => global::setmetatable(interpreter, parameters),
+            Builtin::GetMetatable => global::getmetatable(interpreter, parameters),
+            Builtin::Next => global::next(interpreter, parameters),
+            Builtin::Pairs => global::pairs(interpreter, parameters),
+            Builtin::RawEqual => global::rawequal(interpreter, parameters),
+            Builtin::RawGet => global::rawget(interpreter, parameters),
+            Builtin::RawLen => global::rawlen(interpreter, parameters),
+            Builtin::RawSet => global::rawset(interpreter, parameters),
+            Builtin::CollectGarbage => global::collectgarbage(interpreter, parameters),
+            Builtin::Load => global::load(interpreter, parameters),
+            Builtin::LoadFile => global::loadfile(interpreter, parameters),
+            Builtin::DoFile => global::dofile(interpreter, parameters),
+            Builtin::Require => package::require(interpreter, parameters),
+            Builtin::Type => global::r#type(interpreter, parameters),
+            Builtin::ToNumber => global::tonumber(interpreter, parameters),
+            Builtin::ToString => global::tostring(interpreter, parameters),
+            Builtin::Select => global::select(interpreter, parameters),
+            Builtin::Assert => global::assert(interpreter, parameters),
+            Builtin::Warn => global::warn(interpreter, parameters),
+            Builtin::PackageSearchPath => package::searchpath(interpreter, parameters),
+            Builtin::SearchPreload => package::search_preload(interpreter, parameters),
+            Builtin::SearchLua => package::search_lua(interpreter, parameters),
+            Builtin::StringByte => string::byte(interpreter, parameters),
+            Builtin::StringChar => string::char(interpreter, parameters),
+            Builtin::StringFind => string::find(interpreter, parameters),
+            Builtin::StringFormat => format::format(interpreter, parameters),
+            Builtin::StringGmatchStep => string::gmatch_step(interpreter, parameters),
+            Builtin::StringGsub => string::gsub(interpreter, parameters),
+            Builtin::StringLen => string::len(interpreter, parameters),
+            Builtin::StringLower => string::lower(interpreter, parameters),
+            Builtin::StringMatch => string::r#match(interpreter, parameters),
+            Builtin::StringPack => pack::pack(interpreter, parameters),
+            Builtin::StringPackSize => pack::packsize(interpreter, parameters),
+            Builtin::StringRep => string::rep(interpreter, parameters),
+            Builtin::StringReverse => string::reverse(interpreter, parameters),
+            Builtin::StringSub => string::sub(interpreter, parameters),
+            Builtin::StringUnpack => pack::unpack(interpreter, parameters),
+            Builtin::StringUpper => string::upper(interpreter, parameters),
+            Builtin::TableConcat => table::concat(interpreter, parameters),
+            Builtin::TableInsert => table::insert(interpreter, parameters),
+            Builtin::TableMove => table::r#move(interpreter, parameters),
+            Builtin::TablePack => table::pack(interpreter, parameters),
+            Builtin::TableRemove => table::remove(interpreter, parameters),
+            Builtin::TableSort => table::sort(interpreter, parameters),
+            Builtin::TableUnpack => table::unpack(interpreter, parameters),
+            Builtin::MathAbs => math::abs(interpreter, parameters),
+            Builtin::MathAcos => math::acos(interpreter, parameters),
+            Builtin::MathAsin => math::asin(interpreter, parameters),
+            Builtin::MathAtan => math::atan(interpreter, parameters),
+            Builtin::MathCeil => math::ceil(interpreter, parameters),
+            Builtin::MathCos => math::cos(interpreter, parameters),
+            Builtin::MathExp => math::exp(interpreter, parameters),
+            Builtin::MathFloor => math::floor(interpreter, parameters),
+            Builtin::MathFmod => math::fmod(interpreter, parameters),
+            Builtin::MathLog => math::log(interpreter, parameters),
+            Builtin::MathMax => math::max(interpreter, parameters),
+            Builtin::MathMin => math::min(interpreter, parameters),
+            Builtin::MathModf => math::modf(interpreter, parameters),
+            Builtin::MathSin => math::sin(interpreter, parameters),
+            Builtin::MathSqrt => math::sqrt(interpreter, parameters),
+            Builtin::MathTan => math::tan(interpreter, parameters),
+            Builtin::MathToInteger => math::tointeger(interpreter, parameters),
+            Builtin::MathType => math::r#type(interpreter, parameters),
+            Builtin::MathUlt => math::ult(interpreter, parameters),
+            Builtin::MathRandom => random::random(interpreter, parameters),
+            Builtin::MathRandomSeed => random::randomseed(interpreter, parameters),
+            Builtin::CoroutineCreate => coroutine::create(interpreter, parameters),
+            Builtin::CoroutineResume => coroutine::resume(interpreter, parameters),
+            Builtin::CoroutineYield => coroutine::r#yield(interpreter, parameters),
+            Builtin::CoroutineStatus => coroutine::status(interpreter, parameters),
+            Builtin::CoroutineRunning => coroutine::running(interpreter, parameters),
+            Builtin::CoroutineIsYieldable => coroutine::isyieldable(interpreter, parameters),
+            Builtin::CoroutineClose => coroutine::close(interpreter, parameters),
+            Builtin::IoClose => io::close(interpreter, parameters),
+            Builtin::IoFlush => io::flush(interpreter, parameters),
+            Builtin::IoInput => io::input(interpreter, parameters),
+            Builtin::IoLines => io::lines(interpreter, parameters),
+            Builtin::IoLinesStep => io::lines_step(interpreter, parameters),
+            Builtin::IoOpen => io::open_file(interpreter, parameters),
+            Builtin::IoOutput => io::output(interpreter, parameters),
+            Builtin::IoPopen => io::popen(interpreter, parameters),
+            Builtin::IoRead => io::read_default(interpreter, parameters),
+            Builtin::IoType => io::r#type(interpreter, parameters),
+            Builtin::IoWrite => io::write_default(interpreter, parameters),
+            Builtin::FileClose => io::file_close(interpreter, parameters),
+            Builtin::FileFlush => io::file_flush(interpreter, parameters),
+            Builtin::FileLines => io::file_lines(interpreter, parameters),
+            Builtin::FileRead => io::file_read(interpreter, parameters),
+            Builtin::FileSeek => io::file_seek(interpreter, parameters),
+            Builtin::FileSetvbuf => io::file_setvbuf(interpreter, parameters),
+            Builtin::FileWrite => io::file_write(interpreter, parameters),
+            Builtin::FileGc => io::file_gc(interpreter, parameters),
+            Builtin::FileToString => io::file_tostring(interpreter, parameters),
+            Builtin::OsClock => os::clock(interpreter, parameters),
+            Builtin::OsDate => os::date(interpreter, parameters),
+            Builtin::OsDiffTime => os::difftime(interpreter, parameters),
+            Builtin::OsExecute => os::execute(interpreter, parameters),
+            Builtin::OsExit => os::exit(interpreter, parameters),
+            Builtin::OsGetEnv => os::getenv(interpreter, parameters),
+            Builtin::OsRemove => os::remove(interpreter, parameters),
+            Builtin::OsRename => os::rename(interpreter, parameters),
+            Builtin::OsTime => os::time(interpreter, parameters),
+            Builtin::OsTmpName => os::tmpname(interpreter, parameters),
+            Builtin::Utf8Char => utf8::char(interpreter, parameters),
+            Builtin::Utf8Codes => utf8::codes(interpreter, parameters),
+            Builtin::Utf8CodesStep => utf8::codes_step(interpreter, parameters),
+            Builtin::Utf8CodesStepLax => utf8::codes_step_lax(interpreter, parameters),
+            Builtin::Utf8CodePoint => utf8::codepoint(interpreter, parameters),
+            Builtin::Utf8Len => utf8::len(interpreter, parameters),
+            Builtin::Utf8Offset => utf8::offset(interpreter, parameters),
         }
     }
 }
 
+/// The error of a builtin called with a bad argument, counting from one.
+pub fn argument_error(position: usize, name: &str, message: &str) -> LuaError {
+    LuaError::new(format!("bad argument #{position} to '{name}' ({message})"))
+}
+
+pub(crate) fn argument(parameters: &[Value], position: usize) -> Value {
+    parameters.get(position - 1).cloned().unwrap_or(Value::Nil)
+}
+
+/// Checks that an argument is present, even if `nil`.
+pub(crate) fn check_any(parameters: &[Value], position: usize, name: &str) -> Result<(), LuaError> {
+    if position > parameters.len() {
+        return Err(argument_error(position, name, "value expected"));
+    }
+    Ok(())
+}
+
+fn check_table(parameters: &[Value], position: usize, name: &str) -> Result<TableRef, LuaError> {
+    match argument(parameters, position) {
+        Value::Table(table) => Ok(table),
+        value => Err(argument_error(
+            position,
+            name,
+            &format!(
+                "table expected, got {}",
+                type_name(parameters, position, &value)
+            ),
+        )),
+    }
+}
+
+/// An optional integer argument, `default` when missing or `nil`.
+pub(crate) fn optional_integer(
+    parameters: &[Value],
+    position: usize,
+    name: &str,
+    default: i64,
+) -> Result<i64, LuaError> {
+    if argument(parameters, position).is_nil() {
+        return Ok(default);
+    }
+    check_integer(parameters, position, name)
+}
+
+/// An integer argument. Floats and strings with an exact integer value are
+/// converted.
+pub(crate) fn check_integer(
+    parameters: &[Value],
+    position: usize,
+    name: &str,
+) -> Result<i64, LuaError> {
+    let value = argument(parameters, position);
+    if let Some(n) = value.to_integer() {
+        return Ok(n);
+    }
+    let message = if value.to_number().is_some() {
+        "number has no integer representation".to_string()
+    } else {
+        format!(
+            "number expected, got {}",
+            type_name(parameters, position, &value)
+        )
+    };
+    Err(argument_error(position, name, &message))
+}
+
+/// A number argument as a float. Strings are converted to numbers.
+pub(crate) fn check_number(
+    parameters: &[Value],
+    position: usize,
+    name: &str,
+) -> Result<f64, LuaError> {
+    let value = argument(parameters, position);
+    value.to_float().ok_or_else(|| {
+        argument_error(
+            position,
+            name,
+            &format!(
+                "number expected, got {}",
+                type_name(parameters, position, &value)
+            ),
+        )
+    })
+}
+
+/// A string argument. Numbers are converted to strings.
+pub(crate) fn check_string(
+    parameters: &[Value],
+    position: usize,
+    name: &str,
+) -> Result<LuaString, LuaError> {
+    let value = argument(parameters, position);
+    match value.to_lua_string() {
+        Some(s) => Ok(s),
+        None => Err(argument_error(
+            position,
+            name,
+            &format!(
+                "string expected, got {}",
+                type_name(parameters, position, &value)
+            ),
+        )),
+    }
+}
+
+/// An optional string argument as text, `default` when missing or `nil`.
+/// Numbers are converted to strings.
+pub(crate) fn optional_string(
+    parameters: &[Value],
+    position: usize,
+    name: &str,
+    default: &str,
+) -> Result<String, LuaError> {
+    match argument(parameters, position) {
+        Value::Nil => Ok(default.to_string()),
+        value @ (Value::String(_) | Value::Integer(_) | Value::Float(_)) => Ok(value.to_string()),
+        value => Err(argument_error(
+            position,
+            name,
+            &format!(
+                "string expected, got {}",
+                type_name(parameters, position, &value)
+            ),
+        )),
+    }
+}
+
+/// The message of an error from the operating system, without its code.
+pub(crate) fn os_error_message(error: &std::io::Error) -> String {
+    let message = error.to_string();
+    match message.find(" (os error") {
+        Some(end) => message[..end].to_string(),
+        None => message,
+    }
+}
+
+/// The results of a failed operation on files: `nil`, the message, prefixed
+/// with the file name if given, and the error number.
+pub(crate) fn file_error(error: &std::io::Error, filename: Option<&str>) -> Vec<Value> {
+    let message = os_error_message(error);
+    let message = match filename {
+        Some(filename) => format!("{filename}: {message}"),
+        None => message,
+    };
+    let errno = error.raw_os_error().unwrap_or(0);
+    vec![
+        Value::Nil,
+        Value::String(message.into()),
+        Value::Integer(errno as i64),
+    ]
+}
+
+/// The results of a finished command, as `os.execute` and closing a file of
+/// `io.popen` give them: whether it succeeded, how it ended and its exit code
+/// or signal.
+pub(crate) fn exec_result(status: std::process::ExitStatus) -> Vec<Value> {
+    use std::os::unix::process::ExitStatusExt;
+
+    let (kind, code) = match (status.code(), status.signal()) {
+        (Some(code), _) => ("exit", code),
+        (None, Some(signal)) => ("signal", signal),
+        (None, None) => ("exit", -1),
+    };
+    let success = if kind == "exit" && code == 0 {
+        Value::True
+    } else {
+        Value::Nil
+    };
+    vec![
+        success,
+        Value::String(kind.into()),
+        Value::Integer(code as i64),
+    ]
+}
+
+/// Converts any value to a string, using its `__tostring` metamethod or the
+/// `__name` field of its metatable if it has them.
+pub(crate) fn tostring(
+    interpreter: &mut Interpreter,
+    value: &Value,
+) -> Result<LuaString, LuaError> {
+    let handler = interpreter.metamethod(value, "__tostring");
+    if !handler.is_nil() {
+        let values = interpreter.call(handler, vec![value.clone()])?;
+        return values
+            .into_iter()
+            .next()
+            .and_then(|s| s.to_lua_string())
+            .ok_or_else(|| LuaError::new("'__tostring' must return a string"));
+    }
+    if let Some(s) = value.to_lua_string() {
+        return Ok(s);
+    }
+    let s = value.to_string();
+    match interpreter.metamethod(value, "__name") {
+        Value::String(name) if matches!(value, Value::Table(_) | Value::Userdata(_)) => {
+            let address = s.split_once(": ").map_or("", |(_, address)| address);
+            Ok(format!("{name}: {address}").into())
+        }
+        _ => Ok(s.into()),
+    }
+}
+
+/// The type of an argument for error messages, telling missing arguments apart.
+pub(crate) fn type_name(parameters: &[Value], position: usize, value: &Value) -> &'static str {
+    if position > parameters.len() {
+        "no value"
+    } else {
+        value.type_name()
+    }
+}
+
 pub mod global {
-    use crate::interpreter::value::Value;
+    use std::io::{stderr, stdin, stdout, Read, Write};
+
+    use crate::{
+        interpreter::{check_key, value::Value, Interpreter, LuaError},
+        parser::source_text,
+    };
+
+    use super::{
+        argument, argument_error, check_any, check_integer, check_string, check_table,
+        optional_integer, optional_string, os_error_message, type_name, Builtin,
+    };
 
-    pub fn print(parameters: Vec<Value>) -> Value {
-        for p in parameters {
-            print!("{}\t", p.to_string());
+    /// The registry key of whether warnings are shown, which they are not
+    /// until turned on with `@on`.
+    const WARNINGS: &str = "_WARNINGS";
+
+    pub fn print(
+        interpreter: &mut Interpreter,
+        parameters: Vec<Value>,
+    ) -> Result<Vec<Value>, LuaError> {
+        let mut line = vec![];
+        for (i, value) in parameters.iter().enumerate() {
+            if i > 0 {
+                line.push(b'\t');
+            }
+            // Strings are written as they are, even when not valid UTF-8
+            line.extend_from_slice(&super::tostring(interpreter, value)?);
         }
-        println!();
-        Value::Nil
+        line.push(b'\n');
+        stdout()
+            .lock()
+            .write_all(&line)
+            .map_err(|error| LuaError::new(os_error_message(&error)))?;
+        Ok(vec![])
+    }
+
+    pub fn r#type(
+        _interpreter: &mut Interpreter,
+        parameters: Vec<Value>,
+    ) -> Result<Vec<Value>, LuaError> {
+        check_any(&parameters, 1, "type")?;
+        let name = argument(&parameters, 1).type_name();
+        Ok(vec![Value::String(name.into())])
+    }
+
+    pub fn tonumber(
+        _interpreter: &mut Interpreter,
+        parameters: Vec<Value>,
+    ) -> Result<Vec<Value>, LuaError> {
+        let value = argument(&parameters, 1);
+        if argument(&parameters, 2).is_nil() {
+            check_any(&parameters, 1, "tonumber")?;
+            return Ok(vec![value.to_number().unwrap_or(Value::Nil)]);
+        }
+        let base = check_integer(&parameters, 2, "tonumber")?;
+        let Value::String(s) = value else {
+            let message = format!("string expected, got {}", type_name(&parameters, 1, &value));
+            return Err(argument_error(1, "tonumber", &message));
+        };
+        if !(2..=36).contains(&base) {
+            return Err(argument_error(2, "tonumber", "base out of range"));
+        }
+        Ok(vec![
+            parse_integer(&s, base).map_or(Value::Nil, Value::Integer)
+        ])
+    }
+
+    /// Reads an integer numeral in `base`, with optional surrounding
+    /// whitespace and sign. Overflows wrap around, as in Lua.
+    fn parse_integer(s: &[u8], base: i64) -> Option<i64> {
+        let is_space = |c: &u8| b" \t\n\r\x0b\x0c".contains(c);
+        let start = s.iter().position(|c| !is_space(c)).unwrap_or(s.len());
+        let end = s
+            .iter()
+            .rposition(|c| !is_space(c))
+            .map_or(start, |i| i + 1);
+        let s = &s[start..end];
+        let (negative, digits) = match s.split_first() {
+            Some((b'-', rest)) => (true, rest),
+            Some((b'+', rest)) => (false, rest),
+            _ => (false, s),
+        };
+        if digits.is_empty() {
+            return None;
+        }
+        let mut n: i64 = 0;
+        for &c in digits {
+            let digit = (c as char).to_digit(36).filter(|&d| (d as i64) < base)?;
+            n = n.wrapping_mul(base).wrapping_add(digit as i64);
+        }
+        Some(if negative { n.wrapping_neg() } else { n })
+    }
+
+    pub fn tostring(
+        interpreter: &mut Interpreter,
+        parameters: Vec<Value>,
+    ) -> Result<Vec<Value>, LuaError> {
+        check_any(&parameters, 1, "tostring")?;
+        let s = super::tostring(interpreter, &argument(&parameters, 1))?;
+        Ok(vec![Value::String(s)])
+    }
+
+    pub fn select(
+        _interpreter: &mut Interpreter,
+        mut parameters: Vec<Value>,
+    ) -> Result<Vec<Value>, LuaError> {
+        let count = parameters.len() as i64;
+        if let Value::String(s) = argument(&parameters, 1) {
+            if s.starts_with(b"#") {
+                return Ok(vec![Value::Integer(count - 1)]);
+            }
+        }
+        let mut n = check_integer(&parameters, 1, "select")?;
+        if n < 0 {
+            n += count;
+        } else if n > count {
+            n = count;
+        }
+        if n < 1 {
+            return Err(argument_error(1, "select", "index out of range"));
+        }
+        Ok(parameters.split_off(n as usize))
+    }
+
+    pub fn assert(
+        _interpreter: &mut Interpreter,
+        mut parameters: Vec<Value>,
+    ) -> Result<Vec<Value>, LuaError> {
+        if argument(&parameters, 1).is_truthy() {
+            return Ok(parameters);
+        }
+        check_any(&parameters, 1, "assert")?;
+        // A message given as `nil` is kept, only a missing one has a default
+        let message = match parameters.len() {
+            1 => Value::String("assertion failed!".into()),
+            _ => parameters.swap_remove(1),
+        };
+        Err(LuaError::from(message))
+    }
+
+    pub fn warn(
+        interpreter: &mut Interpreter,
+        parameters: Vec<Value>,
+    ) -> Result<Vec<Value>, LuaError> {
+        let mut message = check_string(&parameters, 1, "warn")?.to_vec();
+        for position in 2..=parameters.len() {
+            message.extend_from_slice(&check_string(&parameters, position, "warn")?);
+        }
+        let registry = interpreter.registry();
+        // Control messages are made of a single piece
+        if parameters.len() == 1 && message.starts_with(b"@") {
+            match &message[1..] {
+                b"on" => registry.borrow_mut().insert_str(WARNINGS, Value::True),
+                b"off" => registry.borrow_mut().insert_str(WARNINGS, Value::Nil),
+                _ => {}
+            }
+            return Ok(vec![]);
+        }
+        if registry.borrow().get_str(WARNINGS).is_truthy() {
+            let mut output = stderr().lock();
+            output
+                .write_all(b"Lua warning: ")
+                .and_then(|_| output.write_all(&message))
+                .and_then(|_| output.write_all(b"\n"))
+                .map_err(|error| LuaError::new(os_error_message(&error)))?;
+        }
+        Ok(vec![])
+    }
+
+    pub fn error(
+        _interpreter: &mut Interpreter,
+        parameters: Vec<Value>,
+    ) -> Result<Vec<Value>, LuaError> {
+        Err(LuaError::from(argument(&parameters, 1)))
+    }
+
+    pub fn setmetatable(
+        interpreter: &mut Interpreter,
+        parameters: Vec<Value>,
+    ) -> Result<Vec<Value>, LuaError> {
+        let table = check_table(&parameters, 1, "setmetatable")?;
+        let metatable = match argument(&parameters, 2) {
+            Value::Nil if parameters.len() >= 2 => None,
+            Value::Table(metatable) => Some(metatable),
+            value => {
+                return Err(argument_error(
+                    2,
+                    "setmetatable",
+                    &format!(
+                        "nil or table expected, got {}",
+                        type_name(&parameters, 2, &value)
+                    ),
+                ))
+            }
+        };
+        if let Some(current) = table.borrow().metatable() {
+            if !current.borrow().get_str("__metatable").is_nil() {
+                return Err(LuaError::new("cannot change a protected metatable"));
+            }
+        }
+        interpreter.set_metatable(&table, metatable);
+        Ok(vec![Value::Table(table)])
+    }
+
+    pub fn getmetatable(
+        interpreter: &mut Interpreter,
+        parameters: Vec<Value>,
+    ) -> Result<Vec<Value>, LuaError> {
+        let Some(metatable) = interpreter.metatable(&argument(&parameters, 1)) else {
+            return Ok(vec![Value::Nil]);
+        };
+        let protected = metatable.borrow().get_str("__metatable");
+        if !protected.is_nil() {
+            return Ok(vec![protected]);
+        }
+        Ok(vec![Value::Table(metatable)])
+    }
+
+    pub fn next(
+        _interpreter: &mut Interpreter,
+        parameters: Vec<Value>,
+    ) -> Result<Vec<Value>, LuaError> {
+        let table = check_table(&parameters, 1, "next")?;
+        let entry = table.borrow().next(&argument(&parameters, 2))?;
+        match entry {
+            Some((key, value)) => Ok(vec![key, value]),
+            None => Ok(vec![Value::Nil]),
+        }
+    }
+
+    pub fn pairs(
+        interpreter: &mut Interpreter,
+        parameters: Vec<Value>,
+    ) -> Result<Vec<Value>, LuaError> {
+        check_any(&parameters, 1, "pairs")?;
+        let value = argument(&parameters, 1);
+        let handler = interpreter.metamethod(&value, "__pairs");
+        if handler.is_nil() {
+            return Ok(vec![Value::Builtin(Builtin::Next), value, Value::Nil]);
+        }
+        let mut results = interpreter.call(handler, vec![value])?;
+        results.resize(3, Value::Nil);
+        Ok(results)
+    }
+
+    pub fn rawequal(
+        _interpreter: &mut Interpreter,
+        parameters: Vec<Value>,
+    ) -> Result<Vec<Value>, LuaError> {
+        check_any(&parameters, 1, "rawequal")?;
+        check_any(&parameters, 2, "rawequal")?;
+        let equal = argument(&parameters, 1) == argument(&parameters, 2);
+        Ok(vec![Value::from(equal)])
+    }
+
+    pub fn rawget(
+        _interpreter: &mut Interpreter,
+        parameters: Vec<Value>,
+    ) -> Result<Vec<Value>, LuaError> {
+        let table = check_table(&parameters, 1, "rawget")?;
+        check_any(&parameters, 2, "rawget")?;
+        let value = table.borrow().get(&argument(&parameters, 2));
+        Ok(vec![value])
+    }
+
+    pub fn rawlen(
+        _interpreter: &mut Interpreter,
+        parameters: Vec<Value>,
+    ) -> Result<Vec<Value>, LuaError> {
+        match argument(&parameters, 1) {
+            Value::Table(table) => Ok(vec![Value::Integer(table.borrow().length())]),
+            Value::String(s) => Ok(vec![Value::Integer(s.len() as i64)]),
+            _ => Err(argument_error(1, "rawlen", "table or string expected")),
+        }
+    }
+
+    pub fn rawset(
+        _interpreter: &mut Interpreter,
+        parameters: Vec<Value>,
+    ) -> Result<Vec<Value>, LuaError> {
+        let table = check_table(&parameters, 1, "rawset")?;
+        check_any(&parameters, 2, "rawset")?;
+        check_any(&parameters, 3, "rawset")?;
+        let key = argument(&parameters, 2);
+        check_key(&key)?;
+        table.borrow_mut().insert(key, argument(&parameters, 3));
+        Ok(vec![Value::Table(table)])
+    }
+
+    pub fn collectgarbage(
+        interpreter: &mut Interpreter,
+        parameters: Vec<Value>,
+    ) -> Result<Vec<Value>, LuaError> {
+        let option = match argument(&parameters, 1) {
+            Value::Nil => "collect".to_string(),
+            value @ (Value::String(_) | Value::Integer(_) | Value::Float(_)) => value.to_string(),
+            value => {
+                return Err(argument_error(
+                    1,
+                    "collectgarbage",
+                    &format!("string expected, got {}", value.type_name()),
+                ))
+            }
+        };
+        let parameter = |position| {
+            optional_integer(&parameters, position, "collectgarbage", 0).map(|n| n.max(0) as usize)
+        };
+        let value = match option.as_str() {
+            "collect" => {
+                interpreter.collect_garbage();
+                Value::Integer(0)
+            }
+            "count" => Value::Float(interpreter.heap().memory() as f64 / 1024.0),
+            "step" => {
+                // Collections are never partial, any step completes a cycle
+                parameter(2)?;
+                interpreter.collect_garbage();
+                Value::True
+            }
+            "stop" | "restart" => {
+                interpreter.heap().set_running(option == "restart");
+                Value::Integer(0)
+            }
+            "isrunning" => Value::from(interpreter.heap().is_running()),
+            "incremental" => {
+                let (pause, multiplier, size) = (parameter(2)?, parameter(3)?, parameter(4)?);
+                let previous = interpreter.heap().set_incremental(pause, multiplier, size);
+                Value::String(previous.name().into())
+            }
+            "generational" => {
+                let (minor, major) = (parameter(2)?, parameter(3)?);
+                let previous = interpreter.heap().set_generational(minor, major);
+                Value::String(previous.name().into())
+            }
+            _ => {
+                return Err(argument_error(
+                    1,
+                    "collectgarbage",
+                    &format!("invalid option '{option}'"),
+                ))
+            }
+        };
+        Ok(vec![value])
+    }
+
+    pub fn load(
+        interpreter: &mut Interpreter,
+        parameters: Vec<Value>,
+    ) -> Result<Vec<Value>, LuaError> {
+        let (source, default_name) = match argument(&parameters, 1) {
+            Value::String(source) => (source_text(&source).into_owned(), source.to_string()),
+            reader @ (Value::Lambda(_) | Value::Builtin(_) | Value::Native(_)) => {
+                let mut source = vec![];
+                loop {
+                    let piece = match interpreter.call(reader.clone(), vec![]) {
+                        Ok(values) => values.into_iter().next().unwrap_or(Value::Nil),
+                        Err(error) if interpreter.exit_status().is_some() => return Err(error),
+                        Err(error) => return Ok(vec![Value::Nil, error.value]),
+                    };
+                    match piece {
+                        Value::Nil => break,
+                        Value::String(piece) if piece.is_empty() => break,
+                        Value::String(piece) => source.extend_from_slice(&piece),
+                        _ => {
+                            let message = "reader function must return a string";
+                            return Ok(vec![Value::Nil, Value::String(message.into())]);
+                        }
+                    }
+                }
+                (source_text(&source).into_owned(), "=(load)".to_string())
+            }
+            value => {
+                return Err(argument_error(
+                    1,
+                    "load",
+                    &format!(
+                        "function expected, got {}",
+                        type_name(&parameters, 1, &value)
+                    ),
+                ))
+            }
+        };
+        let name = optional_string(&parameters, 2, "load", &default_name)?;
+        let mode = optional_string(&parameters, 3, "load", "bt")?;
+        let environment = (parameters.len() >= 4).then(|| argument(&parameters, 4));
+        Ok(load_result(load_chunk(
+            interpreter,
+            &source,
+            &name,
+            &mode,
+            environment,
+        )))
+    }
+
+    /// The function of a loaded chunk, or `nil` and the error message.
+    fn load_result(result: Result<Value, LuaError>) -> Vec<Value> {
+        match result {
+            Ok(function) => vec![function],
+            Err(error) => vec![Value::Nil, error.value],
+        }
+    }
+
+    /// Compiles a chunk if `mode` allows its kind.
+    pub(super) fn load_chunk(
+        interpreter: &mut Interpreter,
+        source: &str,
+        name: &str,
+        mode: &str,
+        environment: Option<Value>,
+    ) -> Result<Value, LuaError> {
+        let (kind, allowed) = if source.starts_with('\x1b') {
+            ("binary", mode.contains('b'))
+        } else {
+            ("text", mode.contains('t'))
+        };
+        if !allowed {
+            Err(LuaError::new(format!(
+                "attempt to load a {kind} chunk (mode is '{mode}')"
+            )))
+        } else if kind == "binary" {
+            Err(LuaError::new("binary chunks are not supported"))
+        } else {
+            interpreter.load(source, name, environment)
+        }
+    }
+
+    /// Reads the source of a file, or of the standard input without a file
+    /// name, skipping a first line starting with `#`. Gives the chunk name.
+    pub(super) fn read_chunk(filename: Option<&str>) -> Result<(String, String), String> {
+        let (mut source, name) = match filename {
+            Some(filename) => match std::fs::read(filename) {
+                Ok(bytes) => (source_text(&bytes).into_owned(), format!("@{filename}")),
+                Err(error) => {
+                    let message = os_error_message(&error);
+                    return Err(format!("cannot open {filename} ({message})"));
+                }
+            },
+            None => {
+                let mut source = vec![];
+                if let Err(error) = stdin().read_to_end(&mut source) {
+                    let message = os_error_message(&error);
+                    return Err(format!("cannot read stdin ({message})"));
+                }
+                (source_text(&source).into_owned(), "=stdin".to_string())
+            }
+        };
+        if source.starts_with('#') {
+            // The newline stays so lines are counted from the file's start
+            let end = source.find('\n').unwrap_or(source.len());
+            source.replace_range(..end, "");
+        }
+        Ok((source, name))
+    }
+
+    fn optional_filename(parameters: &[Value], name: &str) -> Result<Option<String>, LuaError> {
+        match argument(parameters, 1) {
+            Value::Nil => Ok(None),
+            _ => optional_string(parameters, 1, name, "").map(Some),
+        }
+    }
+
+    pub fn loadfile(
+        interpreter: &mut Interpreter,
+        parameters: Vec<Value>,
+    ) -> Result<Vec<Value>, LuaError> {
+        let filename = optional_filename(&parameters, "loadfile")?;
+        let mode = optional_string(&parameters, 2, "loadfile", "bt")?;
+        let environment = (parameters.len() >= 3).then(|| argument(&parameters, 3));
+        match read_chunk(filename.as_deref()) {
+            Ok((source, name)) => Ok(load_result(load_chunk(
+                interpreter,
+                &source,
+                &name,
+                &mode,
+                environment,
+            ))),
+            Err(message) => Ok(vec![Value::Nil, Value::String(message.into())]),
+        }
+    }
+
+    pub fn dofile(
+        interpreter: &mut Interpreter,
+        parameters: Vec<Value>,
+    ) -> Result<Vec<Value>, LuaError> {
+        let filename = optional_filename(&parameters, "dofile")?;
+        let (source, name) = read_chunk(filename.as_deref()).map_err(LuaError::new)?;
+        let function = interpreter.load(&source, &name, None)?;
+        interpreter.call(function, vec![])
     }
 }
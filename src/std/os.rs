@@ -0,0 +1,332 @@
+//! The `os` library. Dates go through the C library, as in the reference
+//! interpreter, so time zones and `strftime` conversions match it.
+
+use std::{
+    ffi::{CString, OsStr},
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    os::unix::ffi::OsStrExt,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::interpreter::{
+    value::{Table, Value},
+    Interpreter, LuaError,
+};
+
+use super::{
+    argument, argument_error, check_integer, check_string, check_table, exec_result, file_error,
+    optional_integer,
+};
+
+/// The conversions `os.date` accepts after a `%`, those of C99. The ones
+/// after `||` take two characters.
+const CONVERSIONS: &str =
+    "aAbBcCdDeFgGhHIjmMnprRStTuUVwWxXyYzZ%||EcECExEXEyEYOdOeOHOIOmOMOSOuOUOVOwOWOy";
+/// Size of the buffer of a single conversion of `os.date`.
+const CONVERSION_SIZE: usize = 250;
+/// Attempts of `os.tmpname` at finding an unused name.
+const TEMPORARY_ATTEMPTS: usize = 100;
+
+/// Broken-down time of `time`, in UTC or in the local time zone.
+fn broken_down(time: i64, utc: bool) -> Option<libc::tm> {
+    // SAFETY: `tm` is plain data, and both functions only write to it
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    let time = time as libc::time_t;
+    let result = unsafe {
+        if utc {
+            libc::gmtime_r(&time, &mut tm)
+        } else {
+            libc::localtime_r(&time, &mut tm)
+        }
+    };
+    (!result.is_null()).then_some(tm)
+}
+
+/// Sets the fields of a date table from a broken-down time.
+fn set_fields(interpreter: &mut Interpreter, table: &Value, tm: &libc::tm) -> Result<(), LuaError> {
+    let fields = [
+        ("year", tm.tm_year as i64 + 1900),
+        ("month", tm.tm_mon as i64 + 1),
+        ("day", tm.tm_mday as i64),
+        ("hour", tm.tm_hour as i64),
+        ("min", tm.tm_min as i64),
+        ("sec", tm.tm_sec as i64),
+        ("yday", tm.tm_yday as i64 + 1),
+        ("wday", tm.tm_wday as i64 + 1),
+    ];
+    for (key, value) in fields {
+        interpreter.set_index(
+            table.clone(),
+            Value::String(key.into()),
+            Value::Integer(value),
+        )?;
+    }
+    if tm.tm_isdst >= 0 {
+        let isdst = Value::from(tm.tm_isdst > 0);
+        interpreter.set_index(table.clone(), Value::String("isdst".into()), isdst)?;
+    }
+    Ok(())
+}
+
+/// A field of a date table, less `delta`, or `default` when absent. Fields
+/// without a default are required.
+fn get_field(
+    interpreter: &mut Interpreter,
+    table: &Value,
+    key: &str,
+    default: Option<i32>,
+    delta: i64,
+) -> Result<i32, LuaError> {
+    let value = interpreter.index(table.clone(), Value::String(key.into()))?;
+    match value.to_integer() {
+        Some(n) => n
+            .checked_sub(delta)
+            .and_then(|n| i32::try_from(n).ok())
+            .ok_or_else(|| LuaError::new(format!("field '{key}' is out-of-bound"))),
+        None if !value.is_nil() => Err(LuaError::new(format!("field '{key}' is not an integer"))),
+        None => {
+            default.ok_or_else(|| LuaError::new(format!("field '{key}' missing in date table")))
+        }
+    }
+}
+
+/// Writes the result of the conversion `conversion` of `strftime`.
+fn strftime(result: &mut Vec<u8>, conversion: &[u8], tm: &libc::tm) {
+    let mut format = b"%".to_vec();
+    format.extend_from_slice(conversion);
+    let format = CString::new(format).expect("Conversions have no NUL");
+    let mut buffer = [0u8; CONVERSION_SIZE];
+    // SAFETY: the buffer is as long as the size given, and the format is
+    // NUL-terminated
+    let length = unsafe {
+        libc::strftime(
+            buffer.as_mut_ptr() as *mut libc::c_char,
+            buffer.len(),
+            format.as_ptr(),
+            tm,
+        )
+    };
+    result.extend_from_slice(&buffer[..length]);
+}
+
+/// The length of the conversion at the start of `s`, if it is a valid one.
+fn conversion_length(s: &[u8]) -> Option<usize> {
+    let (single, double) = CONVERSIONS.split_once("||").unwrap();
+    if s.first().is_some_and(|c| single.as_bytes().contains(c)) {
+        return Some(1);
+    }
+    let double = double.as_bytes();
+    (s.len() >= 2 && double.chunks(2).any(|option| option == &s[..2])).then_some(2)
+}
+
+pub fn clock(
+    _interpreter: &mut Interpreter,
+    _parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    // SAFETY: `timespec` is plain data, which the call fills
+    let mut time: libc::timespec = unsafe { std::mem::zeroed() };
+    unsafe { libc::clock_gettime(libc::CLOCK_PROCESS_CPUTIME_ID, &mut time) };
+    let seconds = time.tv_sec as f64 + time.tv_nsec as f64 / 1e9;
+    Ok(vec![Value::Float(seconds)])
+}
+
+pub fn date(interpreter: &mut Interpreter, parameters: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let format = match argument(&parameters, 1) {
+        Value::Nil => "%c".into(),
+        _ => check_string(&parameters, 1, "date")?,
+    };
+    let time = match argument(&parameters, 2) {
+        Value::Nil => now(),
+        _ => check_integer(&parameters, 2, "date")?,
+    };
+    let (format, utc) = match format.strip_prefix(b"!") {
+        Some(format) => (format, true),
+        None => (&format[..], false),
+    };
+    let Some(tm) = broken_down(time, utc) else {
+        return Err(LuaError::new(
+            "date result cannot be represented in this installation",
+        ));
+    };
+    if format == b"*t" {
+        let table = interpreter.new_table(Table::new());
+        set_fields(interpreter, &table, &tm)?;
+        return Ok(vec![table]);
+    }
+    let mut result = vec![];
+    let mut i = 0;
+    while i < format.len() {
+        if format[i] != b'%' {
+            result.push(format[i]);
+            i += 1;
+            continue;
+        }
+        let rest = &format[i + 1..];
+        let Some(length) = conversion_length(rest) else {
+            let message = format!(
+                "invalid conversion specifier '%{}'",
+                String::from_utf8_lossy(rest)
+            );
+            return Err(argument_error(1, "date", &message));
+        };
+        strftime(&mut result, &rest[..length], &tm);
+        i += 1 + length;
+    }
+    Ok(vec![Value::String(result.into())])
+}
+
+pub fn difftime(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let t1 = check_integer(&parameters, 1, "difftime")?;
+    let t2 = optional_integer(&parameters, 2, "difftime", 0)?;
+    Ok(vec![Value::Float(t1 as f64 - t2 as f64)])
+}
+
+/// `os.execute`, running a command through the shell. Without a command, it
+/// tells whether there is a shell.
+pub fn execute(
+    interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    if argument(&parameters, 1).is_nil() {
+        return Ok(vec![Value::True]);
+    }
+    let command = check_string(&parameters, 1, "execute")?;
+    // Keeps what was written before in order with the output of the command
+    super::io::flush_output(interpreter);
+    let _ = std::io::stdout().flush();
+    let status = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(OsStr::from_bytes(&command))
+        .status();
+    match status {
+        Ok(status) => Ok(exec_result(status)),
+        Err(error) => Ok(file_error(&error, None)),
+    }
+}
+
+/// `os.exit`, which flushes every open file before ending the process, as C's
+/// `exit` does. Asked to close the interpreter first, it unwinds to the host
+/// instead, which exits once it dropped the interpreter, so `<close>`
+/// variables and finalizers run.
+pub fn exit(interpreter: &mut Interpreter, parameters: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let status = match argument(&parameters, 1) {
+        Value::Nil | Value::True => 0,
+        Value::False => 1,
+        _ => optional_integer(&parameters, 1, "exit", 0)? as i32,
+    };
+    if argument(&parameters, 2).is_truthy() {
+        return interpreter.exit(status);
+    }
+    super::io::flush_files();
+    let _ = std::io::stdout().flush();
+    std::process::exit(status)
+}
+
+pub fn getenv(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let name = check_string(&parameters, 1, "getenv")?;
+    match std::env::var_os(name.to_str_lossy().as_ref()) {
+        Some(value) => Ok(vec![Value::String(value.into_encoded_bytes().into())]),
+        None => Ok(vec![Value::Nil]),
+    }
+}
+
+/// Results of an operation on files that gives nothing on success.
+fn file_result(result: io::Result<()>, filename: &str) -> Vec<Value> {
+    match result {
+        Ok(()) => vec![Value::True],
+        Err(error) => file_error(&error, Some(filename)),
+    }
+}
+
+pub fn remove(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let filename = check_string(&parameters, 1, "remove")?.to_string();
+    // Like C's `remove`, empty directories can be removed too
+    let result = match fs::symlink_metadata(&filename) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir(&filename),
+        _ => fs::remove_file(&filename),
+    };
+    Ok(file_result(result, &filename))
+}
+
+pub fn rename(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let from = check_string(&parameters, 1, "rename")?.to_string();
+    let to = check_string(&parameters, 2, "rename")?.to_string();
+    Ok(file_result(fs::rename(&from, &to), &from))
+}
+
+/// The current time, in seconds since the epoch.
+fn now() -> i64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs() as i64,
+        Err(error) => -(error.duration().as_secs() as i64),
+    }
+}
+
+pub fn time(interpreter: &mut Interpreter, parameters: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let table = argument(&parameters, 1);
+    if table.is_nil() {
+        return Ok(vec![Value::Integer(now())]);
+    }
+    if !matches!(table, Value::Table(_)) {
+        check_table(&parameters, 1, "time")?;
+    }
+    // SAFETY: `tm` is plain data
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    tm.tm_year = get_field(interpreter, &table, "year", None, 1900)?;
+    tm.tm_mon = get_field(interpreter, &table, "month", None, 1)?;
+    tm.tm_mday = get_field(interpreter, &table, "day", None, 0)?;
+    tm.tm_hour = get_field(interpreter, &table, "hour", Some(12), 0)?;
+    tm.tm_min = get_field(interpreter, &table, "min", Some(0), 0)?;
+    tm.tm_sec = get_field(interpreter, &table, "sec", Some(0), 0)?;
+    let isdst = interpreter.index(table.clone(), Value::String("isdst".into()))?;
+    tm.tm_isdst = if isdst.is_nil() {
+        -1
+    } else {
+        isdst.is_truthy() as i32
+    };
+    // SAFETY: `mktime` normalizes the fields in place
+    let time = unsafe { libc::mktime(&mut tm) };
+    set_fields(interpreter, &table, &tm)?;
+    if time == -1 {
+        return Err(LuaError::new(
+            "time result cannot be represented in this installation",
+        ));
+    }
+    Ok(vec![Value::Integer(time as i64)])
+}
+
+/// `os.tmpname`, which creates the file so no other process takes its name.
+pub fn tmpname(
+    _interpreter: &mut Interpreter,
+    _parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    let seed = nanos ^ std::process::id().rotate_left(16);
+    for attempt in 0..TEMPORARY_ATTEMPTS as u32 {
+        let suffix = seed.wrapping_add(attempt.wrapping_mul(0x9e37_79b9)) & 0xff_ffff;
+        let name = format!("/tmp/lua_{suffix:06x}");
+        match OpenOptions::new().write(true).create_new(true).open(&name) {
+            Ok(_) => return Ok(vec![Value::String(name.into())]),
+            Err(error) if error.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(_) => break,
+        }
+    }
+    Err(LuaError::new("unable to generate a unique filename"))
+}
@@ -0,0 +1,420 @@
+//! `string.pack`, `string.unpack` and `string.packsize`, which serialize
+//! values to binary following a format string.
+
+use crate::interpreter::{value::Value, Interpreter, LuaError};
+
+use super::{argument_error, check_integer, check_string, optional_integer, type_name};
+
+/// Size of a Lua integer in bytes.
+const INTEGER_SIZE: usize = 8;
+/// Maximum size of the integers that can be packed.
+const MAXIMUM_INTEGER_SIZE: usize = 16;
+/// Default maximum alignment, that of the widest native type.
+const MAXIMUM_ALIGNMENT: usize = 8;
+/// Maximum size of a packed string.
+const MAXIMUM_SIZE: usize = i32::MAX as usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Integer,
+    Unsigned,
+    Float,
+    Double,
+    /// A string of fixed size.
+    Char,
+    /// A string preceded by its length.
+    String,
+    /// A zero-terminated string.
+    ZeroTerminated,
+    Padding,
+    /// Padding to align to the next option.
+    AlignPadding,
+    /// An option that only changes the settings.
+    None,
+}
+
+/// The reader of a format string with its current settings.
+struct Format<'a> {
+    format: &'a [u8],
+    position: usize,
+    little_endian: bool,
+    maximum_alignment: usize,
+    /// Name of the function for argument errors.
+    name: &'static str,
+}
+
+impl<'a> Format<'a> {
+    fn new(format: &'a [u8], name: &'static str) -> Self {
+        Self {
+            format,
+            position: 0,
+            little_endian: cfg!(target_endian = "little"),
+            maximum_alignment: 1,
+            name,
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.position >= self.format.len()
+    }
+
+    fn number(&mut self, default: usize) -> usize {
+        if !self
+            .format
+            .get(self.position)
+            .is_some_and(u8::is_ascii_digit)
+        {
+            return default;
+        }
+        let mut n = 0;
+        while let Some(&c) = self.format.get(self.position) {
+            if !c.is_ascii_digit() || n > (MAXIMUM_SIZE - 9) / 10 {
+                break;
+            }
+            n = n * 10 + (c - b'0') as usize;
+            self.position += 1;
+        }
+        n
+    }
+
+    fn size_limited(&mut self, default: usize) -> Result<usize, LuaError> {
+        let size = self.number(default);
+        if size > MAXIMUM_INTEGER_SIZE || size == 0 {
+            return Err(LuaError::new(format!(
+                "integral size ({size}) out of limits [1,{MAXIMUM_INTEGER_SIZE}]"
+            )));
+        }
+        Ok(size)
+    }
+
+    /// Reads the next option, giving its kind and size.
+    fn option(&mut self) -> Result<(Kind, usize), LuaError> {
+        let option = self.format[self.position];
+        self.position += 1;
+        Ok(match option {
+            b'b' => (Kind::Integer, 1),
+            b'B' => (Kind::Unsigned, 1),
+            b'h' => (Kind::Integer, 2),
+            b'H' => (Kind::Unsigned, 2),
+            b'l' | b'j' => (Kind::Integer, 8),
+            b'L' | b'J' | b'T' => (Kind::Unsigned, 8),
+            b'f' => (Kind::Float, 4),
+            b'n' | b'd' => (Kind::Double, 8),
+            b'i' => (Kind::Integer, self.size_limited(4)?),
+            b'I' => (Kind::Unsigned, self.size_limited(4)?),
+            b's' => (Kind::String, self.size_limited(8)?),
+            b'c' => match self.number(usize::MAX) {
+                usize::MAX => {
+                    return Err(LuaError::new("missing size for format option 'c'"));
+                }
+                size => (Kind::Char, size),
+            },
+            b'z' => (Kind::ZeroTerminated, 0),
+            b'x' => (Kind::Padding, 1),
+            b'X' => (Kind::AlignPadding, 0),
+            b' ' => (Kind::None, 0),
+            b'<' => {
+                self.little_endian = true;
+                (Kind::None, 0)
+            }
+            b'>' => {
+                self.little_endian = false;
+                (Kind::None, 0)
+            }
+            b'=' => {
+                self.little_endian = cfg!(target_endian = "little");
+                (Kind::None, 0)
+            }
+            b'!' => {
+                self.maximum_alignment = self.size_limited(MAXIMUM_ALIGNMENT)?;
+                (Kind::None, 0)
+            }
+            option => {
+                return Err(LuaError::new(format!(
+                    "invalid format option '{}'",
+                    option as char
+                )));
+            }
+        })
+    }
+
+    /// Reads the next option, giving its kind, its size and the padding it
+    /// needs to be aligned after `total` bytes.
+    fn details(&mut self, total: usize) -> Result<(Kind, usize, usize), LuaError> {
+        let (kind, size) = self.option()?;
+        let mut alignment = size;
+        if kind == Kind::AlignPadding {
+            let next = if self.is_done() {
+                None
+            } else {
+                Some(self.option()?)
+            };
+            match next {
+                Some((next, size)) if next != Kind::Char && size != 0 => alignment = size,
+                _ => {
+                    return Err(argument_error(
+                        1,
+                        self.name,
+                        "invalid next option for option 'X'",
+                    ))
+                }
+            }
+        }
+        if alignment <= 1 || kind == Kind::Char {
+            return Ok((kind, size, 0));
+        }
+        let alignment = alignment.min(self.maximum_alignment);
+        if !alignment.is_power_of_two() {
+            return Err(argument_error(
+                1,
+                self.name,
+                "format asks for alignment not power of 2",
+            ));
+        }
+        let padding = (alignment - (total & (alignment - 1))) & (alignment - 1);
+        Ok((kind, size, padding))
+    }
+}
+
+fn pack_integer(result: &mut Vec<u8>, n: u64, little_endian: bool, size: usize, negative: bool) {
+    let mut bytes: Vec<u8> = (0..size)
+        .map(|i| match i < INTEGER_SIZE {
+            true => (n >> (8 * i)) as u8,
+            false if negative => 0xff,
+            false => 0,
+        })
+        .collect();
+    if !little_endian {
+        bytes.reverse();
+    }
+    result.extend_from_slice(&bytes);
+}
+
+fn unpack_integer(bytes: &[u8], little_endian: bool, signed: bool) -> Result<i64, LuaError> {
+    let size = bytes.len();
+    let byte = |i: usize| bytes[if little_endian { i } else { size - 1 - i }];
+    let limit = size.min(INTEGER_SIZE);
+    let mut n: u64 = 0;
+    for i in (0..limit).rev() {
+        n = (n << 8) | byte(i) as u64;
+    }
+    if size < INTEGER_SIZE {
+        if signed {
+            let mask = 1u64 << (size * 8 - 1);
+            n = (n ^ mask).wrapping_sub(mask);
+        }
+    } else if size > INTEGER_SIZE {
+        // The bytes that do not fit must only extend the sign
+        let extension = if signed && (n as i64) < 0 { 0xff } else { 0 };
+        if (limit..size).any(|i| byte(i) != extension) {
+            return Err(LuaError::new(format!(
+                "{size}-byte integer does not fit into Lua Integer"
+            )));
+        }
+    }
+    Ok(n as i64)
+}
+
+fn check_number(parameters: &[Value], position: usize, name: &str) -> Result<f64, LuaError> {
+    let value = parameters.get(position - 1).cloned().unwrap_or(Value::Nil);
+    value.to_float().ok_or_else(|| {
+        argument_error(
+            position,
+            name,
+            &format!(
+                "number expected, got {}",
+                type_name(parameters, position, &value)
+            ),
+        )
+    })
+}
+
+fn string_value(bytes: &[u8]) -> Value {
+    Value::String(bytes.into())
+}
+
+pub fn pack(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let format = check_string(&parameters, 1, "pack")?;
+    let mut format = Format::new(format.as_bytes(), "pack");
+    let mut result = vec![];
+    let mut position = 1;
+    while !format.is_done() {
+        let (kind, size, padding) = format.details(result.len())?;
+        result.resize(result.len() + padding, 0);
+        position += 1;
+        let little_endian = format.little_endian;
+        match kind {
+            Kind::Integer => {
+                let n = check_integer(&parameters, position, "pack")?;
+                if size < INTEGER_SIZE {
+                    let limit = 1i64 << (size * 8 - 1);
+                    if !(-limit..limit).contains(&n) {
+                        return Err(argument_error(position, "pack", "integer overflow"));
+                    }
+                }
+                pack_integer(&mut result, n as u64, little_endian, size, n < 0);
+            }
+            Kind::Unsigned => {
+                let n = check_integer(&parameters, position, "pack")?;
+                if size < INTEGER_SIZE && (n as u64) >= 1 << (size * 8) {
+                    return Err(argument_error(position, "pack", "unsigned overflow"));
+                }
+                pack_integer(&mut result, n as u64, little_endian, size, false);
+            }
+            Kind::Float => {
+                let f = check_number(&parameters, position, "pack")? as f32;
+                let bytes = if little_endian {
+                    f.to_le_bytes()
+                } else {
+                    f.to_be_bytes()
+                };
+                result.extend_from_slice(&bytes);
+            }
+            Kind::Double => {
+                let f = check_number(&parameters, position, "pack")?;
+                let bytes = if little_endian {
+                    f.to_le_bytes()
+                } else {
+                    f.to_be_bytes()
+                };
+                result.extend_from_slice(&bytes);
+            }
+            Kind::Char => {
+                let s = check_string(&parameters, position, "pack")?;
+                if s.len() > size {
+                    let message = "string longer than given size";
+                    return Err(argument_error(position, "pack", message));
+                }
+                result.extend_from_slice(s.as_bytes());
+                result.resize(result.len() + size - s.len(), 0);
+            }
+            Kind::String => {
+                let s = check_string(&parameters, position, "pack")?;
+                if size < INTEGER_SIZE && s.len() as u64 >= 1 << (size * 8) {
+                    let message = "string length does not fit in given size";
+                    return Err(argument_error(position, "pack", message));
+                }
+                pack_integer(&mut result, s.len() as u64, little_endian, size, false);
+                result.extend_from_slice(s.as_bytes());
+            }
+            Kind::ZeroTerminated => {
+                let s = check_string(&parameters, position, "pack")?;
+                if s.contains(&0) {
+                    return Err(argument_error(position, "pack", "string contains zeros"));
+                }
+                result.extend_from_slice(s.as_bytes());
+                result.push(0);
+            }
+            Kind::Padding | Kind::AlignPadding | Kind::None => {
+                if kind == Kind::Padding {
+                    result.push(0);
+                }
+                position -= 1;
+            }
+        }
+    }
+    Ok(vec![string_value(&result)])
+}
+
+pub fn packsize(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let format = check_string(&parameters, 1, "packsize")?;
+    let mut format = Format::new(format.as_bytes(), "packsize");
+    let mut total = 0;
+    while !format.is_done() {
+        let (kind, size, padding) = format.details(total)?;
+        if matches!(kind, Kind::String | Kind::ZeroTerminated) {
+            return Err(argument_error(1, "packsize", "variable-length format"));
+        }
+        let size = size + padding;
+        if total > MAXIMUM_SIZE - size {
+            return Err(argument_error(1, "packsize", "format result too large"));
+        }
+        total += size;
+    }
+    Ok(vec![Value::Integer(total as i64)])
+}
+
+pub fn unpack(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let format = check_string(&parameters, 1, "unpack")?;
+    let data = check_string(&parameters, 2, "unpack")?;
+    let data = data.as_bytes();
+    let mut format = Format::new(format.as_bytes(), "unpack");
+    let start = optional_integer(&parameters, 3, "unpack", 1)?;
+    let mut position = match start {
+        start if start > 0 => start as usize - 1,
+        start if start == 0 || start.unsigned_abs() as usize > data.len() => 0,
+        start => data.len() - start.unsigned_abs() as usize,
+    };
+    if position > data.len() {
+        let message = "initial position out of string";
+        return Err(argument_error(3, "unpack", message));
+    }
+    let too_short = || argument_error(2, "unpack", "data string too short");
+    let mut values = vec![];
+    while !format.is_done() {
+        let (kind, size, padding) = format.details(position)?;
+        if padding + size > data.len() - position {
+            return Err(too_short());
+        }
+        position += padding;
+        let bytes = &data[position..position + size];
+        let little_endian = format.little_endian;
+        match kind {
+            Kind::Integer | Kind::Unsigned => {
+                let signed = kind == Kind::Integer;
+                let n = unpack_integer(bytes, little_endian, signed)?;
+                values.push(Value::Integer(n));
+            }
+            Kind::Float => {
+                let bytes = bytes.try_into().unwrap();
+                let f = if little_endian {
+                    f32::from_le_bytes(bytes)
+                } else {
+                    f32::from_be_bytes(bytes)
+                };
+                values.push(Value::Float(f as f64));
+            }
+            Kind::Double => {
+                let bytes = bytes.try_into().unwrap();
+                let f = if little_endian {
+                    f64::from_le_bytes(bytes)
+                } else {
+                    f64::from_be_bytes(bytes)
+                };
+                values.push(Value::Float(f));
+            }
+            Kind::Char => values.push(string_value(bytes)),
+            Kind::String => {
+                let length = unpack_integer(bytes, little_endian, false)? as u64;
+                let start = position + size;
+                if length > (data.len() - start) as u64 {
+                    return Err(too_short());
+                }
+                let length = length as usize;
+                values.push(string_value(&data[start..start + length]));
+                position += length;
+            }
+            Kind::ZeroTerminated => {
+                let Some(length) = data[position..].iter().position(|&b| b == 0) else {
+                    let message = "unfinished string for format 'z'";
+                    return Err(argument_error(2, "unpack", message));
+                };
+                values.push(string_value(&data[position..position + length]));
+                position += length + 1;
+            }
+            Kind::Padding | Kind::AlignPadding | Kind::None => {}
+        }
+        position += size;
+    }
+    values.push(Value::Integer(position as i64 + 1));
+    Ok(values)
+}
@@ -0,0 +1,231 @@
+use std::fs::File;
+
+use crate::interpreter::{
+    value::{Table, TableRef, Value},
+    Interpreter, LuaError,
+};
+
+use super::{
+    check_string,
+    global::{load_chunk, read_chunk},
+    optional_string, Builtin,
+};
+
+/// Registry key of the table of loaded modules, `package.loaded`.
+pub const LOADED: &str = "_LOADED";
+/// Registry key of the `package` table.
+pub const PACKAGE: &str = "_PACKAGE";
+
+/// Search path used when `LUA_PATH` is not set.
+const DEFAULT_PATH: &str = "/usr/local/share/lua/5.4/?.lua;/usr/local/share/lua/5.4/?/init.lua;\
+                            /usr/local/lib/lua/5.4/?.lua;/usr/local/lib/lua/5.4/?/init.lua;\
+                            ./?.lua;./?/init.lua";
+
+/// Directory separator, template separator, substitution mark, executable
+/// directory mark and ignore mark.
+const CONFIG: &str = "/\n;\n?\n!\n-\n";
+
+/// The search path from `LUA_PATH_5_4` or `LUA_PATH`, where `;;` stands for
+/// the default path.
+pub fn initial_path() -> String {
+    let Some(path) = ["LUA_PATH_5_4", "LUA_PATH"]
+        .into_iter()
+        .find_map(|variable| std::env::var(variable).ok())
+    else {
+        return DEFAULT_PATH.to_string();
+    };
+    match path.split_once(";;") {
+        None => path,
+        Some((prefix, suffix)) => {
+            let mut path = String::new();
+            if !prefix.is_empty() {
+                path.push_str(prefix);
+                path.push(';');
+            }
+            path.push_str(DEFAULT_PATH);
+            if !suffix.is_empty() {
+                path.push(';');
+                path.push_str(suffix);
+            }
+            path
+        }
+    }
+}
+
+/// Fills the `package` table and keeps it and `package.loaded` in the registry.
+pub fn init(interpreter: &mut Interpreter, package: &TableRef) {
+    let loaded = interpreter.new_table(Table::new());
+    let preload = interpreter.new_table(Table::new());
+    let mut searchers = Table::new();
+    searchers.insert(Value::Integer(1), Value::Builtin(Builtin::SearchPreload));
+    searchers.insert(Value::Integer(2), Value::Builtin(Builtin::SearchLua));
+    let searchers = interpreter.new_table(searchers);
+    let mut table = package.borrow_mut();
+    table.insert_str("config", Value::String(CONFIG.into()));
+    table.insert_str("path", Value::String(initial_path().into()));
+    table.insert_str("loaded", loaded.clone());
+    table.insert_str("preload", preload);
+    table.insert_str("searchers", searchers);
+    drop(table);
+    let registry = interpreter.registry();
+    let mut registry = registry.borrow_mut();
+    registry.insert_str(LOADED, loaded);
+    registry.insert_str(PACKAGE, Value::Table(package.clone()));
+}
+
+/// The table kept in the registry under `key`.
+fn registry_table(interpreter: &Interpreter, key: &str) -> Result<TableRef, LuaError> {
+    match interpreter.registry().borrow().get_str(key) {
+        Value::Table(table) => Ok(table),
+        _ => Err(LuaError::new(format!(
+            "'{key}' is missing from the registry"
+        ))),
+    }
+}
+
+/// A field of the `package` table, which must be a table.
+fn package_table(interpreter: &Interpreter, field: &str) -> Result<TableRef, LuaError> {
+    match registry_table(interpreter, PACKAGE)?
+        .borrow()
+        .get_str(field)
+    {
+        Value::Table(table) => Ok(table),
+        _ => Err(LuaError::new(format!("'package.{field}' must be a table"))),
+    }
+}
+
+pub fn require(
+    interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let name = check_string(&parameters, 1, "require")?.to_string();
+    let loaded = registry_table(interpreter, LOADED)?;
+    let module = loaded.borrow().get_str(&name);
+    if module.is_truthy() {
+        return Ok(vec![module]);
+    }
+    let (loader, data) = find_loader(interpreter, &name)?;
+    let arguments = vec![Value::String(name.as_str().into()), data.clone()];
+    let module = interpreter.call(loader, arguments)?;
+    let module = module.into_iter().next().unwrap_or(Value::Nil);
+    if !module.is_nil() {
+        loaded.borrow_mut().insert_str(&name, module);
+    }
+    let mut loaded = loaded.borrow_mut();
+    if loaded.get_str(&name).is_nil() {
+        loaded.insert_str(&name, Value::True);
+    }
+    Ok(vec![loaded.get_str(&name), data])
+}
+
+/// Asks each of `package.searchers` for a loader of the module, giving it
+/// with its data.
+fn find_loader(interpreter: &mut Interpreter, name: &str) -> Result<(Value, Value), LuaError> {
+    let searchers = package_table(interpreter, "searchers")?;
+    let mut message = String::new();
+    for i in 1.. {
+        let searcher = searchers.borrow().get(&Value::Integer(i));
+        if searcher.is_nil() {
+            break;
+        }
+        let mut values = interpreter
+            .call(searcher, vec![Value::String(name.into())])?
+            .into_iter();
+        let loader = values.next().unwrap_or(Value::Nil);
+        let data = values.next().unwrap_or(Value::Nil);
+        match loader {
+            Value::Lambda(_) | Value::Builtin(_) | Value::Native(_) => return Ok((loader, data)),
+            Value::String(_) | Value::Integer(_) | Value::Float(_) => {
+                message.push_str("\n\t");
+                message.push_str(&loader.to_string());
+            }
+            _ => {}
+        }
+    }
+    Err(LuaError::new(format!(
+        "module '{name}' not found:{message}"
+    )))
+}
+
+/// Looks for a readable file in the templates of `path`, separated by `;`,
+/// replacing each `?` with `name` after replacing `separator` in it with
+/// `replacement`. Gives the message listing the files tried otherwise.
+pub fn search_path(
+    name: &str,
+    path: &str,
+    separator: &str,
+    replacement: &str,
+) -> Result<String, String> {
+    let name = if separator.is_empty() {
+        name.to_string()
+    } else {
+        name.replace(separator, replacement)
+    };
+    let mut tried = vec![];
+    for template in path.split(';').filter(|template| !template.is_empty()) {
+        let filename = template.replace('?', &name);
+        if File::open(&filename).is_ok() {
+            return Ok(filename);
+        }
+        tried.push(format!("no file '{filename}'"));
+    }
+    Err(tried.join("\n\t"))
+}
+
+pub fn searchpath(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let name = check_string(&parameters, 1, "searchpath")?.to_string();
+    let path = check_string(&parameters, 2, "searchpath")?.to_string();
+    let separator = optional_string(&parameters, 3, "searchpath", ".")?;
+    let replacement = optional_string(&parameters, 4, "searchpath", "/")?;
+    match search_path(&name, &path, &separator, &replacement) {
+        Ok(filename) => Ok(vec![Value::String(filename.into())]),
+        Err(message) => Ok(vec![Value::Nil, Value::String(message.into())]),
+    }
+}
+
+/// The searcher giving the loader in `package.preload`.
+pub fn search_preload(
+    interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let name = check_string(&parameters, 1, "searcher_preload")?.to_string();
+    let preload = package_table(interpreter, "preload")?;
+    let loader = preload.borrow().get_str(&name);
+    if loader.is_nil() {
+        let message = format!("no field package.preload['{name}']");
+        return Ok(vec![Value::String(message.into())]);
+    }
+    Ok(vec![loader, Value::String(":preload:".into())])
+}
+
+/// The searcher loading Lua files found in `package.path`.
+pub fn search_lua(
+    interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let name = check_string(&parameters, 1, "searcher_Lua")?.to_string();
+    let path = match registry_table(interpreter, PACKAGE)?
+        .borrow()
+        .get_str("path")
+    {
+        Value::String(path) => path.to_string(),
+        _ => return Err(LuaError::new("'package.path' must be a string")),
+    };
+    let filename = match search_path(&name, &path, ".", "/") {
+        Ok(filename) => filename,
+        Err(message) => return Ok(vec![Value::String(message.into())]),
+    };
+    let loader = read_chunk(Some(&filename))
+        .map_err(LuaError::new)
+        .and_then(|(source, chunk)| load_chunk(interpreter, &source, &chunk, "bt", None));
+    match loader {
+        Ok(loader) => Ok(vec![loader, Value::String(filename.into())]),
+        Err(error) => Err(LuaError::new(format!(
+            "error loading module '{name}' from file '{filename}':\n\t{}",
+            error.value
+        ))),
+    }
+}
@@ -0,0 +1,405 @@
+//! The matching engine of Lua patterns, working on bytes.
+
+use crate::interpreter::{value::Value, LuaError};
+
+/// Maximum number of captures in a pattern.
+const MAXIMUM_CAPTURES: usize = 32;
+/// Maximum depth of the recursion while matching.
+const MAXIMUM_DEPTH: usize = 200;
+
+const ESCAPE: u8 = b'%';
+/// Characters that make a pattern more than a plain string.
+const SPECIALS: &[u8] = b"^$*+?.([%-";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CaptureLength {
+    /// The capture is still open.
+    Open,
+    /// A `()` capture of the position.
+    Position,
+    Closed(usize),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Capture {
+    start: usize,
+    length: CaptureLength,
+}
+
+/// The state of a match of `pattern` against `source`.
+pub struct Matcher<'a> {
+    source: &'a [u8],
+    pattern: &'a [u8],
+    depth: usize,
+    captures: Vec<Capture>,
+}
+
+/// Whether `pattern` has no special characters, so it can be searched as is.
+pub fn is_plain(pattern: &[u8]) -> bool {
+    !pattern.iter().any(|c| SPECIALS.contains(c))
+}
+
+fn is_space(c: u8) -> bool {
+    matches!(c, b' ' | b'\t' | b'\n' | 0x0b | 0x0c | b'\r')
+}
+
+/// Whether `c` is in the class `%class`.
+fn single_class(c: u8, class: u8) -> bool {
+    let matches = match class.to_ascii_lowercase() {
+        b'a' => c.is_ascii_alphabetic(),
+        b'c' => c.is_ascii_control(),
+        b'd' => c.is_ascii_digit(),
+        b'g' => c.is_ascii_graphic(),
+        b'l' => c.is_ascii_lowercase(),
+        b'p' => c.is_ascii_punctuation(),
+        b's' => is_space(c),
+        b'u' => c.is_ascii_uppercase(),
+        b'w' => c.is_ascii_alphanumeric(),
+        b'x' => c.is_ascii_hexdigit(),
+        _ => return class == c,
+    };
+    if class.is_ascii_uppercase() {
+        !matches
+    } else {
+        matches
+    }
+}
+
+impl<'a> Matcher<'a> {
+    pub fn new(source: &'a [u8], pattern: &'a [u8]) -> Self {
+        Self {
+            source,
+            pattern,
+            depth: MAXIMUM_DEPTH,
+            captures: vec![],
+        }
+    }
+
+    /// Matches the pattern from `pattern_start` at `start` of the source,
+    /// giving the end of the match.
+    pub fn matches(
+        &mut self,
+        start: usize,
+        pattern_start: usize,
+    ) -> Result<Option<usize>, LuaError> {
+        self.depth = MAXIMUM_DEPTH;
+        self.captures.clear();
+        self.do_match(start, pattern_start)
+    }
+
+    /// The captures of the last match from `start` to `end`, or the whole
+    /// match when the pattern has none and `whole` is set.
+    pub fn captures(&self, start: usize, end: usize, whole: bool) -> Result<Vec<Value>, LuaError> {
+        let count = if self.captures.is_empty() && whole {
+            1
+        } else {
+            self.captures.len()
+        };
+        (0..count).map(|i| self.capture(i, start, end)).collect()
+    }
+
+    /// The capture `index`, counting from zero, with the whole match standing
+    /// for the first one when the pattern has none.
+    pub fn capture(&self, index: usize, start: usize, end: usize) -> Result<Value, LuaError> {
+        let Some(capture) = self.captures.get(index) else {
+            if index != 0 {
+                return Err(LuaError::new(format!(
+                    "invalid capture index %{}",
+                    index + 1
+                )));
+            }
+            return Ok(string_value(&self.source[start..end]));
+        };
+        match capture.length {
+            CaptureLength::Open => Err(LuaError::new("unfinished capture")),
+            CaptureLength::Position => Ok(Value::Integer(capture.start as i64 + 1)),
+            CaptureLength::Closed(length) => Ok(string_value(
+                &self.source[capture.start..capture.start + length],
+            )),
+        }
+    }
+
+    fn do_match(&mut self, mut s: usize, mut p: usize) -> Result<Option<usize>, LuaError> {
+        if self.depth == 0 {
+            return Err(LuaError::new("pattern too complex"));
+        }
+        self.depth -= 1;
+        let result = loop {
+            let Some(&c) = self.pattern.get(p) else {
+                break Some(s);
+            };
+            match c {
+                b'(' => {
+                    break if self.pattern.get(p + 1) == Some(&b')') {
+                        self.start_capture(s, p + 2, CaptureLength::Position)?
+                    } else {
+                        self.start_capture(s, p + 1, CaptureLength::Open)?
+                    };
+                }
+                b')' => break self.end_capture(s, p + 1)?,
+                b'$' if p + 1 == self.pattern.len() => {
+                    break (s == self.source.len()).then_some(s);
+                }
+                ESCAPE if self.pattern.get(p + 1) == Some(&b'b') => {
+                    match self.match_balance(s, p + 2)? {
+                        Some(end) => {
+                            s = end;
+                            p += 4;
+                        }
+                        None => break None,
+                    }
+                }
+                ESCAPE if self.pattern.get(p + 1) == Some(&b'f') => {
+                    p += 2;
+                    if self.pattern.get(p) != Some(&b'[') {
+                        return Err(LuaError::new("missing '[' after '%f' in pattern"));
+                    }
+                    let end = self.class_end(p)?;
+                    let previous = if s == 0 { 0 } else { self.source[s - 1] };
+                    let current = self.source.get(s).copied().unwrap_or(0);
+                    if !self.match_class_set(previous, p, end - 1)
+                        && self.match_class_set(current, p, end - 1)
+                    {
+                        p = end;
+                    } else {
+                        break None;
+                    }
+                }
+                ESCAPE if self.pattern.get(p + 1).is_some_and(|c| c.is_ascii_digit()) => {
+                    match self.match_capture(s, self.pattern[p + 1])? {
+                        Some(end) => {
+                            s = end;
+                            p += 2;
+                        }
+                        None => break None,
+                    }
+                }
+                _ => {
+                    let end = self.class_end(p)?;
+                    let repetition = self.pattern.get(end).copied();
+                    if !self.single_match(s, p, end) {
+                        if matches!(repetition, Some(b'*' | b'?' | b'-')) {
+                            p = end + 1;
+                            continue;
+                        }
+                        break None;
+                    }
+                    match repetition {
+                        Some(b'?') => match self.do_match(s + 1, end + 1)? {
+                            Some(end) => break Some(end),
+                            None => p = end + 1,
+                        },
+                        Some(b'+') => break self.max_expand(s + 1, p, end)?,
+                        Some(b'*') => break self.max_expand(s, p, end)?,
+                        Some(b'-') => break self.min_expand(s, p, end)?,
+                        _ => {
+                            s += 1;
+                            p = end;
+                        }
+                    }
+                }
+            }
+        };
+        self.depth += 1;
+        Ok(result)
+    }
+
+    /// The end of the single character class starting at `p`.
+    fn class_end(&self, mut p: usize) -> Result<usize, LuaError> {
+        let c = self.pattern[p];
+        p += 1;
+        if c == ESCAPE {
+            if p >= self.pattern.len() {
+                return Err(LuaError::new("malformed pattern (ends with '%')"));
+            }
+            return Ok(p + 1);
+        }
+        if c == b'[' {
+            if self.pattern.get(p) == Some(&b'^') {
+                p += 1;
+            }
+            // The first character of a set is never its end, so `[]]` works
+            loop {
+                if p >= self.pattern.len() {
+                    return Err(LuaError::new("malformed pattern (missing ']')"));
+                }
+                let c = self.pattern[p];
+                p += 1;
+                if c == ESCAPE && p < self.pattern.len() {
+                    p += 1;
+                }
+                if self.pattern.get(p) == Some(&b']') {
+                    return Ok(p + 1);
+                }
+            }
+        }
+        Ok(p)
+    }
+
+    /// Whether `c` is in the set from `p`, at its `[`, to `end`, at its `]`.
+    fn match_class_set(&self, c: u8, mut p: usize, end: usize) -> bool {
+        let mut found = true;
+        if self.pattern[p + 1] == b'^' {
+            found = false;
+            p += 1;
+        }
+        p += 1;
+        while p < end {
+            if self.pattern[p] == ESCAPE {
+                p += 1;
+                if single_class(c, self.pattern[p]) {
+                    return found;
+                }
+            } else if self.pattern[p + 1] == b'-' && p + 2 < end {
+                if self.pattern[p] <= c && c <= self.pattern[p + 2] {
+                    return found;
+                }
+                p += 2;
+            } else if self.pattern[p] == c {
+                return found;
+            }
+            p += 1;
+        }
+        !found
+    }
+
+    /// Whether the character at `s` matches the class from `p` to `end`.
+    fn single_match(&self, s: usize, p: usize, end: usize) -> bool {
+        let Some(&c) = self.source.get(s) else {
+            return false;
+        };
+        match self.pattern[p] {
+            b'.' => true,
+            ESCAPE => single_class(c, self.pattern[p + 1]),
+            b'[' => self.match_class_set(c, p, end - 1),
+            class => class == c,
+        }
+    }
+
+    fn max_expand(&mut self, s: usize, p: usize, end: usize) -> Result<Option<usize>, LuaError> {
+        let mut count = 0;
+        while self.single_match(s + count, p, end) {
+            count += 1;
+        }
+        loop {
+            if let Some(end) = self.do_match(s + count, end + 1)? {
+                return Ok(Some(end));
+            }
+            if count == 0 {
+                return Ok(None);
+            }
+            count -= 1;
+        }
+    }
+
+    fn min_expand(
+        &mut self,
+        mut s: usize,
+        p: usize,
+        end: usize,
+    ) -> Result<Option<usize>, LuaError> {
+        loop {
+            if let Some(end) = self.do_match(s, end + 1)? {
+                return Ok(Some(end));
+            }
+            if !self.single_match(s, p, end) {
+                return Ok(None);
+            }
+            s += 1;
+        }
+    }
+
+    fn start_capture(
+        &mut self,
+        s: usize,
+        p: usize,
+        length: CaptureLength,
+    ) -> Result<Option<usize>, LuaError> {
+        if self.captures.len() >= MAXIMUM_CAPTURES {
+            return Err(LuaError::new("too many captures"));
+        }
+        self.captures.push(Capture { start: s, length });
+        let result = self.do_match(s, p)?;
+        if result.is_none() {
+            self.captures.pop();
+        }
+        Ok(result)
+    }
+
+    fn end_capture(&mut self, s: usize, p: usize) -> Result<Option<usize>, LuaError> {
+        let Some(index) = self
+            .captures
+            .iter()
+            .rposition(|capture| capture.length == CaptureLength::Open)
+        else {
+            return Err(LuaError::new("invalid pattern capture"));
+        };
+        let start = self.captures[index].start;
+        self.captures[index].length = CaptureLength::Closed(s - start);
+        let result = self.do_match(s, p)?;
+        if result.is_none() {
+            self.captures[index].length = CaptureLength::Open;
+        }
+        Ok(result)
+    }
+
+    /// Matches `%bxy` from `s`, with `x` at `p`.
+    fn match_balance(&self, s: usize, p: usize) -> Result<Option<usize>, LuaError> {
+        if p + 1 >= self.pattern.len() {
+            return Err(LuaError::new(
+                "malformed pattern (missing arguments to '%b')",
+            ));
+        }
+        let (open, close) = (self.pattern[p], self.pattern[p + 1]);
+        if self.source.get(s) != Some(&open) {
+            return Ok(None);
+        }
+        let mut level = 1;
+        for (i, &c) in self.source.iter().enumerate().skip(s + 1) {
+            if c == close {
+                level -= 1;
+                if level == 0 {
+                    return Ok(Some(i + 1));
+                }
+            } else if c == open {
+                level += 1;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Matches the text of the capture `%digit` again at `s`.
+    fn match_capture(&self, s: usize, digit: u8) -> Result<Option<usize>, LuaError> {
+        let index = digit as i64 - b'1' as i64;
+        let capture = usize::try_from(index)
+            .ok()
+            .and_then(|index| self.captures.get(index));
+        let (start, length) = match capture {
+            Some(Capture {
+                start,
+                length: CaptureLength::Closed(length),
+            }) => (*start, *length),
+            // A position has no text to match
+            Some(Capture {
+                length: CaptureLength::Position,
+                ..
+            }) => return Ok(None),
+            _ => {
+                return Err(LuaError::new(format!(
+                    "invalid capture index %{}",
+                    index + 1
+                )))
+            }
+        };
+        let captured = &self.source[start..start + length];
+        if self.source[s..].starts_with(captured) {
+            Ok(Some(s + length))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+fn string_value(bytes: &[u8]) -> Value {
+    Value::String(bytes.into())
+}
@@ -0,0 +1,125 @@
+//! `math.random` and `math.randomseed`, with the xoshiro256** generator of
+//! Lua 5.4 so a seed gives the same numbers as the reference interpreter.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::interpreter::{value::Value, Interpreter, LuaError};
+
+use super::{argument_error, check_integer, optional_integer};
+
+/// The state of a xoshiro256** generator.
+#[derive(Debug)]
+pub struct Random {
+    state: [u64; 4],
+}
+
+impl Default for Random {
+    /// A generator with a seed that changes from run to run.
+    fn default() -> Self {
+        let mut random = Random { state: [0; 4] };
+        let (n1, n2) = random_seed(&random);
+        random.seed(n1, n2);
+        random
+    }
+}
+
+impl Random {
+    pub fn seed(&mut self, n1: u64, n2: u64) {
+        // The constant avoids a state of all zeros
+        self.state = [n1, 0xff, n2, 0];
+        // Discards the first values to spread the seed through the state
+        for _ in 0..16 {
+            self.next();
+        }
+    }
+
+    pub fn next(&mut self) -> u64 {
+        let state = &mut self.state;
+        let result = state[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = state[1] << 17;
+        state[2] ^= state[0];
+        state[3] ^= state[1];
+        state[1] ^= state[2];
+        state[0] ^= state[3];
+        state[2] ^= t;
+        state[3] = state[3].rotate_left(45);
+        result
+    }
+
+    /// Projects a random value into `[0, n]`, drawing more values until one
+    /// falls in the interval so all results are equally likely.
+    fn project(&mut self, mut random: u64, n: u64) -> u64 {
+        if n & n.wrapping_add(1) == 0 {
+            return random & n;
+        }
+        // The smallest 2^b - 1 not smaller than n
+        let mut limit = n;
+        for shift in [1, 2, 4, 8, 16, 32] {
+            limit |= limit >> shift;
+        }
+        loop {
+            random &= limit;
+            if random <= n {
+                return random;
+            }
+            random = self.next();
+        }
+    }
+}
+
+/// A float in `[0, 1)` from the 53 higher bits of a random value.
+fn to_float(random: u64) -> f64 {
+    (random >> 11) as f64 * 0.5f64.powi(53)
+}
+
+/// A seed made of the current time and an address, which changes between runs.
+fn random_seed(random: &Random) -> (u64, u64) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let address = random as *const Random as u64;
+    (now.as_secs() ^ now.subsec_nanos() as u64, address)
+}
+
+pub fn random(
+    interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let random = interpreter.random();
+    let value = random.next();
+    let (low, up) = match parameters.len() {
+        0 => return Ok(vec![Value::Float(to_float(value))]),
+        1 => {
+            let up = check_integer(&parameters, 1, "random")?;
+            if up == 0 {
+                return Ok(vec![Value::Integer(value as i64)]);
+            }
+            (1, up)
+        }
+        2 => (
+            check_integer(&parameters, 1, "random")?,
+            check_integer(&parameters, 2, "random")?,
+        ),
+        _ => return Err(LuaError::new("wrong number of arguments")),
+    };
+    if low > up {
+        return Err(argument_error(1, "random", "interval is empty"));
+    }
+    let n = random.project(value, (up as u64).wrapping_sub(low as u64));
+    Ok(vec![Value::Integer(n.wrapping_add(low as u64) as i64)])
+}
+
+pub fn randomseed(
+    interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let (n1, n2) = if parameters.is_empty() {
+        random_seed(interpreter.random())
+    } else {
+        let n1 = check_integer(&parameters, 1, "randomseed")?;
+        let n2 = optional_integer(&parameters, 2, "randomseed", 0)?;
+        (n1 as u64, n2 as u64)
+    };
+    interpreter.random().seed(n1, n2);
+    Ok(vec![Value::Integer(n1 as i64), Value::Integer(n2 as i64)])
+}
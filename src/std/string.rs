@@ -0,0 +1,380 @@
+use crate::interpreter::{value::Value, Interpreter, LuaError};
+
+use super::{
+    argument, argument_error, check_integer, check_string, optional_integer,
+    pattern::{self, Matcher},
+    type_name,
+};
+
+/// Strings longer than this cannot be built.
+const MAXIMUM_SIZE: usize = i32::MAX as usize;
+
+/// The start of a range of `length` bytes from a position counting from one,
+/// or back from the end when negative.
+fn start_position(position: i64, length: usize) -> usize {
+    let length = length as i64;
+    if position > 0 {
+        position as usize
+    } else if position == 0 || position < -length {
+        1
+    } else {
+        (length + position + 1) as usize
+    }
+}
+
+/// The end of a range of `length` bytes, clipped to the string.
+fn end_position(position: i64, length: usize) -> usize {
+    let length = length as i64;
+    if position > length {
+        length as usize
+    } else if position >= 0 {
+        position as usize
+    } else if position < -length {
+        0
+    } else {
+        (length + position + 1) as usize
+    }
+}
+
+fn string_value(bytes: &[u8]) -> Value {
+    Value::String(bytes.into())
+}
+
+pub fn len(_interpreter: &mut Interpreter, parameters: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let s = check_string(&parameters, 1, "len")?;
+    Ok(vec![Value::Integer(s.len() as i64)])
+}
+
+pub fn sub(_interpreter: &mut Interpreter, parameters: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let s = check_string(&parameters, 1, "sub")?;
+    let start = start_position(check_integer(&parameters, 2, "sub")?, s.len());
+    let end = end_position(optional_integer(&parameters, 3, "sub", -1)?, s.len());
+    if start > end {
+        return Ok(vec![Value::String(Default::default())]);
+    }
+    Ok(vec![string_value(&s[start - 1..end])])
+}
+
+pub fn upper(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let s = check_string(&parameters, 1, "upper")?;
+    Ok(vec![string_value(&s.to_ascii_uppercase())])
+}
+
+pub fn lower(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let s = check_string(&parameters, 1, "lower")?;
+    Ok(vec![string_value(&s.to_ascii_lowercase())])
+}
+
+pub fn rep(_interpreter: &mut Interpreter, parameters: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let s = check_string(&parameters, 1, "rep")?;
+    let n = check_integer(&parameters, 2, "rep")?;
+    let separator = match parameters.get(2) {
+        Some(Value::Nil) | None => Default::default(),
+        Some(_) => check_string(&parameters, 3, "rep")?,
+    };
+    if n <= 0 || s.len() + separator.len() == 0 {
+        return Ok(vec![Value::String(Default::default())]);
+    }
+    let size = (s.len() + separator.len())
+        .checked_mul(n as usize)
+        .filter(|&size| size - separator.len() < MAXIMUM_SIZE);
+    if size.is_none() {
+        return Err(LuaError::new("resulting string too large"));
+    }
+    let mut result = Vec::with_capacity(size.unwrap());
+    for i in 0..n {
+        if i > 0 {
+            result.extend_from_slice(&separator);
+        }
+        result.extend_from_slice(&s);
+    }
+    Ok(vec![Value::String(result.into())])
+}
+
+pub fn reverse(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let s = check_string(&parameters, 1, "reverse")?;
+    let mut bytes = s.to_vec();
+    bytes.reverse();
+    Ok(vec![string_value(&bytes)])
+}
+
+pub fn byte(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let s = check_string(&parameters, 1, "byte")?;
+    let first = optional_integer(&parameters, 2, "byte", 1)?;
+    let start = start_position(first, s.len());
+    let end = end_position(
+        optional_integer(&parameters, 3, "byte", start as i64)?,
+        s.len(),
+    );
+    if start > end {
+        return Ok(vec![]);
+    }
+    if end - start >= MAXIMUM_SIZE {
+        return Err(LuaError::new("string slice too long"));
+    }
+    let bytes = &s[start - 1..end];
+    Ok(bytes.iter().map(|&b| Value::Integer(b as i64)).collect())
+}
+
+pub fn char(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let mut bytes = Vec::with_capacity(parameters.len());
+    for position in 1..=parameters.len() {
+        let code = check_integer(&parameters, position, "char")?;
+        match u8::try_from(code) {
+            Ok(b) => bytes.push(b),
+            Err(_) => return Err(argument_error(position, "char", "value out of range")),
+        }
+    }
+    Ok(vec![string_value(&bytes)])
+}
+
+/// `string.gmatch`, an iterator over the matches found by `step`, a builtin
+/// giving the position to search from next, the end of the match and its
+/// captures.
+pub const GMATCH: &str = "
+    local step = ...
+    return function(s, pattern, init)
+        local position, last = step(s, pattern, init)
+        local function advance(next, matched, ...)
+            if next then
+                position, last = next, matched
+                return ...
+            end
+            position = nil
+        end
+        return function()
+            if position then
+                return advance(step(s, pattern, position, last))
+            end
+        end
+    end
+";
+
+/// Finds the first match of the pattern in `find` and `match`.
+fn find_match(parameters: Vec<Value>, name: &str, is_find: bool) -> Result<Vec<Value>, LuaError> {
+    let s = check_string(&parameters, 1, name)?;
+    let pattern = check_string(&parameters, 2, name)?;
+    let (s, pattern) = (s.as_bytes(), pattern.as_bytes());
+    let start = start_position(optional_integer(&parameters, 3, name, 1)?, s.len()) - 1;
+    if start > s.len() {
+        return Ok(vec![Value::Nil]);
+    }
+    if is_find && (argument(&parameters, 4).is_truthy() || pattern::is_plain(pattern)) {
+        let found = if pattern.is_empty() {
+            Some(0)
+        } else {
+            s[start..]
+                .windows(pattern.len())
+                .position(|window| window == pattern)
+        };
+        return Ok(match found {
+            Some(i) => vec![
+                Value::Integer((start + i + 1) as i64),
+                Value::Integer((start + i + pattern.len()) as i64),
+            ],
+            None => vec![Value::Nil],
+        });
+    }
+    let anchored = pattern.first() == Some(&b'^');
+    let mut matcher = Matcher::new(s, pattern);
+    for position in start..=s.len() {
+        if let Some(end) = matcher.matches(position, anchored as usize)? {
+            if !is_find {
+                return matcher.captures(position, end, true);
+            }
+            let mut values = vec![
+                Value::Integer(position as i64 + 1),
+                Value::Integer(end as i64),
+            ];
+            values.extend(matcher.captures(position, end, false)?);
+            return Ok(values);
+        }
+        if anchored {
+            break;
+        }
+    }
+    Ok(vec![Value::Nil])
+}
+
+pub fn find(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    find_match(parameters, "find", true)
+}
+
+pub fn r#match(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    find_match(parameters, "match", false)
+}
+
+/// The native part of `gmatch`. Gives the position to start from when called
+/// with the initial position, then the next match after `position`, unless
+/// it ends at `last` like the previous one.
+pub fn gmatch_step(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let s = check_string(&parameters, 1, "gmatch")?;
+    let pattern = check_string(&parameters, 2, "gmatch")?;
+    let position = optional_integer(&parameters, 3, "gmatch", 1)?;
+    let position = start_position(position, s.len()).min(s.len() + 1) - 1;
+    if parameters.len() < 4 {
+        return Ok(vec![Value::Integer(position as i64 + 1)]);
+    }
+    let last = argument(&parameters, 4)
+        .to_integer()
+        .map(|last| last as usize);
+    let mut matcher = Matcher::new(s.as_bytes(), pattern.as_bytes());
+    for start in position..=s.len() {
+        match matcher.matches(start, 0)? {
+            Some(end) if Some(end) != last => {
+                let mut values = vec![Value::Integer(end as i64 + 1), Value::Integer(end as i64)];
+                values.extend(matcher.captures(start, end, true)?);
+                return Ok(values);
+            }
+            _ => {}
+        }
+    }
+    Ok(vec![])
+}
+
+/// Adds the replacement of the match from `start` to `end` to `result`,
+/// giving whether it changed anything.
+fn add_replacement(
+    interpreter: &mut Interpreter,
+    matcher: &Matcher,
+    replacement: &Value,
+    source: &[u8],
+    (start, end): (usize, usize),
+    result: &mut Vec<u8>,
+) -> Result<bool, LuaError> {
+    let value = match replacement {
+        Value::Table(_) => {
+            let key = matcher.capture(0, start, end)?;
+            interpreter.index(replacement.clone(), key)?
+        }
+        Value::Lambda(_) | Value::Builtin(_) | Value::Native(_) => {
+            let captures = matcher.captures(start, end, true)?;
+            let values = interpreter.call(replacement.clone(), captures)?;
+            values.into_iter().next().unwrap_or(Value::Nil)
+        }
+        _ => {
+            let replacement = replacement.to_lua_string().unwrap_or_default();
+            let mut bytes = replacement.iter().copied();
+            while let Some(c) = bytes.next() {
+                if c != b'%' {
+                    result.push(c);
+                    continue;
+                }
+                match bytes.next() {
+                    Some(b'%') => result.push(b'%'),
+                    Some(b'0') => result.extend_from_slice(&source[start..end]),
+                    Some(digit @ b'1'..=b'9') => {
+                        let capture = matcher.capture((digit - b'1') as usize, start, end)?;
+                        result.extend_from_slice(&capture.to_lua_string().unwrap_or_default());
+                    }
+                    _ => return Err(LuaError::new("invalid use of '%' in replacement string")),
+                }
+            }
+            return Ok(true);
+        }
+    };
+    match value {
+        Value::Nil | Value::False => {
+            result.extend_from_slice(&source[start..end]);
+            Ok(false)
+        }
+        value => match value.to_lua_string() {
+            Some(s) => {
+                result.extend_from_slice(&s);
+                Ok(true)
+            }
+            None => Err(LuaError::new(format!(
+                "invalid replacement value (a {})",
+                value.type_name()
+            ))),
+        },
+    }
+}
+
+pub fn gsub(interpreter: &mut Interpreter, parameters: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let s = check_string(&parameters, 1, "gsub")?;
+    let pattern = check_string(&parameters, 2, "gsub")?;
+    let replacement = argument(&parameters, 3);
+    if !matches!(
+        replacement,
+        Value::Integer(_)
+            | Value::Float(_)
+            | Value::String(_)
+            | Value::Table(_)
+            | Value::Lambda(_)
+            | Value::Builtin(_)
+            | Value::Native(_)
+    ) {
+        return Err(argument_error(
+            3,
+            "gsub",
+            &format!(
+                "string/function/table expected, got {}",
+                type_name(&parameters, 3, &replacement)
+            ),
+        ));
+    }
+    let maximum = optional_integer(&parameters, 4, "gsub", s.len() as i64 + 1)?;
+    let (source, pattern) = (s.as_bytes(), pattern.as_bytes());
+    let anchored = pattern.first() == Some(&b'^');
+    let mut matcher = Matcher::new(source, pattern);
+    let mut result = Vec::with_capacity(source.len());
+    let (mut position, mut last, mut count, mut changed) = (0, None, 0, false);
+    while count < maximum {
+        match matcher.matches(position, anchored as usize)? {
+            Some(end) if Some(end) != last => {
+                count += 1;
+                let range = (position, end);
+                changed |= add_replacement(
+                    interpreter,
+                    &matcher,
+                    &replacement,
+                    source,
+                    range,
+                    &mut result,
+                )?;
+                position = end;
+                last = Some(end);
+            }
+            _ if position < source.len() => {
+                result.push(source[position]);
+                position += 1;
+            }
+            _ => break,
+        }
+        if anchored {
+            break;
+        }
+    }
+    let result = if changed {
+        result.extend_from_slice(&source[position..]);
+        string_value(&result)
+    } else {
+        Value::String(s)
+    };
+    Ok(vec![result, Value::Integer(count)])
+}
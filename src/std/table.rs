@@ -0,0 +1,374 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::interpreter::{
+    value::{Table, Value},
+    Interpreter, LuaError,
+};
+
+use super::{
+    argument, argument_error, check_integer, check_string, check_table, optional_integer, type_name,
+};
+
+const INDEX: &[&str] = &["__index"];
+const NEW_INDEX: &[&str] = &["__newindex"];
+const INDEX_LENGTH: &[&str] = &["__index", "__len"];
+const ALL: &[&str] = &["__index", "__newindex", "__len"];
+
+/// Most values a function can return at once.
+const MAXIMUM_RESULTS: i64 = 1_000_000;
+/// Sorted intervals shorter than this always use their middle as pivot.
+const RANDOM_LIMIT: i64 = 100;
+
+/// Checks that the argument is a table, or has a metatable with all the
+/// `events` a table operation needs.
+fn check_table_like(
+    interpreter: &Interpreter,
+    parameters: &[Value],
+    position: usize,
+    name: &str,
+    events: &[&str],
+) -> Result<Value, LuaError> {
+    let value = argument(parameters, position);
+    let is_table_like = match &value {
+        Value::Table(_) => true,
+        value => {
+            interpreter.metatable(value).is_some()
+                && events
+                    .iter()
+                    .all(|event| !interpreter.metamethod(value, event).is_nil())
+        }
+    };
+    if !is_table_like {
+        check_table(parameters, position, name)?;
+    }
+    Ok(value)
+}
+
+/// The length of a table argument, which may come from `__len`.
+fn length(interpreter: &mut Interpreter, table: &Value) -> Result<i64, LuaError> {
+    match interpreter.length(table.clone())? {
+        Value::Integer(n) => Ok(n),
+        _ => Err(LuaError::new("object length is not an integer")),
+    }
+}
+
+fn get(interpreter: &mut Interpreter, table: &Value, i: i64) -> Result<Value, LuaError> {
+    interpreter.index(table.clone(), Value::Integer(i))
+}
+
+fn set(interpreter: &mut Interpreter, table: &Value, i: i64, value: Value) -> Result<(), LuaError> {
+    interpreter.set_index(table.clone(), Value::Integer(i), value)
+}
+
+pub fn insert(
+    interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let table = check_table_like(interpreter, &parameters, 1, "insert", ALL)?;
+    // The first empty position
+    let end = length(interpreter, &table)?.wrapping_add(1);
+    let position = match parameters.len() {
+        2 => end,
+        3 => {
+            let position = check_integer(&parameters, 2, "insert")?;
+            // Also rejects non-positive positions, which wrap around
+            if (position as u64).wrapping_sub(1) >= end as u64 {
+                return Err(argument_error(2, "insert", "position out of bounds"));
+            }
+            for i in (position + 1..=end).rev() {
+                let value = get(interpreter, &table, i - 1)?;
+                set(interpreter, &table, i, value)?;
+            }
+            position
+        }
+        _ => return Err(LuaError::new("wrong number of arguments to 'insert'")),
+    };
+    let value = argument(&parameters, parameters.len());
+    set(interpreter, &table, position, value)?;
+    Ok(vec![])
+}
+
+pub fn remove(
+    interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let table = check_table_like(interpreter, &parameters, 1, "remove", ALL)?;
+    let size = length(interpreter, &table)?;
+    let mut position = optional_integer(&parameters, 2, "remove", size)?;
+    // The position just after the end is allowed, like the end of an empty table
+    if position != size && (position as u64).wrapping_sub(1) > size as u64 {
+        return Err(argument_error(2, "remove", "position out of bounds"));
+    }
+    let removed = get(interpreter, &table, position)?;
+    while position < size {
+        let value = get(interpreter, &table, position + 1)?;
+        set(interpreter, &table, position, value)?;
+        position += 1;
+    }
+    set(interpreter, &table, position, Value::Nil)?;
+    Ok(vec![removed])
+}
+
+pub fn concat(
+    interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let table = check_table_like(interpreter, &parameters, 1, "concat", INDEX_LENGTH)?;
+    let size = length(interpreter, &table)?;
+    let separator = match argument(&parameters, 2) {
+        Value::Nil => Default::default(),
+        _ => check_string(&parameters, 2, "concat")?,
+    };
+    let first = optional_integer(&parameters, 3, "concat", 1)?;
+    let last = optional_integer(&parameters, 4, "concat", size)?;
+    let mut result = vec![];
+    let mut i = first;
+    while i <= last {
+        let Some(s) = get(interpreter, &table, i)?.to_lua_string() else {
+            return Err(LuaError::new(format!(
+                "invalid value (at index {i}) in table for 'concat'"
+            )));
+        };
+        result.extend_from_slice(&s);
+        if i == last {
+            break;
+        }
+        result.extend_from_slice(&separator);
+        i += 1;
+    }
+    Ok(vec![Value::String(result.into())])
+}
+
+pub fn r#move(
+    interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let first = check_integer(&parameters, 2, "move")?;
+    let end = check_integer(&parameters, 3, "move")?;
+    let target = check_integer(&parameters, 4, "move")?;
+    let destination_position = if argument(&parameters, 5).is_nil() {
+        1
+    } else {
+        5
+    };
+    let source = check_table_like(interpreter, &parameters, 1, "move", INDEX)?;
+    let destination = check_table_like(
+        interpreter,
+        &parameters,
+        destination_position,
+        "move",
+        NEW_INDEX,
+    )?;
+    if end >= first {
+        if first <= 0 && end >= i64::MAX + first {
+            return Err(argument_error(3, "move", "too many elements to move"));
+        }
+        let count = end - first + 1;
+        if target > i64::MAX - count + 1 {
+            return Err(argument_error(4, "move", "destination wrap around"));
+        }
+        let is_same = destination_position == 1 || interpreter.equals(&source, &destination)?;
+        // Overlapping moves to the right go backwards so nothing is overwritten
+        if target > end || target <= first || !is_same {
+            for i in 0..count {
+                let value = get(interpreter, &source, first + i)?;
+                set(interpreter, &destination, target + i, value)?;
+            }
+        } else {
+            for i in (0..count).rev() {
+                let value = get(interpreter, &source, first + i)?;
+                set(interpreter, &destination, target + i, value)?;
+            }
+        }
+    }
+    Ok(vec![destination])
+}
+
+pub fn pack(interpreter: &mut Interpreter, parameters: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let mut table = Table::new();
+    let count = parameters.len() as i64;
+    for (i, value) in (1..).zip(parameters) {
+        table.insert(Value::Integer(i), value);
+    }
+    table.insert_str("n", Value::Integer(count));
+    Ok(vec![interpreter.new_table(table)])
+}
+
+pub fn unpack(
+    interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let table = argument(&parameters, 1);
+    let first = optional_integer(&parameters, 2, "unpack", 1)?;
+    let last = match argument(&parameters, 3) {
+        Value::Nil => length(interpreter, &table)?,
+        _ => check_integer(&parameters, 3, "unpack")?,
+    };
+    if first > last {
+        return Ok(vec![]);
+    }
+    if (last as u64).wrapping_sub(first as u64) >= MAXIMUM_RESULTS as u64 {
+        return Err(LuaError::new("too many results to unpack"));
+    }
+    (first..=last)
+        .map(|i| get(interpreter, &table, i))
+        .collect()
+}
+
+/// The state of `table.sort`, a quicksort working through the metamethods of
+/// the table.
+struct Sorter<'a> {
+    interpreter: &'a mut Interpreter,
+    table: Value,
+    comparator: Value,
+}
+
+impl Sorter<'_> {
+    fn get(&mut self, i: i64) -> Result<Value, LuaError> {
+        get(self.interpreter, &self.table, i)
+    }
+
+    fn set(&mut self, i: i64, value: Value) -> Result<(), LuaError> {
+        set(self.interpreter, &self.table, i, value)
+    }
+
+    fn less_than(&mut self, a: &Value, b: &Value) -> Result<bool, LuaError> {
+        if self.comparator.is_nil() {
+            return self.interpreter.less_than(a, b);
+        }
+        let values = self
+            .interpreter
+            .call(self.comparator.clone(), vec![a.clone(), b.clone()])?;
+        Ok(values.first().is_some_and(Value::is_truthy))
+    }
+
+    /// Swaps the elements at `i` and `j`, given their values.
+    fn swap(&mut self, i: i64, a: Value, j: i64, b: Value) -> Result<(), LuaError> {
+        self.set(i, b)?;
+        self.set(j, a)
+    }
+
+    /// Partitions `lo..=up` around the pivot `pivot`, which is at `up - 1`,
+    /// giving its final position.
+    fn partition(&mut self, lo: i64, up: i64, pivot: &Value) -> Result<i64, LuaError> {
+        let (mut i, mut j) = (lo, up - 1);
+        loop {
+            // An inconsistent comparator could run past the ends
+            let a = loop {
+                i += 1;
+                let a = self.get(i)?;
+                if !self.less_than(&a, pivot)? {
+                    break a;
+                }
+                if i == up - 1 {
+                    return Err(LuaError::new("invalid order function for sorting"));
+                }
+            };
+            let b = loop {
+                j -= 1;
+                let b = self.get(j)?;
+                if !self.less_than(pivot, &b)? {
+                    break b;
+                }
+                if j < i {
+                    return Err(LuaError::new("invalid order function for sorting"));
+                }
+            };
+            if j < i {
+                self.set(up - 1, a)?;
+                self.set(i, pivot.clone())?;
+                return Ok(i);
+            }
+            self.swap(i, a, j, b)?;
+        }
+    }
+
+    fn sort(&mut self, mut lo: i64, mut up: i64, mut seed: u32) -> Result<(), LuaError> {
+        while lo < up {
+            let (a, b) = (self.get(lo)?, self.get(up)?);
+            if self.less_than(&b, &a)? {
+                self.swap(lo, a, up, b)?;
+            }
+            if up - lo == 1 {
+                break;
+            }
+            let p = if up - lo < RANDOM_LIMIT || seed == 0 {
+                (lo + up) / 2
+            } else {
+                let quarter = (up - lo) / 4;
+                seed as i64 % (quarter * 2) + lo + quarter
+            };
+            let (a, b) = (self.get(p)?, self.get(lo)?);
+            if self.less_than(&a, &b)? {
+                self.swap(p, a, lo, b)?;
+            } else {
+                let c = self.get(up)?;
+                if self.less_than(&c, &a)? {
+                    self.swap(p, a, up, c)?;
+                }
+            }
+            if up - lo == 2 {
+                break;
+            }
+            let pivot = self.get(p)?;
+            let b = self.get(up - 1)?;
+            self.swap(p, pivot.clone(), up - 1, b)?;
+            let p = self.partition(lo, up, &pivot)?;
+            // Recurse on the smaller side and loop on the larger one
+            let smaller = if p - lo < up - p {
+                self.sort(lo, p - 1, seed)?;
+                let smaller = p - lo;
+                lo = p + 1;
+                smaller
+            } else {
+                self.sort(p + 1, up, seed)?;
+                let smaller = up - p;
+                up = p - 1;
+                smaller
+            };
+            if (up - lo) / 128 > smaller {
+                seed = random_seed();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A seed to choose pivots at random when the partitions are unbalanced.
+fn random_seed() -> u32 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now.subsec_nanos() ^ now.as_secs() as u32
+}
+
+pub fn sort(interpreter: &mut Interpreter, parameters: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let table = check_table_like(interpreter, &parameters, 1, "sort", ALL)?;
+    let size = length(interpreter, &table)?;
+    if size > 1 {
+        if size >= i32::MAX as i64 {
+            return Err(argument_error(1, "sort", "array too big"));
+        }
+        let comparator = argument(&parameters, 2);
+        if !matches!(
+            comparator,
+            Value::Nil | Value::Lambda(_) | Value::Builtin(_) | Value::Native(_)
+        ) {
+            return Err(argument_error(
+                2,
+                "sort",
+                &format!(
+                    "function expected, got {}",
+                    type_name(&parameters, 2, &comparator)
+                ),
+            ));
+        }
+        let mut sorter = Sorter {
+            interpreter,
+            table,
+            comparator,
+        };
+        sorter.sort(1, size, 0)?;
+    }
+    Ok(vec![])
+}
@@ -0,0 +1,249 @@
+//! The `utf8` library. Strings are decoded as Lua does, which also accepts
+//! the sequences of up to six bytes of the original UTF-8 when lax.
+
+use crate::{
+    interpreter::{value::Value, Interpreter, LuaError},
+    parser::expression::utf8_encode,
+};
+
+use super::{argument, argument_error, check_integer, check_string, optional_integer, Builtin};
+
+/// The pattern matching exactly one UTF-8 sequence.
+pub const CHAR_PATTERN: &[u8] = b"[\0-\x7F\xC2-\xFD][\x80-\xBF]*";
+
+/// The largest value of the extended encoding.
+const MAXIMUM_UTF: u32 = 0x7fff_ffff;
+/// The largest code point of Unicode, the limit of strict decoding.
+const MAXIMUM_UNICODE: u32 = 0x10_ffff;
+
+const INVALID: &str = "invalid UTF-8 code";
+
+fn is_continuation(s: &[u8], i: usize) -> bool {
+    s.get(i).is_some_and(|c| c & 0xc0 == 0x80)
+}
+
+/// Decodes the sequence at the start of `s`, giving its code point and its
+/// length. Strict decoding rejects surrogates and values beyond Unicode.
+fn decode(s: &[u8], strict: bool) -> Option<(u32, usize)> {
+    // The smallest value of each length, to reject overlong encodings
+    const LIMITS: [u32; 6] = [u32::MAX, 0x80, 0x800, 0x1_0000, 0x20_0000, 0x400_0000];
+    let mut c = *s.first()? as u32;
+    let mut code = 0;
+    let mut count = 0;
+    if c < 0x80 {
+        code = c;
+    } else {
+        while c & 0x40 != 0 {
+            count += 1;
+            let continuation = *s.get(count)? as u32;
+            if continuation & 0xc0 != 0x80 {
+                return None;
+            }
+            code = (code << 6) | (continuation & 0x3f);
+            c <<= 1;
+        }
+        if count > 5 {
+            return None;
+        }
+        code |= (c & 0x7f) << (count * 5);
+        if code > MAXIMUM_UTF || code < LIMITS[count] {
+            return None;
+        }
+    }
+    if strict && (code > MAXIMUM_UNICODE || (0xd800..=0xdfff).contains(&code)) {
+        return None;
+    }
+    Some((code, count + 1))
+}
+
+/// Converts a position that may count from the end to one from the start.
+fn relative_position(position: i64, length: usize) -> i64 {
+    if position >= 0 {
+        position
+    } else if position.unsigned_abs() > length as u64 {
+        0
+    } else {
+        length as i64 + position + 1
+    }
+}
+
+pub fn char(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let mut result = vec![];
+    for position in 1..=parameters.len() {
+        let code = check_integer(&parameters, position, "char")?;
+        if code as u64 > MAXIMUM_UTF as u64 {
+            return Err(argument_error(position, "char", "value out of range"));
+        }
+        result.extend(utf8_encode(code as u32));
+    }
+    Ok(vec![Value::String(result.into())])
+}
+
+pub fn codes(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let s = check_string(&parameters, 1, "codes")?;
+    if is_continuation(&s, 0) {
+        return Err(argument_error(1, "codes", INVALID));
+    }
+    let step = if argument(&parameters, 2).is_truthy() {
+        Builtin::Utf8CodesStepLax
+    } else {
+        Builtin::Utf8CodesStep
+    };
+    Ok(vec![
+        Value::Builtin(step),
+        Value::String(s),
+        Value::Integer(0),
+    ])
+}
+
+pub fn codes_step(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    step(&parameters, true)
+}
+
+pub fn codes_step_lax(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    step(&parameters, false)
+}
+
+/// The iterator of `codes`, giving the position and code point of the
+/// sequence after the one at the control position.
+fn step(parameters: &[Value], strict: bool) -> Result<Vec<Value>, LuaError> {
+    let s = check_string(parameters, 1, "codes")?;
+    // Negative positions wrap around, ending the loop
+    let mut i = argument(parameters, 2).to_integer().unwrap_or(0) as u64 as usize;
+    if i < s.len() {
+        while is_continuation(&s, i) {
+            i += 1;
+        }
+    }
+    if i >= s.len() {
+        return Ok(vec![]);
+    }
+    match decode(&s[i..], strict) {
+        Some((code, length)) if !is_continuation(&s, i + length) => Ok(vec![
+            Value::Integer(i as i64 + 1),
+            Value::Integer(code as i64),
+        ]),
+        _ => Err(LuaError::new(INVALID)),
+    }
+}
+
+pub fn codepoint(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let s = check_string(&parameters, 1, "codepoint")?;
+    let first = relative_position(optional_integer(&parameters, 2, "codepoint", 1)?, s.len());
+    let last = relative_position(
+        optional_integer(&parameters, 3, "codepoint", first)?,
+        s.len(),
+    );
+    let strict = !argument(&parameters, 4).is_truthy();
+    if first < 1 {
+        return Err(argument_error(2, "codepoint", "out of bounds"));
+    }
+    if last > s.len() as i64 {
+        return Err(argument_error(3, "codepoint", "out of bounds"));
+    }
+    if first > last {
+        return Ok(vec![]);
+    }
+    if last - first >= i32::MAX as i64 {
+        return Err(LuaError::new("string slice too long"));
+    }
+    let mut codes = vec![];
+    let mut i = first as usize - 1;
+    while i < last as usize {
+        let Some((code, length)) = decode(&s[i..], strict) else {
+            return Err(LuaError::new(INVALID));
+        };
+        codes.push(Value::Integer(code as i64));
+        i += length;
+    }
+    Ok(codes)
+}
+
+pub fn len(_interpreter: &mut Interpreter, parameters: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+    let s = check_string(&parameters, 1, "len")?;
+    let first = relative_position(optional_integer(&parameters, 2, "len", 1)?, s.len());
+    let last = relative_position(optional_integer(&parameters, 3, "len", -1)?, s.len());
+    let strict = !argument(&parameters, 4).is_truthy();
+    if first < 1 || first - 1 > s.len() as i64 {
+        return Err(argument_error(2, "len", "initial position out of bounds"));
+    }
+    if last > s.len() as i64 {
+        return Err(argument_error(3, "len", "final position out of bounds"));
+    }
+    let mut i = first - 1;
+    let mut count = 0;
+    while i < last {
+        match decode(&s[i as usize..], strict) {
+            Some((_, length)) => i += length as i64,
+            // Fails with the position of the first invalid byte
+            None => return Ok(vec![Value::Nil, Value::Integer(i + 1)]),
+        }
+        count += 1;
+    }
+    Ok(vec![Value::Integer(count)])
+}
+
+pub fn offset(
+    _interpreter: &mut Interpreter,
+    parameters: Vec<Value>,
+) -> Result<Vec<Value>, LuaError> {
+    let s = check_string(&parameters, 1, "offset")?;
+    let mut n = check_integer(&parameters, 2, "offset")?;
+    let default = if n >= 0 { 1 } else { s.len() as i64 + 1 };
+    let position = relative_position(
+        optional_integer(&parameters, 3, "offset", default)?,
+        s.len(),
+    );
+    if position < 1 || position - 1 > s.len() as i64 {
+        return Err(argument_error(3, "offset", "position out of bounds"));
+    }
+    let mut i = position as usize - 1;
+    if n == 0 {
+        // The start of the sequence holding the byte at `i`
+        while i > 0 && is_continuation(&s, i) {
+            i -= 1;
+        }
+    } else {
+        if is_continuation(&s, i) {
+            return Err(LuaError::new("initial position is a continuation byte"));
+        }
+        if n < 0 {
+            while n < 0 && i > 0 {
+                i -= 1;
+                while i > 0 && is_continuation(&s, i) {
+                    i -= 1;
+                }
+                n += 1;
+            }
+        } else {
+            n -= 1;
+            while n > 0 && i < s.len() {
+                i += 1;
+                while is_continuation(&s, i) {
+                    i += 1;
+                }
+                n -= 1;
+            }
+        }
+    }
+    if n == 0 {
+        Ok(vec![Value::Integer(i as i64 + 1)])
+    } else {
+        Ok(vec![Value::Nil])
+    }
+}
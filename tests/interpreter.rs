@@ -0,0 +1,1672 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    rc::Rc,
+};
+
+use lust::{
+    interpreter::{
+        convert::Variadic,
+        value::{NativeFunction, Table, Value},
+        Interpreter, LuaError,
+    },
+    parser::{ast::build_ast, LuaParser, Rule},
+};
+use pest::Parser;
+use pretty_assertions::assert_eq;
+
+fn run(code: &str) -> Result<Vec<Value>, LuaError> {
+    let mut pairs = LuaParser::parse(Rule::Chunk, code).unwrap();
+    let ast = build_ast(&mut pairs);
+    Interpreter::new().interpret(&ast)
+}
+
+fn string(s: &str) -> Value {
+    Value::String(s.into())
+}
+
+fn error_message(code: &str) -> String {
+    run(code).unwrap_err().to_string()
+}
+
+#[test]
+fn const_variables() {
+    assert_eq!(
+        run("local x <const> = 1; return x + 1").unwrap(),
+        [Value::Integer(2)]
+    );
+    assert_eq!(
+        error_message("local x <const> = 1; x = 2"),
+        "attempt to assign to const variable 'x'"
+    );
+    assert_eq!(
+        error_message("local x <const> = 1; local function f() x = 2 end"),
+        "attempt to assign to const variable 'x'"
+    );
+    assert_eq!(
+        error_message("local x <close> = nil; x = 2"),
+        "attempt to assign to const variable 'x'"
+    );
+}
+
+#[test]
+fn unknown_attributes() {
+    assert_eq!(
+        error_message("local x <foo> = 1"),
+        "unknown attribute 'foo'"
+    );
+    assert_eq!(
+        error_message("local x <close>, y <close> = nil"),
+        "multiple to-be-closed variables in local list"
+    );
+}
+
+const CLOSER: &str = "
+    local log = {}
+    local function closer(name)
+        return setmetatable({}, {__close = function(_, err)
+            log[#log + 1] = name
+            log[#log + 1] = err or 'ok'
+        end})
+    end
+";
+
+fn closed(code: &str) -> Vec<Value> {
+    let code = format!("{CLOSER} {code} return log");
+    let Value::Table(log) = run(&code).unwrap().remove(0) else {
+        panic!("Expected log table");
+    };
+    let log = log.borrow();
+    (1..=log.length())
+        .map(|i| log.get(&Value::Integer(i)))
+        .collect()
+}
+
+#[test]
+fn close_in_reverse_order() {
+    let log = closed(
+        "do
+            local a <close> = closer('a')
+            local b <close> = closer('b')
+            local c <close> = nil
+        end",
+    );
+    assert_eq!(log, [string("b"), string("ok"), string("a"), string("ok")]);
+}
+
+#[test]
+fn close_on_jumps() {
+    let log = closed(
+        "for i = 1, 3 do
+            local x <close> = closer('break')
+            if i == 1 then break end
+        end
+        do
+            local y <close> = closer('goto')
+            goto out
+        end
+        ::out::
+        local function f()
+            local z <close> = closer('return')
+            return 1
+        end
+        f()",
+    );
+    let expected = ["break", "ok", "goto", "ok", "return", "ok"];
+    assert_eq!(log, expected.map(string));
+}
+
+#[test]
+fn close_on_error() {
+    let log = closed(
+        "pcall(function()
+            local a <close> = closer('a')
+            local b <close> = setmetatable({}, {__close = function() error('replaced') end})
+            error('first')
+        end)",
+    );
+    assert_eq!(log, [string("a"), string("replaced")]);
+    assert_eq!(
+        error_message("local x <close> = {}"),
+        "variable 'x' got a non-closable value"
+    );
+    assert_eq!(
+        error_message("for i in print, {}, nil, 5 do end"),
+        "variable '(for state)' got a non-closable value"
+    );
+}
+
+#[test]
+fn tail_calls() {
+    let code = "
+        local function even(n) if n == 0 then return true end return odd(n - 1) end
+        function odd(n) if n == 0 then return false end return even(n - 1) end
+        return even(1000000), odd(7)
+    ";
+    assert_eq!(run(code).unwrap(), [Value::True, Value::True]);
+    let code = "
+        local function count(n) if n > 0 then return count(n - 1) end return 'done' end
+        local callable = setmetatable({}, {__call = function(_, x) return x end})
+        local function call(x) return callable(x) end
+        return count(500000), call(3)
+    ";
+    assert_eq!(run(code).unwrap(), [string("done"), Value::Integer(3)]);
+}
+
+#[test]
+fn stack_overflow() {
+    let code = "
+        local function f() return 1 + f() end
+        local function g() return pcall(g) end
+        local t = setmetatable({}, {__index = function(t, k) return t[k] end})
+        local results = {g()}
+        local _, overflow = pcall(f)
+        local _, index_overflow = pcall(function() return t.x end)
+        return overflow, results[#results], index_overflow
+    ";
+    let values = run(code).unwrap();
+    assert_eq!(
+        values,
+        ["stack overflow", "C stack overflow", "C stack overflow"].map(string)
+    );
+}
+
+#[test]
+fn call_depth_limit() {
+    let code = "
+        local depth = 0
+        local function f() depth = depth + 1; f() end
+        pcall(f)
+        return depth
+    ";
+    let mut pairs = LuaParser::parse(Rule::Chunk, code).unwrap();
+    let ast = build_ast(&mut pairs);
+    let mut interpreter = Interpreter::new();
+    interpreter.set_call_depth_limit(100);
+    // The main chunk and pcall's function take one level each
+    assert_eq!(interpreter.interpret(&ast).unwrap(), [Value::Integer(99)]);
+}
+
+#[test]
+fn coroutines() {
+    let code = "
+        local log = {}
+        local co = coroutine.create(function(a, b)
+            local function inner(x) return coroutine.yield(x) end
+            local c = inner(a + b)
+            local ok, err = pcall(function()
+                error(coroutine.yield(c))
+            end)
+            return ok, err, coroutine.status(coroutine.running())
+        end)
+        log[#log + 1] = coroutine.status(co)
+        local _, sum = coroutine.resume(co, 1, 2)
+        local _, c = coroutine.resume(co, 'c')
+        local _, ok, err, status = coroutine.resume(co, 'raised')
+        local _, dead = coroutine.resume(co)
+        return sum, c, ok, err, status, coroutine.status(co), dead
+    ";
+    assert_eq!(
+        run(code).unwrap(),
+        [
+            Value::Integer(3),
+            string("c"),
+            Value::False,
+            string("raised"),
+            string("running"),
+            string("dead"),
+            string("cannot resume dead coroutine"),
+        ]
+    );
+}
+
+#[test]
+fn coroutine_wrap_and_close() {
+    let code = "
+        local sum = 0
+        for i in coroutine.wrap(function() for i = 1, 4 do coroutine.yield(i) end end) do
+            sum = sum + i
+        end
+        local closed = false
+        local co = coroutine.create(function()
+            local x <close> = setmetatable({}, {__close = function() closed = true end})
+            coroutine.yield()
+        end)
+        coroutine.resume(co)
+        local failing = coroutine.wrap(function() error('inside') end)
+        return sum, coroutine.close(co), closed, coroutine.status(co), pcall(failing)
+    ";
+    assert_eq!(
+        run(code).unwrap(),
+        [
+            Value::Integer(10),
+            Value::True,
+            Value::True,
+            string("dead"),
+            Value::False,
+            string("inside"),
+        ]
+    );
+}
+
+#[test]
+fn yield_through_pcall() {
+    let code = "
+        local f = coroutine.wrap(function(v) return pcall(coroutine.yield, v) end)
+        local g = coroutine.wrap(function(v) return pcall(pcall, coroutine.yield, v) end)
+        f('a')
+        g('b')
+        local ok, x = f('x')
+        return ok, x, g('y', 'z')
+    ";
+    assert_eq!(
+        run(code).unwrap(),
+        [
+            Value::True,
+            string("x"),
+            Value::True,
+            Value::True,
+            string("y"),
+            string("z"),
+        ]
+    );
+}
+
+#[test]
+fn yield_in_metamethods() {
+    let code = "
+        local mt = {
+            __index = function(t, k) return coroutine.yield(k) end,
+            __newindex = function(t, k, v) rawset(t, k, coroutine.yield(v)) end,
+            __lt = function() return coroutine.yield('lt') end,
+            __eq = function() return coroutine.yield('eq') end,
+            __concat = function() return coroutine.yield('concat') end,
+            __close = function() coroutine.yield('close') end,
+        }
+        local a, b = setmetatable({}, mt), setmetatable({}, mt)
+        local object = setmetatable({}, {__index = function(t, k)
+            coroutine.yield(k)
+            return function(self, x) return rawequal(self, t), x end
+        end})
+        local co = coroutine.wrap(function()
+            local x = a.x
+            a.y = 'set'
+            local less, different = a < b, a ~= b
+            local concatenated = a .. b
+            local is_self, argument = object:method('argument')
+            do local c <close> = a end
+            return 'done', x, a.y, less, different, concatenated, is_self, argument
+        end)
+        local yielded = {}
+        local reply = {x = 'index', set = 'new', lt = 1, eq = false, concat = 'both'}
+        local results = {co()}
+        while results[1] ~= 'done' do
+            yielded[#yielded + 1] = results[1]
+            results = {co(reply[results[1]])}
+        end
+        return table.concat(yielded, ' '), table.unpack(results, 2)
+    ";
+    assert_eq!(
+        run(code).unwrap(),
+        [
+            string("x set lt eq concat method close"),
+            string("index"),
+            string("new"),
+            Value::True,
+            Value::True,
+            string("both"),
+            Value::True,
+            string("argument"),
+        ]
+    );
+}
+
+#[test]
+fn yield_errors() {
+    let code = "
+        local function sort() table.sort({3, 2, 1}, function() coroutine.yield() end) end
+        local _, across = coroutine.resume(coroutine.create(sort))
+        local _, outside = pcall(coroutine.yield)
+        return across, outside, coroutine.isyieldable()
+    ";
+    assert_eq!(
+        run(code).unwrap(),
+        [
+            string("attempt to yield across a C-call boundary"),
+            string("attempt to yield from outside a coroutine"),
+            Value::False,
+        ]
+    );
+}
+
+#[test]
+fn garbage_collection() {
+    let code = "
+        collectgarbage('stop')
+        local before = collectgarbage('count')
+        for i = 1, 1000 do
+            local a, b = {}, {}
+            a.b, b.a = b, a
+            local function f() return f end
+        end
+        local grown = collectgarbage('count')
+        local kept = {}
+        kept.self = kept
+        local co = coroutine.wrap(function(x)
+            local t = {x}
+            t.t = t
+            coroutine.yield()
+            return t.t[1]
+        end)
+        co('alive')
+        collectgarbage()
+        local collected = collectgarbage('count')
+        return grown > before, collected < grown, kept.self == kept, co(), collectgarbage('isrunning')
+    ";
+    assert_eq!(
+        run(code).unwrap(),
+        [
+            Value::True,
+            Value::True,
+            Value::True,
+            string("alive"),
+            Value::False
+        ]
+    );
+    let code = "
+        local modes = {collectgarbage('generational'), collectgarbage('incremental', 100)}
+        local _, invalid = pcall(collectgarbage, 'x')
+        return modes[1], modes[2], collectgarbage('step'), collectgarbage(), invalid
+    ";
+    assert_eq!(
+        run(code).unwrap(),
+        [
+            string("incremental"),
+            string("generational"),
+            Value::True,
+            Value::Integer(0),
+            string("bad argument #1 to 'collectgarbage' (invalid option 'x')"),
+        ]
+    );
+}
+
+#[test]
+fn weak_tables() {
+    let code = "
+        local keys = setmetatable({}, {__mode = 'k'})
+        local values = setmetatable({}, {__mode = 'v'})
+        local alive = {}
+        keys[alive], keys[{}] = 'key', 'dead'
+        values[1], values[2], values.f, values.s = alive, {}, function() end, 'string'
+        local ephemerons = setmetatable({}, {__mode = 'k'})
+        local first = {}
+        do
+            local second = {}
+            ephemerons[first], ephemerons[second] = second, 'chained'
+            local cycle = {}
+            ephemerons[cycle] = {cycle}
+        end
+        collectgarbage()
+        return keys[alive], values[1] == alive, values[2], values.f, values.s,
+            ephemerons[ephemerons[first]]
+    ";
+    assert_eq!(
+        run(code).unwrap(),
+        [
+            string("key"),
+            Value::True,
+            Value::Nil,
+            Value::Nil,
+            string("string"),
+            string("chained"),
+        ]
+    );
+}
+
+#[test]
+fn long_chains() {
+    // Freeing a chain releases its links one after the other, not recursively
+    let mut list = Value::Nil;
+    for _ in 0..1_000_000 {
+        let mut node = Table::new();
+        node.insert_str("next", list);
+        list = Value::Table(Rc::new(RefCell::new(node)));
+    }
+    drop(list);
+    let code = "
+        local f
+        for i = 1, 200000 do
+            local g = f
+            f = function() return g end
+        end
+        f = nil
+        return true
+    ";
+    assert_eq!(run(code).unwrap(), [Value::True]);
+}
+
+#[test]
+fn finalizers() {
+    let code = "
+        local log = {}
+        local mt = {__gc = function(o) log[#log + 1] = o.name end}
+        setmetatable({name = 'a'}, mt)
+        local alive = setmetatable({name = 'alive'}, mt)
+        local late = setmetatable({name = 'late'}, {})
+        getmetatable(late).__gc = mt.__gc
+        late = nil
+        local weak = setmetatable({}, {__mode = 'v'})
+        do
+            local cycle = setmetatable({name = 'cycle'}, mt)
+            cycle.self, weak[1] = cycle, cycle
+        end
+        setmetatable({}, {__gc = function(o) saved = o end})
+        setmetatable({}, {__gc = function() error('ignored') end})
+        collectgarbage()
+        local resurrected = saved ~= nil
+        saved = nil
+        collectgarbage()
+        return log[1], log[2], log[3], resurrected, weak[1]
+    ";
+    assert_eq!(
+        run(code).unwrap(),
+        [
+            string("cycle"),
+            string("a"),
+            Value::Nil,
+            Value::True,
+            Value::Nil
+        ]
+    );
+    // Finalizers left run when the interpreter is dropped
+    let code = "
+        local out = {}
+        setmetatable({}, {__gc = function() out.closed = true end})
+        return out
+    ";
+    let Value::Table(out) = run(code).unwrap().remove(0) else {
+        panic!("Expected table");
+    };
+    assert_eq!(out.borrow().get_str("closed"), Value::True);
+    // Objects marked again while closing are not finalized again
+    let code = "
+        local out = {calls = 0}
+        local mt = {}
+        mt.__gc = function(o) out.calls = out.calls + 1 setmetatable(o, mt) end
+        setmetatable({}, mt)
+        return out
+    ";
+    let Value::Table(out) = run(code).unwrap().remove(0) else {
+        panic!("Expected table");
+    };
+    assert_eq!(out.borrow().get_str("calls"), Value::Integer(1));
+    // Errors of finalizers are reported as warnings, once turned on
+    let mut repl = std::process::Command::new(env!("CARGO_BIN_EXE_repl"))
+        .stdin(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let code = "
+        setmetatable({}, {__gc = function() error('hidden') end})
+        collectgarbage()
+        warn('@on')
+        setmetatable({}, {__gc = function() error('shown', 0) end})
+        setmetatable({}, {__gc = function() error({}) end})
+        collectgarbage()
+    ";
+    std::io::Write::write_all(&mut repl.stdin.take().unwrap(), code.as_bytes()).unwrap();
+    let output = repl.wait_with_output().unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "Lua warning: error in __gc (error object is not a string)\n\
+         Lua warning: error in __gc (shown)\n"
+    );
+}
+
+#[test]
+fn global_environment() {
+    let code = "
+        x = 1
+        _G.y = 2
+        local function sandboxed()
+            local _ENV = {}
+            local function inner() z = 3 return z end
+            return inner(), _ENV.z
+        end
+        local function with(_ENV) return a end
+        local a, b = sandboxed()
+        return _G.x, y, _G._G == _G, _ENV == _G, a, b, z, with({a = 'field'})
+    ";
+    assert_eq!(
+        run(code).unwrap(),
+        [
+            Value::Integer(1),
+            Value::Integer(2),
+            Value::True,
+            Value::True,
+            Value::Integer(3),
+            Value::Integer(3),
+            Value::Nil,
+            string("field"),
+        ]
+    );
+    let code = "
+        setmetatable(_G, {__index = function(_, name) error('undefined global ' .. name) end})
+        local ok, err = pcall(function() return undefined end)
+        return err
+    ";
+    assert_eq!(run(code).unwrap(), [string("undefined global undefined")]);
+    let code = "
+        local names = {}
+        for name, value in pairs(_G) do
+            if value == pairs or value == _G then names[#names + 1] = name end
+        end
+        table.sort(names)
+        local proxy = setmetatable({}, {
+            __pairs = function(t) return next, {k = 'v'}, nil end,
+            __index = function() return 'default' end,
+        })
+        local step, state = pairs(proxy)
+        local k, v = step(state)
+        return table.concat(names, ' '), k, v, proxy.missing, rawget(proxy, 'missing'),
+            rawequal(proxy, proxy), rawlen({1, 2}), rawset(proxy, 'x', 1).x, next({})
+    ";
+    assert_eq!(
+        run(code).unwrap(),
+        [
+            string("_G pairs"),
+            string("k"),
+            string("v"),
+            string("default"),
+            Value::Nil,
+            Value::True,
+            Value::Integer(2),
+            Value::Integer(1),
+            Value::Nil,
+        ]
+    );
+    assert_eq!(
+        error_message("local _ENV = nil; print(1)"),
+        "attempt to index a nil value (local '_ENV')"
+    );
+}
+
+#[test]
+fn load_chunks() {
+    let code = "
+        local parts, i = {'return ', '...', ' + y'}, 0
+        local reader = load(function() i = i + 1 return parts[i] end, '=reader', 't', {y = 2})
+        return load('return 1 + ...')(2), reader(3), load('x =', '=chunk')
+    ";
+    assert_eq!(
+        run(code).unwrap(),
+        [
+            Value::Integer(3),
+            Value::Integer(5),
+            Value::Nil,
+            string("chunk:1: expected Expression near <eof>"),
+        ]
+    );
+    let code = "
+        local _, text = load('return 1', 'chunk', 'b')
+        local _, reader = load(function() return {} end)
+        local _, bad = pcall(load, 1)
+        return text, reader, bad
+    ";
+    assert_eq!(
+        run(code).unwrap(),
+        [
+            string("attempt to load a text chunk (mode is 'b')"),
+            string("reader function must return a string"),
+            string("bad argument #1 to 'load' (function expected, got number)"),
+        ]
+    );
+
+    let path = std::env::temp_dir().join(format!("lust-load-{}.lua", std::process::id()));
+    std::fs::write(&path, "#!/usr/bin/env lua\nreturn 10, ...").unwrap();
+    let path = path.to_str().unwrap();
+    let code = format!(
+        "
+        local a, b = dofile('{path}')
+        local f = loadfile('{path}')
+        return a, b, f(20), loadfile('{path}.missing')
+        "
+    );
+    let values = run(&code);
+    std::fs::remove_file(path).unwrap();
+    assert_eq!(
+        values.unwrap(),
+        [
+            Value::Integer(10),
+            Value::Nil,
+            Value::Integer(10),
+            Value::Nil,
+            string(&format!(
+                "cannot open {path}.missing (No such file or directory)"
+            )),
+        ]
+    );
+}
+
+#[test]
+fn nesting_limit() {
+    // Chunks just within the limit still compile and run
+    let code = r#"
+        local function run(code) return load(code)() end
+        return run("return " .. ("{"):rep(190) .. ("}"):rep(190)) ~= nil,
+            run("return " .. ("("):rep(190) .. "1" .. (")"):rep(190)),
+            run("return " .. ("- "):rep(190) .. "1"),
+            #run("return 'a'" .. (" .. 'a'"):rep(190)),
+            run(("do "):rep(190) .. "return 1" .. (" end"):rep(190)),
+            run("return " .. ("function() return "):rep(60) .. "1" .. (" end"):rep(60)) ~= nil
+    "#;
+    assert_eq!(
+        run(code).unwrap(),
+        [
+            Value::True,
+            Value::Integer(1),
+            Value::Integer(1),
+            Value::Integer(191),
+            Value::Integer(1),
+            Value::True,
+        ]
+    );
+    let code = r#"
+        local _, nested = load("return " .. ("{"):rep(5000) .. ("}"):rep(5000))
+        local _, negated = load("return " .. ("- "):rep(300) .. "1")
+        local _, sum = load("x = 1" .. ("\n+ 1"):rep(300))
+        return nested ~= nil, negated, sum
+    "#;
+    assert_eq!(
+        run(code).unwrap(),
+        [
+            Value::True,
+            string("[string \"return - - - - - - - - - - - - - - - - - - - ...\"]:1: chunk has too many C levels"),
+            string("[string \"x = 1...\"]:1: chunk has too many C levels"),
+        ]
+    );
+}
+
+#[test]
+fn require_modules() {
+    let directory = std::env::temp_dir().join(format!("lust-require-{}", std::process::id()));
+    std::fs::create_dir_all(directory.join("nested")).unwrap();
+    std::fs::write(
+        directory.join("counter.lua"),
+        "loads = (loads or 0) + 1 return {name = ...}",
+    )
+    .unwrap();
+    std::fs::write(directory.join("nested/init.lua"), "nested_name = ...").unwrap();
+    let root = directory.to_str().unwrap();
+    let code = format!(
+        "
+        package.path = '{root}/?.lua;{root}/?/init.lua'
+        local counter, file = require('counter')
+        local again = require('counter')
+        package.preload.virtual = function(name, data) return name .. data end
+        local _, missing = pcall(require, 'missing')
+        return counter.name, file, again == counter, loads, require('nested'), nested_name,
+            package.loaded.nested, require('virtual'), missing
+        "
+    );
+    let values = run(&code);
+    std::fs::remove_dir_all(&directory).unwrap();
+    assert_eq!(
+        values.unwrap(),
+        [
+            string("counter"),
+            string(&format!("{root}/counter.lua")),
+            Value::True,
+            Value::Integer(1),
+            Value::True,
+            string("nested"),
+            Value::True,
+            string("virtual:preload:"),
+            string(&format!(
+                "module 'missing' not found:\n\tno field package.preload['missing']\n\t\
+                 no file '{root}/missing.lua'\n\tno file '{root}/missing/init.lua'"
+            )),
+        ]
+    );
+    let code = "
+        package.searchers[#package.searchers + 1] = function(name)
+            return function(...) return {...} end, 'data'
+        end
+        local module = require('anything')
+        return module[1], module[2], package.searchpath('a.b', 'x/?.lua;y/?.so')
+    ";
+    assert_eq!(
+        run(code).unwrap(),
+        [
+            string("anything"),
+            string("data"),
+            Value::Nil,
+            string("no file 'x/a/b.lua'\n\tno file 'y/a/b.so'"),
+        ]
+    );
+}
+
+#[test]
+fn string_library() {
+    let code = "
+        local s = 'hello world'
+        return s:len(), s:sub(2), s:sub(-5), s:sub(2, -3), s:sub(-100, 3), s:sub(20),
+            ('abc'):upper(), string.lower('ABC'), s:rep(2, ', '), ('x'):rep(0),
+            s:reverse(), string.char(72, 105), s:byte(-1), s:byte(1, 3)
+    ";
+    assert_eq!(
+        run(code).unwrap(),
+        [
+            Value::Integer(11),
+            string("ello world"),
+            string("world"),
+            string("ello wor"),
+            string("hel"),
+            string(""),
+            string("ABC"),
+            string("abc"),
+            string("hello world, hello world"),
+            string(""),
+            string("dlrow olleh"),
+            string("Hi"),
+            Value::Integer(100),
+            Value::Integer(104),
+            Value::Integer(101),
+            Value::Integer(108),
+        ]
+    );
+    assert_eq!(
+        error_message("string.char(72, 256)"),
+        "bad argument #2 to 'char' (value out of range)"
+    );
+    assert_eq!(
+        error_message("string.sub('x')"),
+        "bad argument #2 to 'sub' (number expected, got no value)"
+    );
+    assert_eq!(
+        error_message("return ('x'):missing()"),
+        "attempt to call a nil value (method 'missing')"
+    );
+}
+
+#[test]
+fn string_patterns() {
+    let code = "
+        local key, value = string.match('key = value', '(%w+)%s*=%s*(%w+)')
+        local start, finish, inner = ('f(a(b)c)d'):find('a(%b())')
+        local words = {}
+        for word in ('one two  three'):gmatch('%a+') do words[#words + 1] = word end
+        return key, value, start, finish, inner, words[3], #words,
+            ('  trim  '):match('^%s*(.-)%s*$'), ('hello'):match('()ll()')
+    ";
+    assert_eq!(
+        run(code).unwrap(),
+        [
+            string("key"),
+            string("value"),
+            Value::Integer(3),
+            Value::Integer(6),
+            string("(b)"),
+            string("three"),
+            Value::Integer(3),
+            string("trim"),
+            Value::Integer(3),
+            Value::Integer(5),
+        ]
+    );
+    let code = "
+        local _, quoted = ([[x = 'it' .. \"s\"]]):match([[(['\"])(.-)%1]])
+        return ('a.b'):find('.', 1, true), ('THE (quick) fox'):find('%f[%a]%a+', 5),
+            quoted, ('abc'):gsub('', '-')
+    ";
+    assert_eq!(
+        run(code).unwrap(),
+        [
+            Value::Integer(2),
+            Value::Integer(6),
+            string("it"),
+            string("-a-b-c-"),
+            Value::Integer(4),
+        ]
+    );
+    let code = "
+        local a = string.gsub('hello world', '(%w+)', '<%1>')
+        local b = string.gsub('$name is $age', '%$(%w+)', {name = 'Bob', age = 42})
+        local c = string.gsub('1 2 3', '%d', function(d) if d ~= '2' then return d * 2 end end)
+        return a, b, c, string.gsub('hello world', 'o', '0', 1)
+    ";
+    assert_eq!(
+        run(code).unwrap(),
+        [
+            string("<hello> <world>"),
+            string("Bob is 42"),
+            string("2 2 6"),
+            string("hell0 world"),
+            Value::Integer(1),
+        ]
+    );
+    assert_eq!(
+        error_message("string.find('a', '[a')"),
+        "malformed pattern (missing ']')"
+    );
+    assert_eq!(
+        error_message("string.match('a', 'a)')"),
+        "invalid pattern capture"
+    );
+    assert_eq!(
+        error_message("string.match('a', '%1')"),
+        "invalid capture index %1"
+    );
+    assert_eq!(
+        run("return string.gsub(12, 'x', 'y')").unwrap(),
+        [string("12"), Value::Integer(0)]
+    );
+    assert_eq!(
+        error_message("string.gsub('a', 'a', '%2')"),
+        "invalid capture index %2"
+    );
+    assert_eq!(
+        error_message("string.gsub('a', 'a', {a = {}})"),
+        "invalid replacement value (a table)"
+    );
+}
+
+#[test]
+fn string_format() {
+    let code = r#"
+        local named = setmetatable({}, {__tostring = function() return "named" end})
+        return string.format("%5d|%-5d|%05d|%+d|%.3d", 42, 42, 42, 42, 7),
+            string.format("%x|%#X|%o|%u", 255, 255, 8, -1),
+            string.format("%.2f|%10.3f|%e|%.3E|%g|%g", 3.14159, 3.14159, 12345.678, 0.00012, 0.1, 1e20),
+            string.format("%a|%.2a|%A", 1.0, 1.999, 255.5),
+            string.format("%5s|%-5s|%.2s|%s|%s", "ab", "ab", "abcdef", 1.5, named),
+            string.format("%c%c%c %5.1f%%", 76, 117, 97, 99.5),
+            string.format("%q", 'say "hi"\n' .. string.char(0) .. '1\t\r'),
+            string.format("%q|%q|%q|%q", 42, 1.5, 1/0, 1 == 1)
+    "#;
+    assert_eq!(
+        run(code).unwrap(),
+        [
+            string("   42|42   |00042|+42|007"),
+            string("ff|0XFF|10|18446744073709551615"),
+            string("3.14|     3.142|1.234568e+04|1.200E-04|0.1|1e+20"),
+            string("0x1p+0|0x2.00p+0|0X1.FFP+7"),
+            string("   ab|ab   |ab|1.5|named"),
+            string("Lua  99.5%"),
+            string("\"say \\\"hi\\\"\\\n\\0001\\9\\13\""),
+            string("42|0x1.8p+0|1e9999|true"),
+        ]
+    );
+    assert_eq!(
+        error_message("string.format('%d', nil)"),
+        "bad argument #2 to 'format' (number expected, got nil)"
+    );
+    assert_eq!(
+        error_message("string.format('%s %s', 1)"),
+        "bad argument #3 to 'format' (no value)"
+    );
+    assert_eq!(
+        error_message("string.format('%d', 1.5)"),
+        "bad argument #2 to 'format' (number has no integer representation)"
+    );
+    assert_eq!(
+        error_message("string.format('%y', 1)"),
+        "invalid conversion '%y' to 'format'"
+    );
+    assert_eq!(
+        error_message("string.format('%#d', 1)"),
+        "invalid conversion specification: '%#d'"
+    );
+    assert_eq!(
+        error_message("string.format('%q', {})"),
+        "bad argument #2 to 'format' (value has no literal form)"
+    );
+}
+
+#[test]
+fn string_packing() {
+    let code = r#"
+        local packed = string.pack("<i4 >I2 z s1 c3", 100, 258, "hi", "abc", "xy")
+        local a, b, c, d, e, next = string.unpack("<i4 >I2 z s1 c3", packed)
+        return #packed, a, b, c, d, e, next, string.unpack("<d", string.pack("<d", 2.5)),
+            string.packsize("i4i8"), string.packsize("!i4i8"), string.packsize("!8 b Xi8 i2"),
+            string.unpack("<i3", "abc"), string.unpack(">i3", "abc")
+    "#;
+    assert_eq!(
+        run(code).unwrap(),
+        [
+            Value::Integer(16),
+            Value::Integer(100),
+            Value::Integer(258),
+            string("hi"),
+            string("abc"),
+            string("xy\0"),
+            Value::Integer(17),
+            Value::Float(2.5),
+            Value::Integer(12),
+            Value::Integer(16),
+            Value::Integer(10),
+            Value::Integer(0x636261),
+            Value::Integer(0x616263),
+            Value::Integer(4),
+        ]
+    );
+    assert_eq!(
+        error_message("string.pack('i2', 40000)"),
+        "bad argument #2 to 'pack' (integer overflow)"
+    );
+    assert_eq!(
+        error_message("string.pack('i17', 1)"),
+        "integral size (17) out of limits [1,16]"
+    );
+    assert_eq!(
+        error_message("string.packsize('s')"),
+        "bad argument #1 to 'packsize' (variable-length format)"
+    );
+    assert_eq!(
+        error_message("string.unpack('i4', 'ab')"),
+        "bad argument #2 to 'unpack' (data string too short)"
+    );
+    assert_eq!(
+        error_message("string.pack('!3 i4', 1)"),
+        "bad argument #1 to 'pack' (format asks for alignment not power of 2)"
+    );
+}
+
+#[test]
+fn byte_strings() {
+    let code = r#"
+        local s = "\xff\0\65\u{20AC}\z
+                   a\
+b"
+        local packed = string.pack("<i2 d", -2, 1.5)
+        return #s, s:byte(1), s:byte(2), s:byte(3), s:byte(-1), "\xe4" < "\xff",
+            string.char(200) == "\200", #string.char(200, 201), string.unpack("<i2 d", packed)
+    "#;
+    assert_eq!(
+        run(code).unwrap(),
+        [
+            Value::Integer(9),
+            Value::Integer(0xff),
+            Value::Integer(0),
+            Value::Integer(65),
+            Value::Integer(b'b' as i64),
+            Value::True,
+            Value::True,
+            Value::Integer(2),
+            Value::Integer(-2),
+            Value::Float(1.5),
+            Value::Integer(11),
+        ]
+    );
+    assert_eq!(
+        run("return ('\\xff\\xfe'):upper()").unwrap(),
+        [Value::String([0xffu8, 0xfe].as_slice().into())]
+    );
+    // Chunks loaded from strings keep raw bytes in their literals
+    assert_eq!(
+        run(r#"return load("return '\xff\xf4\x8f\xbc\x81', [[\xfe]]")()"#).unwrap(),
+        [
+            Value::String([0xffu8, 0xf4, 0x8f, 0xbc, 0x81].as_slice().into()),
+            Value::String([0xfeu8].as_slice().into()),
+        ]
+    );
+    assert!(LuaParser::parse(Rule::Chunk, r"return '\300'").is_err());
+    assert!(LuaParser::parse(Rule::Chunk, r"return '\q'").is_err());
+}
+
+#[test]
+fn table_library() {
+    let code = "
+        local t = {1, 2, 3}
+        table.insert(t, 4)
+        table.insert(t, 1, 0)
+        local last, first = table.remove(t), table.remove(t, 1)
+        local moved = table.move({1, 2, 3, 4, 5}, 1, 3, 2)
+        local sorted = {5, 2, 8, 1, 9, 3, 7, 4, 6, 0}
+        table.sort(sorted, function(a, b) return a > b end)
+        local packed = table.pack(1, nil, 3)
+        local log = {}
+        local proxy = setmetatable({}, {
+            __index = function(_, k) return k * 10 end,
+            __newindex = function(_, k, v) log[#log + 1] = k .. '=' .. v end,
+            __len = function() return 3 end,
+        })
+        table.insert(proxy, 99)
+        return table.concat(t, ','), last, first, table.concat(moved, ','),
+            table.concat(sorted, ' '), packed.n, table.concat(proxy, ','), log[1],
+            table.concat({1, 2.5, 'x'}, '-', 2, 3), table.unpack({1, 2, 3}, 2)
+    ";
+    assert_eq!(
+        run(code).unwrap(),
+        [
+            string("1,2,3"),
+            Value::Integer(4),
+            Value::Integer(0),
+            string("1,1,2,3,5"),
+            string("9 8 7 6 5 4 3 2 1 0"),
+            Value::Integer(3),
+            string("10,20,30"),
+            string("4=99"),
+            string("2.5-x"),
+            Value::Integer(2),
+            Value::Integer(3),
+        ]
+    );
+    assert_eq!(
+        error_message("table.concat({1, {}, 3})"),
+        "invalid value (at index 2) in table for 'concat'"
+    );
+    assert_eq!(
+        error_message("table.insert({1}, 5, 2)"),
+        "bad argument #2 to 'insert' (position out of bounds)"
+    );
+    assert_eq!(
+        error_message("table.insert({}, 1, 2, 3)"),
+        "wrong number of arguments to 'insert'"
+    );
+    assert_eq!(
+        error_message("table.sort({3, 2, 1, 4, 5}, function(a, b) return true end)"),
+        "invalid order function for sorting"
+    );
+}
+
+#[test]
+fn math_library() {
+    let code = "
+        local integral, fraction = math.modf(-3.5)
+        return math.floor(3.7), math.floor(-3.5), math.ceil(3.2), math.floor(1e100),
+            math.type(1), math.type(1.0), math.type('1'), math.abs(-3), math.fmod(-7, 3),
+            math.fmod(7, -3.0), integral, fraction, math.max(1, 2.5, 2), math.min(3, 1, 2),
+            math.tointeger(3.0), math.tointeger(3.5), math.ult(1, -1), math.sqrt(16),
+            math.log(8, 2), math.huge, math.maxinteger, math.mininteger
+    ";
+    assert_eq!(
+        run(code).unwrap(),
+        [
+            Value::Integer(3),
+            Value::Integer(-4),
+            Value::Integer(4),
+            Value::Float(1e100),
+            string("integer"),
+            string("float"),
+            Value::Nil,
+            Value::Integer(3),
+            Value::Integer(-1),
+            Value::Float(1.0),
+            Value::Float(-3.0),
+            Value::Float(-0.5),
+            Value::Float(2.5),
+            Value::Integer(1),
+            Value::Integer(3),
+            Value::Nil,
+            Value::True,
+            Value::Float(4.0),
+            Value::Float(3.0),
+            Value::Float(f64::INFINITY),
+            Value::Integer(i64::MAX),
+            Value::Integer(i64::MIN),
+        ]
+    );
+    assert_eq!(
+        error_message("math.fmod(1, 0)"),
+        "bad argument #2 to 'fmod' (zero)"
+    );
+    assert_eq!(
+        error_message("math.max()"),
+        "bad argument #1 to 'max' (number expected, got no value)"
+    );
+}
+
+#[test]
+fn random_numbers() {
+    let code = "
+        local function draw()
+            return math.random(1, 100), math.random(10), math.random(0), math.random()
+        end
+        local seeds = {math.randomseed(42)}
+        local a = {draw()}
+        math.randomseed(42)
+        local b = {draw()}
+        local same = true
+        for i = 1, 4 do same = same and a[i] == b[i] end
+        local in_range = true
+        for _ = 1, 1000 do
+            local n, f = math.random(-3, 3), math.random()
+            in_range = in_range and -3 <= n and n <= 3 and 0 <= f and f < 1
+        end
+        return same, in_range, seeds[1], seeds[2], a[1], a[2], math.type(a[3]),
+            math.random(5, 5)
+    ";
+    assert_eq!(
+        run(code).unwrap(),
+        [
+            Value::True,
+            Value::True,
+            Value::Integer(42),
+            Value::Integer(0),
+            Value::Integer(50),
+            Value::Integer(6),
+            string("integer"),
+            Value::Integer(5),
+        ]
+    );
+    assert_eq!(
+        error_message("math.random(3, 1)"),
+        "bad argument #1 to 'random' (interval is empty)"
+    );
+    assert_eq!(
+        error_message("math.random(1, 2, 3)"),
+        "wrong number of arguments"
+    );
+}
+
+#[test]
+fn io_library() {
+    let path = std::env::temp_dir().join(format!("lust_io_{}.txt", std::process::id()));
+    let name = path.to_str().unwrap();
+    let code = format!(
+        r#"
+        local name = "{name}"
+        local f = io.open(name, "w")
+        local kind = io.type(f)
+        local same = f:write("first line\n", 42, " ", 1.5, "\n0x10 rest") == f
+        f:close()
+        f = io.open(name)
+        local line, number, float, hex = f:read("l", "n", "n", "n")
+        local bytes, at = f:read(2), f:seek()
+        local rest, eof = f:read("a"), f:read(0)
+        local start = f:seek("set")
+        local first = f:read("L")
+        f:close()
+        local lines = {{}}
+        for l in io.lines(name) do lines[#lines + 1] = l end
+        return kind, same, line, number, float, hex, bytes, at, rest, eof, start, first,
+            #lines, lines[3], io.type(f), io.type(42)
+    "#
+    );
+    let values = run(&code).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(
+        values,
+        [
+            string("file"),
+            Value::True,
+            string("first line"),
+            Value::Integer(42),
+            Value::Float(1.5),
+            Value::Integer(16),
+            string(" r"),
+            Value::Integer(24),
+            string("est"),
+            Value::Nil,
+            Value::Integer(0),
+            string("first line\n"),
+            Value::Integer(3),
+            string("0x10 rest"),
+            string("closed file"),
+            Value::Nil,
+        ]
+    );
+    let missing = run("return io.open('/nonexistent/file')").unwrap();
+    assert_eq!(missing[0], Value::Nil);
+    assert_eq!(
+        missing[1],
+        string("/nonexistent/file: No such file or directory")
+    );
+    assert_eq!(
+        run("return io.stdout:close()").unwrap(),
+        [Value::Nil, string("cannot close standard file")]
+    );
+    assert_eq!(
+        error_message("io.open('x', 'rw')"),
+        "bad argument #2 to 'open' (invalid mode)"
+    );
+    assert_eq!(
+        error_message("io.read('x')"),
+        "bad argument #1 to 'read' (invalid format)"
+    );
+    assert_eq!(
+        error_message("io.lines('/nonexistent/file')"),
+        "cannot open file '/nonexistent/file' (No such file or directory)"
+    );
+}
+
+#[test]
+fn os_library() {
+    let code = "
+        local date = {year = 2020, month = 14, day = 35, hour = 25}
+        local time = os.time(date)
+        local utc = os.date('!*t', 86400)
+        local name = os.tmpname()
+        local renamed = os.rename(name, name .. '.old')
+        local removed = os.remove(name .. '.old')
+        local _, message = os.remove(name .. '.old')
+        return date.year, date.month, date.day, date.hour, date.wday, date.yday,
+            os.date('!%Y-%m-%d %H:%M:%S %a %j %%', 0), utc.day, utc.wday, utc.isdst,
+            os.difftime(10, 4), os.getenv('LUST_NO_SUCH_VARIABLE'), renamed, removed,
+            message == name .. '.old: No such file or directory', math.type(time)
+    ";
+    assert_eq!(
+        run(code).unwrap(),
+        [
+            Value::Integer(2021),
+            Value::Integer(3),
+            Value::Integer(8),
+            Value::Integer(1),
+            Value::Integer(2),
+            Value::Integer(67),
+            string("1970-01-01 00:00:00 Thu 001 %"),
+            Value::Integer(2),
+            Value::Integer(6),
+            Value::False,
+            Value::Float(6.0),
+            Value::Nil,
+            Value::True,
+            Value::True,
+            Value::True,
+            string("integer"),
+        ]
+    );
+    assert_eq!(
+        run("return os.time{year = 2000, month = 1, day = 1, hour = 0} - os.time{year = 1999, month = 12, day = 31, hour = 0}").unwrap(),
+        [Value::Integer(86400)]
+    );
+    assert_eq!(
+        error_message("os.date('%Ez')"),
+        "bad argument #1 to 'date' (invalid conversion specifier '%Ez')"
+    );
+    assert_eq!(
+        error_message("os.time{year = 2020}"),
+        "field 'month' missing in date table"
+    );
+    assert_eq!(
+        error_message("os.time{year = 2020, month = 1.5, day = 1}"),
+        "field 'month' is not an integer"
+    );
+}
+
+#[test]
+fn exit() {
+    // Closing the interpreter unwinds past pcall, then the host drops it
+    let mut interpreter = Interpreter::new();
+    let code = "
+        out = {}
+        setmetatable({}, {__gc = function() out.finalized = pcall(error) == false end})
+        local closed <close> = setmetatable({}, {__close = function() out.closed = true end})
+        pcall(os.exit, 2, true)
+        out.reached = true
+    ";
+    let mut pairs = LuaParser::parse(Rule::Chunk, code).unwrap();
+    let ast = build_ast(&mut pairs);
+    assert!(interpreter.interpret(&ast).is_err());
+    assert_eq!(interpreter.exit_status(), Some(2));
+    let Value::Table(out) = interpreter.get_global("out") else {
+        panic!("Expected table");
+    };
+    drop(interpreter);
+    let out = out.borrow();
+    assert_eq!(
+        [
+            out.get_str("closed"),
+            out.get_str("finalized"),
+            out.get_str("reached")
+        ],
+        [Value::True, Value::True, Value::Nil]
+    );
+    // Exiting right away still flushes the files left open
+    let path = std::env::temp_dir().join(format!("lust_exit_{}.txt", std::process::id()));
+    let mut repl = std::process::Command::new(env!("CARGO_BIN_EXE_repl"))
+        .stdin(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let code = format!(
+        "file = io.open('{}', 'w') file:write('data') os.exit(3)",
+        path.display()
+    );
+    std::io::Write::write_all(&mut repl.stdin.take().unwrap(), code.as_bytes()).unwrap();
+    let status = repl.wait().unwrap();
+    let written = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!((status.code(), written.as_str()), (Some(3), "data"));
+}
+
+#[test]
+fn commands() {
+    let code = "
+        local ok, kind, code = os.execute('exit 3')
+        local pipe = io.popen('echo hello; echo world')
+        local first, rest = pipe:read('l', 'a')
+        local closed = {pipe:close()}
+        local failed = {io.popen('false'):close()}
+        return os.execute(), os.execute('true'), ok, kind, code, first, rest,
+            closed[1], closed[3], failed[1], failed[2], failed[3]
+    ";
+    assert_eq!(
+        run(code).unwrap(),
+        [
+            Value::True,
+            Value::True,
+            Value::Nil,
+            string("exit"),
+            Value::Integer(3),
+            string("hello"),
+            string("world\n"),
+            Value::True,
+            Value::Integer(0),
+            Value::Nil,
+            string("exit"),
+            Value::Integer(1),
+        ]
+    );
+    assert_eq!(
+        run("return os.execute('kill -9 $$')").unwrap(),
+        [Value::Nil, string("signal"), Value::Integer(9)]
+    );
+    // Output left unread does not keep the command from ending
+    let code = "
+        local pipe = io.popen('head -c 1000000 /dev/zero')
+        local dropped = io.popen('head -c 1000000 /dev/zero')
+        dropped:read(1)
+        return #pipe:read(5), (pipe:close())
+    ";
+    assert_eq!(run(code).unwrap(), [Value::Integer(5), Value::Nil]);
+    assert_eq!(
+        error_message("io.popen('true', 'rw')"),
+        "bad argument #2 to 'popen' (invalid mode)"
+    );
+}
+
+#[test]
+fn utf8_library() {
+    let code = r#"
+        local s = "héllo, 世界!"
+        local positions, codes = {}, {}
+        for p, c in utf8.codes("aé世") do
+            positions[#positions + 1] = p
+            codes[#codes + 1] = c
+        end
+        local _, invalid = utf8.len(s, 3)
+        return utf8.len(s), invalid, utf8.char(72, 233, 0x4e16) == "H\u{e9}\u{4e16}",
+            table.concat(positions, ","), table.concat(codes, ","),
+            table.concat({utf8.codepoint(s, 8, -1)}, ","), utf8.len("\u{d800}"),
+            utf8.len("\u{d800}", 1, -1, true), utf8.offset(s, 3), utf8.offset(s, -1),
+            utf8.offset(s, 0, 3), utf8.offset(s, 20), string.match("世界", utf8.charpattern)
+    "#;
+    assert_eq!(
+        run(code).unwrap(),
+        [
+            Value::Integer(10),
+            Value::Integer(3),
+            Value::True,
+            string("1,2,4"),
+            string("97,233,19990"),
+            string("32,19990,30028,33"),
+            Value::Nil,
+            Value::Integer(1),
+            Value::Integer(4),
+            Value::Integer(15),
+            Value::Integer(2),
+            Value::Nil,
+            string("世"),
+        ]
+    );
+    assert_eq!(
+        error_message("utf8.codepoint('\\xff')"),
+        "invalid UTF-8 code"
+    );
+    assert_eq!(
+        error_message("for _ in utf8.codes('\\u{110000}') do end"),
+        "invalid UTF-8 code"
+    );
+    assert_eq!(
+        error_message("utf8.char(-1)"),
+        "bad argument #1 to 'char' (value out of range)"
+    );
+    assert_eq!(
+        error_message("utf8.offset('é', 1, 2)"),
+        "initial position is a continuation byte"
+    );
+}
+
+#[test]
+fn base_functions() {
+    let code = "
+        local point = setmetatable({}, {__tostring = function() return 'point' end})
+        local named = tostring(setmetatable({}, {__name = 'Named'}))
+        return type(nil), type(1), type('x'), type({}), type(print), type(io.stdout),
+            tostring(1.0), tostring(point), named:match('^Named: ') ~= nil,
+            tostring(io.stdout):match('^file %(') ~= nil, select('#', nil, nil),
+            select(-1, 'a', 'b'), select(2, 'a', 'b', 'c')
+    ";
+    assert_eq!(
+        run(code).unwrap(),
+        [
+            string("nil"),
+            string("number"),
+            string("string"),
+            string("table"),
+            string("function"),
+            string("userdata"),
+            string("1.0"),
+            string("point"),
+            Value::True,
+            Value::True,
+            Value::Integer(2),
+            string("b"),
+            string("b"),
+            string("c"),
+        ]
+    );
+    assert_eq!(
+        run(
+            "return tonumber(' 0x10 '), tonumber('1e1'), tonumber('z'), tonumber('ff', 16),
+            tonumber(' -zz ', 36), tonumber('8', 8), tonumber('1.0', 10)"
+        )
+        .unwrap(),
+        [
+            Value::Integer(16),
+            Value::Float(10.0),
+            Value::Nil,
+            Value::Integer(255),
+            Value::Integer(-1295),
+            Value::Nil,
+            Value::Nil,
+        ]
+    );
+    assert_eq!(
+        error_message("tonumber('1', 37)"),
+        "bad argument #2 to 'tonumber' (base out of range)"
+    );
+    assert_eq!(
+        run("return assert(1, 'message', 3)").unwrap(),
+        [Value::Integer(1), string("message"), Value::Integer(3)]
+    );
+    assert_eq!(
+        run("warn('@on'); warn('@off'); warn('hidden', ' warning')").unwrap(),
+        []
+    );
+    assert_eq!(error_message("assert(false)"), "assertion failed!");
+    assert_eq!(error_message("assert(nil, 'custom')"), "custom");
+    assert_eq!(
+        error_message("assert()"),
+        "bad argument #1 to 'assert' (value expected)"
+    );
+    assert_eq!(
+        error_message("type()"),
+        "bad argument #1 to 'type' (value expected)"
+    );
+    assert_eq!(
+        error_message("select(0, 1)"),
+        "bad argument #1 to 'select' (index out of range)"
+    );
+    assert_eq!(
+        error_message("warn('a', {})"),
+        "bad argument #2 to 'warn' (string expected, got table)"
+    );
+    assert_eq!(
+        error_message("tostring(setmetatable({}, {__tostring = function() return {} end}))"),
+        "'__tostring' must return a string"
+    );
+}
+
+#[test]
+fn native_functions() {
+    let mut interpreter = Interpreter::new();
+    let calls = Rc::new(Cell::new(0));
+    let counter = calls.clone();
+    interpreter.set_global(
+        "count",
+        NativeFunction::new("count", move |_, arguments| {
+            counter.set(counter.get() + 1);
+            Ok(vec![
+                Value::Integer(arguments.len() as i64),
+                Value::Integer(counter.get()),
+            ])
+        }),
+    );
+    interpreter.set_global(
+        "apply",
+        NativeFunction::new("apply", |interpreter, mut arguments| {
+            let function = arguments.remove(0);
+            interpreter.call(function, arguments)
+        }),
+    );
+    interpreter.set_global(
+        "fail",
+        NativeFunction::new("fail", |_, _| Err(LuaError::new("failed"))),
+    );
+    let pieces = RefCell::new(vec!["40 + 2", "return "]);
+    interpreter.set_global(
+        "reader",
+        NativeFunction::new("reader", move |_, _| {
+            Ok(pieces.borrow_mut().pop().map(string).into_iter().collect())
+        }),
+    );
+    let code = "
+        local n, calls = count(1, nil, 3)
+        local co = coroutine.wrap(function(...) return count(coroutine.yield(...)) end)
+        co('a')
+        local list = {3, 1, 2}
+        table.sort(list, function(a, b) return apply(function(x, y) return x < y end, a, b) end)
+        return n, calls, type(count), count == count, count ~= apply, co('x', 'y'),
+            apply(function(...) return ... end, 'r'), select(2, pcall(fail)), table.concat(list),
+            print == print, load(reader)()
+    ";
+    let mut pairs = LuaParser::parse(Rule::Chunk, code).unwrap();
+    let ast = build_ast(&mut pairs);
+    assert_eq!(
+        interpreter.interpret(&ast).unwrap(),
+        [
+            Value::Integer(3),
+            Value::Integer(1),
+            string("function"),
+            Value::True,
+            Value::True,
+            Value::Integer(2),
+            string("r"),
+            string("failed"),
+            string("123"),
+            Value::True,
+            Value::Integer(42),
+        ]
+    );
+    assert_eq!(calls.get(), 2);
+}
+
+#[test]
+fn typed_native_functions() {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_global(
+        "add",
+        NativeFunction::typed("add", |_, (a, b): (i64, f64)| Ok(a as f64 + b)),
+    );
+    interpreter.set_global(
+        "greet",
+        NativeFunction::typed("greet", |_, (name, times): (String, Option<usize>)| {
+            Ok(format!("hello {name}").repeat(times.unwrap_or(1)))
+        }),
+    );
+    interpreter.set_global(
+        "sum",
+        NativeFunction::typed("sum", |_, values: Variadic<i64>| {
+            Ok((values.iter().sum::<i64>(), values.len()))
+        }),
+    );
+    interpreter.set_global(
+        "split",
+        NativeFunction::typed("split", |_, list: Vec<i64>| {
+            let (even, odd): (Vec<i64>, Vec<i64>) = list.into_iter().partition(|n| n % 2 == 0);
+            Ok(Variadic(vec![even, odd]))
+        }),
+    );
+    interpreter.set_global(
+        "invert",
+        NativeFunction::typed("invert", |_, map: HashMap<String, i64>| {
+            let inverted: HashMap<i64, String> = map.into_iter().map(|(k, v)| (v, k)).collect();
+            Ok(inverted)
+        }),
+    );
+    interpreter.set_global(
+        "flag",
+        NativeFunction::typed("flag", |_, flag: bool| Ok(!flag)),
+    );
+    let code = "
+        local even, odd = split({1, 2, 3, 4, 5})
+        local inverted = invert({one = 1, two = 2})
+        local total, count = sum(1, 2, '3')
+        return add(1, 2.5), greet('lua'), greet('x', 2), total, count, #even, #odd,
+            inverted[2], flag(nil), sum()
+    ";
+    let mut pairs = LuaParser::parse(Rule::Chunk, code).unwrap();
+    let ast = build_ast(&mut pairs);
+    assert_eq!(
+        interpreter.interpret(&ast).unwrap(),
+        [
+            Value::Float(3.5),
+            string("hello lua"),
+            string("hello xhello x"),
+            Value::Integer(6),
+            Value::Integer(3),
+            Value::Integer(2),
+            Value::Integer(3),
+            string("two"),
+            Value::True,
+            Value::Integer(0),
+            Value::Integer(0),
+        ]
+    );
+    let mut error = |code: &str| {
+        let mut pairs = LuaParser::parse(Rule::Chunk, code).unwrap();
+        let ast = build_ast(&mut pairs);
+        interpreter.interpret(&ast).unwrap_err().to_string()
+    };
+    assert_eq!(
+        error("add(1, 'x')"),
+        "bad argument #2 to 'add' (number expected, got string)"
+    );
+    assert_eq!(
+        error("add(1)"),
+        "bad argument #2 to 'add' (number expected, got no value)"
+    );
+    assert_eq!(
+        error("add(1.5, 2)"),
+        "bad argument #1 to 'add' (number has no integer representation)"
+    );
+    assert_eq!(
+        error("greet('x', -1)"),
+        "bad argument #2 to 'greet' (integer out of range for usize)"
+    );
+    assert_eq!(
+        error("sum(1, 2, {})"),
+        "bad argument #3 to 'sum' (number expected, got table)"
+    );
+    assert_eq!(
+        error("split({1, 'x'})"),
+        "bad argument #1 to 'split' (number expected at index 2, got string)"
+    );
+    assert_eq!(
+        error("invert({a = true})"),
+        "bad argument #1 to 'invert' (number expected at key 'a', got boolean)"
+    );
+}